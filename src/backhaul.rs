@@ -15,6 +15,11 @@ pub(crate) trait Backhaul: Send + Sync {
 
     /// Waits for the next datagram
     async fn recv_from(&self) -> io::Result<(Buff, SocketAddr)>;
+
+    /// Returns the real address of the peer behind an address this backhaul gave out, for backhauls that give out placeholders.
+    fn peer_addr(&self, addr: SocketAddr) -> SocketAddr {
+        addr
+    }
 }
 
 /// A structure that wraps a Backhaul with statistics.
@@ -51,6 +56,10 @@ impl<B: Backhaul> Backhaul for StatsBackhaul<B> {
         (self.on_recv)(bts.len(), addr);
         Ok((bts, addr))
     }
+
+    fn peer_addr(&self, addr: SocketAddr) -> SocketAddr {
+        self.haul.peer_addr(addr)
+    }
 }
 
 #[async_trait::async_trait]
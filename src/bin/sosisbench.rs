@@ -1,7 +1,10 @@
 use std::{
     convert::TryInto,
     net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -29,6 +32,7 @@ enum Subcmds {
     Server(ServerArgs),
     Flood(FloodArgs),
     SelfTest(SelfTestArgs),
+    HandshakeLoad(HandshakeLoadArgs),
 }
 
 /// Client
@@ -62,6 +66,9 @@ struct ServerArgs {
     #[argh(option)]
     /// listening address
     listen: SocketAddr,
+    #[argh(option, default = "1")]
+    /// number of UDP sockets sharing the listening address
+    sockets: usize,
 }
 
 /// Self test
@@ -69,6 +76,18 @@ struct ServerArgs {
 #[argh(subcommand, name = "selftest")]
 struct SelfTestArgs {}
 
+/// Data-path latency under handshake load
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "hsload")]
+struct HandshakeLoadArgs {
+    #[argh(option, default = "2")]
+    /// number of flooding tasks
+    flooders: usize,
+    #[argh(option, default = "1000")]
+    /// number of pings measured with and without load
+    pings: usize,
+}
+
 // #[global_allocator]
 // static ALLOCATOR: dhat::DhatAlloc = dhat::DhatAlloc;
 fn main() -> anyhow::Result<()> {
@@ -78,6 +97,7 @@ fn main() -> anyhow::Result<()> {
         Subcmds::Flood(flood) => smolscale::block_on(flood_main(flood)),
         Subcmds::Client(client) => smolscale::block_on(client_main(client)),
         Subcmds::Server(server) => smolscale::block_on(server_main(server)),
+        Subcmds::HandshakeLoad(args) => smolscale::block_on(hsload_main(args)),
         Subcmds::SelfTest(_) => {
             let client_args = ClientArgs {
                 connect: "127.0.0.1:19999".into(),
@@ -85,6 +105,7 @@ fn main() -> anyhow::Result<()> {
             };
             let server_args = ServerArgs {
                 listen: "127.0.0.1:19999".parse().unwrap(),
+                sockets: 1,
             };
             smolscale::block_on(async move {
                 // for _ in 0..100 {
@@ -139,6 +160,113 @@ async fn flood_main(args: FloodArgs) -> anyhow::Result<()> {
     smol::future::pending().await
 }
 
+async fn hsload_main(args: HandshakeLoadArgs) -> anyhow::Result<()> {
+    let mut cfg = sosistab::ListenerConfig::new(SNAKEOIL_SK.clone());
+    cfg.udp_addr = Some("127.0.0.1:0".parse().unwrap());
+    let listener = cfg.listen().await?;
+    let server_addr = listener.local_addr();
+    let listener = Arc::new(listener);
+    // the server only sees the session once the client sends something, so we echo in the background
+    let _echo: smol::Task<Option<()>> = smolscale::spawn({
+        let listener = listener.clone();
+        async move {
+            let server_session = listener.accept_session().await?;
+            loop {
+                let msg = server_session.recv_bytes().await.ok()?;
+                server_session.send_bytes(msg).await.ok()?;
+            }
+        }
+    });
+    let session = ClientConfig::new(
+        Protocol::DirectUdp,
+        server_addr,
+        (&*SNAKEOIL_SK).into(),
+        Default::default(),
+    )
+    .connect()
+    .await
+    .context("cannot connect to sosistab")?;
+
+    let measure = |label: &'static str| {
+        let session = &session;
+        async move {
+            let mut latencies = Vec::with_capacity(args.pings);
+            let mut lost = 0;
+            for i in 0..args.pings {
+                let start = Instant::now();
+                let ping = (i as u64).to_be_bytes();
+                session.send_bytes(Buff::copy_from_slice(&ping)).await?;
+                // pings can get lost, and then their echoes may arrive late
+                let echoed = async {
+                    loop {
+                        if session.recv_bytes().await?[..] == ping[..] {
+                            return Ok::<_, anyhow::Error>(true);
+                        }
+                    }
+                }
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(1)).await;
+                    Ok(false)
+                })
+                .await?;
+                if echoed {
+                    latencies.push(start.elapsed());
+                } else {
+                    lost += 1;
+                }
+            }
+            anyhow::ensure!(!latencies.is_empty(), "all pings lost");
+            latencies.sort_unstable();
+            let pct = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+            eprintln!(
+                "{}: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}, {} lost",
+                label,
+                pct(50),
+                pct(90),
+                pct(99),
+                latencies[latencies.len() - 1],
+                lost
+            );
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+
+    measure("no load").await?;
+    // every flooder uses many different loopback addresses, like a distributed flood would
+    let flooders: Vec<smol::Task<anyhow::Result<()>>> = (0..args.flooders)
+        .map(|_| {
+            smolscale::spawn(async move {
+                let mut sockets = Vec::new();
+                for _ in 0..64 {
+                    let src: [u8; 3] = rand::random();
+                    let src = SocketAddr::from(([127, src[0], src[1], src[2].max(2)], 0));
+                    sockets.push(smol::net::UdpSocket::bind(src).await?);
+                }
+                let mut garbage = [0u8; 1028];
+                for socket in sockets.iter().cycle() {
+                    rand::Rng::fill(&mut rand::thread_rng(), &mut garbage[..]);
+                    socket.send_to(&garbage, server_addr).await?;
+                    // sending rarely blocks, so yield to avoid starving everything else
+                    smol::future::yield_now().await;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    smol::Timer::after(Duration::from_secs(1)).await;
+    measure("handshake flood").await?;
+    drop(flooders);
+    let stats = listener.listener_stats();
+    eprintln!(
+        "listener processed {} packets: {} failed decryption, {} rate-limited, {} dropped by overloaded workers",
+        stats.packets_processed.load(Ordering::Relaxed),
+        stats.packets_failed.load(Ordering::Relaxed),
+        stats.handshakes_ratelimited.load(Ordering::Relaxed),
+        stats.handshakes_overloaded.load(Ordering::Relaxed),
+    );
+    Ok(())
+}
+
 async fn client_main(args: ClientArgs) -> anyhow::Result<()> {
     // smolscale::permanently_single_threaded();
     let start = Instant::now();
@@ -185,16 +313,14 @@ async fn client_main(args: ClientArgs) -> anyhow::Result<()> {
 
 async fn server_main(args: ServerArgs) -> anyhow::Result<()> {
     // let _dhat = Dhat::start_heap_profiling();
-    let listener_udp =
-        sosistab::Listener::listen_udp(args.listen, SNAKEOIL_SK.clone(), |_, _| (), |_, _| ())
-            .await?;
-    let listener_tcp =
-        sosistab::Listener::listen_tcp(args.listen, SNAKEOIL_SK.clone(), |_, _| (), |_, _| ())
-            .await?;
+    let mut cfg = sosistab::ListenerConfig::new(SNAKEOIL_SK.clone());
+    cfg.udp_addr = Some(args.listen);
+    cfg.tcp_addr = Some(args.listen);
+    cfg.udp_socket_count = args.sockets;
+    let listener = cfg.listen().await?;
     for count in 1u128..3 {
-        let session = listener_udp
+        let session = listener
             .accept_session()
-            .race(listener_tcp.accept_session())
             .await
             .ok_or_else(|| anyhow::anyhow!("failed to accept"))?;
         eprintln!("accepted session {}", count);
@@ -1,8 +1,8 @@
 use crate::{buffer::Buff, crypt};
-use crate::{protocol, runtime, Backhaul, Session, SessionConfig, StatsGatherer};
+use crate::{protocol, runtime, Backhaul, Session, SessionConfig, StatsGatherer, TrafficShape};
 
-use probability::distribution::{Binomial, Distribution};
-use smallvec::SmallVec;
+use futures_util::stream::FuturesUnordered;
+use parking_lot::RwLock;
 use smol::{prelude::*, Task};
 use std::{
     collections::VecDeque,
@@ -11,123 +11,442 @@ use std::{
     time::{Duration, Instant},
 };
 
-use super::worker::ClientWorker;
+use super::scheduler::{ShardHealth, ShardScheduler};
+use super::worker::{ClientWorker, ResumeInfo, ServerPath};
+use super::ConnectError;
+
+/// Creates the backhauls of one network path, for talking to the given server address. Failing is not fatal, since the network interface may come back later.
+pub(crate) type BackhaulGen =
+    Arc<dyn Fn(SocketAddr) -> std::io::Result<Arc<dyn Backhaul>> + 'static + Send + Sync>;
+
+/// How long every shard of a session must be down before the session is declared dead, unless configured otherwise.
+pub(crate) const DEAD_AFTER: Duration = Duration::from_secs(30);
+
+/// How long the handshake with one server address gets before the next one is tried in parallel, as in RFC 8305.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// A network path, through some local interface and transport, that shards can go through.
+#[derive(Clone)]
+pub(crate) struct ClientPath {
+    pub backhaul_gen: BackhaulGen,
+    /// Paths of a higher tier only carry traffic while every path of a lower tier is down.
+    pub tier: usize,
+}
+
+impl ClientPath {
+    /// Creates a path of the lowest tier.
+    pub fn new(backhaul_gen: BackhaulGen) -> Self {
+        Self {
+            backhaul_gen,
+            tier: 0,
+        }
+    }
+}
 
 /// Configures the client.
 #[derive(Clone)]
 pub(crate) struct LowlevelClientConfig {
-    pub server_addr: SocketAddr,
+    /// Candidate addresses of the server, in order of preference. The handshake is raced over them, and the session sticks with the winner.
+    pub server_addrs: Vec<SocketAddr>,
     pub server_pubkey: x25519_dalek::PublicKey,
-    pub backhaul_gen: Arc<dyn Fn() -> Arc<dyn Backhaul> + 'static + Send + Sync>,
+    /// Network paths to the server. Shards are spread over them round-robin.
+    pub paths: Vec<ClientPath>,
     pub num_shards: usize,
     pub reset_interval: Option<Duration>,
     pub gather: Arc<StatsGatherer>,
+    /// Give up connecting after this long.
+    pub connect_timeout: Option<Duration>,
+    /// Give up connecting after this many handshake attempts.
+    pub max_attempts: Option<usize>,
+    pub traffic_shape: TrafficShape,
+    /// Handshake version. Version 3 uses the legacy crypto construction, and version 5 is the hybrid post-quantum handshake.
+    pub version: u64,
+    /// How long every shard must be down before the session is declared dead.
+    pub dead_after: Duration,
+}
+
+impl LowlevelClientConfig {
+    /// Creates the crypter for handshake frames, given a cookie key.
+    pub fn handshake_aead(&self, key: &[u8]) -> crypt::HandshakeAead {
+        crypt::HandshakeAead::new(key, self.version == 3)
+    }
+
+    /// Which network path the given shard goes through.
+    pub fn path_of(&self, shard_id: usize) -> usize {
+        shard_id % self.paths.len()
+    }
+
+    /// Creates a backhaul for the given shard, through its network path, for talking to the given server address.
+    pub fn new_backhaul(
+        &self,
+        shard_id: usize,
+        server_addr: SocketAddr,
+    ) -> std::io::Result<Arc<dyn Backhaul>> {
+        (self.paths[self.path_of(shard_id)].backhaul_gen)(server_addr)
+    }
 }
 
 /// Connects to a remote server, given a closure that generates socket addresses.
-pub(crate) async fn connect_custom(cfg: LowlevelClientConfig) -> std::io::Result<Session> {
+pub(crate) async fn connect_custom(cfg: LowlevelClientConfig) -> Result<Session, ConnectError> {
     let my_long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
     let my_eph_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
     // do the handshake
     let cookie = crypt::Cookie::new(cfg.server_pubkey);
+    // version 5 and above mix in a post-quantum KEM
+    let hybrid = cfg.version >= 5;
+    #[cfg(feature = "post-quantum")]
+    let my_kem_sk = hybrid.then(crypt::MlKemSecret::generate);
     let init_hello = protocol::HandshakeFrame::ClientHello {
         long_pk: (&my_long_sk).into(),
         eph_pk: (&my_eph_sk).into(),
-        version: VERSION,
+        version: cfg.version,
+    };
+    #[cfg(feature = "post-quantum")]
+    let init_hello = match &my_kem_sk {
+        Some(my_kem_sk) => protocol::HandshakeFrame::ClientHelloHybrid {
+            long_pk: (&my_long_sk).into(),
+            eph_pk: (&my_eph_sk).into(),
+            version: cfg.version,
+            kem_ek: Buff::copy_from_slice(my_kem_sk.encapsulation_key()),
+        },
+        None => init_hello,
     };
-    for timeout_factor in (0u32..).map(|x| 2u64.pow(x.min(10))) {
-        let backhaul = (cfg.backhaul_gen)();
-        // send hello
-        let init_hello = crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap())
-            .pad_encrypt_v1(std::slice::from_ref(&init_hello), 1000);
-        backhaul.send_to(init_hello, cfg.server_addr).await?;
-        tracing::trace!("sent client hello");
-        // wait for response
-        let res = backhaul
-            .recv_from()
+    if cfg.server_addrs.is_empty() {
+        return Err(ConnectError::Unreachable(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no server addresses to connect to",
+        )));
+    }
+    let deadline = cfg.connect_timeout.map(|timeout| Instant::now() + timeout);
+    let remaining = |wanted: Duration| match deadline {
+        Some(deadline) => wanted.min(deadline.saturating_duration_since(Instant::now())),
+        None => wanted,
+    };
+    let mut candidates = interleave_families(&cfg.server_addrs);
+    let mut last_err = ConnectError::NoResponse;
+    let mut skew_probes = skew_probes();
+    // every skew a hello has been sent for, since replies may come late
+    let mut probed_skews = vec![0];
+    for (attempt, timeout_factor) in (0u32..).map(|x| 2u64.pow(x.min(10))).enumerate() {
+        let out_of_attempts = cfg
+            .max_attempts
+            .map(|max_attempts| attempt >= max_attempts)
+            .unwrap_or_default();
+        if out_of_attempts || remaining(Duration::MAX).is_zero() {
+            return Err(last_err);
+        }
+        // once the plain hello goes unanswered, the clocks may disagree too much for the server to even decrypt it, so every later attempt also tries a few other clocks
+        let new_skews: Vec<i64> = if attempt >= 1 {
+            skew_probes.by_ref().take(SKEW_PROBES_PER_ATTEMPT).collect()
+        } else {
+            vec![]
+        };
+        probed_skews.extend_from_slice(&new_skews);
+        let hellos = std::iter::once(&0)
+            .chain(&new_skews)
+            .map(|skew| {
+                cfg.handshake_aead(&cookie.generate_c2s_skewed(*skew).next().unwrap())
+                    .pad_encrypt(std::slice::from_ref(&init_hello), 1000)
+            })
+            .collect();
+        // later candidates start late, so they must get the full timeout too
+        let stagger = HAPPY_EYEBALLS_DELAY * (candidates.len() as u32 - 1);
+        let timeout = remaining(Duration::from_secs(timeout_factor.min(10)) + stagger);
+        let res = race_hellos(&cfg, &candidates, hellos)
             .or(async {
-                smol::Timer::after(Duration::from_secs(timeout_factor.min(10))).await;
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timed out",
-                ))
+                smol::Timer::after(timeout).await;
+                Err(ConnectError::NoResponse)
             })
             .await;
         match res {
-            Ok((buf, _)) => {
-                for possible_key in cookie.generate_s2c() {
-                    let decrypter = crypt::LegacyAead::new(&possible_key);
-                    let response = decrypter.pad_decrypt_v1(&buf);
+            Ok((buf, server_addr)) => {
+                let possible_keys = probed_skews.iter().flat_map(|skew| {
+                    cookie
+                        .generate_s2c_skewed(*skew)
+                        .map(move |key| (*skew, key))
+                });
+                for (skew, possible_key) in possible_keys {
+                    let decrypter = cfg.handshake_aead(&possible_key);
+                    let response = decrypter.pad_decrypt(&buf);
                     for response in response.unwrap_or_default() {
-                        if let protocol::HandshakeFrame::ServerHello {
-                            long_pk,
-                            eph_pk,
-                            resume_token,
-                        } = response
-                        {
-                            tracing::trace!("obtained response from server");
-                            if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::ConnectionRefused,
-                                    "bad pubkey",
-                                ));
+                        // replies are not authenticated until the key exchange is done, so anybody could have sent a rejection. they only count if nothing better turns up before we give up
+                        if skew != 0 {
+                            last_err = ConnectError::ClockSkew { minutes: skew };
+                            continue;
+                        }
+                        // never accept a classical reply to a hybrid hello, or vice versa
+                        let (long_pk, resume_token, shared_sec) = match response {
+                            protocol::HandshakeFrame::ServerHello {
+                                long_pk,
+                                eph_pk,
+                                resume_token,
+                            } if !hybrid => (
+                                long_pk,
+                                resume_token,
+                                crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk),
+                            ),
+                            #[cfg(feature = "post-quantum")]
+                            protocol::HandshakeFrame::ServerHelloHybrid {
+                                long_pk,
+                                eph_pk,
+                                resume_token,
+                                kem_ct,
+                            } if hybrid => {
+                                let kem_secret = match my_kem_sk
+                                    .as_ref()
+                                    .and_then(|my_kem_sk| my_kem_sk.decapsulate(&kem_ct))
+                                {
+                                    Some(v) => v,
+                                    None => continue,
+                                };
+                                let shared_sec = crypt::hybrid_mix(
+                                    crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk),
+                                    &kem_secret,
+                                );
+                                (long_pk, resume_token, shared_sec)
+                            }
+                            protocol::HandshakeFrame::ServerRejectVersion { supported } => {
+                                last_err = ConnectError::VersionRejected {
+                                    version: cfg.version,
+                                    supported,
+                                };
+                                continue;
                             }
-                            let shared_sec =
-                                crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                            return Ok(init_session(cookie, resume_token, shared_sec, cfg.clone()));
+                            _ => continue,
+                        };
+                        tracing::trace!("obtained response from server");
+                        if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
+                            last_err = ConnectError::BadServerKey;
+                            continue;
                         }
+                        // tokens from version 4 onwards are bound to our ephemeral key
+                        let proof_key = if cfg.version >= 4 {
+                            Some(crypt::resume_proof_key(&my_eph_sk, &cfg.server_pubkey))
+                        } else {
+                            None
+                        };
+                        tracing::debug!("connected to {}", server_addr);
+                        return Ok(init_session(
+                            server_addr,
+                            cookie,
+                            ResumeInfo::new(
+                                resume_token,
+                                proof_key,
+                                crypt::path_response_key(shared_sec.as_bytes()),
+                            ),
+                            shared_sec,
+                            cfg.clone(),
+                        ));
                     }
                 }
+                // the reply was of no use, so we wait out the attempt rather than asking again right away
+                tracing::debug!("unusable reply from {}: {:?}", server_addr, last_err);
+                smol::Timer::after(remaining(Duration::from_secs(timeout_factor.min(10)))).await;
+            }
+            Err(ConnectError::NoResponse) => {
+                tracing::trace!(
+                    "timed out to {:?} with {}s timeout; trying again",
+                    candidates,
+                    timeout_factor
+                );
+                // the next attempt leads with another candidate, in case the first is blackholed
+                candidates.rotate_left(1);
+                // an unusable reply says more than silence does
+                if !matches!(
+                    last_err,
+                    ConnectError::VersionRejected { .. }
+                        | ConnectError::ClockSkew { .. }
+                        | ConnectError::BadServerKey
+                ) {
+                    last_err = ConnectError::NoResponse;
+                }
             }
             Err(err) => {
-                if err.kind() == std::io::ErrorKind::TimedOut {
-                    tracing::trace!(
-                        "timed out to {} with {}s timeout; trying again",
-                        cfg.server_addr,
-                        timeout_factor
-                    );
+                if cfg.paths.len() > 1 {
+                    // every path failed right away, but their interfaces may come back
+                    tracing::warn!("cannot send client hello through any path: {:?}", err);
+                    smol::Timer::after(remaining(Duration::from_secs(1))).await;
+                    last_err = err;
                     continue;
                 }
                 return Err(err);
             }
         }
     }
-    unimplemented!()
+    unreachable!()
+}
+
+/// How many of the [skew_probes] are sent along with the plain hello on every attempt after the first. They are spread over attempts rather than sent at once, so that a client whose clock is fine doesn't send a burst of hellos whenever the server is slow to answer.
+const SKEW_PROBES_PER_ATTEMPT: usize = 4;
+
+/// Clock skews, in minutes, that hellos are also sent for when the plain hello goes unanswered, most likely first. Cookie keys tolerate two minutes either way, so these cover being off by up to a quarter of an hour, as well as by whole hours, as with time zone mistakes.
+fn skew_probes() -> impl Iterator<Item = i64> {
+    [5, 10, 15]
+        .into_iter()
+        .chain((1..=12).map(|hours| hours * 60))
+        .flat_map(|minutes| [minutes, -minutes])
+}
+
+/// Sends the given client hellos to every candidate server address, through every path, and returns the first reply along with the address it came from. The candidates are started one after another, each when the previous one has had [HAPPY_EYEBALLS_DELAY] to answer or has failed outright.
+async fn race_hellos(
+    cfg: &LowlevelClientConfig,
+    candidates: &[SocketAddr],
+    init_hellos: Vec<Buff>,
+) -> Result<(Buff, SocketAddr), ConnectError> {
+    let hello_to = |server_addr: SocketAddr, path_id: usize| {
+        let init_hellos = init_hellos.clone();
+        async move {
+            let backhaul =
+                (cfg.paths[path_id].backhaul_gen)(server_addr).map_err(ConnectError::Backhaul)?;
+            for init_hello in init_hellos {
+                backhaul
+                    .send_to(init_hello, server_addr)
+                    .await
+                    .map_err(ConnectError::Unreachable)?;
+            }
+            tracing::trace!(
+                "sent client hello to {} through path {}",
+                server_addr,
+                path_id
+            );
+            let (buf, _) = backhaul
+                .recv_from()
+                .await
+                .map_err(ConnectError::Unreachable)?;
+            Ok((buf, server_addr))
+        }
+        .boxed()
+    };
+    let mut running = FuturesUnordered::new();
+    let mut next_candidate = candidates.iter();
+    let mut last_err = None;
+    loop {
+        if running.is_empty() {
+            // the hellos running so far all failed, so there is no point waiting to start the next
+            match next_candidate.next() {
+                Some(server_addr) => running
+                    .extend((0..cfg.paths.len()).map(|path_id| hello_to(*server_addr, path_id))),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        ConnectError::Backhaul(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "no network paths",
+                        ))
+                    }))
+                }
+            }
+        }
+        let delay = async {
+            smol::Timer::after(HAPPY_EYEBALLS_DELAY).await;
+            None
+        };
+        match running.next().or(delay).await {
+            Some(Ok(reply)) => return Ok(reply),
+            Some(Err(err)) => last_err = Some(err),
+            None => {
+                if let Some(server_addr) = next_candidate.next() {
+                    running
+                        .extend((0..cfg.paths.len()).map(|path_id| hello_to(*server_addr, path_id)))
+                }
+            }
+        }
+    }
+}
+
+/// Orders candidate addresses so that IPv4 and IPv6 alternate, starting with the family of the most preferred one, as in RFC 8305. Duplicates are removed.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut deduped: Vec<SocketAddr> = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !deduped.contains(addr) {
+            deduped.push(*addr);
+        }
+    }
+    let first_is_v4 = deduped
+        .first()
+        .map(|addr| addr.is_ipv4())
+        .unwrap_or_default();
+    let (mut first, mut second): (VecDeque<SocketAddr>, VecDeque<SocketAddr>) = deduped
+        .into_iter()
+        .partition(|addr| addr.is_ipv4() == first_is_v4);
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    while let Some(addr) = first.pop_front() {
+        interleaved.push(addr);
+        interleaved.extend(second.pop_front());
+    }
+    interleaved.extend(second);
+    interleaved
 }
-const VERSION: u64 = 3;
 
 fn init_session(
+    server_addr: SocketAddr,
     cookie: crypt::Cookie,
-    resume_token: Buff,
+    resume: ResumeInfo,
     shared_sec: blake3::Hash,
     cfg: LowlevelClientConfig,
 ) -> Session {
     let (mut session, back) = Session::new(SessionConfig {
-        version: VERSION,
+        version: cfg.version,
         gather: cfg.gather.clone(),
         session_key: shared_sec.as_bytes().to_vec(),
         role: crate::Role::Client,
+        shape: cfg.traffic_shape.clone(),
     });
     let back = Arc::new(back);
+    let resume = Arc::new(resume);
+    let path = Arc::new(RwLock::new(ServerPath::new(server_addr, cookie)));
+    let mut workers: Vec<ClientWorker> = (0..cfg.num_shards)
+        .map(|shard_id| {
+            ClientWorker::start(
+                path.clone(),
+                resume.clone(),
+                back.clone(),
+                shard_id as u8,
+                cfg.clone(),
+            )
+        })
+        .collect();
+    // the health of every shard's current worker, for the watchdog
+    let healths: Arc<RwLock<Vec<Arc<ShardHealth>>>> = Arc::new(RwLock::new(
+        workers.iter().map(|w| w.health().clone()).collect(),
+    ));
+    // a session that none of its shards get through for anymore is as good as dead. this runs apart from uploading, so that nothing uploading waits on can keep the session from being declared dead
+    let watchdog: Task<()> = runtime::spawn({
+        let back = back.clone();
+        let healths = healths.clone();
+        let dead_after = cfg.dead_after;
+        async move {
+            let mut all_down_since: Option<Instant> = None;
+            loop {
+                smol::Timer::after(Duration::from_secs(1)).await;
+                if healths.read().iter().all(|h| h.is_down() || h.is_broken()) {
+                    if all_down_since.get_or_insert_with(Instant::now).elapsed() > dead_after {
+                        back.declare_dead();
+                        return;
+                    }
+                } else {
+                    all_down_since = None;
+                }
+            }
+        }
+    });
     let uploader: Task<anyhow::Result<()>> = runtime::spawn(async move {
-        let mut workers: Vec<ClientWorker> = (0..cfg.num_shards)
-            .map(|shard_id| {
-                ClientWorker::start(
-                    cookie.clone(),
-                    resume_token.clone(),
-                    back.clone(),
-                    shard_id as u8,
-                    cfg.clone(),
-                )
-            })
-            .collect();
+        let mut scheduler = ShardScheduler::new((0..workers.len()).map(|shard_id| {
+            let path_id = cfg.path_of(shard_id);
+            (path_id, cfg.paths[path_id].tier)
+        }));
         let mut fired_workers: VecDeque<ClientWorker> = VecDeque::new();
         let mut last_reset = Instant::now();
-        let mut just_respawned = false;
-        for ctr in (0..).cycle() {
-            let to_upload = back.next_outgoing().await?;
-            let random_worker = ctr % workers.len();
-            workers[random_worker].send_upload(to_upload).await;
+        loop {
+            let (to_upload, important) = back.next_outgoing().await?;
+            scheduler.refresh(workers.iter().map(|w| w.health().as_ref()), &cfg.gather);
+            // with every shard down, packets are lost as they would be on the network
+            if important {
+                for shard_id in scheduler.pick_redundant() {
+                    workers[shard_id].send_upload(to_upload.clone());
+                }
+            } else if let Some(shard_id) = scheduler.pick() {
+                workers[shard_id].send_upload(to_upload);
+            }
             if cfg
                 .reset_interval
                 .map(|dur| last_reset.elapsed() > dur)
@@ -135,66 +454,185 @@ fn init_session(
             {
                 tracing::debug!("reset timer expired!");
                 last_reset = Instant::now();
-                if just_respawned {
-                    for worker in workers.iter() {
-                        worker.reset_received_count();
-                    }
-                    just_respawned = false;
-                } else {
-                    // check: are we even that bad?
-                    let worker_packet_count: SmallVec<[usize; 16]> =
-                        workers.iter().map(|w| w.get_received_count()).collect();
-                    let p_value = uniform_pvalue(&worker_packet_count);
-                    tracing::debug!("p-value = {}; {:?}", p_value, worker_packet_count);
-                    if p_value < 0.01 {
-                        // find the worst worker and fire it
-                        let worst_worker_id = workers
-                            .iter()
-                            .enumerate()
-                            .min_by_key(|(worker_id, worker)| {
-                                let count = worker.get_received_count();
-                                tracing::debug!("worker {} has {}", worker_id, count);
-                                count
-                            })
-                            .map(|x| x.0)
-                            .expect("must have a worst worker");
-                        tracing::debug!("replacing worst worker {}", worst_worker_id);
+                // a shard that stopped hearing from the server while others still do probably lost its NAT mapping or got blocked, so it starts over with a fresh socket
+                if workers
+                    .iter()
+                    .any(|w| !w.health().is_down() && !w.health().is_broken())
+                {
+                    for shard_id in 0..workers.len() {
+                        if !workers[shard_id].health().is_down() {
+                            continue;
+                        }
+                        tracing::debug!("replacing down worker {}", shard_id);
                         let new_worker = ClientWorker::start(
-                            cookie.clone(),
-                            resume_token.clone(),
+                            path.clone(),
+                            resume.clone(),
                             back.clone(),
-                            worst_worker_id as u8,
+                            shard_id as u8,
                             cfg.clone(),
                         );
-                        let worst_worker =
-                            std::mem::replace(&mut workers[worst_worker_id], new_worker);
-                        fired_workers.push_back(worst_worker);
+                        healths.write()[shard_id] = new_worker.health().clone();
+                        let down_worker = std::mem::replace(&mut workers[shard_id], new_worker);
+                        fired_workers.push_back(down_worker);
                         if fired_workers.len() > workers.len() {
                             fired_workers.pop_front();
                         }
-                        just_respawned = true;
                     }
                 }
             }
         }
-        unreachable!()
     });
     session.on_drop(move || {
         drop(uploader);
+        drop(watchdog);
     });
     session
 }
 
-// guess whether the given slice is uniformly distributed
-fn uniform_pvalue(vals: &[usize]) -> f64 {
-    if vals.is_empty() {
-        return 0.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientConfig, Protocol};
+
+    #[test]
+    fn forged_rejections_do_not_end_connecting() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let server_pk = x25519_dalek::PublicKey::from(&server_sk);
+            let socket = smol::Async::<std::net::UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let server_addr = socket.get_ref().local_addr().unwrap();
+            // anybody who knows the server's public key can answer for it
+            let hellos = std::sync::atomic::AtomicUsize::new(0);
+            let forger = async {
+                let cookie = crypt::Cookie::new(server_pk);
+                let s2c = crypt::HandshakeAead::new(&cookie.generate_s2c().next().unwrap(), false);
+                let mut buf = [0u8; 2048];
+                loop {
+                    let (_, client_addr) = socket.recv_from(&mut buf).await.unwrap();
+                    hellos.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let reject = protocol::HandshakeFrame::ServerRejectVersion {
+                        supported: vec![99],
+                    };
+                    socket
+                        .send_to(&s2c.pad_encrypt(&[reject], 1000), client_addr)
+                        .await
+                        .unwrap();
+                }
+            };
+            let mut cfg = ClientConfig::new(
+                Protocol::DirectUdp,
+                server_addr,
+                server_pk,
+                Default::default(),
+            );
+            cfg.max_attempts = Some(2);
+            let res = cfg.connect().or(forger).await;
+            // the rejection is only reported once every attempt is used up
+            assert!(hellos.into_inner() > 1);
+            assert!(matches!(
+                res,
+                Err(ConnectError::VersionRejected { supported, .. }) if supported == vec![99]
+            ));
+        })
+    }
+
+    #[test]
+    fn clock_skew_is_probed_a_little_at_a_time() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let server_pk = x25519_dalek::PublicKey::from(&server_sk);
+            let socket = smol::Async::<std::net::UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let server_addr = socket.get_ref().local_addr().unwrap();
+            // a server whose clock is ten minutes ahead
+            let hellos = std::sync::atomic::AtomicUsize::new(0);
+            let server = async {
+                let cookie = crypt::Cookie::new(server_pk);
+                let s2c = crypt::HandshakeAead::new(
+                    &cookie.generate_s2c_skewed(10).next().unwrap(),
+                    false,
+                );
+                let mut buf = [0u8; 2048];
+                loop {
+                    let (n, client_addr) = socket.recv_from(&mut buf).await.unwrap();
+                    hellos.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let readable = cookie.generate_c2s_skewed(10).any(|key| {
+                        crypt::HandshakeAead::new(&key, false)
+                            .pad_decrypt::<protocol::HandshakeFrame>(&buf[..n])
+                            .is_some()
+                    });
+                    if readable {
+                        let reject =
+                            protocol::HandshakeFrame::ServerRejectVersion { supported: vec![4] };
+                        socket
+                            .send_to(&s2c.pad_encrypt(&[reject], 1000), client_addr)
+                            .await
+                            .unwrap();
+                    }
+                }
+            };
+            let mut cfg = ClientConfig::new(
+                Protocol::DirectUdp,
+                server_addr,
+                server_pk,
+                Default::default(),
+            );
+            cfg.max_attempts = Some(2);
+            let res = cfg.connect().or(server).await;
+            assert!(matches!(res, Err(ConnectError::ClockSkew { minutes: 10 })));
+            // the plain hello, then the plain hello with the first few probes
+            assert_eq!(hellos.into_inner(), 2 + SKEW_PROBES_PER_ATTEMPT);
+        })
     }
-    if vals.iter().all(|a| *a == vals[0]) {
-        return 0.0;
+
+    #[test]
+    fn sessions_without_backhauls_die() {
+        smol::block_on(async {
+            let server_pk =
+                x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::new(rand::thread_rng()));
+            let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            // as when the network interface of the only path goes away
+            let cfg = LowlevelClientConfig {
+                server_addrs: vec![server_addr],
+                server_pubkey: server_pk,
+                paths: vec![ClientPath::new(Arc::new(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::AddrNotAvailable,
+                        "interface gone",
+                    ))
+                }))],
+                num_shards: 4,
+                reset_interval: None,
+                gather: Default::default(),
+                connect_timeout: None,
+                max_attempts: None,
+                traffic_shape: Default::default(),
+                version: 4,
+                dead_after: Duration::from_secs(2),
+            };
+            let session = init_session(
+                server_addr,
+                crypt::Cookie::new(server_pk),
+                ResumeInfo::new(Buff::copy_from_slice(b"token"), None, [0; 32]),
+                blake3::hash(b"session key"),
+                cfg,
+            );
+            // far more than the queues of the shards hold
+            for i in 0..5000 {
+                session
+                    .send_bytes(Buff::copy_from_slice(&[1; 100]))
+                    .await
+                    .unwrap();
+                if i % 100 == 0 {
+                    smol::Timer::after(Duration::from_millis(1)).await;
+                }
+            }
+            session
+                .wait_dead()
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(10)).await;
+                    panic!("session never declared dead")
+                })
+                .await;
+        })
     }
-    let total_count = vals.iter().sum::<usize>();
-    let min = vals.iter().min().copied().unwrap();
-    let distro = Binomial::new(total_count, 1.0 / (vals.len() as f64));
-    distro.distribution(min as f64)
 }
@@ -1,21 +1,55 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use smol::{future::Boxed, net::TcpStream};
+use smol::{future::Boxed, net::TcpStream, prelude::*};
+use thiserror::Error;
 
-use crate::{runtime, tcp::TcpClientBackhaul, Session, StatsGatherer};
+use crate::{
+    mimic::MimicBackhaul, runtime, tcp::TcpClientBackhaul, Mimicry, Role, Session, StatsGatherer,
+    TlsConfig, TrafficShape, WebSocketConfig,
+};
+
+use inner::{BackhaulGen, ClientPath};
 
 mod inner;
+mod scheduler;
 mod worker;
 
 /// Configuration of a client.
 #[derive(Clone)]
 pub struct ClientConfig {
     pub server_addr: SocketAddr,
+    /// Further addresses of the same server, such as its address of the other IP family or other ports it listens on. The handshake is raced over all the candidate addresses with staggered starts, and the session uses whichever answers first.
+    pub fallback_addrs: Vec<SocketAddr>,
+    /// Called on every connect to look up candidate addresses, which are preferred over `server_addr` and `fallback_addrs`. If it fails, only those are tried.
+    pub resolver: Option<Resolver>,
     pub server_pk: x25519_dalek::PublicKey,
     pub gather: Arc<StatsGatherer>,
     pub protocol: Protocol,
+    /// Number of shards, each with its own socket, that traffic is spread over. Shards with lower RTT and loss get more of it, and important packets are sent over two shards at once. The health of every shard is recorded in `gather` as `shard{N}_rtt`, `shard{N}_loss` and `shard{N}_weight`, the last being its share of traffic. With [Protocol::Auto], every transport gets this many shards.
     pub shard_count: usize,
+    /// How often shards that seem down while others are up get replaced by new ones, with fresh sockets. If None, they never are.
     pub reset_interval: Option<Duration>,
+    /// Use the legacy (version 3) handshake, for talking to old servers.
+    pub legacy_handshake: bool,
+    /// Use the hybrid post-quantum (version 5) handshake, which mixes an ML-KEM exchange into the session key. Ignored if `legacy_handshake` is set. Only available with the `post-quantum` feature.
+    #[cfg(feature = "post-quantum")]
+    pub post_quantum: bool,
+    /// Local IP addresses to send traffic from, typically those of different network interfaces such as Wi-Fi and cellular. Each is a separate network path with its own shards, and traffic is striped over the paths that work. Statistics of every path are recorded in `gather` as `path{N}_rtt`, `path{N}_loss`, `path{N}_weight` and `path{N}_up`. If empty, the OS picks the path. Ignored for `ProxiedTcp` and `ProxiedTls`, and for `WebSocket` through a connector.
+    pub local_ips: Vec<IpAddr>,
+    /// Give up connecting after this long. If neither this nor `max_attempts` is set, connecting goes on until the server answers.
+    pub connect_timeout: Option<Duration>,
+    /// Give up connecting after this many handshake attempts, each of which tries every candidate address. Attempts time out after 1, 2, 4... seconds, up to 10.
+    pub max_attempts: Option<usize>,
+    /// How the session shapes what it sends, to make its traffic harder to fingerprint.
+    pub traffic_shape: TrafficShape,
+    /// Protocol that UDP traffic is disguised as. The server must disguise its traffic the same way, through `ListenerConfig::udp_mimicry`. Ignored for TCP-based protocols.
+    pub mimicry: Mimicry,
+    /// How TLS is done with [Protocol::DirectTls] and [Protocol::ProxiedTls], the TLS fallback of [Protocol::Auto], and WebSocket over TLS.
+    pub tls: TlsConfig,
 }
 
 impl ClientConfig {
@@ -28,57 +62,201 @@ impl ClientConfig {
     ) -> Self {
         Self {
             server_addr,
+            fallback_addrs: Vec::new(),
+            resolver: None,
             server_pk,
             gather,
             protocol,
             shard_count: 1,
             reset_interval: None,
+            legacy_handshake: false,
+            #[cfg(feature = "post-quantum")]
+            post_quantum: false,
+            local_ips: Vec::new(),
+            connect_timeout: None,
+            max_attempts: None,
+            traffic_shape: Default::default(),
+            mimicry: Mimicry::None,
+            tls: Default::default(),
         }
     }
 
-    /// Builds a Session out of this ClientConfig.
-    pub async fn connect(self) -> std::io::Result<Session> {
-        let server_addr = self.server_addr;
-        let server_pk = self.server_pk;
+    /// Builds a Session out of this ClientConfig. Dropping the returned future cancels connecting.
+    pub async fn connect(self) -> Result<Session, ConnectError> {
+        let mut server_addrs = match &self.resolver {
+            Some(resolver) => resolver().await.unwrap_or_else(|err| {
+                tracing::warn!("cannot resolve server addresses: {:?}", err);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        server_addrs.push(self.server_addr);
+        server_addrs.extend(self.fallback_addrs.iter().copied());
+        // with Auto, the transports are fallbacks for each other, so each gets a tier of its own
+        let transports = match &self.protocol {
+            Protocol::Auto => vec![
+                Protocol::DirectUdp,
+                Protocol::DirectTcp,
+                Protocol::DirectTls,
+            ],
+            protocol => vec![protocol.clone()],
+        };
+        let local_ips = if self.local_ips.is_empty() {
+            vec![None]
+        } else {
+            self.local_ips.iter().copied().map(Some).collect()
+        };
+        let mut paths = Vec::new();
+        for local_ip in local_ips {
+            for (tier, transport) in transports.iter().enumerate() {
+                paths.push(ClientPath {
+                    backhaul_gen: self.backhaul_gen(transport, local_ip, &server_addrs),
+                    tier,
+                });
+            }
+        }
         inner::connect_custom(inner::LowlevelClientConfig {
-            server_addr,
-            server_pubkey: server_pk,
-            backhaul_gen: match self.protocol {
-                Protocol::DirectTcp => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(None, false).add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::DirectTls => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(None, true).add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::ProxiedTcp(cnctr) => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(Some(cnctr.clone()), false)
-                            .add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::DirectUdp => Arc::new(move || {
-                    let addr = if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
-                        .parse::<SocketAddr>().unwrap();
-
-                    #[cfg(not(any(target_os = "linux", target_os = "android")))]
-                    let socket = runtime::new_udp_socket_bind(addr).unwrap();
-
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    let socket =
-                        fastudp::FastUdpSocket::from(std::net::UdpSocket::bind(addr).unwrap());
-                    Arc::new(socket)
-                }),
-            },
-            num_shards: self.shard_count,
+            server_addrs,
+            server_pubkey: self.server_pk,
+            version: self.handshake_version(),
+            // every transport gets the configured number of shards, and every path at least one
+            num_shards: (self.shard_count * transports.len()).max(paths.len()),
+            paths,
             reset_interval: self.reset_interval,
             gather: self.gather,
+            connect_timeout: self.connect_timeout,
+            max_attempts: self.max_attempts,
+            traffic_shape: self.traffic_shape,
+            dead_after: inner::DEAD_AFTER,
         })
         .await
     }
+
+    /// The version of the handshake to speak.
+    fn handshake_version(&self) -> u64 {
+        if self.legacy_handshake {
+            return 3;
+        }
+        #[cfg(feature = "post-quantum")]
+        if self.post_quantum {
+            return 5;
+        }
+        4
+    }
+
+    /// Creates the backhaul generator for the network path over the given transport, through the given local IP address or whatever the OS picks. TCP-based backhauls know the server key at every candidate address.
+    fn backhaul_gen(
+        &self,
+        transport: &Protocol,
+        local_ip: Option<IpAddr>,
+        server_addrs: &[SocketAddr],
+    ) -> BackhaulGen {
+        let server_pk = self.server_pk;
+        let mimicry = self.mimicry;
+        let server_addrs = server_addrs.to_vec();
+        let tcp_backhaul = move |connector: Option<Connector>, tls: Option<TlsConfig>| {
+            server_addrs
+                .iter()
+                .fold(TcpClientBackhaul::new(connector, tls), |backhaul, addr| {
+                    backhaul.add_remote_key(*addr, server_pk)
+                })
+        };
+        let local_connector = || -> Option<Connector> {
+            let local_addr = SocketAddr::new(local_ip?, 0);
+            Some(Arc::new(move |addr| {
+                runtime::connect_tcp_from(local_addr, addr).boxed()
+            }))
+        };
+        match transport.clone() {
+            Protocol::DirectTcp => {
+                let connector = local_connector();
+                Arc::new(move |_| Ok(Arc::new(tcp_backhaul(connector.clone(), None))))
+            }
+            Protocol::DirectTls => {
+                let connector = local_connector();
+                let tls = self.tls.clone();
+                Arc::new(move |_| Ok(Arc::new(tcp_backhaul(connector.clone(), Some(tls.clone())))))
+            }
+            Protocol::ProxiedTcp(cnctr) => {
+                Arc::new(move |_| Ok(Arc::new(tcp_backhaul(Some(cnctr.clone()), None))))
+            }
+            Protocol::ProxiedTls(cnctr) => {
+                let tls = self.tls.clone();
+                Arc::new(move |_| {
+                    Ok(Arc::new(tcp_backhaul(
+                        Some(cnctr.clone()),
+                        Some(tls.clone()),
+                    )))
+                })
+            }
+            Protocol::WebSocket(websocket) => {
+                let connector = websocket.connector.clone().or_else(local_connector);
+                let tls = Some(self.tls.clone()).filter(|_| websocket.tls);
+                Arc::new(move |_| {
+                    Ok(Arc::new(
+                        tcp_backhaul(connector.clone(), tls.clone()).websocket(websocket.clone()),
+                    ))
+                })
+            }
+            Protocol::DirectUdp => Arc::new(move |server_addr: SocketAddr| {
+                let addr = match local_ip {
+                    Some(ip) => SocketAddr::new(ip, 0),
+                    None => if server_addr.is_ipv4() {
+                        "0.0.0.0:0"
+                    } else {
+                        "[::]:0"
+                    }
+                    .parse::<SocketAddr>()
+                    .unwrap(),
+                };
+
+                #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                let socket = runtime::new_udp_socket_bind(addr)?;
+
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                let socket = fastudp::FastUdpSocket::from(std::net::UdpSocket::bind(addr)?);
+                Ok(Arc::new(MimicBackhaul::new(socket, mimicry, Role::Client)))
+            }),
+            Protocol::Auto => unreachable!("Auto is not a transport by itself"),
+        }
+    }
+}
+
+/// Why connecting to a server failed.
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    /// Hellos could not even be sent to the server, for example because the network is down.
+    #[error("server unreachable: {0}")]
+    Unreachable(#[source] std::io::Error),
+    /// The server never answered, for example because it is down or blocked.
+    #[error("no response from server")]
+    NoResponse,
+    /// The server does not accept our handshake version.
+    #[error("server rejected handshake version {version}, accepting only {supported:?}")]
+    VersionRejected { version: u64, supported: Vec<u64> },
+    /// The server only answered a hello made as if our clock were off by this many minutes, so it is too far off for a session to work.
+    #[error("clock is off from the server's by about {minutes} minutes")]
+    ClockSkew { minutes: i64 },
+    /// No backhaul could be created to send hellos through, for example because a local IP address is gone.
+    #[error("cannot create backhaul: {0}")]
+    Backhaul(#[source] std::io::Error),
+    /// The server answered with a different long-term public key than expected.
+    #[error("server has a different public key")]
+    BadServerKey,
+}
+
+impl From<ConnectError> for std::io::Error {
+    fn from(err: ConnectError) -> Self {
+        let kind = match &err {
+            ConnectError::Unreachable(err) | ConnectError::Backhaul(err) => err.kind(),
+            ConnectError::NoResponse => std::io::ErrorKind::TimedOut,
+            ConnectError::VersionRejected { .. } | ConnectError::BadServerKey => {
+                std::io::ErrorKind::ConnectionRefused
+            }
+            ConnectError::ClockSkew { .. } => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
 }
 
 /// Underlying protocol for a sosistab session.
@@ -88,12 +266,22 @@ pub enum Protocol {
     DirectTcp,
     /// "Direct" TLS.
     DirectTls,
-    /// "Proxied" TCP that instead calls a function that returns a TCP connection.
+    /// "Proxied" TCP that instead calls a function that returns a TCP connection, such as the connector of an [HttpProxy](crate::HttpProxy) or a [Socks5Proxy](crate::Socks5Proxy).
     ProxiedTcp(Connector),
+    /// "Proxied" TLS, done as the client's TLS config says over a connection that the function returns.
+    ProxiedTls(Connector),
     /// "Direct UDP that does not go through a proxy.
     DirectUdp,
+    /// WebSocket, perhaps over TLS or through a proxy, to a server listening for TCP.
+    WebSocket(WebSocketConfig),
+    /// Races UDP, TCP and TLS when connecting, then uses UDP, falling back to TCP and then TLS whenever the preferred transports stop getting through. The session, and any [Multiplex](crate::Multiplex) over it, survives switching transports. The server must listen for UDP and TCP on the same address.
+    Auto,
 }
 
+/// Looks up candidate addresses of the server, in order of preference.
+pub type Resolver =
+    Arc<dyn Fn() -> Boxed<std::io::Result<Vec<SocketAddr>>> + Send + Sync + 'static>;
+
 pub type Connector =
     Arc<dyn Fn(SocketAddr) -> Boxed<std::io::Result<TcpStream>> + Send + Sync + 'static>;
 
@@ -105,21 +293,31 @@ pub async fn connect_udp(
     gather: Arc<StatsGatherer>,
 ) -> std::io::Result<Session> {
     inner::connect_custom(inner::LowlevelClientConfig {
-        server_addr,
+        server_addrs: vec![server_addr],
         server_pubkey: pubkey,
-        backhaul_gen: Arc::new(move || {
-            Arc::new(
-                runtime::new_udp_socket_bind(
-                    if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
-                        .parse::<SocketAddr>().unwrap()
-                ).unwrap(),
-            )
-        }),
+        paths: vec![ClientPath::new(Arc::new(move |server_addr: SocketAddr| {
+            Ok(Arc::new(runtime::new_udp_socket_bind(
+                if server_addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                }
+                .parse::<SocketAddr>()
+                .unwrap(),
+            )?))
+        }))],
         num_shards: 4,
         reset_interval: Some(Duration::from_secs(3)),
         gather,
+        connect_timeout: None,
+        max_attempts: None,
+        traffic_shape: Default::default(),
+        // callers of this predate version 4, and may still talk to servers that only know version 3
+        version: 3,
+        dead_after: inner::DEAD_AFTER,
     })
     .await
+    .map_err(Into::into)
 }
 
 /// Connects to a remote server over UDP.
@@ -130,14 +328,23 @@ pub async fn connect_tcp(
     gather: Arc<StatsGatherer>,
 ) -> std::io::Result<Session> {
     inner::connect_custom(inner::LowlevelClientConfig {
-        server_addr,
+        server_addrs: vec![server_addr],
         server_pubkey: pubkey,
-        backhaul_gen: Arc::new(move || {
-            Arc::new(TcpClientBackhaul::new(None, false).add_remote_key(server_addr, pubkey))
-        }),
+        paths: vec![ClientPath::new(Arc::new(move |_| {
+            Ok(Arc::new(
+                TcpClientBackhaul::new(None, None).add_remote_key(server_addr, pubkey),
+            ))
+        }))],
         num_shards: 16,
         reset_interval: None,
         gather,
+        connect_timeout: None,
+        max_attempts: None,
+        traffic_shape: Default::default(),
+        // callers of this predate version 4, and may still talk to servers that only know version 3
+        version: 3,
+        dead_after: inner::DEAD_AFTER,
     })
     .await
+    .map_err(Into::into)
 }
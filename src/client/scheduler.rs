@@ -0,0 +1,329 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use smallvec::smallvec;
+
+use crate::{EmaCalculator, SVec, StatsGatherer};
+
+/// How often shard weights are recomputed from the health measurements.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Smallest weight of a shard, relative to the healthiest one. Every shard keeps getting some traffic, since that is what drives its measurements.
+const MIN_WEIGHT: f64 = 0.1;
+
+/// RTTs below this are considered equally good, so that scheduling does not chase noise on fast links.
+const MIN_RTT: Duration = Duration::from_millis(5);
+
+/// A shard whose ClientResumes have gone unanswered for this long is considered down, typically because its network path is. A shard that has not sent any for this long is no longer considered down, since nothing is known about it anymore.
+const DOWN_AFTER: Duration = Duration::from_secs(3);
+
+/// Health of a shard, measured by timing the ClientResumes its worker regularly sends to the server.
+pub(crate) struct ShardHealth {
+    /// Whether the shard has no backhaul, typically because its network interface is gone.
+    broken: AtomicBool,
+    inner: Mutex<HealthInner>,
+}
+
+struct HealthInner {
+    rtt: EmaCalculator,
+    loss: EmaCalculator,
+    /// When the ClientResume we are waiting for an answer to was sent.
+    outstanding: Option<Instant>,
+    /// When the first of the ClientResumes sent since the last answer was sent.
+    first_unanswered: Option<Instant>,
+    /// When a ClientResume was last sent.
+    last_sent: Option<Instant>,
+}
+
+impl ShardHealth {
+    /// Creates the health of a shard that has not been measured yet.
+    pub fn new() -> Self {
+        Self {
+            broken: AtomicBool::new(false),
+            inner: Mutex::new(HealthInner {
+                rtt: EmaCalculator::new_unset(0.2),
+                loss: EmaCalculator::new(0.0, 0.1),
+                outstanding: None,
+                first_unanswered: None,
+                last_sent: None,
+            }),
+        }
+    }
+
+    /// Records that a ClientResume was just sent. If the previous one is still unanswered, it counts as lost, unless the shard was idle for so long that it tells us nothing.
+    pub fn resume_sent(&self) {
+        let mut inner = self.inner.lock();
+        let now = Instant::now();
+        let was_idle = inner
+            .last_sent
+            .map(|last| now.saturating_duration_since(last) > DOWN_AFTER)
+            .unwrap_or(true);
+        if inner.outstanding.replace(now).is_some() && !was_idle {
+            inner.loss.update(1.0);
+        }
+        if was_idle || inner.first_unanswered.is_none() {
+            inner.first_unanswered = Some(now);
+        }
+        inner.last_sent = Some(now);
+    }
+
+    /// Records that the server answered a ClientResume.
+    pub fn resume_answered(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(sent) = inner.outstanding.take() {
+            inner.rtt.update(sent.elapsed().as_secs_f64());
+            inner.loss.update(0.0);
+            inner.first_unanswered = None;
+        }
+    }
+
+    /// Records whether the shard could create its backhaul.
+    pub fn set_broken(&self, broken: bool) {
+        self.broken.store(broken, Ordering::Relaxed)
+    }
+
+    /// Whether the shard has no backhaul. Such a shard must not be sent anything, since nothing drains its queue.
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    /// Whether the shard seems to be down.
+    pub fn is_down(&self) -> bool {
+        let inner = self.inner.lock();
+        let unanswered = inner
+            .first_unanswered
+            .map(|first| first.elapsed() > DOWN_AFTER)
+            .unwrap_or_default();
+        let active = inner
+            .last_sent
+            .map(|last| last.elapsed() < DOWN_AFTER)
+            .unwrap_or_default();
+        unanswered && active
+    }
+
+    /// Smoothed round-trip time, if measured yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        let inner = self.inner.lock();
+        if inner.rtt.is_set() {
+            Some(Duration::from_secs_f64(inner.rtt.mean()))
+        } else {
+            None
+        }
+    }
+
+    /// Smoothed loss rate, between 0 and 1.
+    pub fn loss(&self) -> f64 {
+        self.inner.lock().loss.mean()
+    }
+}
+
+/// Decides which shards upload packets go through. Traffic is spread by smooth weighted round-robin, with weights favoring shards with low RTT and loss. Shards that are down or broken get nothing, and the keepalives of their workers notice when they come back. Since every network path has its own shards, this stripes traffic over the paths and fails over between them.
+///
+/// Only the lowest tier of paths with a shard that is up carries traffic, so that fallback transports such as TCP are only used while the preferred ones are down.
+pub(crate) struct ShardScheduler {
+    /// Network path of every shard.
+    paths: SVec<usize>,
+    /// Tier of the network path of every shard.
+    tiers: SVec<usize>,
+    weights: SVec<f64>,
+    credits: SVec<f64>,
+    last_refresh: Option<Instant>,
+}
+
+impl ShardScheduler {
+    /// Creates a scheduler over shards going through the given network paths, each given with its tier. Initially, the shards of the lowest tier are weighted equally.
+    pub fn new(paths: impl Iterator<Item = (usize, usize)>) -> Self {
+        let (paths, tiers): (SVec<usize>, SVec<usize>) = paths.unzip();
+        let shard_count = paths.len();
+        let lowest_tier = tiers.iter().min().copied().unwrap_or_default();
+        Self {
+            weights: tiers
+                .iter()
+                .map(|tier| if *tier == lowest_tier { 1.0 } else { 0.0 })
+                .collect(),
+            paths,
+            tiers,
+            credits: smallvec![0.0; shard_count],
+            last_refresh: None,
+        }
+    }
+
+    /// Recomputes the weights from the health of every shard if they are due, recording them in the gatherer.
+    pub fn refresh<'a>(
+        &mut self,
+        healths: impl Iterator<Item = &'a ShardHealth>,
+        gather: &StatsGatherer,
+    ) {
+        if self
+            .last_refresh
+            .map(|last| last.elapsed() < REFRESH_INTERVAL)
+            .unwrap_or_default()
+        {
+            return;
+        }
+        self.last_refresh = Some(Instant::now());
+        let healths: SVec<&ShardHealth> = healths.collect();
+        let measured: SVec<(Option<Duration>, f64, bool)> = healths
+            .iter()
+            .map(|health| {
+                let down = health.is_down() || health.is_broken();
+                (health.rtt(), health.loss(), down)
+            })
+            .collect();
+        // shards of fallback tiers are left alone, as long as a better tier works
+        let active_tier = measured
+            .iter()
+            .zip(self.tiers.iter())
+            .filter(|((_, _, down), _)| !down)
+            .map(|(_, tier)| *tier)
+            .min()
+            .or_else(|| self.tiers.iter().min().copied())
+            .unwrap_or_default();
+        // unmeasured shards get the benefit of the doubt, so that they are tried
+        let scores: SVec<Option<f64>> = measured
+            .iter()
+            .map(|(rtt, loss, down)| {
+                rtt.filter(|_| !down)
+                    .map(|rtt| (1.0 - loss).powi(2) / rtt.max(MIN_RTT).as_secs_f64())
+            })
+            .collect();
+        let best = scores
+            .iter()
+            .zip(self.tiers.iter())
+            .filter(|(_, tier)| **tier == active_tier)
+            .filter_map(|(score, _)| *score)
+            .fold(None, |best: Option<f64>, score| {
+                Some(best.map(|best| best.max(score)).unwrap_or(score))
+            });
+        self.weights = scores
+            .iter()
+            .zip(measured.iter())
+            .zip(self.tiers.iter())
+            .map(|((score, (_, _, down)), tier)| match (score, best) {
+                _ if *down || *tier > active_tier => 0.0,
+                (Some(score), Some(best)) if best > 0.0 => (score / best).max(MIN_WEIGHT),
+                _ => 1.0,
+            })
+            .collect();
+        // keeps the recorded weights meaningful when every shard is down
+        let total: f64 = self.weights.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+        for (shard_id, ((rtt, loss, _), weight)) in
+            measured.iter().zip(self.weights.iter()).enumerate()
+        {
+            if let Some(rtt) = rtt {
+                gather.update(&format!("shard{}_rtt", shard_id), rtt.as_secs_f32());
+            }
+            gather.update(&format!("shard{}_loss", shard_id), *loss as f32);
+            gather.update(
+                &format!("shard{}_weight", shard_id),
+                (weight / total) as f32,
+            );
+        }
+        // the same, aggregated over the shards of every path
+        let path_count = self.paths.iter().max().map(|max| max + 1).unwrap_or(0);
+        for path_id in 0..path_count {
+            let shards = || {
+                self.paths
+                    .iter()
+                    .zip(measured.iter().zip(self.weights.iter()))
+                    .filter(move |(path, _)| **path == path_id)
+                    .map(|(_, shard)| shard)
+            };
+            let shard_count = shards().count() as f64;
+            if let Some(rtt) = shards()
+                .filter(|((_, _, down), _)| !down)
+                .filter_map(|((rtt, _, _), _)| *rtt)
+                .min()
+            {
+                gather.update(&format!("path{}_rtt", path_id), rtt.as_secs_f32());
+            }
+            gather.update(
+                &format!("path{}_loss", path_id),
+                (shards().map(|((_, loss, _), _)| loss).sum::<f64>() / shard_count) as f32,
+            );
+            gather.update(
+                &format!("path{}_weight", path_id),
+                (shards().map(|(_, weight)| weight).sum::<f64>() / total) as f32,
+            );
+            gather.update(
+                &format!("path{}_up", path_id),
+                if shards().any(|((_, _, down), _)| !down) {
+                    1.0
+                } else {
+                    0.0
+                },
+            );
+        }
+    }
+
+    /// Picks the shard to send the next packet through, if any shard is up.
+    pub fn pick(&mut self) -> Option<usize> {
+        let total: f64 = self.weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        for (credit, weight) in self.credits.iter_mut().zip(self.weights.iter()) {
+            *credit += weight;
+        }
+        let chosen = self
+            .credits
+            .iter()
+            .zip(self.weights.iter())
+            .enumerate()
+            .filter(|(_, (_, weight))| **weight > 0.0)
+            .max_by(|a, b| a.1 .0.total_cmp(b.1 .0))
+            .map(|(shard_id, _)| shard_id)?;
+        self.credits[chosen] -= total;
+        Some(chosen)
+    }
+
+    /// Picks two different shards to send an important packet through: the one it would normally go through, and the healthiest of the others. Fewer are picked if fewer shards are up.
+    pub fn pick_redundant(&mut self) -> SVec<usize> {
+        let mut chosen: SVec<usize> = SVec::new();
+        let first = match self.pick() {
+            Some(first) => first,
+            None => return chosen,
+        };
+        chosen.push(first);
+        if let Some((second, _)) = self
+            .weights
+            .iter()
+            .enumerate()
+            .filter(|(shard_id, weight)| *shard_id != first && **weight > 0.0)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+        {
+            chosen.push(second);
+        }
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_shards_get_nothing() {
+        let healths: Vec<ShardHealth> = (0..3).map(|_| ShardHealth::new()).collect();
+        healths[1].set_broken(true);
+        let gather = StatsGatherer::default();
+        let mut scheduler = ShardScheduler::new((0..3).map(|shard_id| (shard_id, 0)));
+        scheduler.refresh(healths.iter(), &gather);
+        for _ in 0..100 {
+            assert_ne!(scheduler.pick(), Some(1));
+            assert!(!scheduler.pick_redundant().contains(&1));
+        }
+        assert_eq!(scheduler.pick_redundant().len(), 2);
+        // nothing goes anywhere once every shard is broken
+        for health in healths.iter() {
+            health.set_broken(true);
+        }
+        scheduler.last_refresh = None;
+        scheduler.refresh(healths.iter(), &gather);
+        assert_eq!(scheduler.pick(), None);
+        assert!(scheduler.pick_redundant().is_empty());
+    }
+}
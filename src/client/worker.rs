@@ -1,22 +1,103 @@
 use std::{
     net::SocketAddr,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use smol::channel::{Receiver, Sender};
+use parking_lot::RwLock;
+use smol::{
+    channel::{Receiver, Sender},
+    prelude::*,
+};
 
 use crate::{backhaul::Backhaul, protocol::HandshakeFrame, runtime, Buff, SessionBack};
 
-use super::inner::LowlevelClientConfig;
+use super::{inner::LowlevelClientConfig, scheduler::ShardHealth};
+
+/// Resume credentials shared by all the workers of a session.
+pub(crate) struct ResumeInfo {
+    token: RwLock<Buff>,
+    /// Key proving possession of the ephemeral key the token is bound to. Legacy (version 3) tokens are not bound.
+    proof_key: Option<[u8; 32]>,
+    /// Key for answering the server's path challenges, derived from the session key.
+    path_key: [u8; 32],
+}
+
+impl ResumeInfo {
+    /// Creates new resume credentials.
+    pub fn new(token: Buff, proof_key: Option<[u8; 32]>, path_key: [u8; 32]) -> Self {
+        Self {
+            token: RwLock::new(token),
+            proof_key,
+            path_key,
+        }
+    }
+
+    /// Creates the frame for resuming the given shard.
+    fn client_resume(&self, shard_id: u8) -> HandshakeFrame {
+        let resume_token = self.token.read().clone();
+        if let Some(proof_key) = &self.proof_key {
+            let time_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            let proof = crate::crypt::resume_proof(proof_key, &resume_token, shard_id, time_ms);
+            HandshakeFrame::ClientResumeBound {
+                resume_token,
+                shard_id,
+                time_ms,
+                proof,
+            }
+        } else {
+            HandshakeFrame::ClientResume {
+                resume_token,
+                shard_id,
+            }
+        }
+    }
+}
+
+/// Where the server is. Shared by all the workers of a session, since the server can ask the session to migrate elsewhere.
+pub(crate) struct ServerPath {
+    addr: SocketAddr,
+    cookie: crate::crypt::Cookie,
+    /// Where the server was before the last migration. Packets from there are still accepted, since the server only moves each shard once it hears from it at the new address.
+    prev_addr: Option<SocketAddr>,
+}
+
+impl ServerPath {
+    /// Creates a new server path.
+    pub fn new(addr: SocketAddr, cookie: crate::crypt::Cookie) -> Self {
+        Self {
+            addr,
+            cookie,
+            prev_addr: None,
+        }
+    }
+
+    fn is_server(&self, src: SocketAddr) -> bool {
+        src == self.addr || Some(src) == self.prev_addr
+    }
+}
+
+/// An alternative server address we were asked to migrate to, and are checking before committing to it.
+struct Probe {
+    addr: SocketAddr,
+    cookie: crate::crypt::Cookie,
+    tries: usize,
+    last_sent: Instant,
+}
+
+/// How many times a migration target is probed before giving up on it.
+const PROBE_TRIES: usize = 5;
+
+/// How long a shard can go without hearing from the server before it starts sending a ClientResume every second until it does. This keeps NAT mappings alive, and lets sessions that only receive notice that the server is gone.
+const KEEPALIVE_AFTER: Duration = Duration::from_secs(10);
 
 /// Encapsulates a worker "actor".
 pub(crate) struct ClientWorker {
-    received_count: Arc<AtomicUsize>,
+    health: Arc<ShardHealth>,
     send_upload: Sender<Buff>,
     _task: smol::Task<()>,
 }
@@ -24,26 +105,26 @@ pub(crate) struct ClientWorker {
 impl ClientWorker {
     /// Spins off a new ClientWorker.
     pub fn start(
-        cookie: crate::crypt::Cookie,
-        resume_token: Buff,
+        path: Arc<RwLock<ServerPath>>,
+        resume: Arc<ResumeInfo>,
         session_back: Arc<SessionBack>,
         shard_id: u8,
         cfg: LowlevelClientConfig,
     ) -> Self {
-        let received_count = Arc::new(AtomicUsize::new(0));
+        let health = Arc::new(ShardHealth::new());
         let (send_upload, recv_upload) = smol::channel::bounded(128);
         // spawn a task
         let _task = {
-            let received_count = received_count.clone();
+            let health = health.clone();
             runtime::spawn(async move {
                 while let Err(err) = client_backhaul_once(
-                    cookie.clone(),
-                    resume_token.clone(),
+                    path.clone(),
+                    resume.clone(),
                     session_back.clone(),
                     recv_upload.clone(),
                     shard_id,
                     cfg.clone(),
-                    received_count.clone(),
+                    health.clone(),
                 )
                 .await
                 {
@@ -54,49 +135,54 @@ impl ClientWorker {
         };
         // create the stuff
         Self {
-            received_count,
+            health,
             send_upload,
             _task,
         }
     }
 
-    /// Sends an upload through this ClientWorker.
-    pub async fn send_upload(&self, buff: Buff) {
-        self.send_upload.send(buff).await.expect("somehow died")
-    }
-
-    /// Reads the received count off of this ClientWorker
-    pub fn get_received_count(&self) -> usize {
-        self.received_count.load(Ordering::SeqCst)
+    /// Sends an upload through this ClientWorker. If its queue is full, as when the worker has no backhaul to drain it, the upload is dropped like any packet the network loses.
+    pub fn send_upload(&self, buff: Buff) {
+        if self.send_upload.try_send(buff).is_err() {
+            tracing::trace!("upload queue of shard full, dropping packet");
+        }
     }
 
-    /// Resets the received count.
-    pub fn reset_received_count(&self) {
-        self.received_count.store(0, Ordering::SeqCst)
+    /// Gets the measured health of this ClientWorker's shard.
+    pub fn health(&self) -> &Arc<ShardHealth> {
+        &self.health
     }
 }
 
 async fn client_backhaul_once(
-    cookie: crate::crypt::Cookie,
-    resume_token: Buff,
+    path: Arc<RwLock<ServerPath>>,
+    resume: Arc<ResumeInfo>,
     session_back: Arc<SessionBack>,
     recv_upload: Receiver<Buff>,
     shard_id: u8,
     cfg: LowlevelClientConfig,
-    received_count: Arc<AtomicUsize>,
+    health: Arc<ShardHealth>,
 ) -> anyhow::Result<()> {
     let mut updated = false;
-    let socket: Arc<dyn Backhaul> = (cfg.backhaul_gen)();
+    let socket = cfg.new_backhaul(shard_id as usize, path.read().addr);
+    health.set_broken(socket.is_err());
+    let socket = socket.context("cannot create backhaul")?;
     // let mut _old_cleanup: Option<smol::Task<Option<()>>> = None;
 
     #[derive(Debug)]
     enum Evt {
         Incoming((Buff, SocketAddr)),
         Outgoing(Buff),
+        Keepalive,
     }
     // last remind time
     let mut last_incoming_time: Option<Instant> = None;
     let mut last_outgoing_time: Option<Instant> = None;
+    // the server address we last sent a ClientResume to
+    let mut resumed_addr = path.read().addr;
+    let mut probe: Option<Probe> = None;
+    let started = Instant::now();
+    let mut keepalive_timer = smol::Timer::interval(Duration::from_secs(1));
 
     loop {
         let down = {
@@ -113,13 +199,71 @@ async fn client_backhaul_once(
             let raw_upload = recv_upload.recv().await?;
             Ok::<_, anyhow::Error>(Evt::Outgoing(raw_upload))
         };
+        let keepalive = async {
+            keepalive_timer.next().await;
+            Ok::<_, anyhow::Error>(Evt::Keepalive)
+        };
 
-        match smol::future::race(down, up).await {
+        match smol::future::race(down, up.or(keepalive)).await {
             Ok(Evt::Incoming((bts, src))) => {
                 tracing::trace!("received on shard {} from {}", shard_id, src);
-                if src == cfg.server_addr {
-                    received_count.fetch_add(1, Ordering::Relaxed);
-                    let _ = session_back.inject_incoming(&bts);
+                if path.read().is_server(src) {
+                    if session_back.inject_incoming(&bts).is_err() {
+                        // not a session packet, so it might be the server's response to a ClientResume
+                        let cookie = path.read().cookie.clone();
+                        if let Some(incarnation) =
+                            handle_server_resume(&*socket, src, &cookie, &cfg, &resume, &bts).await
+                        {
+                            health.resume_answered();
+                            session_back.observe_remote_incarnation(incarnation);
+                        }
+                    } else if let Some((addr, server_pk)) = session_back.take_migration_hint() {
+                        // the hint is sent several times, so we may already be probing it
+                        let already_probing =
+                            probe.as_ref().map(|p| p.addr == addr).unwrap_or(false);
+                        if addr != path.read().addr && !already_probing {
+                            tracing::debug!("server asked us to migrate to {}; probing", addr);
+                            let new_probe = Probe {
+                                addr,
+                                cookie: crate::crypt::Cookie::new(server_pk),
+                                tries: 1,
+                                last_sent: Instant::now(),
+                            };
+                            send_resume(&*socket, &cfg, &new_probe.cookie, &resume, shard_id, addr)
+                                .await;
+                            probe = Some(new_probe);
+                        }
+                    }
+                } else if let Some(p) = probe.as_ref().filter(|p| p.addr == src) {
+                    // the migration target answered our probe. it must have the very same session state as the current address, or it is not really the same server
+                    if let Some(incarnation) =
+                        handle_server_resume(&*socket, src, &p.cookie, &cfg, &resume, &bts).await
+                    {
+                        match session_back.remote_incarnation() {
+                            Some(current) if current == incarnation => {
+                                tracing::debug!("migrating to {}", src);
+                                let mut path = path.write();
+                                path.prev_addr = Some(path.addr);
+                                path.addr = p.addr;
+                                path.cookie = p.cookie.clone();
+                                probe = None;
+                            }
+                            Some(_) => {
+                                tracing::warn!(
+                                    "migration target {} has different session state",
+                                    src
+                                );
+                                probe = None;
+                            }
+                            // we cannot tell yet whether it is the same server, so we keep probing until the current address tells us
+                            None => {
+                                tracing::debug!(
+                                    "not migrating to {} before the session state is known",
+                                    src
+                                );
+                            }
+                        }
+                    }
                 } else {
                     tracing::warn!("stray packet from {}", src)
                 }
@@ -128,6 +272,10 @@ async fn client_backhaul_once(
             Ok(Evt::Outgoing(bts)) => {
                 let bts: Buff = bts;
                 let now = Instant::now();
+                let (server_addr, cookie) = {
+                    let path = path.read();
+                    (path.addr, path.cookie.clone())
+                };
                 if last_incoming_time
                     .map(|f| now.saturating_duration_since(f) > Duration::from_secs(1))
                     .unwrap_or_default()
@@ -135,33 +283,116 @@ async fn client_backhaul_once(
                         .map(|f| now.saturating_duration_since(f) > Duration::from_secs(1))
                         .unwrap_or_default()
                     || !updated
+                    || resumed_addr != server_addr
                 {
                     updated = true;
                     last_outgoing_time = Some(now);
-                    let g_encrypt =
-                        crate::crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap());
-                    drop(
-                        socket
-                            .send_to(
-                                g_encrypt.pad_encrypt_v1(
-                                    &[HandshakeFrame::ClientResume {
-                                        resume_token: resume_token.clone(),
-                                        shard_id,
-                                    }],
-                                    1000,
-                                ),
-                                cfg.server_addr,
-                            )
-                            .await,
-                    );
+                    resumed_addr = server_addr;
+                    health.resume_sent();
+                    send_resume(&*socket, &cfg, &cookie, &resume, shard_id, server_addr).await;
+                }
+                if let Some(p) = probe.as_mut() {
+                    if p.last_sent.elapsed() > Duration::from_secs(1) {
+                        if p.tries >= PROBE_TRIES {
+                            tracing::debug!("giving up on migrating to {}", p.addr);
+                            probe = None;
+                        } else {
+                            p.tries += 1;
+                            p.last_sent = now;
+                            send_resume(&*socket, &cfg, &p.cookie, &resume, shard_id, p.addr).await;
+                        }
+                    }
                 }
-                if let Err(err) = socket.send_to(bts, cfg.server_addr).await {
+                if let Err(err) = socket.send_to(bts, server_addr).await {
                     tracing::warn!("error sending packet: {:?}", err)
                 }
             }
+            Ok(Evt::Keepalive) => {
+                if last_incoming_time.unwrap_or(started).elapsed() > KEEPALIVE_AFTER {
+                    let (server_addr, cookie) = {
+                        let path = path.read();
+                        (path.addr, path.cookie.clone())
+                    };
+                    health.resume_sent();
+                    send_resume(&*socket, &cfg, &cookie, &resume, shard_id, server_addr).await;
+                }
+            }
             Err(err) => {
                 anyhow::bail!("FATAL error in down/up: {:?}", err);
             }
         }
     }
 }
+
+/// Sends a ClientResume for the given shard to the given address, where the server uses the given cookie.
+async fn send_resume(
+    socket: &dyn Backhaul,
+    cfg: &LowlevelClientConfig,
+    cookie: &crate::crypt::Cookie,
+    resume: &ResumeInfo,
+    shard_id: u8,
+    addr: SocketAddr,
+) {
+    let g_encrypt = cfg.handshake_aead(&cookie.generate_c2s().next().unwrap());
+    drop(
+        socket
+            .send_to(
+                g_encrypt.pad_encrypt(&[resume.client_resume(shard_id)], 1000),
+                addr,
+            )
+            .await,
+    );
+}
+
+/// Processes what might be the server's reply to a ClientResume, answering any path challenge, and returns the incarnation of the server's session state if it was one.
+async fn handle_server_resume(
+    socket: &dyn Backhaul,
+    src: SocketAddr,
+    cookie: &crate::crypt::Cookie,
+    cfg: &LowlevelClientConfig,
+    resume: &ResumeInfo,
+    bts: &[u8],
+) -> Option<u64> {
+    for possible_key in cookie.generate_s2c() {
+        let decrypter = cfg.handshake_aead(&possible_key);
+        if let Some(frames) = decrypter.pad_decrypt::<HandshakeFrame>(bts) {
+            let mut incarnation_seen = None;
+            for frame in frames {
+                match frame {
+                    HandshakeFrame::ServerResume {
+                        shard_id,
+                        incarnation,
+                        resumed,
+                    } => {
+                        tracing::trace!(
+                            "ServerResume on shard {} (incarnation {:x}, resumed {})",
+                            shard_id,
+                            incarnation,
+                            resumed
+                        );
+                        incarnation_seen = Some(incarnation);
+                    }
+                    HandshakeFrame::NewResumeToken { resume_token } => {
+                        tracing::debug!("server refreshed our resume token");
+                        *resume.token.write() = resume_token;
+                    }
+                    HandshakeFrame::PathChallenge { challenge } => {
+                        tracing::debug!("answering path challenge from {}", src);
+                        let response = HandshakeFrame::PathResponse {
+                            response: crate::crypt::path_response(&resume.path_key, &challenge),
+                        };
+                        let g_encrypt = cfg.handshake_aead(&cookie.generate_c2s().next().unwrap());
+                        drop(
+                            socket
+                                .send_to(g_encrypt.pad_encrypt(&[response], 1000), src)
+                                .await,
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            return incarnation_seen;
+        }
+    }
+    None
+}
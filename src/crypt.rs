@@ -10,6 +10,11 @@ use thiserror::Error;
 
 use crate::buffer::{Buff, BuffMut};
 
+#[cfg(feature = "post-quantum")]
+mod mlkem;
+#[cfg(feature = "post-quantum")]
+pub use mlkem::{encapsulate as mlkem_encapsulate, MlKemSecret};
+
 pub const UP_KEY: &[u8; 32] = b"upload--------------------------";
 pub const DN_KEY: &[u8; 32] = b"download------------------------";
 
@@ -81,40 +86,52 @@ impl LegacyAead {
 
     /// Pad and encrypt.
     pub fn pad_encrypt_v1(&self, msgs: &[impl Serialize], target_len: usize) -> Buff {
-        let target_len = target_len + rand::thread_rng().gen_range(0, 10);
-        let mut plain = Vec::with_capacity(1500);
-        for msg in msgs {
-            bincode::serialize_into(&mut plain, &msg).unwrap();
-        }
-        let plainlen = plain.len();
-        plain.extend_from_slice(&vec![0xff; target_len - plain.len()]);
+        let plain = pad_serialize(msgs, target_len);
         let encrypted = self.encrypt(&plain, rand::thread_rng().gen());
-        tracing::trace!("PAD and ENCRYPT {} => {}", plainlen, encrypted.len());
+        tracing::trace!("PAD and ENCRYPT {} => {}", plain.len(), encrypted.len());
         encrypted
     }
 
     /// Decrypt and depad.
     pub fn pad_decrypt_v1<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<Vec<T>> {
         let plain = self.decrypt(ctext)?;
-        let mut reader = plain.as_ref();
-        let mut output = Vec::with_capacity(1);
-        while !reader.is_empty() {
-            let cfg = DefaultOptions::new()
-                .with_fixint_encoding()
-                .with_limit(10000)
-                .allow_trailing_bytes();
-            let boolayah: Option<T> = cfg.deserialize_from(&mut reader).ok();
-            if let Some(boolayah) = boolayah {
-                output.push(boolayah);
-            } else {
-                break;
-            }
-        }
-        if output.is_empty() {
-            return None;
+        depad_deserialize(&plain)
+    }
+}
+
+/// Serializes a bunch of messages back to back, then pads them to around the target length.
+fn pad_serialize(msgs: &[impl Serialize], target_len: usize) -> Vec<u8> {
+    let target_len = target_len + rand::thread_rng().gen_range(0, 10);
+    let mut plain = Vec::with_capacity(1500);
+    for msg in msgs {
+        bincode::serialize_into(&mut plain, &msg).unwrap();
+    }
+    if plain.len() < target_len {
+        plain.extend_from_slice(&vec![0xff; target_len - plain.len()]);
+    }
+    plain
+}
+
+/// Inverse of [pad_serialize].
+fn depad_deserialize<T: DeserializeOwned>(plain: &[u8]) -> Option<Vec<T>> {
+    let mut reader = plain;
+    let mut output = Vec::with_capacity(1);
+    while !reader.is_empty() {
+        let cfg = DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(10000)
+            .allow_trailing_bytes();
+        let boolayah: Option<T> = cfg.deserialize_from(&mut reader).ok();
+        if let Some(boolayah) = boolayah {
+            output.push(boolayah);
+        } else {
+            break;
         }
-        Some(output)
     }
+    if output.is_empty() {
+        return None;
+    }
+    Some(output)
 }
 
 /// Next generation AEAD, based on `ring`'s ChaCha20/Poly1305, used in versions 3 and above
@@ -172,6 +189,67 @@ impl NgAead {
         let truncate_to = ctext.len() - CHACHA20_POLY1305.tag_len();
         Ok(ctext.freeze().slice(0..truncate_to))
     }
+
+    /// Pad and encrypt.
+    pub fn pad_encrypt(&self, msgs: &[impl Serialize], target_len: usize) -> Buff {
+        let plain = pad_serialize(msgs, target_len);
+        let encrypted = self.encrypt(&plain);
+        tracing::trace!("PAD and ENCRYPT {} => {}", plain.len(), encrypted.len());
+        encrypted
+    }
+
+    /// Decrypt and depad.
+    pub fn pad_decrypt<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<Vec<T>> {
+        let plain = self.decrypt(ctext).ok()?;
+        depad_deserialize(&plain)
+    }
+}
+
+/// Crypter for handshake frames. Handshake versions 4 and above use [NgAead], while version 3 uses [LegacyAead].
+#[derive(Debug, Clone)]
+pub enum HandshakeAead {
+    Legacy(LegacyAead),
+    Ng(NgAead),
+}
+
+impl HandshakeAead {
+    /// Creates a new handshake crypter given a key and whether to use the legacy construction.
+    pub fn new(key: &[u8], legacy: bool) -> Self {
+        if legacy {
+            Self::Legacy(LegacyAead::new(key))
+        } else {
+            Self::Ng(NgAead::new(key))
+        }
+    }
+
+    /// Whether this is the legacy construction.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Self::Legacy(_))
+    }
+
+    /// Returns the overhead.
+    pub fn overhead(&self) -> usize {
+        match self {
+            Self::Legacy(_) => 24,
+            Self::Ng(_) => NgAead::overhead(),
+        }
+    }
+
+    /// Pad and encrypt.
+    pub fn pad_encrypt(&self, msgs: &[impl Serialize], target_len: usize) -> Buff {
+        match self {
+            Self::Legacy(aead) => aead.pad_encrypt_v1(msgs, target_len),
+            Self::Ng(aead) => aead.pad_encrypt(msgs, target_len),
+        }
+    }
+
+    /// Decrypt and depad.
+    pub fn pad_decrypt<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<Vec<T>> {
+        match self {
+            Self::Legacy(aead) => aead.pad_decrypt_v1(ctext),
+            Self::Ng(aead) => aead.pad_decrypt(ctext),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -210,15 +288,29 @@ impl Cookie {
 
     /// Generate a bunch of symmetric keys given the current time, for client to server.
     pub fn generate_c2s(&self) -> impl Iterator<Item = [u8; 32]> {
-        self.generate_temp_keys("sosistab-1-c2s", curr_epoch())
-            .into_iter()
+        self.generate_c2s_skewed(0)
     }
 
     /// Generate a bunch of symmetric keys given the current time, for server to client.
     pub fn generate_s2c(&self) -> impl Iterator<Item = [u8; 32]> {
-        self.generate_temp_keys("sosistab-1-s2c", curr_epoch())
+        self.generate_s2c_skewed(0)
+    }
+
+    /// Like generate_c2s, but as if the clock were off by the given number of minutes. Used for telling whether the clocks of the client and server disagree.
+    pub fn generate_c2s_skewed(&self, minutes: i64) -> impl Iterator<Item = [u8; 32]> {
+        self.generate_temp_keys("sosistab-1-c2s", skewed_epoch(minutes))
             .into_iter()
     }
+
+    /// Like generate_s2c, but as if the clock were off by the given number of minutes.
+    pub fn generate_s2c_skewed(&self, minutes: i64) -> impl Iterator<Item = [u8; 32]> {
+        self.generate_temp_keys("sosistab-1-s2c", skewed_epoch(minutes))
+            .into_iter()
+    }
+}
+
+fn skewed_epoch(minutes: i64) -> u64 {
+    (curr_epoch() as i64 + minutes) as u64
 }
 
 fn curr_epoch() -> u64 {
@@ -253,3 +345,100 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+/// Mixes an ML-KEM shared secret into a [triple_ecdh] result, for the hybrid post-quantum handshake (version 5). The result is secure as long as either input is.
+#[cfg(feature = "post-quantum")]
+pub fn hybrid_mix(ecdh_secret: blake3::Hash, kem_secret: &[u8; 32]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-5-hybrid");
+    hasher.update(ecdh_secret.as_bytes());
+    hasher.update(kem_secret);
+    hasher.finalize()
+}
+
+/// Derives the key with which a client proves, when resuming, that it holds the ephemeral key its resume token is bound to. Both sides compute it from the client's ephemeral key and the server's long-term key.
+pub fn resume_proof_key(
+    my_sk: &x25519_dalek::StaticSecret,
+    their_pk: &x25519_dalek::PublicKey,
+) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    blake3::derive_key(
+        "sosistab-resume-proof",
+        my_sk.diffie_hellman(their_pk).as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+/// Derives the key with which a client answers path challenges, from the session key.
+pub fn path_response_key(session_key: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    blake3::derive_key("sosistab-path-response", session_key, &mut key);
+    key
+}
+
+/// Computes the answer to a path challenge.
+pub fn path_response(path_key: &[u8; 32], challenge: &[u8; 32]) -> [u8; 32] {
+    *blake3::keyed_hash(path_key, challenge).as_bytes()
+}
+
+/// Computes the proof attached to a ClientResumeBound.
+pub fn resume_proof(
+    proof_key: &[u8; 32],
+    resume_token: &[u8],
+    shard_id: u8,
+    time_ms: u64,
+) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(proof_key);
+    hasher.update(resume_token);
+    hasher.update(&[shard_id]);
+    hasher.update(&time_ms.to_be_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::HandshakeFrame;
+
+    fn frame(n: u8) -> HandshakeFrame {
+        HandshakeFrame::PathResponse { response: [n; 32] }
+    }
+
+    fn decrypt(aead: &HandshakeAead, encrypted: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let frames = aead.pad_decrypt::<HandshakeFrame>(encrypted)?;
+        Some(frames.iter().map(|frame| frame.to_bytes()).collect())
+    }
+
+    #[test]
+    fn handshake_aead_round_trips() {
+        for legacy in [false, true] {
+            let aead = HandshakeAead::new(&[7; 32], legacy);
+            assert_eq!(aead.is_legacy(), legacy);
+            let frames = [frame(1), frame(2)];
+            let encrypted = aead.pad_encrypt(&frames, 1000);
+            assert!(encrypted.len() >= 1000 + aead.overhead());
+            assert_eq!(
+                decrypt(&aead, &encrypted),
+                Some(frames.iter().map(|frame| frame.to_bytes()).collect())
+            );
+        }
+    }
+
+    #[test]
+    fn handshake_aead_rejects_other_constructions_and_keys() {
+        let legacy = HandshakeAead::new(&[7; 32], true);
+        let ng = HandshakeAead::new(&[7; 32], false);
+        let from_legacy = legacy.pad_encrypt(&[frame(1)], 1000);
+        let from_ng = ng.pad_encrypt(&[frame(1)], 1000);
+        assert!(decrypt(&ng, &from_legacy).is_none());
+        assert!(decrypt(&legacy, &from_ng).is_none());
+        for (aead, encrypted) in [(legacy, from_legacy), (ng, from_ng)] {
+            let other_key = HandshakeAead::new(&[8; 32], aead.is_legacy());
+            assert!(decrypt(&other_key, &encrypted).is_none());
+            let mut tampered = encrypted.to_vec();
+            tampered[0] ^= 1;
+            assert!(decrypt(&aead, &tampered).is_none());
+            assert!(decrypt(&aead, &encrypted[..10]).is_none());
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! A straightforward implementation of ML-KEM-512 (FIPS 203), used for the hybrid post-quantum handshake.
+//!
+//! We use the smallest parameter set because its encapsulation key and ciphertext both fit into the 1000-byte padded handshake frames, so hybrid handshakes look exactly like classical ones on the wire.
+//!
+//! Nothing here is performance-critical, since it only runs once per handshake, so clarity wins over speed. The one exception is that secret values are never divided by anything, not even the constant q, since whether the compiler turns such divisions into multiplications depends on the target and optimization level, and hardware division takes a data-dependent time (the "KyberSlash" attacks). Everything is reduced with [div_q] instead.
+//!
+//! This module is only built with the `post-quantum` feature, which is off by default. It is vendored rather than taken from a vetted crate such as `ml-kem` because none could be depended on when the hybrid handshake was written, and it has not been independently audited, so review it before turning the feature on. What has been checked: `known_answers` compares key generation, encapsulation, decapsulation and implicit rejection byte-for-byte against OpenSSL's ML-KEM-512, `malformed_keys_are_refused` covers the modulus check on encapsulation keys, the re-encryption check in decapsulation is constant-time, and `arithmetic_matches_division` checks [div_q] and compression against real division. Since the KEM secret is only mixed into an X25519 exchange, a flaw here can cost the post-quantum protection but not the classical one. Once a vetted implementation is available, it should replace this module; the wire format doesn't depend on which one is used.
+
+use rand::RngCore;
+use sha3::{
+    digest::{ExtendableOutput, Update, XofReader},
+    Digest, Sha3_256, Sha3_512, Shake128, Shake256,
+};
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 2;
+const ETA1: usize = 3;
+const ETA2: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+
+/// Length of an encapsulation key.
+pub const EK_LEN: usize = 384 * K + 32;
+/// Length of a decapsulation key.
+pub const DK_LEN: usize = 768 * K + 96;
+/// Length of a ciphertext.
+pub const CT_LEN: usize = 32 * (DU * K + DV);
+
+/// Shift and multiplier with which [div_q] divides by q: the multiplier is 2^40 / q, rounded up.
+const DIV_Q_SHIFT: u32 = 40;
+const DIV_Q_MULTIPLIER: u64 = (1u64 << DIV_Q_SHIFT).div_ceil(Q as u64);
+
+type Poly = [u16; N];
+
+/// An ML-KEM-512 decapsulation key, together with its encapsulation key.
+#[derive(Clone)]
+pub struct MlKemSecret {
+    dk: Vec<u8>,
+}
+
+impl std::fmt::Debug for MlKemSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MlKemSecret")
+    }
+}
+
+impl MlKemSecret {
+    /// Generates a fresh keypair.
+    pub fn generate() -> Self {
+        let mut d = [0u8; 32];
+        let mut z = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut d);
+        rand::thread_rng().fill_bytes(&mut z);
+        Self::from_seed(&d, &z)
+    }
+
+    /// Derives a keypair from its seeds, as the internal key generation of FIPS 203 does.
+    fn from_seed(d: &[u8; 32], z: &[u8; 32]) -> Self {
+        let (ek, dk_pke) = pke_keygen(d);
+        let mut dk = Vec::with_capacity(DK_LEN);
+        dk.extend_from_slice(&dk_pke);
+        dk.extend_from_slice(&ek);
+        dk.extend_from_slice(&h(&ek));
+        dk.extend_from_slice(z);
+        Self { dk }
+    }
+
+    /// Gets the encapsulation key, which is sent to the other side.
+    pub fn encapsulation_key(&self) -> &[u8] {
+        &self.dk[384 * K..768 * K + 32]
+    }
+
+    /// Decapsulates a ciphertext into a shared secret. Invalid ciphertexts yield a pseudorandom secret ("implicit rejection"), so this never fails if the ciphertext has the right length.
+    pub fn decapsulate(&self, ct: &[u8]) -> Option<[u8; 32]> {
+        if ct.len() != CT_LEN {
+            return None;
+        }
+        let dk_pke = &self.dk[..384 * K];
+        let ek = &self.dk[384 * K..768 * K + 32];
+        let ek_hash = &self.dk[768 * K + 32..768 * K + 64];
+        let z = &self.dk[768 * K + 64..];
+        let m = pke_decrypt(dk_pke, ct);
+        let (shared, r) = g(&[&m, ek_hash]);
+        let rejected = j(&[z, ct]);
+        let ct_again = pke_encrypt(ek, &m, &r);
+        // picks one of the two without branching on which
+        let keep_shared = 0u8.wrapping_sub(constant_time_eq::constant_time_eq(ct, &ct_again) as u8);
+        let mut out = rejected;
+        for (out, shared) in out.iter_mut().zip(shared.iter()) {
+            *out ^= keep_shared & (*out ^ shared);
+        }
+        Some(out)
+    }
+}
+
+/// Encapsulates a fresh shared secret to the given encapsulation key, returning the ciphertext and the shared secret. Returns None if the key is malformed.
+pub fn encapsulate(ek: &[u8]) -> Option<(Vec<u8>, [u8; 32])> {
+    if ek.len() != EK_LEN {
+        return None;
+    }
+    // modulus check: every coefficient must already be reduced
+    for chunk in ek[..384 * K].chunks(384) {
+        if byte_encode(&byte_decode(chunk, 12), 12) != chunk {
+            return None;
+        }
+    }
+    let mut m = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut m);
+    Some(encapsulate_with(ek, &m))
+}
+
+/// Encapsulates the shared secret for the given message, as the internal encapsulation of FIPS 203 does.
+fn encapsulate_with(ek: &[u8], m: &[u8; 32]) -> (Vec<u8>, [u8; 32]) {
+    let (shared, r) = g(&[m, &h(ek)]);
+    (pke_encrypt(ek, m, &r), shared)
+}
+
+fn pke_keygen(d: &[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+    let (rho, sigma) = g(&[d, &[K as u8]]);
+    let a_hat = expand_matrix(&rho);
+    let mut nonce = 0u8;
+    let mut s_hat = [[0u16; N]; K];
+    for s in s_hat.iter_mut() {
+        *s = ntt(sample_cbd(&prf(&sigma, nonce, ETA1), ETA1));
+        nonce += 1;
+    }
+    let mut e_hat = [[0u16; N]; K];
+    for e in e_hat.iter_mut() {
+        *e = ntt(sample_cbd(&prf(&sigma, nonce, ETA1), ETA1));
+        nonce += 1;
+    }
+    let mut ek = Vec::with_capacity(EK_LEN);
+    let mut dk = Vec::with_capacity(384 * K);
+    for i in 0..K {
+        let mut t_hat = e_hat[i];
+        for j in 0..K {
+            t_hat = add(&t_hat, &multiply_ntts(&a_hat[i][j], &s_hat[j]));
+        }
+        ek.extend_from_slice(&byte_encode(&t_hat, 12));
+        dk.extend_from_slice(&byte_encode(&s_hat[i], 12));
+    }
+    ek.extend_from_slice(&rho);
+    (ek, dk)
+}
+
+fn pke_encrypt(ek: &[u8], m: &[u8], r: &[u8; 32]) -> Vec<u8> {
+    let mut t_hat = [[0u16; N]; K];
+    for (i, t) in t_hat.iter_mut().enumerate() {
+        *t = byte_decode(&ek[384 * i..384 * (i + 1)], 12);
+    }
+    let rho: [u8; 32] = ek[384 * K..].try_into().unwrap();
+    let a_hat = expand_matrix(&rho);
+    let mut nonce = 0u8;
+    let mut y_hat = [[0u16; N]; K];
+    for y in y_hat.iter_mut() {
+        *y = ntt(sample_cbd(&prf(r, nonce, ETA1), ETA1));
+        nonce += 1;
+    }
+    let mut e1 = [[0u16; N]; K];
+    for e in e1.iter_mut() {
+        *e = sample_cbd(&prf(r, nonce, ETA2), ETA2);
+        nonce += 1;
+    }
+    let e2 = sample_cbd(&prf(r, nonce, ETA2), ETA2);
+    let mut ct = Vec::with_capacity(CT_LEN);
+    for (i, e) in e1.iter().enumerate() {
+        let mut u_hat = [0u16; N];
+        for j in 0..K {
+            u_hat = add(&u_hat, &multiply_ntts(&a_hat[j][i], &y_hat[j]));
+        }
+        let u = add(&ntt_inverse(u_hat), e);
+        ct.extend_from_slice(&byte_encode(&compress(&u, DU), DU));
+    }
+    let mut v_hat = [0u16; N];
+    for i in 0..K {
+        v_hat = add(&v_hat, &multiply_ntts(&t_hat[i], &y_hat[i]));
+    }
+    let mu = decompress(&byte_decode(m, 1), 1);
+    let v = add(&add(&ntt_inverse(v_hat), &e2), &mu);
+    ct.extend_from_slice(&byte_encode(&compress(&v, DV), DV));
+    ct
+}
+
+fn pke_decrypt(dk_pke: &[u8], ct: &[u8]) -> Vec<u8> {
+    let (c1, c2) = ct.split_at(32 * DU * K);
+    let v = decompress(&byte_decode(c2, DV), DV);
+    let mut su_hat = [0u16; N];
+    for i in 0..K {
+        let u = decompress(&byte_decode(&c1[32 * DU * i..32 * DU * (i + 1)], DU), DU);
+        let s_hat = byte_decode(&dk_pke[384 * i..384 * (i + 1)], 12);
+        su_hat = add(&su_hat, &multiply_ntts(&s_hat, &ntt(u)));
+    }
+    let w = sub(&v, &ntt_inverse(su_hat));
+    byte_encode(&compress(&w, 1), 1)
+}
+
+/// Expands the matrix A-hat from a seed, indexed as `[row][column]`.
+fn expand_matrix(rho: &[u8; 32]) -> [[Poly; K]; K] {
+    let mut a_hat = [[[0u16; N]; K]; K];
+    for (i, row) in a_hat.iter_mut().enumerate() {
+        for (j, entry) in row.iter_mut().enumerate() {
+            *entry = sample_ntt(rho, j as u8, i as u8);
+        }
+    }
+    a_hat
+}
+
+fn sample_ntt(rho: &[u8; 32], j: u8, i: u8) -> Poly {
+    let mut xof = Shake128::default();
+    xof.update(rho);
+    xof.update(&[j, i]);
+    let mut reader = xof.finalize_xof();
+    let mut out = [0u16; N];
+    let mut idx = 0;
+    while idx < N {
+        let mut c = [0u8; 3];
+        reader.read(&mut c);
+        let d1 = c[0] as u32 + 256 * (c[1] as u32 % 16);
+        let d2 = c[1] as u32 / 16 + 16 * c[2] as u32;
+        if d1 < Q {
+            out[idx] = d1 as u16;
+            idx += 1;
+        }
+        if d2 < Q && idx < N {
+            out[idx] = d2 as u16;
+            idx += 1;
+        }
+    }
+    out
+}
+
+fn sample_cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |i: usize| ((bytes[i / 8] >> (i % 8)) & 1) as u32;
+    let mut out = [0u16; N];
+    for (i, coeff) in out.iter_mut().enumerate() {
+        let x: u32 = (0..eta).map(|j| bit(2 * i * eta + j)).sum();
+        let y: u32 = (0..eta).map(|j| bit(2 * i * eta + eta + j)).sum();
+        *coeff = reduce(x + Q - y);
+    }
+    out
+}
+
+/// Powers of the primitive 256th root of unity 17, in bit-reversed order.
+fn zeta(i: usize) -> u32 {
+    pow17((i as u8).reverse_bits() as u32 >> 1)
+}
+
+fn pow17(exp: u32) -> u32 {
+    (0..exp).fold(1, |acc, _| reduce(acc * 17) as u32)
+}
+
+fn ntt(mut f: Poly) -> Poly {
+    let mut i = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let z = zeta(i);
+            i += 1;
+            for j in start..start + len {
+                let t = reduce(z * f[j + len] as u32) as u32;
+                f[j + len] = reduce(f[j] as u32 + Q - t);
+                f[j] = reduce(f[j] as u32 + t);
+            }
+        }
+        len /= 2;
+    }
+    f
+}
+
+fn ntt_inverse(mut f: Poly) -> Poly {
+    let mut i = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let z = zeta(i);
+            i -= 1;
+            for j in start..start + len {
+                let t = f[j] as u32;
+                f[j] = reduce(t + f[j + len] as u32);
+                f[j + len] = reduce(z * reduce(f[j + len] as u32 + Q - t) as u32);
+            }
+        }
+        len *= 2;
+    }
+    for coeff in f.iter_mut() {
+        *coeff = reduce(*coeff as u32 * 3303);
+    }
+    f
+}
+
+fn multiply_ntts(f: &Poly, g: &Poly) -> Poly {
+    let mut out = [0u16; N];
+    for i in 0..N / 2 {
+        let gamma = pow17(2 * ((i as u8).reverse_bits() as u32 >> 1) + 1);
+        let (a0, a1) = (f[2 * i] as u32, f[2 * i + 1] as u32);
+        let (b0, b1) = (g[2 * i] as u32, g[2 * i + 1] as u32);
+        out[2 * i] = reduce(a0 * b0 + reduce(a1 * b1) as u32 * gamma);
+        out[2 * i + 1] = reduce(a0 * b1 + a1 * b0);
+    }
+    out
+}
+
+fn add(f: &Poly, g: &Poly) -> Poly {
+    let mut out = [0u16; N];
+    for i in 0..N {
+        out[i] = reduce(f[i] as u32 + g[i] as u32);
+    }
+    out
+}
+
+fn sub(f: &Poly, g: &Poly) -> Poly {
+    let mut out = [0u16; N];
+    for i in 0..N {
+        out[i] = reduce(f[i] as u32 + Q - g[i] as u32);
+    }
+    out
+}
+
+fn compress(f: &Poly, d: usize) -> Poly {
+    let mut out = [0u16; N];
+    for i in 0..N {
+        // round(2^d * x / q), which is never exactly halfway since q is odd
+        out[i] = (div_q(((f[i] as u32) << d) + Q / 2) & ((1 << d) - 1)) as u16;
+    }
+    out
+}
+
+fn decompress(f: &Poly, d: usize) -> Poly {
+    let mut out = [0u16; N];
+    for i in 0..N {
+        out[i] = ((f[i] as u32 * Q + (1 << (d - 1))) >> d) as u16;
+    }
+    out
+}
+
+fn byte_encode(f: &Poly, d: usize) -> Vec<u8> {
+    let mut out = vec![0u8; 32 * d];
+    for (i, coeff) in f.iter().enumerate() {
+        for j in 0..d {
+            let bit = (coeff >> j) & 1;
+            out[(i * d + j) / 8] |= (bit as u8) << ((i * d + j) % 8);
+        }
+    }
+    out
+}
+
+fn byte_decode(bytes: &[u8], d: usize) -> Poly {
+    let mut out = [0u16; N];
+    for (i, coeff) in out.iter_mut().enumerate() {
+        let mut val = 0u32;
+        for j in 0..d {
+            let bit = (bytes[(i * d + j) / 8] >> ((i * d + j) % 8)) & 1;
+            val |= (bit as u32) << j;
+        }
+        *coeff = if d == 12 { reduce(val) } else { val as u16 };
+    }
+    out
+}
+
+/// Divides by q without dividing, by multiplying and shifting instead. This is exact for everything below 2^26, which is more than any product of two reduced coefficients plus another.
+fn div_q(x: u32) -> u32 {
+    debug_assert!(x < 1 << 26);
+    ((x as u64 * DIV_Q_MULTIPLIER) >> DIV_Q_SHIFT) as u32
+}
+
+/// Reduces modulo q, with [div_q].
+fn reduce(x: u32) -> u16 {
+    (x - div_q(x) * Q) as u16
+}
+
+fn prf(seed: &[u8; 32], nonce: u8, eta: usize) -> Vec<u8> {
+    let mut xof = Shake256::default();
+    xof.update(seed);
+    xof.update(&[nonce]);
+    let mut out = vec![0u8; 64 * eta];
+    xof.finalize_xof().read(&mut out);
+    out
+}
+
+fn h(input: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(input).into()
+}
+
+fn j(inputs: &[&[u8]]) -> [u8; 32] {
+    let mut xof = Shake256::default();
+    for input in inputs {
+        xof.update(input);
+    }
+    let mut out = [0u8; 32];
+    xof.finalize_xof().read(&mut out);
+    out
+}
+
+fn g(inputs: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut hasher = Sha3_512::new();
+    for input in inputs {
+        Digest::update(&mut hasher, input);
+    }
+    let out = hasher.finalize();
+    (out[..32].try_into().unwrap(), out[32..].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A known answer, generated with the ML-KEM-512 of OpenSSL 3.5: the seeds `d || z` and message `m`, then SHA3-256 hashes of the encapsulation key, decapsulation key and ciphertext, the shared secret, and the secret that the ciphertext decapsulates to with the byte at the given index flipped.
+    type Kat = (
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        usize,
+    );
+
+    const KATS: &[Kat] = &[
+        (
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f",
+            "6465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f80818283",
+            "82f101ff648063b376e2bb6c5b7455f655a50c2feadade150efa0e0e6f365aea",
+            "0bd3f5df01098ac9c29d687c7f1bd0588a5573feeef8f1e3b4573fa7f6ab57c8",
+            "144d39e2ea1f02e0404dc85dca8ef2b51f1813212ab2af4d8a1d0362c4c60ae8",
+            "3a607cff6eafff95c45dcfb474aba90719265620a28c465f3f6ffe39d5dc5f18",
+            "187f324465dcbe8cf2d62a6429118e7920a92c30c0ded6d9312ffe263d47e489",
+            5,
+        ),
+        (
+            "a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a55a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a",
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "5b6cd07b00773b5a36460dac8ff9f7116974409bbd8043d94cfb365ae2593209",
+            "c23e7ab9e38816c04510e3436e5d1932098a83be780a41bea0c33893d235c388",
+            "4cc8a815b059460e8392037fb206a251e9e98ba9f29b7455f7045f7a494acedf",
+            "303972afcaa2e5c63a1d66097e95c84e56f0ca0597060193fb20edcbf4a807a8",
+            "e5a7e0716885b3cfa84760d9a1be90929512781f9022d22dcd20dd0b594cf065",
+            105,
+        ),
+        (
+            "9f6b44e590a9f77eb1982164cf06da5f3ec7f2a7608969fe7a53dac4c6ce0392076d5af0cca2357a7362151da078a338e86e005c08858979218ef0a52a6e03bb",
+            "1be7a27d8ba88a3d2f0a0c06bb2ddbe020f14e9d970d50e5d7e3baf5d00b0379",
+            "352cf16e47351fedcfb42a1fab2c9c4944af3b882207c24ae8ea08fcd4eec09b",
+            "75edea5833099ea87777c6d0c92e02292270afa714dd9963e041478c27be7511",
+            "27acda2082ec6a46233c97f61962d3adef0b25fe7a134faaf5825cf7cabc3f82",
+            "2e1dcaa4109b108efebad1b9ac73b03e2f9402222004d7ccc3837aafdd8afb7c",
+            "4d1e194762f27dd3f929f545f3177d608896bdc6ced3fe9d4707eb8684881395",
+            205,
+        ),
+    ];
+
+    #[test]
+    fn known_answers() {
+        for &(seed, m, ek_hash, dk_hash, ct_hash, shared, rejected, flip) in KATS {
+            let seed = hex::decode(seed).unwrap();
+            let secret = MlKemSecret::from_seed(
+                seed[..32].try_into().unwrap(),
+                seed[32..].try_into().unwrap(),
+            );
+            let ek = secret.encapsulation_key();
+            assert_eq!(hex::encode(h(ek)), ek_hash);
+            assert_eq!(hex::encode(h(&secret.dk)), dk_hash);
+            let m: [u8; 32] = hex::decode(m).unwrap().try_into().unwrap();
+            let (ct, encapsulated) = encapsulate_with(ek, &m);
+            assert_eq!(hex::encode(h(&ct)), ct_hash);
+            assert_eq!(hex::encode(encapsulated), shared);
+            assert_eq!(hex::encode(secret.decapsulate(&ct).unwrap()), shared);
+            let mut tampered = ct.clone();
+            tampered[flip] ^= 1;
+            assert_eq!(
+                hex::encode(secret.decapsulate(&tampered).unwrap()),
+                rejected
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for _ in 0..20 {
+            let secret = MlKemSecret::generate();
+            assert_eq!(secret.encapsulation_key().len(), EK_LEN);
+            let (ct, shared) = encapsulate(secret.encapsulation_key()).unwrap();
+            assert_eq!(ct.len(), CT_LEN);
+            assert_eq!(secret.decapsulate(&ct), Some(shared));
+            let mut tampered = ct.clone();
+            tampered[CT_LEN - 1] ^= 0x80;
+            assert_ne!(secret.decapsulate(&tampered), Some(shared));
+            assert_eq!(secret.decapsulate(&ct[1..]), None);
+        }
+    }
+
+    #[test]
+    fn malformed_keys_are_refused() {
+        let secret = MlKemSecret::generate();
+        let ek = secret.encapsulation_key();
+        assert!(encapsulate(&ek[1..]).is_none());
+        // a coefficient of 4095, which is not reduced
+        let mut unreduced = ek.to_vec();
+        unreduced[0] = 0xff;
+        unreduced[1] |= 0x0f;
+        assert!(encapsulate(&unreduced).is_none());
+    }
+
+    #[test]
+    fn arithmetic_matches_division() {
+        for x in (0..1 << 26).step_by(101).chain((1 << 26) - 1000..1 << 26) {
+            assert_eq!(div_q(x), x / Q);
+            assert_eq!(reduce(x) as u32, x % Q);
+        }
+        for d in [1, 4, DU, DV, 11] {
+            let mut f = [0u16; N];
+            for start in (0..Q).step_by(N) {
+                for (i, coeff) in f.iter_mut().enumerate() {
+                    *coeff = (start as usize + i).min(Q as usize - 1) as u16;
+                }
+                let compressed = compress(&f, d);
+                for i in 0..N {
+                    let exact = (((f[i] as u32) << (d + 1)) + Q) / (2 * Q) % (1 << d);
+                    assert_eq!(compressed[i] as u32, exact);
+                }
+            }
+        }
+    }
+}
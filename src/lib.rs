@@ -37,10 +37,16 @@ pub mod runtime;
 mod session;
 pub use session::*;
 mod backhaul;
+mod mimic;
+pub use mimic::Mimicry;
 mod mux;
 pub use mux::*;
 mod tcp;
 use backhaul::*;
+pub use tcp::{
+    CertVerification, ClientHelloProfile, HelloExtension, HttpProxy, Socks5Proxy, TlsConfig,
+    TlsIdentity, TlsVersion, WebSocketConfig,
+};
 mod recfilter;
 mod stats;
 pub use stats::*;
@@ -0,0 +1,139 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{runtime, Mimicry, StatsGatherer, TlsIdentity, TlsVersion, TrafficShape};
+
+use super::Listener;
+
+/// Decides whether a client may establish a session, given its address and long-term public key. For TCP clients, this is the address of the TCP connection that the handshake came over.
+pub type AuthHook =
+    Arc<dyn Fn(SocketAddr, x25519_dalek::PublicKey) -> bool + Send + Sync + 'static>;
+
+/// Creates the statistics gatherer of a new session, given the address of the client.
+pub type GathererFactory = Arc<dyn Fn(SocketAddr) -> Arc<StatsGatherer> + Send + Sync + 'static>;
+
+/// Configuration of a listener, which can serve UDP, TCP, or both.
+#[derive(Clone)]
+pub struct ListenerConfig {
+    /// Long-term secret keys. Clients may connect using the public key of any of them, which allows keys to be rotated without breaking clients that still know the old one.
+    pub long_sks: Vec<x25519_dalek::StaticSecret>,
+    /// Address to listen for UDP clients on.
+    pub udp_addr: Option<SocketAddr>,
+    /// Additional UDP addresses to listen on, each with a single socket. These are typically where clients are asked to migrate to, since sessions are shared between all the sockets of a listener.
+    pub udp_alt_addrs: Vec<SocketAddr>,
+    /// Address to listen for TCP clients on, and TLS and WebSocket clients.
+    pub tcp_addr: Option<SocketAddr>,
+    /// Certificate chain presented to TLS clients, such as a real one for a domain that the server is reached through. If None, every TLS connection gets a fresh self-signed certificate for a made-up domain.
+    pub tls_identity: Option<TlsIdentity>,
+    /// ALPN protocols that TLS clients may negotiate, in order of preference.
+    pub tls_alpn: Vec<String>,
+    /// Oldest TLS version that TLS clients may negotiate. If None, whatever the TLS library allows.
+    pub tls_min_version: Option<TlsVersion>,
+    /// Newest TLS version that TLS clients may negotiate. Defaults to TLS 1.2, so that servers speak the same TLS on every platform. If None, the newest the TLS library supports as a server, which with OpenSSL, as on Linux, is TLS 1.2, but elsewhere may be TLS 1.3.
+    pub tls_max_version: Option<TlsVersion>,
+    /// Number of UDP sockets sharing the UDP address through `SO_REUSEPORT`, each with its own receive loop, so that more than one core can process incoming packets. Sessions are shared between all the sockets, so clients can roam between them. On platforms without `SO_REUSEPORT`, only one socket is opened.
+    pub udp_socket_count: usize,
+    /// Receive buffer size of UDP sockets.
+    pub udp_recv_buffer: usize,
+    /// Send buffer size of UDP sockets.
+    pub udp_send_buffer: usize,
+    /// Protocol that traffic on all the UDP sockets is disguised as. Clients must disguise their traffic the same way, or they cannot connect.
+    pub udp_mimicry: Mimicry,
+    /// Address, and the long-term public key used there, that every new session is asked to migrate to. Clients that cannot reach it stay where they connected.
+    pub preferred_addr: Option<(SocketAddr, x25519_dalek::PublicKey)>,
+    /// Outgoing session packets larger than this are dropped.
+    pub max_packet_size: usize,
+    /// How sessions shape what they send. Padding must keep packets within `max_packet_size`.
+    pub traffic_shape: TrafficShape,
+    /// How many sessions can wait to be accepted before new ones are refused. Unbounded if None.
+    pub accept_queue_len: Option<usize>,
+    /// Gatherer for the listener's traffic, recorded as `listener_recv_bytes` and `listener_send_bytes`.
+    pub gather: Arc<StatsGatherer>,
+    /// Creates the statistics gatherer of every accepted session, available through [Session::stats](crate::Session::stats). If None, sessions get a no-op gatherer, since active ones cost some memory per session.
+    pub session_gather: Option<GathererFactory>,
+    /// Called on every handshake from a new client. The handshake is ignored if this returns false.
+    pub auth_hook: Option<AuthHook>,
+    /// Limits enforced on sessions.
+    pub limits: ListenerLimits,
+    /// Secret that the keys of resume tokens are derived from. Listeners with the same seed, such as the same server before and after a restart, accept each other's tokens, so clients get their sessions back instead of waiting for them to time out. If None, keys are random, and are ratcheted forward so that old tokens cannot be decrypted even if the server is compromised later on; a seed gives that up, and must be kept as secret as the long-term keys.
+    pub token_seed: Option<[u8; 32]>,
+    /// Accept handshakes and resume tokens that use the legacy (version 3) crypto construction, for clients too old to speak anything newer. Turning this off leaves old clients unable to connect, but means that nothing the listener accepts relies on the legacy construction.
+    pub allow_legacy_handshake: bool,
+}
+
+impl ListenerConfig {
+    /// Creates a new ListenerConfig that listens nowhere and imposes no limits.
+    pub fn new(long_sk: x25519_dalek::StaticSecret) -> Self {
+        Self {
+            long_sks: vec![long_sk],
+            udp_addr: None,
+            udp_alt_addrs: Vec::new(),
+            tcp_addr: None,
+            tls_identity: None,
+            tls_alpn: Vec::new(),
+            tls_min_version: None,
+            tls_max_version: Some(TlsVersion::Tls12),
+            udp_socket_count: 1,
+            udp_recv_buffer: runtime::UDP_RECV_BUFFER,
+            udp_send_buffer: runtime::UDP_SEND_BUFFER,
+            udp_mimicry: Mimicry::None,
+            preferred_addr: None,
+            max_packet_size: 1400,
+            traffic_shape: Default::default(),
+            accept_queue_len: None,
+            gather: Default::default(),
+            session_gather: None,
+            auth_hook: None,
+            limits: Default::default(),
+            token_seed: None,
+            allow_legacy_handshake: true,
+        }
+    }
+
+    /// Builds a Listener out of this ListenerConfig.
+    pub async fn listen(self) -> std::io::Result<Listener> {
+        let gather = self.gather.clone();
+        let gather2 = self.gather.clone();
+        Listener::listen_with_hooks(
+            self,
+            move |len, _| gather.increment("listener_recv_bytes", len as f32),
+            move |len, _| gather2.increment("listener_send_bytes", len as f32),
+        )
+        .await
+    }
+}
+
+/// Limits enforced by a [Listener] on the sessions it accepts and on handshakes. The default imposes no limits on sessions, and allows 100 handshakes per second, in bursts of up to 200, from every IP address or IPv6 /64, and 10000 per second, in bursts of up to 20000, in total.
+#[derive(Debug, Clone)]
+pub struct ListenerLimits {
+    /// Maximum number of sessions alive at the same time.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of sessions created from the same IP address.
+    pub max_sessions_per_ip: Option<usize>,
+    /// Maximum bytes per second each session may receive, and separately send. Packets beyond the limit are dropped.
+    pub session_rate_limit: Option<u64>,
+    /// Sessions that neither send nor receive anything for this long are removed.
+    pub idle_timeout: Option<Duration>,
+    /// Handshake packets per second processed from every IP address, or IPv6 /64. These include the resumes of shards from addresses that no session is bound to yet; those from bound addresses are not limited, since they are just keepalives.
+    pub handshake_rate_per_ip: f64,
+    /// Handshake packets processed from every IP address, or IPv6 /64, in a burst.
+    pub handshake_burst_per_ip: f64,
+    /// Handshake packets per second processed in total.
+    pub handshake_rate: f64,
+    /// Handshake packets processed in total in a burst.
+    pub handshake_burst: f64,
+}
+
+impl Default for ListenerLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: None,
+            max_sessions_per_ip: None,
+            session_rate_limit: None,
+            idle_timeout: None,
+            handshake_rate_per_ip: 100.0,
+            handshake_burst_per_ip: 200.0,
+            handshake_rate: 10000.0,
+            handshake_burst: 20000.0,
+        }
+    }
+}
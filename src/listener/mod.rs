@@ -1,33 +1,61 @@
-use crate::tcp::TcpServerBackhaul;
+#[cfg(feature = "post-quantum")]
+use crate::crypt::{hybrid_mix, mlkem_encapsulate};
+use crate::tcp::{TcpServerBackhaul, TlsServer};
 use crate::{
     backhaul::{Backhaul, StatsBackhaul},
-    crypt::{triple_ecdh, Cookie, LegacyAead},
+    crypt::{
+        path_response, path_response_key, resume_proof, resume_proof_key, triple_ecdh, Cookie,
+        HandshakeAead,
+    },
+    mimic::MimicBackhaul,
     protocol::HandshakeFrame,
-    runtime, safe_deserialize, Role,
+    runtime, Role,
 };
 use crate::{buffer::Buff, protocol::HandshakeFrame::*};
 use crate::{
     recfilter::RECENT_FILTER,
-    session::{Session, SessionConfig},
+    session::{Session, SessionConfig, TrafficShape},
 };
-use parking_lot::RwLock;
+use arc_swap::ArcSwap;
+use parking_lot::{Mutex, RwLock};
 use rand::prelude::*;
-use serde::{Deserialize, Serialize};
+use rustc_hash::FxHasher;
 use smol::net::AsyncToSocketAddrs;
 use smol::{
     channel::{Receiver, Sender},
     net::TcpListener,
 };
+use std::hash::{Hash, Hasher};
 use std::sync::{atomic::Ordering, Arc};
 use std::{
     net::SocketAddr,
     sync::atomic::{AtomicBool, AtomicUsize},
+    time::{Duration, Instant},
 };
 use table::ShardedAddrs;
 
-use table::SessionTable;
+use ratelimit::{HandshakeLimiter, SessionLimiter};
+use table::{PendingPaths, SessEntry, SessionTable};
+use token::{TokenInfo, TokenKeys, TOKEN_REFRESH_AGE};
 
+pub use config::{AuthHook, GathererFactory, ListenerConfig, ListenerLimits};
+
+mod config;
+mod ratelimit;
 mod table;
+mod token;
+
+/// How many handshake packets can wait for a handshake worker before new ones are dropped.
+const HANDSHAKE_QUEUE_LEN: usize = 1000;
+
+/// How often idle sessions are looked for.
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a shutting-down listener checks whether all sessions are gone.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How far the time of a resume proof may be from ours. The replay filter remembers proofs for longer than this, so none can be used twice.
+const RESUME_PROOF_MAX_SKEW: Duration = Duration::from_secs(150);
 
 /// Statistics for a sosistab listener.
 #[derive(Debug, Default)]
@@ -35,16 +63,43 @@ pub struct ListenerStats {
     pub packets_processed: AtomicUsize,
     pub packets_failed: AtomicUsize,
     pub packets_replay: AtomicUsize,
+    /// Handshake packets dropped by the per-source or global handshake rate limits.
+    pub handshakes_ratelimited: AtomicUsize,
+    /// Handshake packets dropped because the handshake workers were too far behind.
+    pub handshakes_overloaded: AtomicUsize,
+    /// Handshake replies dropped because they would have been larger than the request to an unvalidated address.
+    pub replies_amplification: AtomicUsize,
+    /// Handshakes rejected by the auth hook.
+    pub handshakes_unauthorized: AtomicUsize,
     pub injecting: AtomicBool,
     pub handshaking: AtomicBool,
     pub sessions_queued: AtomicUsize,
+    /// Sessions currently alive.
+    pub sessions_active: AtomicUsize,
+    /// New sessions refused because there were already too many sessions.
+    pub sessions_rejected: AtomicUsize,
+    /// New sessions refused because their IP address already had too many sessions.
+    pub sessions_rejected_per_ip: AtomicUsize,
+    /// Session packets dropped, in either direction, because the session exceeded its rate limit.
+    pub packets_ratelimited: AtomicUsize,
+    /// Sessions removed because they were idle for too long.
+    pub sessions_evicted_idle: AtomicUsize,
+    /// New addresses of existing sessions that were challenged, or, for legacy clients, that were waited on for session traffic.
+    pub paths_challenged: AtomicUsize,
+    /// New addresses that answered their path challenge or sent session traffic, and that sessions were rebound to.
+    pub paths_validated: AtomicUsize,
 }
 
 /// A sosistab listener.
 pub struct Listener {
     accepted: Receiver<Session>,
-    local_addr: SocketAddr,
+    udp_local_addr: Option<SocketAddr>,
+    udp_alt_local_addrs: Vec<SocketAddr>,
+    tcp_local_addr: Option<SocketAddr>,
     stats: Arc<ListenerStats>,
+    limits: Arc<ArcSwap<ListenerLimits>>,
+    session_table: SessionTable,
+    shutting_down: Arc<AtomicBool>,
     _task: Vec<smol::Task<()>>,
 }
 
@@ -54,118 +109,374 @@ impl Listener {
     pub async fn accept_session(&self) -> Option<Session> {
         self.accepted.recv().await.ok()
     }
+
     /// Creates a new listener given the parameters.
+    #[deprecated(note = "use ListenerConfig")]
     pub async fn listen_udp(
         addr: SocketAddr,
         long_sk: x25519_dalek::StaticSecret,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
     ) -> std::io::Result<Self> {
-        #[cfg(not(target_os = "linux"))]
-        let socket = runtime::new_udp_socket_bind(addr)?;
-        #[cfg(target_os = "linux")]
-        let socket = fastudp::FastUdpSocket::from(runtime::new_udp_socket_bind_sync(addr)?);
-        let local_addr = socket.get_ref().local_addr().unwrap();
-        let cookie = Cookie::new((&long_sk).into());
-        let (send, recv) = smol::channel::unbounded();
-        let stats: Arc<ListenerStats> = Default::default();
-        let la = ListenerActor::new(
-            Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
-            cookie,
-            long_sk,
-            stats.clone(),
-        );
-        // let task = (0..std::thread::available_parallelism().unwrap().get())
-        //     .map(|_| runtime::spawn(la.clone().run(send.clone())))
-        //     .collect();
-        Ok(Listener {
-            accepted: recv,
-            local_addr,
-            stats,
-            _task: vec![runtime::spawn(la.run(send))],
-        })
+        let mut cfg = ListenerConfig::new(long_sk);
+        cfg.udp_addr = Some(addr);
+        Self::listen_with_hooks(cfg, on_recv, on_send).await
     }
 
     /// Creates a new listener given the parameters.
+    #[deprecated(note = "use ListenerConfig")]
     pub async fn listen_tcp(
         addr: impl AsyncToSocketAddrs,
         long_sk: x25519_dalek::StaticSecret,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
     ) -> std::io::Result<Self> {
-        // let addr = async_net::resolve(addr).await;
-        let listener = TcpListener::bind(addr).await?;
-        let local_addr = listener.local_addr().unwrap();
-        let cookie = Cookie::new((&long_sk).into());
-        let socket = TcpServerBackhaul::new(listener, long_sk.clone());
-        let (send, recv) = smol::channel::unbounded();
+        let mut cfg = ListenerConfig::new(long_sk);
+        cfg.tcp_addr = smol::net::resolve(addr).await?.first().copied();
+        Self::listen_with_hooks(cfg, on_recv, on_send).await
+    }
+
+    /// Creates a listener from a config, calling the given closures with the length and address of every packet received and sent.
+    async fn listen_with_hooks(
+        cfg: ListenerConfig,
+        on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+    ) -> std::io::Result<Self> {
+        if cfg.long_sks.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "listener needs at least one key",
+            ));
+        }
+        if cfg.udp_addr.is_none() && cfg.tcp_addr.is_none() && cfg.udp_alt_addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "listener needs a UDP or TCP address",
+            ));
+        }
+        let on_recv: ByteHook = Arc::new(on_recv);
+        let on_send: ByteHook = Arc::new(on_send);
         let stats: Arc<ListenerStats> = Default::default();
-        let task = runtime::spawn(
-            ListenerActor::new(
-                Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
-                cookie,
-                long_sk,
-                stats.clone(),
-            )
-            .run(send),
+        let limits = Arc::new(ArcSwap::from_pointee(cfg.limits.clone()));
+        let (send, recv) = match cfg.accept_queue_len {
+            Some(len) => smol::channel::bounded(len),
+            None => smol::channel::unbounded(),
+        };
+        // every socket gets its own actor, but they share all other state, including the sessions
+        let mut sockets = Vec::new();
+        let mut udp_local_addr = None;
+        if let Some(addr) = cfg.udp_addr {
+            let reuse_port = cfg!(unix) && cfg.udp_socket_count > 1;
+            let socket_count = if reuse_port { cfg.udp_socket_count } else { 1 };
+            let bind = |addr: SocketAddr| {
+                let socket = runtime::new_udp_socket_bind_sync(
+                    addr,
+                    reuse_port,
+                    cfg.udp_recv_buffer,
+                    cfg.udp_send_buffer,
+                )?;
+                #[cfg(not(target_os = "linux"))]
+                let socket = smol::Async::new(socket)?;
+                #[cfg(target_os = "linux")]
+                let socket = fastudp::FastUdpSocket::from(socket);
+                Ok::<_, std::io::Error>(socket)
+            };
+            let first_socket = bind(addr)?;
+            // if we were asked for a random port, all the other sockets must use the same one
+            let local_addr = first_socket.get_ref().local_addr().unwrap();
+            udp_local_addr = Some(local_addr);
+            let mimic = |socket| MimicBackhaul::new(socket, cfg.udp_mimicry, Role::Server);
+            sockets.push(with_hooks(mimic(first_socket), &on_recv, &on_send));
+            for _ in 1..socket_count {
+                sockets.push(with_hooks(mimic(bind(local_addr)?), &on_recv, &on_send));
+            }
+        }
+        let mut udp_alt_local_addrs = Vec::new();
+        for addr in cfg.udp_alt_addrs.iter() {
+            let socket = runtime::new_udp_socket_bind_sync(
+                *addr,
+                false,
+                cfg.udp_recv_buffer,
+                cfg.udp_send_buffer,
+            )?;
+            udp_alt_local_addrs.push(socket.local_addr()?);
+            #[cfg(not(target_os = "linux"))]
+            let socket = smol::Async::new(socket)?;
+            #[cfg(target_os = "linux")]
+            let socket = fastudp::FastUdpSocket::from(socket);
+            sockets.push(with_hooks(
+                MimicBackhaul::new(socket, cfg.udp_mimicry, Role::Server),
+                &on_recv,
+                &on_send,
+            ));
+        }
+        let mut tcp_local_addr = None;
+        if let Some(addr) = cfg.tcp_addr {
+            let listener = TcpListener::bind(addr).await?;
+            tcp_local_addr = Some(listener.local_addr()?);
+            sockets.push(with_hooks(
+                TcpServerBackhaul::new(
+                    listener,
+                    cfg.long_sks.clone(),
+                    TlsServer::new(
+                        cfg.tls_identity.clone(),
+                        cfg.tls_alpn.clone(),
+                        (cfg.tls_min_version, cfg.tls_max_version),
+                    )?,
+                ),
+                &on_recv,
+                &on_send,
+            ));
+        }
+        // handshakes are expensive, so they go to a bounded pool of workers, shared by all the sockets, rather than delaying packets of existing sessions
+        let worker_count = num_cpus::get();
+        let (handshake_queues, handshake_recvs): (Vec<_>, Vec<_>) = (0..worker_count)
+            .map(|_| smol::channel::bounded(HANDSHAKE_QUEUE_LEN / worker_count + 1))
+            .unzip();
+        let la = ListenerActor::new(
+            sockets[0].clone(),
+            &cfg,
+            stats.clone(),
+            limits.clone(),
+            send,
+            handshake_queues,
+        );
+        let mut tasks = vec![runtime::spawn(la.clone().idle_sweeper())];
+        tasks.extend(
+            handshake_recvs
+                .into_iter()
+                .map(|recv| runtime::spawn(la.clone().handshake_worker(recv))),
+        );
+        tasks.extend(
+            sockets
+                .into_iter()
+                .map(|socket| runtime::spawn(la.with_socket(socket).run())),
         );
         Ok(Listener {
             accepted: recv,
-            local_addr,
+            udp_local_addr,
+            udp_alt_local_addrs,
+            tcp_local_addr,
             stats,
-            _task: vec![task],
+            limits,
+            session_table: la.session_table.clone(),
+            shutting_down: la.shutting_down.clone(),
+            _task: tasks,
         })
     }
 
+    /// Shuts down the listener gracefully. New handshakes are refused right away, while existing sessions keep working until they are dropped or the grace period ends, after which they are all killed and the sockets released. If `close_sessions` is set, clients are told that their sessions are closing, so that they can go elsewhere instead of waiting for the sessions to time out. Returns how many sessions were still alive at the end of the grace period.
+    pub async fn shutdown(self, grace: Duration, close_sessions: bool) -> usize {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        // sessions nobody accepted yet will never be used
+        self.accepted.close();
+        while self.accepted.try_recv().is_ok() {}
+        if close_sessions {
+            for session_back in self.session_table.session_backs() {
+                session_back.send_close();
+            }
+        }
+        let deadline = Instant::now() + grace;
+        while !self.session_table.is_empty() && Instant::now() < deadline {
+            smol::Timer::after(SHUTDOWN_POLL_INTERVAL).await;
+        }
+        let remaining = self.session_table.len();
+        tracing::debug!("listener shut down with {} sessions remaining", remaining);
+        self.session_table.clear();
+        remaining
+    }
+
+    /// Asks every current client to migrate its session to the given address, where the listener uses the given long-term public key. The address must lead back to this listener, for example through one of the `udp_alt_addrs`. Clients that cannot reach it stay where they are. Returns how many sessions were asked.
+    pub fn migrate_sessions(&self, addr: SocketAddr, server_pk: x25519_dalek::PublicKey) -> usize {
+        let session_backs = self.session_table.session_backs();
+        for session_back in session_backs.iter() {
+            session_back.send_migrate(addr, server_pk);
+        }
+        session_backs.len()
+    }
+
     /// Obtains the stats of this listener
     pub fn listener_stats(&self) -> Arc<ListenerStats> {
         self.stats.clone()
     }
 
-    /// Gets the local address.
+    /// Sets the limits enforced on sessions and handshakes. Session limits, handshake limits and the idle timeout take effect immediately, while session rate limits only apply to sessions created afterwards.
+    pub fn set_limits(&self, limits: ListenerLimits) {
+        self.limits.store(Arc::new(limits));
+    }
+
+    /// Gets the current limits enforced on sessions and handshakes.
+    pub fn limits(&self) -> ListenerLimits {
+        ListenerLimits::clone(&self.limits.load())
+    }
+
+    /// Gets the local address, preferring the UDP one if the listener serves both UDP and TCP.
     pub fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+        self.udp_local_addr
+            .or(self.tcp_local_addr)
+            .expect("listener has no address")
+    }
+
+    /// Gets the local UDP address, if the listener serves UDP.
+    pub fn udp_local_addr(&self) -> Option<SocketAddr> {
+        self.udp_local_addr
+    }
+
+    /// Gets the local addresses of the additional UDP sockets, in the same order as in the config.
+    pub fn udp_alt_local_addrs(&self) -> &[SocketAddr] {
+        &self.udp_alt_local_addrs
+    }
+
+    /// Gets the local TCP address, if the listener serves TCP.
+    pub fn tcp_local_addr(&self) -> Option<SocketAddr> {
+        self.tcp_local_addr
     }
 }
 
+type ByteHook = Arc<dyn Fn(usize, SocketAddr) + Send + Sync + 'static>;
+
+/// Wraps a socket so that it reports the length and address of every packet it receives and sends.
+fn with_hooks<B: Backhaul + 'static>(
+    socket: B,
+    on_recv: &ByteHook,
+    on_send: &ByteHook,
+) -> Arc<dyn Backhaul> {
+    let on_recv = on_recv.clone();
+    let on_send = on_send.clone();
+    Arc::new(StatsBackhaul::new(
+        socket,
+        move |len, addr| on_recv(len, addr),
+        move |len, addr| on_send(len, addr),
+    ))
+}
+
+/// Attempts to decrypt a handshake frame under every possible cookie key, returning the frame, whether it used the legacy construction, and the index of the long-term key it was sent to.
+fn decrypt_handshake(
+    keys: &[(x25519_dalek::StaticSecret, Cookie)],
+    buffer: &[u8],
+    allow_legacy: bool,
+) -> Option<(HandshakeFrame, bool, usize)> {
+    for (key_idx, (_, cookie)) in keys.iter().enumerate() {
+        for possible_key in cookie.generate_c2s() {
+            for legacy in [false, true] {
+                if legacy && !allow_legacy {
+                    continue;
+                }
+                let crypter = HandshakeAead::new(&possible_key, legacy);
+                if let Some(mut handshake) = crypter.pad_decrypt::<HandshakeFrame>(buffer) {
+                    return Some((handshake.swap_remove(0), legacy, key_idx));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether a resume proof made at the given time is recent enough to accept. Clocks may disagree by as much as the cookie keys tolerate.
+fn is_fresh(time_ms: u64) -> bool {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    now_ms.abs_diff(time_ms) <= RESUME_PROOF_MAX_SKEW.as_millis() as u64
+}
+
+/// Handshake versions the listener accepts.
+fn supported_versions(allow_legacy: bool) -> Vec<u64> {
+    let mut versions = vec![4];
+    if allow_legacy {
+        versions.insert(0, 3);
+    }
+    if cfg!(feature = "post-quantum") {
+        versions.push(5);
+    }
+    versions
+}
+
+/// A packet that may be a handshake, along with the socket it came from.
+type HandshakeJob = (Arc<dyn Backhaul>, Buff, SocketAddr);
+
 #[derive(Clone)]
 struct ListenerActor {
     socket: Arc<dyn Backhaul>,
-    cookie: Cookie,
-    long_sk: x25519_dalek::StaticSecret,
-    token_key: [u8; 32],
+    /// Long-term secret keys, together with their cookies.
+    keys: Arc<Vec<(x25519_dalek::StaticSecret, Cookie)>>,
+    token_keys: Arc<Mutex<TokenKeys>>,
+    handshake_limiter: Arc<Mutex<HandshakeLimiter>>,
+    session_creation: Arc<Mutex<()>>,
 
     session_table: SessionTable,
+    pending_paths: PendingPaths,
+    accepted: Sender<Session>,
+    // channel for dropping sessions
+    send_dead: Sender<Buff>,
+    recv_dead: Receiver<Buff>,
 
+    /// Set once the listener starts shutting down, after which no new sessions are created.
+    shutting_down: Arc<AtomicBool>,
+    max_packet_size: usize,
+    traffic_shape: TrafficShape,
+    preferred_addr: Option<(SocketAddr, x25519_dalek::PublicKey)>,
+    auth_hook: Option<AuthHook>,
+    session_gather: Option<GathererFactory>,
     stats: Arc<ListenerStats>,
+    limits: Arc<ArcSwap<ListenerLimits>>,
+    /// Whether handshakes that use the legacy (version 3) crypto construction are accepted.
+    allow_legacy: bool,
+    /// Queues of the handshake workers, which all the sockets share.
+    handshake_queues: Arc<[Sender<HandshakeJob>]>,
 }
 impl ListenerActor {
     fn new(
         socket: Arc<dyn Backhaul>,
-        cookie: Cookie,
-        long_sk: x25519_dalek::StaticSecret,
+        cfg: &ListenerConfig,
         stats: Arc<ListenerStats>,
+        limits: Arc<ArcSwap<ListenerLimits>>,
+        accepted: Sender<Session>,
+        handshake_queues: Vec<Sender<HandshakeJob>>,
     ) -> Self {
-        let token_key = {
-            let mut buf = [0u8; 32];
-            rand::thread_rng().fill_bytes(&mut buf);
-            buf
-        };
-
+        let (send_dead, recv_dead) = smol::channel::unbounded();
         Self {
             socket,
-            cookie,
-            long_sk,
-            token_key,
+            keys: Arc::new(
+                cfg.long_sks
+                    .iter()
+                    .map(|long_sk| (long_sk.clone(), Cookie::new(long_sk.into())))
+                    .collect(),
+            ),
+            token_keys: Arc::new(Mutex::new(TokenKeys::new(
+                cfg.token_seed,
+                cfg.allow_legacy_handshake,
+            ))),
+            handshake_limiter: Arc::new(Mutex::new(HandshakeLimiter::new(&limits.load()))),
+            session_creation: Default::default(),
             session_table: SessionTable::default(),
+            pending_paths: PendingPaths::default(),
+            accepted,
+            send_dead,
+            recv_dead,
+            shutting_down: Default::default(),
+            max_packet_size: cfg.max_packet_size,
+            traffic_shape: cfg.traffic_shape.clone(),
+            preferred_addr: cfg.preferred_addr,
+            auth_hook: cfg.auth_hook.clone(),
+            session_gather: cfg.session_gather.clone(),
             stats,
+            limits,
+            allow_legacy: cfg.allow_legacy_handshake,
+            handshake_queues: handshake_queues.into(),
         }
     }
 
-    async fn run(mut self, accepted: Sender<Session>) {
-        // channel for dropping sessions
-        let (send_dead, recv_dead) = smol::channel::unbounded();
+    /// Creates an actor that shares all state with this one, but uses a different socket.
+    fn with_socket(&self, socket: Arc<dyn Backhaul>) -> Self {
+        Self {
+            socket,
+            ..self.clone()
+        }
+    }
 
+    async fn run(self) {
         // two possible events
         enum Evt {
             NewRecv((Buff, SocketAddr)),
@@ -175,72 +486,262 @@ impl ListenerActor {
         loop {
             let event = smol::future::race(
                 async { Evt::NewRecv(self.socket.recv_from().await.unwrap()) },
-                async { Evt::DeadSess(recv_dead.recv().await.unwrap()) },
+                async { Evt::DeadSess(self.recv_dead.recv().await.unwrap()) },
             );
             self.stats
                 .sessions_queued
-                .store(accepted.len(), Ordering::Relaxed);
+                .store(self.accepted.len(), Ordering::Relaxed);
             match event.await {
-                Evt::DeadSess(resume_token) => {
-                    self.session_table.delete(resume_token);
+                Evt::DeadSess(sess_id) => {
+                    self.session_table.delete(sess_id);
+                    self.stats
+                        .sessions_active
+                        .store(self.session_table.len(), Ordering::Relaxed);
                 }
                 Evt::NewRecv((buffer, addr)) => {
                     self.stats.packets_processed.fetch_add(1, Ordering::Relaxed);
                     // first we attempt to map this to an existing session
-                    if let Some(handle) = self.session_table.lookup(addr) {
+                    let bound = self.session_table.lookup(addr);
+                    if let Some(handle) = &bound {
+                        if !handle.limiter.allow_recv(buffer.len()) {
+                            self.stats
+                                .packets_ratelimited
+                                .fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
                         self.stats.injecting.store(true, Ordering::Relaxed);
                         scopeguard::defer!(self.stats.injecting.store(false, Ordering::Relaxed));
-                        if handle.inject_incoming(&buffer).is_ok() {
+                        if let Ok(fresh) = handle.session_back.inject_incoming(&buffer) {
+                            handle.limiter.touch();
+                            if fresh {
+                                handle.addrs.read().carried_data(addr);
+                            }
                             continue;
                         }
                     }
-                    // we know it's not part of an existing session then. we decrypt it under the current key
-                    let stats = self.stats.clone();
-                    stats.handshaking.store(true, Ordering::Relaxed);
-                    scopeguard::defer!(stats.handshaking.store(false, Ordering::Relaxed));
-                    let s2c_key = self.cookie.generate_s2c().next().unwrap();
-                    let mut failed = true;
-                    for possible_key in self.cookie.generate_c2s() {
-                        let crypter = LegacyAead::new(&possible_key);
-                        if let Some(handshake) = crypter.pad_decrypt_v1::<HandshakeFrame>(&buffer) {
-                            if !RECENT_FILTER.lock().check(&buffer) {
-                                tracing::error!(
-                                    "discarding replay attempt with len {} from {addr}: {:?}",
-                                    buffer.len(),
-                                    handshake
-                                );
-                                self.stats.packets_replay.fetch_add(1, Ordering::Relaxed);
-                                break;
-                            }
-                            tracing::trace!("decoded some sort of handshake: {:?}", handshake);
-                            let handshake = handshake[0].clone();
-                            self.handle_handshake(
-                                handshake,
-                                s2c_key,
-                                addr,
-                                send_dead.clone(),
-                                accepted.clone(),
-                            )
-                            .await;
-                            failed = false;
-                            break;
-                        }
+                    // we know it's not part of an existing session then. before doing any expensive decryption, we check the rate limits, unless a session is bound to the address, since its shards send resumes every second to keep alive, and are already limited by the session's own rate limit
+                    if bound.is_none()
+                        && !self
+                            .handshake_limiter
+                            .lock()
+                            .check(self.socket.peer_addr(addr).ip(), &self.limits.load())
+                    {
+                        self.stats
+                            .handshakes_ratelimited
+                            .fetch_add(1, Ordering::Relaxed);
+                        continue;
                     }
-                    if failed {
-                        self.stats.packets_failed.fetch_add(1, Ordering::Relaxed);
+                    // every source address always goes to the same worker, so that its packets are still processed in order
+                    let worker_idx = {
+                        let mut hasher = FxHasher::default();
+                        addr.hash(&mut hasher);
+                        hasher.finish() as usize % self.handshake_queues.len()
+                    };
+                    if self.handshake_queues[worker_idx]
+                        .try_send((self.socket.clone(), buffer, addr))
+                        .is_err()
+                    {
+                        self.stats
+                            .handshakes_overloaded
+                            .fetch_add(1, Ordering::Relaxed);
                     }
                 }
             }
         }
     }
 
+    /// Periodically removes sessions that have been idle for longer than the configured timeout.
+    async fn idle_sweeper(self) {
+        loop {
+            smol::Timer::after(IDLE_SWEEP_INTERVAL).await;
+            if let Some(timeout) = self.limits.load().idle_timeout {
+                let evicted = self.session_table.evict_idle(timeout);
+                if evicted > 0 {
+                    tracing::debug!("evicted {} idle sessions", evicted);
+                    self.stats
+                        .sessions_evicted_idle
+                        .fetch_add(evicted, Ordering::Relaxed);
+                    self.stats
+                        .sessions_active
+                        .store(self.session_table.len(), Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Processes potential handshake packets from the queue, on behalf of whichever socket each came from.
+    async fn handshake_worker(self, recv_handshake: Receiver<HandshakeJob>) {
+        while let Ok((socket, buffer, addr)) = recv_handshake.recv().await {
+            self.with_socket(socket)
+                .process_handshake(buffer, addr)
+                .await;
+        }
+    }
+
+    /// Processes a packet that may be a handshake.
+    async fn process_handshake(&self, buffer: Buff, addr: SocketAddr) {
+        // an earlier handshake in our queue may have created the session this packet belongs to
+        if let Some(handle) = self.session_table.lookup(addr) {
+            if handle.session_back.inject_incoming(&buffer).is_ok() {
+                handle.limiter.touch();
+                return;
+            }
+        }
+        // or it may come from a legacy client moving its session here
+        if self.validate_by_traffic(&buffer, addr) {
+            return;
+        }
+        // we decrypt it under the current key
+        let stats = self.stats.clone();
+        stats.handshaking.store(true, Ordering::Relaxed);
+        scopeguard::defer!(stats.handshaking.store(false, Ordering::Relaxed));
+        if let Some((handshake, legacy, key_idx)) =
+            decrypt_handshake(&self.keys, &buffer, self.allow_legacy)
+        {
+            if !RECENT_FILTER.lock().check(&buffer) {
+                tracing::error!(
+                    "discarding replay attempt with len {} from {addr}: {:?}",
+                    buffer.len(),
+                    handshake
+                );
+                self.stats.packets_replay.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            tracing::trace!("decoded some sort of handshake: {:?}", handshake);
+            // we reply under the key the client used
+            let (long_sk, cookie) = &self.keys[key_idx];
+            let s2c_key = cookie.generate_s2c().next().unwrap();
+            self.handle_handshake(
+                handshake,
+                HandshakeAead::new(&s2c_key, legacy),
+                long_sk,
+                addr,
+                buffer.len(),
+            )
+            .await;
+        } else {
+            self.stats.packets_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Creates a resume token that lets the client with the given ephemeral key reconstruct a session with the given key.
+    fn new_resume_token(
+        &self,
+        sess_key: blake3::Hash,
+        version: u64,
+        client_eph_pk: [u8; 32],
+    ) -> Buff {
+        self.token_keys.lock().encrypt(&TokenInfo {
+            sess_key: Buff::copy_from_slice(sess_key.as_bytes()),
+            init_time_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            version,
+            client_eph_pk,
+        })
+    }
+
+    /// Sends a handshake reply, padded to the usual length. Unless the address is already validated by having a session bound to it, the reply is never larger than the request, so that the listener cannot be used for UDP amplification.
+    async fn send_reply(
+        &self,
+        s2c_crypter: &HandshakeAead,
+        frames: &[HandshakeFrame],
+        addr: SocketAddr,
+        request_len: usize,
+    ) {
+        let validated = self.session_table.lookup(addr).is_some();
+        let target_len = if validated {
+            1000
+        } else {
+            // leave room for the random extra padding
+            1000.min(request_len.saturating_sub(s2c_crypter.overhead() + 10))
+        };
+        let reply = s2c_crypter.pad_encrypt(frames, target_len);
+        if !validated && reply.len() > request_len {
+            tracing::debug!(
+                "not sending {}-byte reply to {}-byte request from unvalidated {}",
+                reply.len(),
+                request_len,
+                addr
+            );
+            self.stats
+                .replies_amplification
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Err(err) = self.socket.send_to(reply, addr).await {
+            tracing::error!("weird socket error {:?}", err);
+        }
+    }
+
+    /// Tells a client that its hello has a version we do not accept, listing the ones we do.
+    async fn reject_version(
+        &self,
+        s2c_crypter: &HandshakeAead,
+        addr: SocketAddr,
+        request_len: usize,
+    ) {
+        let reply = ServerRejectVersion {
+            supported: supported_versions(self.allow_legacy),
+        };
+        self.send_reply(s2c_crypter, &[reply], addr, request_len)
+            .await;
+    }
+
+    /// Asks the auth hook, if any, whether the client may establish a session.
+    fn authorize(&self, addr: SocketAddr, long_pk: x25519_dalek::PublicKey) -> bool {
+        let authorized = self
+            .auth_hook
+            .as_ref()
+            .map(|hook| hook(self.socket.peer_addr(addr), long_pk))
+            .unwrap_or(true);
+        if !authorized {
+            tracing::debug!("auth hook rejected handshake from {}", addr);
+            self.stats
+                .handshakes_unauthorized
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        authorized
+    }
+
+    /// Moves a shard of a legacy session to the given address, if the packet is session traffic from there that delivers fresh data. Returns whether the packet belonged to the session.
+    fn validate_by_traffic(&self, buffer: &[u8], addr: SocketAddr) -> bool {
+        let mut belongs = false;
+        let pending = self.pending_paths.take_by_traffic(addr, |sess_id| {
+            match self.session_table.lookup_id(sess_id) {
+                Some(handle) => match handle.session_back.inject_incoming(buffer) {
+                    Ok(fresh) => {
+                        belongs = true;
+                        handle.limiter.touch();
+                        fresh
+                    }
+                    Err(_) => false,
+                },
+                None => false,
+            }
+        });
+        if let Some(pending) = pending {
+            let _guard = self.session_creation.lock();
+            if self
+                .session_table
+                .rebind(addr, pending.socket, pending.shard_id, pending.sess_id)
+                .is_some()
+            {
+                tracing::debug!("validated new legacy path {}", addr);
+                self.stats.paths_validated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        belongs
+    }
+
     async fn handle_handshake(
-        &mut self,
+        &self,
         handshake: HandshakeFrame,
-        s2c_key: [u8; 32],
+        s2c_crypter: HandshakeAead,
+        long_sk: &x25519_dalek::StaticSecret,
         addr: SocketAddr,
-        send_dead: Sender<Buff>,
-        accepted: Sender<Session>,
+        request_len: usize,
     ) {
         match handshake {
             ClientHello {
@@ -248,128 +749,551 @@ impl ListenerActor {
                 eph_pk,
                 version,
             } => {
-                if version != 3 {
+                if self.shutting_down.load(Ordering::Relaxed) {
+                    tracing::debug!("refusing ClientHello from {} while shutting down", addr);
+                    return;
+                }
+                // version 3 uses the legacy construction, and later versions don't
+                if !matches!((version, s2c_crypter.is_legacy()), (3, true) | (4, false)) {
                     tracing::warn!("got packet with incorrect version {}", version);
+                    self.reject_version(&s2c_crypter, addr, request_len).await;
+                    return;
+                }
+                if !self.authorize(addr, long_pk) {
                     return;
                 }
                 // generate session key
                 let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-                let token = TokenInfo {
-                    sess_key: Buff::copy_from_slice(
-                        triple_ecdh(&self.long_sk, &my_eph_sk, &long_pk, &eph_pk).as_bytes(),
-                    ),
-                    init_time_ms: std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64,
-                    version,
-                }
-                .encrypt(&self.token_key);
+                let sess_key = triple_ecdh(long_sk, &my_eph_sk, &long_pk, &eph_pk);
                 let reply = HandshakeFrame::ServerHello {
-                    long_pk: (&self.long_sk).into(),
+                    long_pk: long_sk.into(),
                     eph_pk: (&my_eph_sk).into(),
-                    resume_token: token,
+                    resume_token: self.new_resume_token(sess_key, version, *eph_pk.as_bytes()),
                 };
-                let reply = LegacyAead::new(&s2c_key).pad_encrypt_v1(&[reply], 1000);
                 tracing::debug!("GONNA reply to ClientHello from {}", addr);
-                if let Err(err) = self.socket.send_to(reply, addr).await {
-                    tracing::error!("weird socket error {:?}", err);
-                }
+                self.send_reply(&s2c_crypter, &[reply], addr, request_len)
+                    .await;
                 tracing::debug!("replied to ClientHello from {}", addr);
             }
+            #[cfg(feature = "post-quantum")]
+            ClientHelloHybrid {
+                long_pk,
+                eph_pk,
+                version,
+                kem_ek,
+            } => {
+                if self.shutting_down.load(Ordering::Relaxed) {
+                    tracing::debug!(
+                        "refusing ClientHelloHybrid from {} while shutting down",
+                        addr
+                    );
+                    return;
+                }
+                // only version 5 is hybrid
+                if version != 5 || s2c_crypter.is_legacy() {
+                    tracing::warn!("got hybrid packet with incorrect version {}", version);
+                    self.reject_version(&s2c_crypter, addr, request_len).await;
+                    return;
+                }
+                if !self.authorize(addr, long_pk) {
+                    return;
+                }
+                let (kem_ct, kem_secret) = match mlkem_encapsulate(&kem_ek) {
+                    Some(v) => v,
+                    None => {
+                        tracing::warn!("got malformed ML-KEM key from {}", addr);
+                        return;
+                    }
+                };
+                // generate session key, mixing in the KEM secret
+                let my_eph_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+                let sess_key = hybrid_mix(
+                    triple_ecdh(long_sk, &my_eph_sk, &long_pk, &eph_pk),
+                    &kem_secret,
+                );
+                let reply = HandshakeFrame::ServerHelloHybrid {
+                    long_pk: long_sk.into(),
+                    eph_pk: (&my_eph_sk).into(),
+                    resume_token: self.new_resume_token(sess_key, version, *eph_pk.as_bytes()),
+                    kem_ct: Buff::copy_from_slice(&kem_ct),
+                };
+                self.send_reply(&s2c_crypter, &[reply], addr, request_len)
+                    .await;
+                tracing::debug!("replied to ClientHelloHybrid from {}", addr);
+            }
+            #[cfg(not(feature = "post-quantum"))]
+            ClientHelloHybrid { version, .. } => {
+                tracing::warn!(
+                    "got hybrid packet with version {}, but post-quantum support is not compiled in",
+                    version
+                );
+                self.reject_version(&s2c_crypter, addr, request_len).await;
+            }
             ClientResume {
                 resume_token,
                 shard_id,
             } => {
-                tracing::trace!("Got ClientResume-{} from {}!", shard_id, addr);
-                let tokinfo = TokenInfo::decrypt(&self.token_key, &resume_token);
-                if let Some(tokinfo) = tokinfo {
-                    // first check whether we know about the resume token
-                    if !self
-                        .session_table
-                        .rebind(addr, shard_id, resume_token.clone())
-                    {
-                        tracing::debug!("ClientResume from {} ({:?}) is new!", addr, resume_token);
-
-                        let write_socket = self.socket.clone();
-                        let locked_addrs = ShardedAddrs::new(shard_id, addr);
-                        let locked_addrs = Arc::new(RwLock::new(locked_addrs));
-                        let (mut session, session_back) = Session::new(SessionConfig {
-                            gather: Default::default(),
-                            version: tokinfo.version,
-                            session_key: tokinfo.sess_key.to_vec(),
-                            role: Role::Server,
-                        });
-                        let session_back = Arc::new(session_back);
-                        let output_poller = {
-                            let locked_addrs = locked_addrs.clone();
-                            let session_back = session_back.clone();
-                            runtime::spawn(async move {
-                                loop {
-                                    match session_back.next_outgoing().await {
-                                        Ok(data) => {
-                                            // let start = Instant::now();
-                                            let remote_addr = locked_addrs.write().get_addr();
-                                            if data.len() > 1400 {
-                                                tracing::warn!(
-                                                    "dropping oversize session pkt of length {}",
-                                                    data.len()
-                                                );
-                                                continue;
-                                            }
-                                            drop(write_socket.send_to(data, remote_addr).await);
-                                        }
-                                        Err(_) => smol::future::pending::<()>().await,
+                self.handle_resume(resume_token, shard_id, None, s2c_crypter, addr, request_len)
+                    .await
+            }
+            ClientResumeBound {
+                resume_token,
+                shard_id,
+                time_ms,
+                proof,
+            } => {
+                self.handle_resume(
+                    resume_token,
+                    shard_id,
+                    Some((time_ms, proof)),
+                    s2c_crypter,
+                    addr,
+                    request_len,
+                )
+                .await
+            }
+            PathResponse { response } => {
+                let pending = if let Some(pending) = self.pending_paths.take_challenged(addr) {
+                    pending
+                } else {
+                    return;
+                };
+                let (challenge, path_key) = pending.challenge.expect("taken path was challenged");
+                let expected = path_response(&path_key, &challenge);
+                if !constant_time_eq::constant_time_eq(&response, &expected) {
+                    tracing::warn!("PathResponse from {} is invalid", addr);
+                    return;
+                }
+                let _guard = self.session_creation.lock();
+                if self
+                    .session_table
+                    .rebind(addr, pending.socket, pending.shard_id, pending.sess_id)
+                    .is_some()
+                {
+                    tracing::debug!("validated new path {}", addr);
+                    self.stats.paths_validated.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_resume(
+        &self,
+        resume_token: Buff,
+        shard_id: u8,
+        proof: Option<(u64, [u8; 32])>,
+        s2c_crypter: HandshakeAead,
+        addr: SocketAddr,
+        request_len: usize,
+    ) {
+        tracing::trace!("Got ClientResume-{} from {}!", shard_id, addr);
+        let tokinfo = self.token_keys.lock().decrypt(&resume_token);
+        if let Some(tokinfo) = tokinfo {
+            if tokinfo.is_expired() {
+                tracing::debug!("ClientResume from {} has an expired token", addr);
+                return;
+            }
+            // tokens from version 4 onwards can only be used by the client they were issued to.
+            // the proof is bound to the key the client originally connected to, which after a migration need not be the key it used for this packet
+            if tokinfo.version >= 4 {
+                let valid = proof
+                    .map(|(time_ms, proof)| {
+                        self.keys.iter().any(|(long_sk, _)| {
+                            let proof_key =
+                                resume_proof_key(long_sk, &tokinfo.client_eph_pk.into());
+                            let expected =
+                                resume_proof(&proof_key, &resume_token, shard_id, time_ms);
+                            constant_time_eq::constant_time_eq(&proof, &expected)
+                        })
+                    })
+                    .unwrap_or_default();
+                if !valid {
+                    tracing::warn!("ClientResume from {} has no valid proof", addr);
+                    return;
+                }
+                // since anybody can decrypt and re-encrypt handshake frames, a captured proof must not be usable again, whether now or later
+                if let Some((time_ms, proof)) = proof {
+                    if !is_fresh(time_ms) || !RECENT_FILTER.lock().check(&proof) {
+                        tracing::warn!("ClientResume from {} has a stale proof", addr);
+                        self.stats.packets_replay.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            }
+            // first check whether we know about the session. this is done under a lock, so that workers concurrently handling ClientResumes for a new session don't both create it
+            let sess_id = tokinfo.sess_id();
+            let creation_guard = self.session_creation.lock();
+            // an existing session only moves to a new address once the client proves, from there, that it holds the session key. otherwise, anybody who sees a ClientResume could replay it from a spoofed address to redirect the session's traffic.
+            // legacy clients cannot answer challenges, so they prove it by sending session traffic from there instead. until then, their traffic keeps going to the old address.
+            let mut challenge = None;
+            let moving = self.session_table.incarnation(&sess_id).is_some()
+                && !self.session_table.is_bound_to(addr, &sess_id);
+            let existing = if moving && tokinfo.version < 4 {
+                if !self.pending_paths.expect_traffic(
+                    addr,
+                    sess_id.clone(),
+                    shard_id,
+                    self.socket.clone(),
+                ) {
+                    tracing::warn!("too many pending paths, ignoring {}", addr);
+                    return;
+                }
+                tracing::debug!(
+                    "legacy ClientResume from new address {}, waiting for traffic",
+                    addr
+                );
+                self.stats.paths_challenged.fetch_add(1, Ordering::Relaxed);
+                self.session_table.incarnation(&sess_id)
+            } else if moving {
+                challenge = self.pending_paths.challenge(
+                    addr,
+                    sess_id.clone(),
+                    shard_id,
+                    path_response_key(&tokinfo.sess_key),
+                    self.socket.clone(),
+                );
+                if challenge.is_none() {
+                    tracing::warn!("too many pending path challenges, ignoring {}", addr);
+                    return;
+                }
+                tracing::debug!("ClientResume from new address {}, challenging", addr);
+                self.stats.paths_challenged.fetch_add(1, Ordering::Relaxed);
+                self.session_table.incarnation(&sess_id)
+            } else {
+                self.session_table
+                    .rebind(addr, self.socket.clone(), shard_id, sess_id.clone())
+            };
+            let incarnation = if let Some(incarnation) = existing {
+                tracing::trace!("ClientResume from {} rebound", addr);
+                incarnation
+            } else {
+                tracing::debug!("ClientResume from {} is new!", addr);
+                if self.shutting_down.load(Ordering::Relaxed) {
+                    tracing::debug!("refusing new session from {} while shutting down", addr);
+                    return;
+                }
+                let limits = self.limits.load();
+                let peer = self.socket.peer_addr(addr);
+                if limits
+                    .max_sessions
+                    .map(|max| self.session_table.len() >= max)
+                    .unwrap_or_default()
+                {
+                    tracing::debug!("refusing new session from {}: too many sessions", addr);
+                    self.stats.sessions_rejected.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                if limits
+                    .max_sessions_per_ip
+                    .map(|max| self.session_table.count_ip(peer.ip()) >= max)
+                    .unwrap_or_default()
+                {
+                    tracing::debug!(
+                        "refusing new session from {}: too many sessions from its IP",
+                        addr
+                    );
+                    self.stats
+                        .sessions_rejected_per_ip
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                let incarnation: u64 = rand::thread_rng().gen();
+                let limiter = Arc::new(SessionLimiter::new(limits.session_rate_limit));
+
+                let locked_addrs = ShardedAddrs::new(shard_id, addr, self.socket.clone());
+                let locked_addrs = Arc::new(RwLock::new(locked_addrs));
+                let (mut session, session_back) = Session::new(SessionConfig {
+                    gather: self
+                        .session_gather
+                        .as_ref()
+                        .map(|factory| factory(peer))
+                        .unwrap_or_default(),
+                    version: tokinfo.version,
+                    session_key: tokinfo.sess_key.to_vec(),
+                    role: Role::Server,
+                    shape: self.traffic_shape.clone(),
+                });
+                let session_back = Arc::new(session_back);
+                let output_poller = {
+                    let locked_addrs = locked_addrs.clone();
+                    let session_back = session_back.clone();
+                    let limiter = limiter.clone();
+                    let stats = self.stats.clone();
+                    let max_packet_size = self.max_packet_size;
+                    runtime::spawn(async move {
+                        loop {
+                            match session_back.next_outgoing().await {
+                                // only clients send important packets redundantly, since they pick between shards
+                                Ok((data, _)) => {
+                                    // let start = Instant::now();
+                                    let (remote_addr, write_socket) =
+                                        locked_addrs.read().get_addr();
+                                    if data.len() > max_packet_size {
+                                        tracing::warn!(
+                                            "dropping oversize session pkt of length {}",
+                                            data.len()
+                                        );
+                                        continue;
                                     }
+                                    if !limiter.allow_send(data.len()) {
+                                        stats.packets_ratelimited.fetch_add(1, Ordering::Relaxed);
+                                        continue;
+                                    }
+                                    limiter.touch();
+                                    drop(write_socket.send_to(data, remote_addr).await);
                                 }
-                            })
-                        };
-                        let send_dead_clo = send_dead.clone();
-                        let resume_token_clo = resume_token.clone();
-                        session.on_drop(move || {
-                            drop(output_poller);
-                            drop(send_dead_clo.try_send(resume_token_clo))
-                        });
-                        // spawn a task that writes to the socket.
-                        self.session_table.new_sess(
-                            resume_token.clone(),
-                            session_back,
-                            locked_addrs,
-                        );
-                        self.session_table.rebind(addr, shard_id, resume_token);
-                        tracing::debug!("accept {}", addr);
-                        let _ = accepted.try_send(session);
-                    } else {
-                        tracing::trace!("ClientResume from {} rebound", addr);
+                                Err(_) => smol::future::pending::<()>().await,
+                            }
+                        }
+                    })
+                };
+                let send_dead_clo = self.send_dead.clone();
+                let sess_id_clo = sess_id.clone();
+                session.on_drop(move || drop(send_dead_clo.try_send(sess_id_clo)));
+                // the output poller lives in the table, so that removing the session stops it
+                self.session_table.new_sess(
+                    sess_id.clone(),
+                    SessEntry {
+                        session_back,
+                        addrs: locked_addrs,
+                        incarnation,
+                        origin_ip: peer.ip(),
+                        limiter,
+                        _output_poller: output_poller,
+                    },
+                );
+                self.session_table
+                    .rebind(addr, self.socket.clone(), shard_id, sess_id);
+                self.stats
+                    .sessions_active
+                    .store(self.session_table.len(), Ordering::Relaxed);
+                if let Some((preferred_addr, preferred_pk)) = self.preferred_addr {
+                    if let Some(handle) = self.session_table.lookup(addr) {
+                        handle
+                            .session_back
+                            .send_migrate(preferred_addr, preferred_pk);
                     }
                 }
+                tracing::debug!("accept {}", addr);
+                if self.accepted.try_send(session).is_err() {
+                    tracing::debug!("accept queue full, dropping session from {}", addr);
+                }
+                incarnation
+            };
+            drop(creation_guard);
+            // tell the client whether we still had its session, so that it can detect us losing state
+            let reply = HandshakeFrame::ServerResume {
+                shard_id,
+                incarnation,
+                resumed: existing.is_some(),
+            };
+            let mut frames = vec![reply];
+            // replace the token before it gets too old to resume with
+            if tokinfo.age() > TOKEN_REFRESH_AGE {
+                let resume_token = self.token_keys.lock().encrypt(&tokinfo.refreshed());
+                frames.push(NewResumeToken { resume_token });
             }
-            _ => {}
+            if let Some(challenge) = challenge {
+                frames.push(PathChallenge { challenge });
+            }
+            // the address is unvalidated while challenged, so the reply is still subject to the amplification limit
+            self.send_reply(&s2c_crypter, &frames, addr, request_len)
+                .await;
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TokenInfo {
-    sess_key: Buff,
-    init_time_ms: u64,
-    version: u64,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl TokenInfo {
-    fn decrypt(key: &[u8], encrypted: &[u8]) -> Option<Self> {
-        // first we decrypt
-        let crypter = LegacyAead::new(key);
-        let plain = crypter.decrypt(encrypted)?;
-        safe_deserialize(&plain).ok()
-    }
-
-    fn encrypt(&self, key: &[u8]) -> Buff {
-        let crypter = LegacyAead::new(key);
-        let mut rng = rand::thread_rng();
-        crypter.encrypt(
-            &bincode::serialize(self).expect("must serialize"),
-            rng.gen(),
-        )
+    fn keys() -> Vec<(x25519_dalek::StaticSecret, Cookie)> {
+        (0..2)
+            .map(|_| {
+                let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+                let cookie = Cookie::new((&long_sk).into());
+                (long_sk, cookie)
+            })
+            .collect()
+    }
+
+    fn hello(keys: &[(x25519_dalek::StaticSecret, Cookie)], key_idx: usize, version: u64) -> Buff {
+        let c2s_key = keys[key_idx].1.generate_c2s().next().unwrap();
+        let eph_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+        let frame = ClientHello {
+            long_pk: (&eph_sk).into(),
+            eph_pk: (&eph_sk).into(),
+            version,
+        };
+        HandshakeAead::new(&c2s_key, version == 3).pad_encrypt(&[frame], 1000)
+    }
+
+    #[test]
+    fn handshakes_decrypt_under_either_construction() {
+        let keys = keys();
+        for key_idx in 0..keys.len() {
+            let (frame, legacy, idx) =
+                decrypt_handshake(&keys, &hello(&keys, key_idx, 4), true).unwrap();
+            assert!(matches!(frame, ClientHello { version: 4, .. }));
+            assert!(!legacy);
+            assert_eq!(idx, key_idx);
+            let (frame, legacy, idx) =
+                decrypt_handshake(&keys, &hello(&keys, key_idx, 3), true).unwrap();
+            assert!(matches!(frame, ClientHello { version: 3, .. }));
+            assert!(legacy);
+            assert_eq!(idx, key_idx);
+        }
+        assert!(decrypt_handshake(&keys, &hello(&self::keys(), 0, 4), true).is_none());
+    }
+
+    #[test]
+    fn resume_proofs_expire() {
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let skew_ms = RESUME_PROOF_MAX_SKEW.as_millis() as u64;
+        assert!(is_fresh(now_ms));
+        assert!(is_fresh(now_ms - skew_ms / 2));
+        assert!(is_fresh(now_ms + skew_ms / 2));
+        assert!(!is_fresh(now_ms - skew_ms - 1000));
+        assert!(!is_fresh(now_ms + skew_ms + 1000));
+        assert!(!is_fresh(0));
+        let proof = |time_ms| resume_proof(&[1; 32], b"token", 0, time_ms);
+        assert_ne!(proof(now_ms), proof(now_ms + 1));
+    }
+
+    #[test]
+    fn legacy_handshakes_can_be_refused() {
+        let keys = keys();
+        assert!(decrypt_handshake(&keys, &hello(&keys, 0, 3), false).is_none());
+        assert!(decrypt_handshake(&keys, &hello(&keys, 0, 4), false).is_some());
+        assert!(!supported_versions(false).contains(&3));
+        assert!(supported_versions(true).contains(&3));
+    }
+
+    #[cfg(feature = "post-quantum")]
+    #[test]
+    fn hybrid_handshakes_connect() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let mut cfg = ListenerConfig::new(long_sk.clone());
+            cfg.udp_addr = Some("127.0.0.1:0".parse().unwrap());
+            let listener = cfg.listen().await.unwrap();
+            let mut client_cfg = crate::ClientConfig::new(
+                crate::Protocol::DirectUdp,
+                listener.local_addr(),
+                (&long_sk).into(),
+                Default::default(),
+            );
+            client_cfg.post_quantum = true;
+            let client = client_cfg.connect().await.unwrap();
+            client
+                .send_bytes(Buff::copy_from_slice(b"hello"))
+                .await
+                .unwrap();
+            let server = listener.accept_session().await.unwrap();
+            assert_eq!(&server.recv_bytes().await.unwrap()[..], b"hello");
+        })
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_clients_speak_the_legacy_handshake() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let mut cfg = ListenerConfig::new(long_sk.clone());
+            cfg.udp_addr = Some("127.0.0.1:0".parse().unwrap());
+            let listener = cfg.listen().await.unwrap();
+            let client =
+                crate::connect_udp(listener.local_addr(), (&long_sk).into(), Default::default())
+                    .await
+                    .unwrap();
+            client
+                .send_bytes(Buff::copy_from_slice(b"hello"))
+                .await
+                .unwrap();
+            let server = listener.accept_session().await.unwrap();
+            assert_eq!(&server.recv_bytes().await.unwrap()[..], b"hello");
+        })
+    }
+
+    #[test]
+    fn sockets_share_the_handshake_workers() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let mut cfg = ListenerConfig::new(long_sk.clone());
+            cfg.udp_addr = Some("127.0.0.1:0".parse().unwrap());
+            cfg.udp_socket_count = 4;
+            let listener = cfg.listen().await.unwrap();
+            // with SO_REUSEPORT, the clients' packets are spread over the sockets, and every reply must go out through the socket its hello came in on
+            for i in 0..8u8 {
+                let mut client_cfg = crate::ClientConfig::new(
+                    crate::Protocol::DirectUdp,
+                    listener.local_addr(),
+                    (&long_sk).into(),
+                    Default::default(),
+                );
+                client_cfg.shard_count = 4;
+                client_cfg.connect_timeout = Some(Duration::from_secs(10));
+                let client = client_cfg.connect().await.unwrap();
+                client
+                    .send_bytes(Buff::copy_from_slice(&[i; 10]))
+                    .await
+                    .unwrap();
+                let server = listener.accept_session().await.unwrap();
+                assert_eq!(&server.recv_bytes().await.unwrap()[..], &[i; 10]);
+            }
+        })
+    }
+
+    #[test]
+    fn bound_shards_are_not_handshake_limited() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let mut cfg = ListenerConfig::new(long_sk.clone());
+            cfg.udp_addr = Some("127.0.0.1:0".parse().unwrap());
+            let listener = cfg.listen().await.unwrap();
+            let mut client_cfg = crate::ClientConfig::new(
+                crate::Protocol::DirectUdp,
+                listener.local_addr(),
+                (&long_sk).into(),
+                Default::default(),
+            );
+            client_cfg.shard_count = 4;
+            let client = client_cfg.connect().await.unwrap();
+            // every upload more than a second after the last one comes with a resume on each shard
+            let upload = |n: u8| {
+                let client = &client;
+                async move {
+                    for i in 0..n {
+                        client
+                            .send_bytes(Buff::copy_from_slice(&[i; 10]))
+                            .await
+                            .unwrap();
+                        smol::Timer::after(Duration::from_millis(300)).await;
+                    }
+                }
+            };
+            // by now, every shard is bound
+            upload(10).await;
+            let server = listener.accept_session().await.unwrap();
+            let stats = listener.listener_stats();
+            let ratelimited = stats.handshakes_ratelimited.load(Ordering::Relaxed);
+            listener.set_limits(ListenerLimits {
+                handshake_rate_per_ip: 0.0,
+                handshake_burst_per_ip: 0.0,
+                ..Default::default()
+            });
+            upload(10).await;
+            assert_eq!(
+                stats.handshakes_ratelimited.load(Ordering::Relaxed),
+                ratelimited
+            );
+            while smol::future::poll_once(server.recv_bytes()).await.is_some() {}
+            upload(1).await;
+            assert!(smol::future::poll_once(server.recv_bytes()).await.is_some());
+        })
     }
 }
@@ -0,0 +1,211 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+
+use super::ListenerLimits;
+
+/// Beyond this many tracked sources, idle ones are forgotten right away rather than periodically.
+const MAX_TRACKED_SOURCES: usize = 100000;
+
+/// A classic token bucket.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new, full token bucket that refills at the given rate per second.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes the given number of tokens, returning false if there are not enough.
+    pub fn try_take(&mut self, n: f64) -> bool {
+        self.refill();
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Changes the rate and burst of the bucket, keeping the tokens it has as far as the new burst allows.
+    pub fn set_limits(&mut self, rate: f64, burst: f64) {
+        self.refill();
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    /// Whether the bucket is full, i.e. has not been used recently.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+}
+
+/// Limits how many packets per second go down the expensive handshake path, per source and globally.
+pub(crate) struct HandshakeLimiter {
+    per_source: FxHashMap<IpAddr, TokenBucket>,
+    global: TokenBucket,
+    last_cleanup: Instant,
+}
+
+impl HandshakeLimiter {
+    /// Creates a new limiter, enforcing the given limits until others are passed to [HandshakeLimiter::check].
+    pub fn new(limits: &ListenerLimits) -> Self {
+        Self {
+            per_source: FxHashMap::default(),
+            global: TokenBucket::new(limits.handshake_rate, limits.handshake_burst),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Checks whether a handshake packet from the given address should be processed under the given limits.
+    pub fn check(&mut self, ip: IpAddr, limits: &ListenerLimits) -> bool {
+        if self.last_cleanup.elapsed() > Duration::from_secs(10)
+            || self.per_source.len() > MAX_TRACKED_SOURCES
+        {
+            self.per_source.retain(|_, bucket| !bucket.is_full());
+            if self.per_source.len() > MAX_TRACKED_SOURCES {
+                // probably a spoofed flood, so tracking individual sources is hopeless
+                self.per_source.clear();
+            }
+            self.last_cleanup = Instant::now();
+        }
+        self.global
+            .set_limits(limits.handshake_rate, limits.handshake_burst);
+        let source = self.per_source.entry(source_prefix(ip)).or_insert_with(|| {
+            TokenBucket::new(limits.handshake_rate_per_ip, limits.handshake_burst_per_ip)
+        });
+        source.set_limits(limits.handshake_rate_per_ip, limits.handshake_burst_per_ip);
+        // check the source first, so that a single flooder cannot use up the global limit
+        source.try_take(1.0) && self.global.try_take(1.0)
+    }
+}
+
+/// Maps IPv6 addresses to their /64, since a single host usually controls a whole /64.
+fn source_prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[4..].fill(0);
+            IpAddr::V6(segments.into())
+        }
+    }
+}
+
+/// Smallest burst, in bytes, of a session's rate limit, so that even very low limits let full-size packets through.
+const MIN_SESSION_BURST: f64 = 2000.0;
+
+/// Per-session bandwidth limits in each direction, plus tracking of when the session was last active.
+pub(crate) struct SessionLimiter {
+    recv_bucket: Option<Mutex<TokenBucket>>,
+    send_bucket: Option<Mutex<TokenBucket>>,
+    last_active: Mutex<Instant>,
+}
+
+impl SessionLimiter {
+    /// Creates a limiter allowing the given number of bytes per second in each direction, or no limit.
+    pub fn new(rate: Option<u64>) -> Self {
+        let bucket = || {
+            rate.map(|rate| {
+                let rate = rate as f64;
+                Mutex::new(TokenBucket::new(rate, rate.max(MIN_SESSION_BURST)))
+            })
+        };
+        Self {
+            recv_bucket: bucket(),
+            send_bucket: bucket(),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Whether a received packet of the given length is within the limits.
+    pub fn allow_recv(&self, len: usize) -> bool {
+        Self::allow(&self.recv_bucket, len)
+    }
+
+    /// Whether a packet of the given length can be sent within the limits.
+    pub fn allow_send(&self, len: usize) -> bool {
+        Self::allow(&self.send_bucket, len)
+    }
+
+    fn allow(bucket: &Option<Mutex<TokenBucket>>, len: usize) -> bool {
+        bucket
+            .as_ref()
+            .map(|bucket| bucket.lock().try_take(len as f64))
+            .unwrap_or(true)
+    }
+
+    /// Marks the session as active.
+    pub fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    /// How long since the session was last active.
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.lock().elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes_are_limited_as_configured() {
+        let mut limits = ListenerLimits {
+            handshake_rate_per_ip: 0.0,
+            handshake_burst_per_ip: 2.0,
+            handshake_rate: 0.0,
+            handshake_burst: 3.0,
+            ..Default::default()
+        };
+        let mut limiter = HandshakeLimiter::new(&limits);
+        let ip = |last: u8| IpAddr::from([192, 0, 2, last]);
+        assert!(limiter.check(ip(1), &limits));
+        assert!(limiter.check(ip(1), &limits));
+        assert!(!limiter.check(ip(1), &limits));
+        // another source still gets through, until the global limit is hit
+        assert!(limiter.check(ip(2), &limits));
+        assert!(!limiter.check(ip(3), &limits));
+        // the whole /64 of an IPv6 source shares its limit
+        let v6 = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, last]);
+        limits.handshake_burst = 100.0;
+        limits.handshake_rate = 1000.0;
+        assert!(!limiter.check(ip(3), &limits));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check(v6(1), &limits));
+        assert!(limiter.check(v6(2), &limits));
+        assert!(!limiter.check(v6(3), &limits));
+        // changed limits apply from the next check on
+        limits.handshake_rate_per_ip = 1000.0;
+        assert!(!limiter.check(ip(1), &limits));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limiter.check(ip(1), &limits));
+    }
+}
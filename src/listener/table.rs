@@ -1,126 +1,473 @@
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{buffer::Buff, SVec, SessionBack};
+use crate::{buffer::Buff, Backhaul, SVec, SessionBack};
 
-use parking_lot::RwLock;
+use super::ratelimit::SessionLimiter;
+
+use parking_lot::{Mutex, RwLock};
 use rand::Rng;
 use rustc_hash::FxHashMap;
 
+/// Shards that carried data from the client within this long are the ones that data is sent back down.
+const CARRIED_DATA_RECENTLY: Duration = Duration::from_secs(3);
+
+/// Where a shard of a session can be reached.
+struct ShardAddr {
+    addr: SocketAddr,
+    /// Socket it was last seen on.
+    socket: Arc<dyn Backhaul>,
+    /// When it was last bound.
+    bound: Instant,
+    /// When it last carried fresh data from the client, in milliseconds since the table was created, or 0 if never.
+    carried_data: AtomicU64,
+}
+
 pub struct ShardedAddrs {
-    // maps shard ID to socketaddr and last update time
-    map: FxHashMap<u8, (SocketAddr, Instant)>,
+    // maps shard ID to where the shard can be reached
+    map: FxHashMap<u8, ShardAddr>,
+    created: Instant,
 }
 
 impl ShardedAddrs {
     /// Creates a new table of shard addresses.
-    pub fn new(initial_shard: u8, initial_addr: SocketAddr) -> Self {
-        let mut map = FxHashMap::default();
-        map.insert(initial_shard, (initial_addr, Instant::now()));
-        Self { map }
+    pub fn new(initial_shard: u8, initial_addr: SocketAddr, socket: Arc<dyn Backhaul>) -> Self {
+        let mut table = Self {
+            map: FxHashMap::default(),
+            created: Instant::now(),
+        };
+        table.insert_addr(initial_shard, initial_addr, socket);
+        table
     }
 
-    /// Gets the most appropriate address to send a packet down.
-    pub fn get_addr(&self) -> SocketAddr {
+    /// Gets the most appropriate address to send a packet down, together with the socket to send it through.
+    ///
+    /// Clients only send data over the shards of their preferred transport that work, while keeping the others alive with ClientResumes, so data goes back down the shards that recently carried data up, rather than every shard that was recently bound. Otherwise, fallback transports would carry downlink traffic even while the preferred one works.
+    pub fn get_addr(&self) -> (SocketAddr, Arc<dyn Backhaul>) {
+        let now = self.now_ms();
+        let recent = CARRIED_DATA_RECENTLY.as_millis() as u64;
         // svec to prevent allocating in such an extremely hot path
-        let recently_used_shards = self
+        let carrying_shards = self
             .map
-            .iter()
-            .filter(|(_, (_, usage))| usage.elapsed().as_millis() < 10000)
-            .map(|f| f.1 .0)
+            .values()
+            .filter(|shard| {
+                let carried_data = shard.carried_data.load(Ordering::Relaxed);
+                carried_data != 0 && now.saturating_sub(carried_data) < recent
+            })
             .collect::<SVec<_>>();
-        // if no recently used, then push the most recently used one
-        if recently_used_shards.is_empty() {
-            let (most_recent, _) = self
+        // if none carried data recently, then use the one that carried data last, or failing that, the most recently bound one
+        let shard = if carrying_shards.is_empty() {
+            let most_recent = self
                 .map
                 .values()
-                .max_by_key(|v| v.1)
-                .copied()
+                .max_by_key(|shard| (shard.carried_data.load(Ordering::Relaxed), shard.bound))
                 .expect("no shards at all");
-            tracing::trace!("sending down most recent {}", most_recent);
+            tracing::trace!("sending down most recent {}", most_recent.addr);
             most_recent
         } else {
-            let random =
-                recently_used_shards[rand::thread_rng().gen_range(0, recently_used_shards.len())];
-            tracing::trace!("sending down random {}", random);
+            let random = carrying_shards[rand::thread_rng().gen_range(0, carrying_shards.len())];
+            tracing::trace!("sending down random {}", random.addr);
             random
-        }
+        };
+        (shard.addr, shard.socket.clone())
     }
 
     /// Sets an index to a particular address
-    pub fn insert_addr(&mut self, index: u8, addr: SocketAddr) -> Option<SocketAddr> {
-        self.map.insert(index, (addr, Instant::now())).map(|v| v.0)
+    pub fn insert_addr(
+        &mut self,
+        index: u8,
+        addr: SocketAddr,
+        socket: Arc<dyn Backhaul>,
+    ) -> Option<SocketAddr> {
+        // rebinding to the same address, as every ClientResume does, keeps the record of carried data
+        let carried_data = self
+            .map
+            .get(&index)
+            .filter(|shard| shard.addr == addr)
+            .map(|shard| shard.carried_data.load(Ordering::Relaxed))
+            .unwrap_or_default();
+        self.map
+            .insert(
+                index,
+                ShardAddr {
+                    addr,
+                    socket,
+                    bound: Instant::now(),
+                    carried_data: AtomicU64::new(carried_data),
+                },
+            )
+            .map(|shard| shard.addr)
+    }
+
+    /// Records that the shard at the given address just carried fresh data from the client.
+    pub fn carried_data(&self, addr: SocketAddr) {
+        let now = self.now_ms();
+        for shard in self.map.values().filter(|shard| shard.addr == addr) {
+            shard.carried_data.store(now, Ordering::Relaxed);
+        }
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64 + 1
     }
 }
 
-struct SessEntry {
-    session_back: Arc<SessionBack>,
-    addrs: Arc<RwLock<ShardedAddrs>>,
+/// Everything the listener keeps about a live session.
+pub(crate) struct SessEntry {
+    pub session_back: Arc<SessionBack>,
+    pub addrs: Arc<RwLock<ShardedAddrs>>,
+    pub incarnation: u64,
+    /// The IP address that created the session, for per-IP limits.
+    pub origin_ip: IpAddr,
+    pub limiter: Arc<SessionLimiter>,
+    /// Task that sends the session's outgoing packets. Removing the entry from the table stops it.
+    pub _output_poller: smol::Task<()>,
 }
 
+/// What the listener needs to handle packets of a live session.
+pub(crate) struct SessHandle {
+    pub session_back: Arc<SessionBack>,
+    pub limiter: Arc<SessionLimiter>,
+    pub addrs: Arc<RwLock<ShardedAddrs>>,
+}
+
+impl SessHandle {
+    fn new(entry: &SessEntry) -> Self {
+        Self {
+            session_back: entry.session_back.clone(),
+            limiter: entry.limiter.clone(),
+            addrs: entry.addrs.clone(),
+        }
+    }
+}
+
+/// Table of live sessions. Sessions are identified by a hash of their session key, which unlike their resume token never changes.
 #[derive(Default, Clone)]
 pub(crate) struct SessionTable {
-    token_to_sess: Arc<RwLock<BTreeMap<Buff, SessEntry>>>,
-    addr_to_token: Arc<RwLock<BTreeMap<SocketAddr, Buff>>>,
+    id_to_sess: Arc<RwLock<BTreeMap<Buff, SessEntry>>>,
+    addr_to_id: Arc<RwLock<BTreeMap<SocketAddr, Buff>>>,
+    ip_counts: Arc<RwLock<FxHashMap<IpAddr, usize>>>,
 }
 
 impl SessionTable {
-    pub fn rebind(&self, addr: SocketAddr, shard_id: u8, token: Buff) -> bool {
-        let token_to_sess = self.token_to_sess.write();
-        let mut addr_to_token = self.addr_to_token.write();
-        if let Some(entry) = token_to_sess.get(&token) {
-            let old = entry.addrs.write().insert_addr(shard_id, addr);
+    /// Rebinds a shard of the session with the given ID to a new address, reached through the given socket. Returns the incarnation of the session, or None if the ID is unknown.
+    pub fn rebind(
+        &self,
+        addr: SocketAddr,
+        socket: Arc<dyn Backhaul>,
+        shard_id: u8,
+        sess_id: Buff,
+    ) -> Option<u64> {
+        let id_to_sess = self.id_to_sess.write();
+        let mut addr_to_id = self.addr_to_id.write();
+        if let Some(entry) = id_to_sess.get(&sess_id) {
+            let old = entry.addrs.write().insert_addr(shard_id, addr, socket);
             tracing::trace!("binding {}=>{}", shard_id, addr);
             if let Some(old) = old {
-                addr_to_token.remove(&old);
+                addr_to_id.remove(&old);
             }
-            addr_to_token.insert(addr, token);
-            true
+            addr_to_id.insert(addr, sess_id);
+            Some(entry.incarnation)
         } else {
             tracing::debug!(
-                "[{:p}] token {:?} not in {:?}",
+                "[{:p}] sess_id {:?} not in {:?}",
                 self,
-                token,
-                token_to_sess.keys().collect::<Vec<_>>()
+                sess_id,
+                id_to_sess.keys().collect::<Vec<_>>()
             );
-            false
+            None
         }
     }
-    pub fn delete(&self, token: Buff) {
-        tracing::debug!("removing token {:?}", token);
-        let mut token_to_sess = self.token_to_sess.write();
-        let mut addr_to_token = self.addr_to_token.write();
-        if let Some(entry) = token_to_sess.remove(&token) {
-            for (addr, _) in entry.addrs.read().map.values() {
-                addr_to_token.remove(addr);
+    pub fn delete(&self, sess_id: Buff) {
+        tracing::debug!("removing sess_id {:?}", sess_id);
+        let mut id_to_sess = self.id_to_sess.write();
+        let mut addr_to_id = self.addr_to_id.write();
+        if let Some(entry) = id_to_sess.remove(&sess_id) {
+            for shard in entry.addrs.read().map.values() {
+                addr_to_id.remove(&shard.addr);
+            }
+            let mut ip_counts = self.ip_counts.write();
+            if let Some(count) = ip_counts.get_mut(&entry.origin_ip) {
+                *count -= 1;
+                if *count == 0 {
+                    ip_counts.remove(&entry.origin_ip);
+                }
             }
         }
     }
 
-    pub fn lookup(&self, addr: SocketAddr) -> Option<Arc<SessionBack>> {
-        let token_to_sess = self.token_to_sess.read();
-        let addr_to_token = self.addr_to_token.read();
-        let token = addr_to_token.get(&addr)?;
-        let entry = token_to_sess.get(token)?;
-        Some(entry.session_back.clone())
+    /// Removes every session that has been idle for longer than the timeout, returning how many were removed.
+    pub fn evict_idle(&self, timeout: Duration) -> usize {
+        let idle = self
+            .id_to_sess
+            .read()
+            .iter()
+            .filter(|(_, entry)| entry.limiter.idle_time() > timeout)
+            .map(|(sess_id, _)| sess_id.clone())
+            .collect::<Vec<_>>();
+        let count = idle.len();
+        for sess_id in idle {
+            tracing::debug!("evicting idle session");
+            self.delete(sess_id);
+        }
+        count
     }
 
-    pub fn new_sess(
-        &self,
-        token: Buff,
-        session_back: Arc<SessionBack>,
-        locked_addrs: Arc<RwLock<ShardedAddrs>>,
-    ) {
-        let mut token_to_sess = self.token_to_sess.write();
-        let entry = SessEntry {
-            session_back,
-            addrs: locked_addrs,
-        };
-        token_to_sess.insert(token.clone(), entry);
+    pub fn lookup(&self, addr: SocketAddr) -> Option<SessHandle> {
+        let id_to_sess = self.id_to_sess.read();
+        let addr_to_id = self.addr_to_id.read();
+        let sess_id = addr_to_id.get(&addr)?;
+        id_to_sess.get(sess_id).map(SessHandle::new)
+    }
+
+    /// Looks up a session by its ID rather than an address.
+    pub fn lookup_id(&self, sess_id: &Buff) -> Option<SessHandle> {
+        self.id_to_sess.read().get(sess_id).map(SessHandle::new)
+    }
+
+    /// Gets all the live sessions.
+    pub fn session_backs(&self) -> Vec<Arc<SessionBack>> {
+        self.id_to_sess
+            .read()
+            .values()
+            .map(|entry| entry.session_back.clone())
+            .collect()
+    }
+
+    /// Removes all sessions.
+    pub fn clear(&self) {
+        let mut id_to_sess = self.id_to_sess.write();
+        self.addr_to_id.write().clear();
+        self.ip_counts.write().clear();
+        id_to_sess.clear();
+    }
+
+    /// Incarnation of the session with the given ID, if it exists.
+    pub fn incarnation(&self, sess_id: &Buff) -> Option<u64> {
+        self.id_to_sess
+            .read()
+            .get(sess_id)
+            .map(|entry| entry.incarnation)
+    }
+
+    /// Whether the given address is already bound to the session with the given ID.
+    pub fn is_bound_to(&self, addr: SocketAddr, sess_id: &Buff) -> bool {
+        self.addr_to_id.read().get(&addr) == Some(sess_id)
+    }
+
+    /// Total number of sessions.
+    pub fn len(&self) -> usize {
+        self.id_to_sess.read().len()
+    }
+
+    /// Whether there are no sessions.
+    pub fn is_empty(&self) -> bool {
+        self.id_to_sess.read().is_empty()
+    }
+
+    /// Number of sessions created from the given IP address.
+    pub fn count_ip(&self, ip: IpAddr) -> usize {
+        self.ip_counts.read().get(&ip).copied().unwrap_or_default()
+    }
+
+    pub fn new_sess(&self, sess_id: Buff, entry: SessEntry) {
+        let mut id_to_sess = self.id_to_sess.write();
+        *self.ip_counts.write().entry(entry.origin_ip).or_default() += 1;
+        id_to_sess.insert(sess_id.clone(), entry);
         tracing::debug!(
-            "[{:p}] token {:?} now in {:?}",
+            "[{:p}] sess_id {:?} now in {:?}",
             self,
-            token,
-            token_to_sess.keys().collect::<Vec<_>>()
+            sess_id,
+            id_to_sess.keys().collect::<Vec<_>>()
+        );
+    }
+}
+
+/// How long a path challenge can go unanswered.
+const PENDING_PATH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Beyond this many unanswered path challenges, expired ones are forgotten right away, and new ones are refused if that does not help.
+const MAX_PENDING_PATHS: usize = 100000;
+
+/// A new address that a shard of a session wants to move to, waiting for the client to validate it.
+pub(crate) struct PendingPath {
+    pub sess_id: Buff,
+    pub shard_id: u8,
+    /// Challenge sent to the address, together with the key that the response must be computed with. None for legacy clients, which cannot answer challenges, and instead validate the address with the first session packet from there that delivers fresh data.
+    pub challenge: Option<([u8; 32], [u8; 32])>,
+    /// Socket the challenge was sent through, which the shard is bound to once validated.
+    pub socket: Arc<dyn Backhaul>,
+    created: Instant,
+}
+
+/// Table of unanswered path challenges, indexed by the address they were sent to.
+#[derive(Default, Clone)]
+pub(crate) struct PendingPaths {
+    map: Arc<Mutex<FxHashMap<SocketAddr, PendingPath>>>,
+}
+
+impl PendingPaths {
+    /// Challenges the given address, returning the challenge to send, or None if there are too many challenges pending. Re-challenging an address replaces the earlier challenge.
+    pub fn challenge(
+        &self,
+        addr: SocketAddr,
+        sess_id: Buff,
+        shard_id: u8,
+        path_key: [u8; 32],
+        socket: Arc<dyn Backhaul>,
+    ) -> Option<[u8; 32]> {
+        let mut map = self.map.lock();
+        if map.len() >= MAX_PENDING_PATHS {
+            map.retain(|_, pending| pending.created.elapsed() < PENDING_PATH_TIMEOUT);
+            if map.len() >= MAX_PENDING_PATHS {
+                return None;
+            }
+        }
+        let challenge: [u8; 32] = rand::thread_rng().gen();
+        map.insert(
+            addr,
+            PendingPath {
+                sess_id,
+                shard_id,
+                challenge: Some((challenge, path_key)),
+                socket,
+                created: Instant::now(),
+            },
         );
+        Some(challenge)
+    }
+
+    /// Waits for a legacy client to send session traffic from the given address, returning false if there are too many paths pending.
+    pub fn expect_traffic(
+        &self,
+        addr: SocketAddr,
+        sess_id: Buff,
+        shard_id: u8,
+        socket: Arc<dyn Backhaul>,
+    ) -> bool {
+        let mut map = self.map.lock();
+        if map.len() >= MAX_PENDING_PATHS {
+            map.retain(|_, pending| pending.created.elapsed() < PENDING_PATH_TIMEOUT);
+            if map.len() >= MAX_PENDING_PATHS {
+                return false;
+            }
+        }
+        map.insert(
+            addr,
+            PendingPath {
+                sess_id,
+                shard_id,
+                challenge: None,
+                socket,
+                created: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Takes the unexpired path pending for the given address, if it was challenged.
+    pub fn take_challenged(&self, addr: SocketAddr) -> Option<PendingPath> {
+        self.take_if(addr, |pending| pending.challenge.is_some())
+    }
+
+    /// Takes the unexpired path pending for the given address, if it waits for traffic and the given function accepts that traffic as coming from its session.
+    pub fn take_by_traffic(
+        &self,
+        addr: SocketAddr,
+        validate: impl FnOnce(&Buff) -> bool,
+    ) -> Option<PendingPath> {
+        self.take_if(addr, |pending| {
+            pending.challenge.is_none() && validate(&pending.sess_id)
+        })
+    }
+
+    fn take_if(
+        &self,
+        addr: SocketAddr,
+        pred: impl FnOnce(&PendingPath) -> bool,
+    ) -> Option<PendingPath> {
+        let mut map = self.map.lock();
+        let pending = map.get(&addr)?;
+        if pending.created.elapsed() >= PENDING_PATH_TIMEOUT {
+            map.remove(&addr);
+            return None;
+        }
+        if pred(pending) {
+            map.remove(&addr)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket() -> Arc<dyn Backhaul> {
+        Arc::new(smol::Async::<std::net::UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap())
+    }
+
+    #[test]
+    fn data_goes_down_shards_that_carried_it() {
+        let primary: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let fallback: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let mut addrs = ShardedAddrs::new(0, primary, socket());
+        // before any data, the most recently bound shard is used
+        addrs.insert_addr(1, fallback, socket());
+        assert_eq!(addrs.get_addr().0, fallback);
+        addrs.carried_data(primary);
+        // the fallback shard keeps being rebound by ClientResumes, but carries no data
+        for _ in 0..100 {
+            addrs.insert_addr(1, fallback, socket());
+            addrs.insert_addr(0, primary, socket());
+            assert_eq!(addrs.get_addr().0, primary);
+        }
+        // moving a shard to another address forgets that it carried data
+        let moved: SocketAddr = "192.0.2.2:1234".parse().unwrap();
+        addrs.insert_addr(0, moved, socket());
+        addrs.carried_data(fallback);
+        assert_eq!(addrs.get_addr().0, fallback);
+    }
+
+    #[test]
+    fn legacy_paths_need_traffic() {
+        let paths = PendingPaths::default();
+        let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let sess_id = Buff::copy_from_slice(b"session");
+        assert!(paths.expect_traffic(addr, sess_id.clone(), 0, socket()));
+        // a path response cannot validate it, and neither can traffic that is not fresh session traffic
+        assert!(paths.take_challenged(addr).is_none());
+        assert!(paths.take_by_traffic(addr, |_| false).is_none());
+        let pending = paths
+            .take_by_traffic(addr, |id| id == &sess_id)
+            .expect("fresh traffic validates the path");
+        assert_eq!(pending.sess_id, sess_id);
+        assert!(paths.take_by_traffic(addr, |_| true).is_none());
+    }
+
+    #[test]
+    fn challenged_paths_need_responses() {
+        let paths = PendingPaths::default();
+        let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let challenge = paths
+            .challenge(
+                addr,
+                Buff::copy_from_slice(b"session"),
+                0,
+                [1; 32],
+                socket(),
+            )
+            .unwrap();
+        assert!(paths.take_by_traffic(addr, |_| true).is_none());
+        let pending = paths.take_challenged(addr).unwrap();
+        assert_eq!(pending.challenge, Some((challenge, [1; 32])));
     }
 }
@@ -0,0 +1,259 @@
+use std::{collections::VecDeque, time::Duration};
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer::{Buff, BuffMut},
+    crypt::{LegacyAead, NgAead},
+    safe_deserialize,
+};
+
+/// How long a resume token can be used to resume a session.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(86400);
+
+/// How old a resume token must be before the server replaces it with a fresh one.
+pub const TOKEN_REFRESH_AGE: Duration = Duration::from_secs(3600);
+
+/// How often the token keys are ratcheted forward.
+const TOKEN_EPOCH_SECS: u64 = 600;
+
+/// Information contained in a resume token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub sess_key: Buff,
+    pub init_time_ms: u64,
+    pub version: u64,
+    /// The ephemeral key of the client the token was issued to. Resuming with tokens of versions 4 and above requires proving possession of it.
+    pub client_eph_pk: [u8; 32],
+}
+
+impl TokenInfo {
+    /// The identifier of the session, which is the same for all tokens of the session.
+    pub fn sess_id(&self) -> Buff {
+        Buff::copy_from_slice(blake3::hash(&self.sess_key).as_bytes())
+    }
+
+    /// How long ago the token was issued.
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(now_ms().saturating_sub(self.init_time_ms))
+    }
+
+    /// Whether the token is too old to resume with.
+    pub fn is_expired(&self) -> bool {
+        self.age() > TOKEN_LIFETIME
+    }
+
+    /// The same token, but freshly issued.
+    pub fn refreshed(&self) -> Self {
+        Self {
+            init_time_ms: now_ms(),
+            ..self.clone()
+        }
+    }
+}
+
+/// A ratchet of keys for encrypting resume tokens. Keys are derived one-way from their predecessors and erased once every token they could have encrypted has expired, so compromising the server later on does not reveal the session keys of old tokens. With a seed, keys are instead derived from the seed and their epoch, so that they are the same after a restart, at the cost of the seed revealing every key.
+pub struct TokenKeys {
+    seed: Option<[u8; 32]>,
+    /// The epoch of the newest key.
+    epoch: u64,
+    /// Keys, newest first.
+    keys: VecDeque<[u8; 32]>,
+    /// Whether tokens encrypted with the legacy (version 3) construction are accepted.
+    allow_legacy: bool,
+}
+
+impl TokenKeys {
+    /// Creates a new ratchet, from the given seed or else at random, that accepts legacy tokens only if told to.
+    pub fn new(seed: Option<[u8; 32]>, allow_legacy: bool) -> Self {
+        let epoch = curr_epoch();
+        let keys = match &seed {
+            // every key that may have encrypted a token that is still valid
+            Some(seed) => (0..max_keys() as u64)
+                .filter_map(|age| epoch.checked_sub(age))
+                .map(|epoch| seeded_key(seed, epoch))
+                .collect(),
+            None => {
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                std::iter::once(key).collect()
+            }
+        };
+        Self {
+            seed,
+            epoch,
+            keys,
+            allow_legacy,
+        }
+    }
+
+    /// Encrypts a token under the current key.
+    pub fn encrypt(&mut self, info: &TokenInfo) -> Buff {
+        self.ratchet();
+        let key = &self.keys[0];
+        let plain = bincode::serialize(info).expect("must serialize");
+        let mut out = BuffMut::new();
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        if info.version >= 4 {
+            out.extend_from_slice(&NgAead::new(key).encrypt(&plain));
+        } else {
+            out.extend_from_slice(&LegacyAead::new(key).encrypt(&plain, rand::thread_rng().gen()));
+        }
+        out.freeze()
+    }
+
+    /// Decrypts a token, if its key still exists.
+    pub fn decrypt(&mut self, encrypted: &[u8]) -> Option<TokenInfo> {
+        self.ratchet();
+        if encrypted.len() < 8 {
+            return None;
+        }
+        let (epoch, encrypted) = encrypted.split_at(8);
+        let epoch = u64::from_be_bytes(epoch.try_into().unwrap());
+        let key = self.keys.get(self.epoch.checked_sub(epoch)? as usize)?;
+        // try the legacy construction only if the modern one fails
+        let plain = match NgAead::new(key).decrypt(encrypted) {
+            Ok(plain) => plain,
+            Err(_) if self.allow_legacy => LegacyAead::new(key).decrypt(encrypted)?,
+            Err(_) => return None,
+        };
+        safe_deserialize(&plain).ok()
+    }
+
+    /// Moves the ratchet forward to the current epoch, erasing keys that can only decrypt expired tokens.
+    fn ratchet(&mut self) {
+        let now = curr_epoch();
+        if now.saturating_sub(self.epoch) >= max_keys() as u64 {
+            // every key is useless by now, so there's no point ratcheting all the way
+            *self = Self::new(self.seed, self.allow_legacy);
+            return;
+        }
+        while self.epoch < now {
+            self.epoch += 1;
+            let next = match &self.seed {
+                Some(seed) => seeded_key(seed, self.epoch),
+                None => {
+                    let mut next = [0u8; 32];
+                    blake3::derive_key("sosistab-token-ratchet", &self.keys[0], &mut next);
+                    next
+                }
+            };
+            self.keys.push_front(next);
+        }
+        self.keys.truncate(max_keys());
+    }
+}
+
+/// How many keys can have encrypted tokens that are still valid.
+fn max_keys() -> usize {
+    (TOKEN_LIFETIME.as_secs() / TOKEN_EPOCH_SECS) as usize + 1
+}
+
+/// Derives the key of an epoch from a seed.
+fn seeded_key(seed: &[u8; 32], epoch: u64) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    let mut material = seed.to_vec();
+    material.extend_from_slice(&epoch.to_be_bytes());
+    blake3::derive_key("sosistab-token-seeded", &material, &mut key);
+    key
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn curr_epoch() -> u64 {
+    now_ms() / 1000 / TOKEN_EPOCH_SECS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(version: u64) -> TokenInfo {
+        TokenInfo {
+            sess_key: Buff::copy_from_slice(&[42; 32]),
+            init_time_ms: now_ms(),
+            version,
+            client_eph_pk: [7; 32],
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let mut keys = TokenKeys::new(None, true);
+        for version in [3, 4, 5] {
+            let token = keys.encrypt(&info(version));
+            let decrypted = keys.decrypt(&token).unwrap();
+            assert_eq!(decrypted.version, version);
+            assert_eq!(decrypted.sess_key, info(version).sess_key);
+            assert_eq!(decrypted.client_eph_pk, [7; 32]);
+            assert!(!decrypted.is_expired());
+        }
+    }
+
+    #[test]
+    fn tokens_need_the_right_keys() {
+        let mut keys = TokenKeys::new(None, true);
+        let mut other_keys = TokenKeys::new(None, true);
+        for version in [3, 4] {
+            let token = keys.encrypt(&info(version));
+            assert!(other_keys.decrypt(&token).is_none());
+            let mut tampered = token.to_vec();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(keys.decrypt(&tampered).is_none());
+            // a token claiming to be from a future epoch has no key
+            let mut future = token.to_vec();
+            future[..8].copy_from_slice(&(keys.epoch + 1).to_be_bytes());
+            assert!(keys.decrypt(&future).is_none());
+        }
+        assert!(keys.decrypt(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn seeded_keys_survive_restarts() {
+        let mut keys = TokenKeys::new(Some([1; 32]), true);
+        let token = keys.encrypt(&info(4));
+        let mut restarted = TokenKeys::new(Some([1; 32]), true);
+        assert_eq!(restarted.decrypt(&token).unwrap().version, 4);
+        let mut other_seed = TokenKeys::new(Some([2; 32]), true);
+        assert!(other_seed.decrypt(&token).is_none());
+        // a restarted listener still has the keys of earlier epochs
+        assert_eq!(restarted.keys.len(), max_keys());
+        let epoch = curr_epoch() - 5;
+        let mut old_token = epoch.to_be_bytes().to_vec();
+        old_token.extend_from_slice(
+            &NgAead::new(&seeded_key(&[1; 32], epoch))
+                .encrypt(&bincode::serialize(&info(4)).unwrap()),
+        );
+        assert!(restarted.decrypt(&old_token).is_some());
+        assert!(other_seed.decrypt(&old_token).is_none());
+    }
+
+    #[test]
+    fn legacy_tokens_can_be_refused() {
+        let mut keys = TokenKeys::new(None, false);
+        let legacy = keys.encrypt(&info(3));
+        let modern = keys.encrypt(&info(4));
+        assert!(keys.decrypt(&legacy).is_none());
+        assert_eq!(keys.decrypt(&modern).unwrap().version, 4);
+    }
+
+    #[test]
+    fn old_epochs_still_decrypt() {
+        let mut keys = TokenKeys::new(None, true);
+        let token = keys.encrypt(&info(4));
+        // pretend the token was issued a few epochs ago
+        keys.epoch -= 3;
+        keys.ratchet();
+        assert_eq!(keys.keys.len(), 4);
+        assert!(keys.decrypt(&token).is_none());
+        let mut old_token = token.to_vec();
+        old_token[..8].copy_from_slice(&(keys.epoch - 3).to_be_bytes());
+        assert!(keys.decrypt(&old_token).is_some());
+    }
+}
@@ -0,0 +1,361 @@
+use std::sync::atomic::Ordering;
+
+use crate::Role;
+
+use super::{extension, fake_signature, with_len, Peer, Received};
+
+/// Length of the cookies that servers make clients repeat their ClientHellos with.
+pub(super) const COOKIE_LEN: usize = 20;
+
+/// DTLS 1.2.
+const VERSION: [u8; 2] = [0xfe, 0xfd];
+
+/// Length of a record header.
+const HEADER_LEN: usize = 13;
+
+// types of records
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+// types of handshake messages
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const HELLO_VERIFY_REQUEST: u8 = 3;
+const CERTIFICATE: u8 = 11;
+const SERVER_KEY_EXCHANGE: u8 = 12;
+const CERTIFICATE_REQUEST: u8 = 13;
+const SERVER_HELLO_DONE: u8 = 14;
+const CERTIFICATE_VERIFY: u8 = 15;
+const CLIENT_KEY_EXCHANGE: u8 = 16;
+
+/// Length of an encrypted Finished message, with its explicit nonce and tag.
+const FINISHED_LEN: usize = 48;
+
+/// Appends the application data record carrying the given payload to a peer.
+pub(super) fn frame(peer: &Peer, payload: &[u8], out: &mut Vec<u8>) {
+    record(
+        out,
+        APPLICATION_DATA,
+        1,
+        peer.next_seq.fetch_add(1, Ordering::Relaxed),
+        |out| out.extend_from_slice(payload),
+    );
+}
+
+/// Appends the ClientHello that a client starts the fake handshake with, repeating the cookie the server asked for if it did.
+pub(super) fn client_hello(peer: &Peer, out: &mut Vec<u8>) {
+    let cookie = peer.cookie.lock().clone();
+    let seq = if cookie.is_empty() { 0 } else { 1 };
+    handshake(out, seq, CLIENT_HELLO, seq as u16, |out| {
+        out.extend_from_slice(&VERSION);
+        out.extend_from_slice(&peer.hello_random);
+        out.push(0);
+        with_len(out, 1, |out| out.extend_from_slice(&cookie));
+        with_len(out, 2, |out| {
+            out.extend_from_slice(&[
+                0xc0, 0x2b, 0xc0, 0x2f, 0xcc, 0xa9, 0xcc, 0xa8, 0xc0, 0x09, 0xc0, 0x13, 0xc0, 0x0a,
+                0xc0, 0x14,
+            ])
+        });
+        out.extend_from_slice(&[1, 0]);
+        with_len(out, 2, |out| {
+            extension(out, 0x0017, |_| {});
+            extension(out, 0xff01, |out| out.push(0));
+            extension(out, 0x000a, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[0x00, 0x1d, 0x00, 0x17, 0x00, 0x18])
+                })
+            });
+            extension(out, 0x000b, |out| with_len(out, 1, |out| out.push(0)));
+            extension(out, 0x000d, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[
+                        0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x03, 0x08, 0x05, 0x05, 0x01,
+                        0x08, 0x06, 0x06, 0x01, 0x02, 0x01,
+                    ])
+                })
+            });
+            // SRTP profiles, as WebRTC offers them
+            extension(out, 0x000e, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[0x00, 0x07, 0x00, 0x08, 0x00, 0x01, 0x00, 0x02])
+                });
+                out.push(0);
+            });
+        });
+    });
+}
+
+/// Makes sense of a datagram from a peer, writing whatever the fake handshake calls for into `reply`. Clients must repeat their ClientHello with the given cookie before servers answer it, so that servers cannot be used to amplify floods.
+pub(super) fn parse(
+    peer: &Peer,
+    role: Role,
+    pkt: &[u8],
+    cookie: &[u8],
+    cert: &[u8],
+    reply: &mut Vec<u8>,
+) -> Received {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < pkt.len() {
+        let header = match pkt.get(pos..pos + HEADER_LEN) {
+            Some(header) => header,
+            None => return Received::Garbage,
+        };
+        let len = u16::from_be_bytes([header[11], header[12]]) as usize;
+        let body = match pkt.get(pos + HEADER_LEN..pos + HEADER_LEN + len) {
+            Some(body) => body,
+            None => return Received::Garbage,
+        };
+        if header[1..3] != VERSION && header[1..3] != [0xfe, 0xff] {
+            return Received::Garbage;
+        }
+        let epoch = u16::from_be_bytes([header[3], header[4]]);
+        records.push((header[0], epoch, body));
+        pos += HEADER_LEN + len;
+    }
+    // records of epoch 0 are in the clear, so we can see what they carry
+    let first_message = |content_type| {
+        records
+            .iter()
+            .find(|(ty, epoch, _)| *ty == content_type && *epoch == 0)
+            .and_then(|(_, _, body)| body.first().copied())
+    };
+    match (records.as_slice(), role) {
+        ([(APPLICATION_DATA, _, _)], _) => Received::Data(HEADER_LEN..pkt.len()),
+        (_, Role::Server) if first_message(HANDSHAKE) == Some(CLIENT_HELLO) => {
+            match client_hello_cookie(records[0].2) {
+                Some(theirs) if theirs == cookie => server_flight(cert, reply),
+                Some(_) => {
+                    handshake(reply, 0, HELLO_VERIFY_REQUEST, 0, |out| {
+                        // as RFC 6347 recommends, whatever the version to be negotiated
+                        out.extend_from_slice(&[0xfe, 0xff]);
+                        with_len(out, 1, |out| out.extend_from_slice(cookie));
+                    })
+                }
+                None => return Received::Garbage,
+            }
+            Received::Handshake
+        }
+        (_, Role::Server) if first_message(CHANGE_CIPHER_SPEC).is_some() => {
+            record(reply, CHANGE_CIPHER_SPEC, 0, 6, |out| out.push(1));
+            finished(peer, reply);
+            Received::Handshake
+        }
+        (_, Role::Client) if first_message(HANDSHAKE) == Some(HELLO_VERIFY_REQUEST) => {
+            let cookie = records[0].2.get(14..).and_then(|rest| {
+                let len = *rest.first()? as usize;
+                rest.get(1..1 + len)
+            });
+            if let Some(cookie) = cookie {
+                *peer.cookie.lock() = cookie.to_vec();
+                client_hello(peer, reply);
+            }
+            Received::Handshake
+        }
+        (_, Role::Client) if first_message(HANDSHAKE) == Some(SERVER_HELLO) => {
+            if !peer.established.swap(true, Ordering::Relaxed) {
+                client_flight(peer, cert, reply);
+            }
+            Received::Handshake
+        }
+        ([], _) => Received::Garbage,
+        _ => Received::Handshake,
+    }
+}
+
+/// Gets the cookie out of the body of a record carrying a ClientHello.
+fn client_hello_cookie(body: &[u8]) -> Option<&[u8]> {
+    // skips the handshake header, version and random
+    let rest = body.get(12 + 2 + 32..)?;
+    let session_id_len = *rest.first()? as usize;
+    let rest = rest.get(1 + session_id_len..)?;
+    let cookie_len = *rest.first()? as usize;
+    rest.get(1..1 + cookie_len)
+}
+
+/// Appends the flight a server answers a ClientHello with a valid cookie with, asking for a client certificate as WebRTC does.
+fn server_flight(cert: &[u8], out: &mut Vec<u8>) {
+    handshake(out, 1, SERVER_HELLO, 1, |out| {
+        out.extend_from_slice(&VERSION);
+        out.extend((0..32).map(|_| rand::random::<u8>()));
+        out.push(0);
+        out.extend_from_slice(&[0xc0, 0x2b, 0x00]);
+        with_len(out, 2, |out| {
+            extension(out, 0xff01, |out| out.push(0));
+            extension(out, 0x0017, |_| {});
+            extension(out, 0x000b, |out| with_len(out, 1, |out| out.push(0)));
+            extension(out, 0x000e, |out| {
+                with_len(out, 2, |out| out.extend_from_slice(&[0x00, 0x07]));
+                out.push(0);
+            });
+        });
+    });
+    certificate(out, 2, cert);
+    handshake(out, 3, SERVER_KEY_EXCHANGE, 3, |out| {
+        // an ephemeral x25519 key, signed with ECDSA
+        out.extend_from_slice(&[0x03, 0x00, 0x1d]);
+        with_len(out, 1, |out| {
+            out.extend((0..32).map(|_| rand::random::<u8>()))
+        });
+        out.extend_from_slice(&[0x04, 0x03]);
+        with_len(out, 2, fake_signature);
+    });
+    handshake(out, 4, CERTIFICATE_REQUEST, 4, |out| {
+        with_len(out, 1, |out| out.extend_from_slice(&[0x01, 0x40]));
+        with_len(out, 2, |out| {
+            out.extend_from_slice(&[0x04, 0x03, 0x08, 0x04, 0x04, 0x01])
+        });
+        with_len(out, 2, |_| {});
+    });
+    handshake(out, 5, SERVER_HELLO_DONE, 5, |_| {});
+}
+
+/// Appends the flight a client answers the server's flight with, up to and including its Finished.
+fn client_flight(peer: &Peer, cert: &[u8], out: &mut Vec<u8>) {
+    certificate(out, 2, cert);
+    handshake(out, 3, CLIENT_KEY_EXCHANGE, 3, |out| {
+        with_len(out, 1, |out| {
+            out.extend((0..32).map(|_| rand::random::<u8>()))
+        })
+    });
+    handshake(out, 4, CERTIFICATE_VERIFY, 4, |out| {
+        out.extend_from_slice(&[0x04, 0x03]);
+        with_len(out, 2, fake_signature);
+    });
+    record(out, CHANGE_CIPHER_SPEC, 0, 5, |out| out.push(1));
+    finished(peer, out);
+}
+
+/// Appends a handshake record carrying a certificate chain of just the given certificate.
+fn certificate(out: &mut Vec<u8>, seq: u64, cert: &[u8]) {
+    handshake(out, seq, CERTIFICATE, seq as u16, |out| {
+        with_len(out, 3, |out| {
+            with_len(out, 3, |out| out.extend_from_slice(cert))
+        })
+    });
+}
+
+/// Appends a Finished, the first record of epoch 1, which is encrypted and so looks random.
+fn finished(peer: &Peer, out: &mut Vec<u8>) {
+    record(
+        out,
+        HANDSHAKE,
+        1,
+        peer.next_seq.fetch_add(1, Ordering::Relaxed),
+        |out| out.extend((0..FINISHED_LEN).map(|_| rand::random::<u8>())),
+    );
+}
+
+/// Appends a record of epoch 0 carrying a handshake message in a single fragment.
+fn handshake(
+    out: &mut Vec<u8>,
+    seq: u64,
+    msg_type: u8,
+    message_seq: u16,
+    contents: impl FnOnce(&mut Vec<u8>),
+) {
+    record(out, HANDSHAKE, 0, seq, |out| {
+        let start = out.len();
+        out.push(msg_type);
+        out.extend_from_slice(&[0; 3]);
+        out.extend_from_slice(&message_seq.to_be_bytes());
+        out.extend_from_slice(&[0; 6]);
+        contents(out);
+        // the length of the message, and of its only fragment
+        let len = ((out.len() - start - 12) as u32).to_be_bytes();
+        out[start + 1..start + 4].copy_from_slice(&len[1..]);
+        out[start + 9..start + 12].copy_from_slice(&len[1..]);
+    });
+}
+
+/// Appends a record with the contents written by the given closure.
+fn record(
+    out: &mut Vec<u8>,
+    content_type: u8,
+    epoch: u16,
+    seq: u64,
+    contents: impl FnOnce(&mut Vec<u8>),
+) {
+    out.push(content_type);
+    out.extend_from_slice(&VERSION);
+    out.extend_from_slice(&epoch.to_be_bytes());
+    out.extend_from_slice(&seq.to_be_bytes()[2..]);
+    with_len(out, 2, contents);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIE: [u8; COOKIE_LEN] = [7; COOKIE_LEN];
+
+    fn parse_as(role: Role, pkt: &[u8]) -> (Received, Vec<u8>) {
+        let mut reply = Vec::new();
+        let received = parse(&Peer::new(), role, pkt, &COOKIE, b"cert", &mut reply);
+        (received, reply)
+    }
+
+    #[test]
+    fn handshakes_need_cookies() {
+        let client = Peer::new();
+        let mut hello = Vec::new();
+        client_hello(&client, &mut hello);
+        // the first hello only gets a cookie, which the client repeats its hello with
+        let (received, verify_request) = parse_as(Role::Server, &hello);
+        assert!(matches!(received, Received::Handshake));
+        let mut hello = Vec::new();
+        assert!(matches!(
+            parse(&client, Role::Client, &verify_request, &[], b"", &mut hello),
+            Received::Handshake
+        ));
+        assert_eq!(*client.cookie.lock(), COOKIE);
+        let (received, flight) = parse_as(Role::Server, &hello);
+        assert!(matches!(received, Received::Handshake));
+        assert!(flight.len() > verify_request.len());
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut pkt = Vec::new();
+        frame(&Peer::new(), b"hello", &mut pkt);
+        assert_eq!(&pkt[..3], &[APPLICATION_DATA, 0xfe, 0xfd]);
+        match parse_as(Role::Server, &pkt).0 {
+            Received::Data(range) => assert_eq!(&pkt[range], b"hello"),
+            _ => panic!("data record not recognized"),
+        }
+        // truncated records are garbage, and nothing is answered
+        for len in 1..pkt.len() {
+            let (received, reply) = parse_as(Role::Server, &pkt[..len]);
+            assert!(matches!(received, Received::Garbage), "{}", len);
+            assert!(reply.is_empty());
+        }
+    }
+
+    #[test]
+    fn garbage_is_survived() {
+        let mut hello = Vec::new();
+        client_hello(&Peer::new(), &mut hello);
+        for _ in 0..10000 {
+            // mangled hellos get further than random bytes
+            let mut pkt = if rand::random() {
+                hello.clone()
+            } else {
+                (0..rand::random::<usize>() % 100)
+                    .map(|_| rand::random())
+                    .collect()
+            };
+            if !pkt.is_empty() {
+                let idx = rand::random::<usize>() % pkt.len();
+                pkt[idx] = rand::random();
+                pkt.truncate(rand::random::<usize>() % (pkt.len() + 1));
+            }
+            for role in [Role::Client, Role::Server] {
+                if let (Received::Data(range), _) = parse_as(role, &pkt) {
+                    assert!(range.end <= pkt.len());
+                }
+            }
+        }
+    }
+}
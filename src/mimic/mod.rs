@@ -0,0 +1,293 @@
+use std::{
+    io,
+    net::SocketAddr,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use moka::sync::Cache;
+use parking_lot::Mutex;
+
+use crate::{
+    buffer::{Buff, BuffMut},
+    Backhaul, Role,
+};
+
+mod dtls;
+mod quic;
+
+/// How long the state of a peer that we stop hearing from is kept.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Most peers whose state is kept at the same time.
+const MAX_PEERS: u64 = 100_000;
+
+/// How often a client repeats the first flight of a fake handshake that the server has not answered.
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+
+/// A protocol that UDP traffic is disguised as, for networks that block traffic looking like nothing in particular. The disguise is only skin-deep: it fools classifiers looking at headers and handshakes, not anyone trying to actually talk the protocol with us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mimicry {
+    /// Packets are sent as they are, looking like uniformly random bytes.
+    #[default]
+    None,
+    /// QUIC version 1, as used by HTTP/3. Every flow starts with a fake handshake, whose Initial packets are protected as QUIC requires, so that they decrypt to a TLS ClientHello and ServerHello. Packets sent before the server answers go out as 0-RTT packets, and later ones with short headers.
+    Quic,
+    /// DTLS 1.2, as used by WebRTC data channels. Every flow starts with a fake handshake, complete with a cookie exchange and self-signed certificates, and packets go out as application data records.
+    Dtls,
+}
+
+/// What a received datagram turned out to be.
+enum Received {
+    /// A packet carrying the given range of the datagram as payload.
+    Data(Range<usize>),
+    /// Part of a fake handshake.
+    Handshake,
+    /// Something that does not look like the protocol at all.
+    Garbage,
+}
+
+/// What we know about a peer, most of which is made up once and then kept consistent.
+struct Peer {
+    /// Our connection ID, for QUIC.
+    local_id: [u8; 8],
+    /// The peer's connection ID, for QUIC. Until the peer tells us, this is the one the client first made up.
+    remote_id: Mutex<[u8; 8]>,
+    /// Random of our ClientHello, which stays the same when it is repeated.
+    hello_random: [u8; 32],
+    /// Key share of our ClientHello, for QUIC.
+    key_share: [u8; 32],
+    /// Server name of our ClientHello, for QUIC.
+    server_name: String,
+    /// Cookie that the server asked us to repeat our ClientHello with, for DTLS.
+    cookie: Mutex<Vec<u8>>,
+    /// Whether the server answered the fake handshake.
+    established: AtomicBool,
+    /// When we last sent the first flight of the fake handshake.
+    greeted: Mutex<Option<Instant>>,
+    /// Packet number of our next Initial packet for QUIC, or sequence number of our next record in epoch 1 for DTLS.
+    next_seq: AtomicU64,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Self {
+            local_id: rand::random(),
+            remote_id: Mutex::new(rand::random()),
+            hello_random: rand::random(),
+            key_share: rand::random(),
+            server_name: format!(
+                "{}{}.com",
+                eff_wordlist::large::random_word(),
+                eff_wordlist::large::random_word()
+            ),
+            cookie: Default::default(),
+            established: AtomicBool::new(false),
+            greeted: Mutex::new(None),
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the first flight of the fake handshake should be sent now, because it never was or has gone unanswered for too long. If so, it is recorded as sent.
+    fn greeting_due(&self) -> bool {
+        if self.established.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut greeted = self.greeted.lock();
+        if greeted
+            .map(|greeted| greeted.elapsed() < HANDSHAKE_RETRY)
+            .unwrap_or_default()
+        {
+            return false;
+        }
+        *greeted = Some(Instant::now());
+        true
+    }
+}
+
+/// A backhaul that disguises the datagrams of another backhaul as some other protocol. Both ends must use the same [Mimicry], and the client end starts the fake handshake of every flow.
+pub(crate) struct MimicBackhaul<B: Backhaul + 'static> {
+    haul: B,
+    mimicry: Mimicry,
+    role: Role,
+    peers: Cache<SocketAddr, Arc<Peer>>,
+    /// Key that DTLS cookies are made with.
+    cookie_key: [u8; 32],
+    /// Self-signed certificate presented in DTLS handshakes.
+    cert: Vec<u8>,
+}
+
+impl<B: Backhaul + 'static> MimicBackhaul<B> {
+    pub fn new(haul: B, mimicry: Mimicry, role: Role) -> Self {
+        Self {
+            haul,
+            mimicry,
+            role,
+            peers: Cache::builder()
+                .max_capacity(MAX_PEERS)
+                .time_to_idle(PEER_IDLE_TIMEOUT)
+                .build(),
+            cookie_key: rand::random(),
+            cert: if mimicry == Mimicry::Dtls {
+                self_signed_cert()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    fn peer(&self, addr: SocketAddr) -> Arc<Peer> {
+        self.peers.get_with(addr, || Arc::new(Peer::new()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backhaul + 'static> Backhaul for MimicBackhaul<B> {
+    async fn send_to(&self, to_send: Buff, dest: SocketAddr) -> io::Result<()> {
+        if self.mimicry == Mimicry::None {
+            return self.haul.send_to(to_send, dest).await;
+        }
+        let peer = self.peer(dest);
+        if matches!(self.role, Role::Client) && peer.greeting_due() {
+            let mut hello = BuffMut::new();
+            match self.mimicry {
+                Mimicry::Quic => quic::client_initial(&peer, &mut hello),
+                Mimicry::Dtls => dtls::client_hello(&peer, &mut hello),
+                Mimicry::None => unreachable!(),
+            }
+            self.haul.send_to(hello.freeze(), dest).await?;
+        }
+        let mut framed = BuffMut::new();
+        match self.mimicry {
+            Mimicry::Quic => quic::frame(&peer, self.role, &to_send, &mut framed),
+            Mimicry::Dtls => dtls::frame(&peer, &to_send, &mut framed),
+            Mimicry::None => unreachable!(),
+        }
+        self.haul.send_to(framed.freeze(), dest).await
+    }
+
+    async fn recv_from(&self) -> io::Result<(Buff, SocketAddr)> {
+        loop {
+            let (buf, addr) = self.haul.recv_from().await?;
+            if self.mimicry == Mimicry::None {
+                return Ok((buf, addr));
+            }
+            let peer = self.peer(addr);
+            let mut reply = BuffMut::new();
+            let received = match self.mimicry {
+                Mimicry::Quic => quic::parse(&peer, self.role, &buf, &mut reply),
+                Mimicry::Dtls => {
+                    let cookie = blake3::keyed_hash(&self.cookie_key, addr.to_string().as_bytes());
+                    dtls::parse(
+                        &peer,
+                        self.role,
+                        &buf,
+                        &cookie.as_bytes()[..dtls::COOKIE_LEN],
+                        &self.cert,
+                        &mut reply,
+                    )
+                }
+                Mimicry::None => unreachable!(),
+            };
+            if !reply.is_empty() {
+                if let Err(err) = self.haul.send_to(reply.freeze(), addr).await {
+                    tracing::debug!("cannot answer fake handshake from {}: {:?}", addr, err);
+                }
+            }
+            match received {
+                Received::Data(range) => return Ok((buf.slice(range), addr)),
+                Received::Handshake => {}
+                Received::Garbage => tracing::trace!(
+                    "dropping packet from {} that does not look like {:?}",
+                    addr,
+                    self.mimicry
+                ),
+            }
+        }
+    }
+}
+
+/// Creates a self-signed certificate like those WebRTC endpoints make up for every connection.
+fn self_signed_cert() -> Vec<u8> {
+    let mut params = rcgen::CertificateParams::new(Vec::new());
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "WebRTC");
+    params.serial_number = Some(rand::random());
+    let now = rcgen::date_time_ymd(1970, 1, 1)
+        + SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+    params.not_before = now - Duration::from_secs(86400);
+    params.not_after = now + Duration::from_secs(86400 * 30);
+    rcgen::Certificate::from_params(params)
+        .and_then(|cert| cert.serialize_der())
+        .expect("cannot create self-signed certificate")
+}
+
+/// Appends a field prefixed with its length in the given number of bytes, with the contents written by the given closure.
+pub(crate) fn with_len(out: &mut Vec<u8>, len_bytes: usize, contents: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.resize(start + len_bytes, 0);
+    contents(out);
+    let len = (out.len() - start - len_bytes) as u64;
+    out[start..start + len_bytes].copy_from_slice(&len.to_be_bytes()[8 - len_bytes..]);
+}
+
+/// Appends a TLS extension of the given type.
+pub(crate) fn extension(out: &mut Vec<u8>, ext_type: u16, contents: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    with_len(out, 2, contents);
+}
+
+/// Appends something that looks like a DER-encoded ECDSA signature over P-256.
+fn fake_signature(out: &mut Vec<u8>) {
+    let mut r: [u8; 32] = rand::random();
+    let mut s: [u8; 32] = rand::random();
+    // a high bit set in r calls for a leading zero, and a high bit clear in s does not
+    r[0] |= 0x80;
+    s[0] = (s[0] & 0x7f).max(1);
+    out.extend_from_slice(&[0x30, 0x45, 0x02, 0x21, 0x00]);
+    out.extend_from_slice(&r);
+    out.extend_from_slice(&[0x02, 0x20]);
+    out.extend_from_slice(&s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp() -> smol::Async<std::net::UdpSocket> {
+        smol::Async::<std::net::UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap()
+    }
+
+    #[test]
+    fn backhauls_round_trip() {
+        for mimicry in [Mimicry::None, Mimicry::Quic, Mimicry::Dtls] {
+            smol::block_on(async {
+                let (client, server) = (udp(), udp());
+                let server_addr = server.get_ref().local_addr().unwrap();
+                let client = MimicBackhaul::new(client, mimicry, Role::Client);
+                let server = MimicBackhaul::new(server, mimicry, Role::Server);
+                for i in 0..10u8 {
+                    let up = Buff::copy_from_slice(&[i; 100]);
+                    client.send_to(up.clone(), server_addr).await.unwrap();
+                    let (received, client_addr) = server.recv_from().await.unwrap();
+                    assert_eq!(received, up, "{:?}", mimicry);
+                    let down = Buff::copy_from_slice(&[i; 200]);
+                    server.send_to(down.clone(), client_addr).await.unwrap();
+                    let (received, from) = client.recv_from().await.unwrap();
+                    assert_eq!((received, from), (down, server_addr), "{:?}", mimicry);
+                }
+                // by now, the fake handshake is over
+                let established = client.peer(server_addr).established.load(Ordering::Relaxed);
+                assert_eq!(established, mimicry != Mimicry::None, "{:?}", mimicry);
+            })
+        }
+    }
+}
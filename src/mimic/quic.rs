@@ -0,0 +1,527 @@
+use std::sync::atomic::Ordering;
+
+use ring::{aead, hkdf};
+
+use crate::Role;
+
+use super::{extension, with_len, Peer, Received};
+
+/// QUIC version 1.
+const VERSION: [u8; 4] = [0, 0, 0, 1];
+
+/// Salt that the keys of Initial packets of QUIC version 1 are derived with, from RFC 9001.
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Datagrams carrying Initial packets must be at least this large.
+const MIN_INITIAL_DATAGRAM: usize = 1200;
+
+/// Our connection IDs are 8 bytes long, so a short header is a flags byte followed by one.
+const SHORT_HEADER_LEN: usize = 9;
+
+/// Length of long headers with our 8-byte connection IDs, up to the length field.
+const LONG_HEADER_LEN: usize = 23;
+
+/// Packet numbers of Initial packets are 2 bytes long.
+const PN_LEN: usize = 2;
+
+/// Length of the authentication tag of protected packets.
+const TAG_LEN: usize = 16;
+
+// types of long header packets
+const INITIAL: u8 = 0;
+const ZERO_RTT: u8 = 1;
+const HANDSHAKE: u8 = 2;
+
+/// Appends the packet carrying the given payload to a peer. Until the server has answered the fake handshake, clients send 0-RTT packets.
+pub(super) fn frame(peer: &Peer, role: Role, payload: &[u8], out: &mut Vec<u8>) {
+    let remote_id = *peer.remote_id.lock();
+    if matches!(role, Role::Client) && !peer.established.load(Ordering::Relaxed) {
+        long_header(out, ZERO_RTT, &remote_id, &peer.local_id);
+        varint2(out, payload.len());
+    } else {
+        // the spin bit stays clear, while the rest of the flags are protected and look random
+        out.push(0x40 | (rand::random::<u8>() & 0x1f));
+        out.extend_from_slice(&remote_id);
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Appends the Initial packet that a client starts the fake handshake with.
+pub(super) fn client_initial(peer: &Peer, out: &mut Vec<u8>) {
+    let original_id = *peer.remote_id.lock();
+    let mut frames = Vec::new();
+    crypto_frame(&mut frames, |out| client_hello(peer, out));
+    initial_packet(
+        out,
+        peer.next_seq.fetch_add(1, Ordering::Relaxed),
+        &original_id,
+        (&original_id, &peer.local_id),
+        Role::Client,
+        &frames,
+        MIN_INITIAL_DATAGRAM,
+    );
+}
+
+/// Makes sense of a packet from a peer, writing whatever the fake handshake calls for into `reply`.
+pub(super) fn parse(peer: &Peer, role: Role, pkt: &[u8], reply: &mut Vec<u8>) -> Received {
+    let first = match pkt.first() {
+        Some(first) => *first,
+        None => return Received::Garbage,
+    };
+    if first & 0x80 == 0 {
+        if first & 0x40 == 0 || pkt.len() <= SHORT_HEADER_LEN {
+            return Received::Garbage;
+        }
+        return Received::Data(SHORT_HEADER_LEN..pkt.len());
+    }
+    let header = match LongHeader::parse(pkt) {
+        Some(header) => header,
+        None => return Received::Garbage,
+    };
+    let mut cid = [0u8; 8];
+    match (header.packet_type, role) {
+        (ZERO_RTT, Role::Server) => return Received::Data(header.payload),
+        (INITIAL, Role::Server) if header.scid.len() == cid.len() => {
+            cid.copy_from_slice(header.scid);
+            *peer.remote_id.lock() = cid;
+            server_initial(peer, header.dcid, reply);
+        }
+        // only the first answer counts, since the server answers every Initial we repeat
+        (INITIAL, Role::Client)
+            if header.scid.len() == cid.len()
+                && !peer.established.swap(true, Ordering::Relaxed) =>
+        {
+            cid.copy_from_slice(header.scid);
+            *peer.remote_id.lock() = cid;
+            // what would carry the client's Finished, which nobody but the server can decrypt
+            opaque_packet(reply, HANDSHAKE, &cid, &peer.local_id, 60);
+        }
+        // nothing else needs an answer
+        _ => {}
+    }
+    Received::Handshake
+}
+
+/// The unprotected parts of a long header packet.
+struct LongHeader<'a> {
+    packet_type: u8,
+    dcid: &'a [u8],
+    scid: &'a [u8],
+    /// Where the packet number and payload are, or for 0-RTT packets of ours, just the payload.
+    payload: std::ops::Range<usize>,
+}
+
+impl<'a> LongHeader<'a> {
+    fn parse(pkt: &'a [u8]) -> Option<Self> {
+        let mut reader = Reader { pkt, pos: 0 };
+        let first = reader.bytes(1)?[0];
+        if reader.bytes(4)? != VERSION {
+            return None;
+        }
+        let packet_type = (first >> 4) & 0b11;
+        let dcid_len = reader.bytes(1)?[0] as usize;
+        let dcid = reader.bytes(dcid_len)?;
+        let scid_len = reader.bytes(1)?[0] as usize;
+        let scid = reader.bytes(scid_len)?;
+        if dcid_len > 20 || scid_len > 20 {
+            return None;
+        }
+        if packet_type == INITIAL {
+            let token_len = reader.varint()?;
+            reader.bytes(token_len)?;
+        }
+        let len = reader.varint()?;
+        let start = reader.pos;
+        reader.bytes(len)?;
+        Some(Self {
+            packet_type,
+            dcid,
+            scid,
+            payload: start..start + len,
+        })
+    }
+}
+
+struct Reader<'a> {
+    pkt: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.pkt.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn varint(&mut self) -> Option<usize> {
+        let first = self.bytes(1)?[0];
+        let len = 1 << (first >> 6);
+        let rest = self.bytes(len - 1)?;
+        Some(
+            rest.iter()
+                .fold((first & 0x3f) as usize, |acc, b| (acc << 8) | *b as usize),
+        )
+    }
+}
+
+/// Appends the datagram a server answers the Initial packet of a client with: an Initial packet carrying a ServerHello, coalesced with what would be the rest of the server's handshake.
+fn server_initial(peer: &Peer, original_id: &[u8], out: &mut Vec<u8>) {
+    let remote_id = *peer.remote_id.lock();
+    let mut frames = Vec::new();
+    // acknowledges the client's Initial packet
+    frames.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00]);
+    crypto_frame(&mut frames, server_hello);
+    initial_packet(
+        out,
+        0,
+        original_id,
+        (&remote_id, &peer.local_id),
+        Role::Server,
+        &frames,
+        0,
+    );
+    let handshake_len = MIN_INITIAL_DATAGRAM.saturating_sub(out.len() + LONG_HEADER_LEN + 2);
+    opaque_packet(out, HANDSHAKE, &remote_id, &peer.local_id, handshake_len);
+}
+
+/// Appends a long header up to, but not including, the length field.
+fn long_header(out: &mut Vec<u8>, packet_type: u8, dcid: &[u8], scid: &[u8]) {
+    // the low bits are protected, so they look random
+    out.push(0xc0 | (packet_type << 4) | (rand::random::<u8>() & 0x0f));
+    out.extend_from_slice(&VERSION);
+    out.push(dcid.len() as u8);
+    out.extend_from_slice(dcid);
+    out.push(scid.len() as u8);
+    out.extend_from_slice(scid);
+}
+
+/// Appends a long header packet of the given type whose contents are protected with keys that an observer cannot know, so they are just random.
+fn opaque_packet(out: &mut Vec<u8>, packet_type: u8, dcid: &[u8], scid: &[u8], len: usize) {
+    long_header(out, packet_type, dcid, scid);
+    varint2(out, len);
+    out.extend((0..len).map(|_| rand::random::<u8>()));
+}
+
+/// Appends an Initial packet with the given packet number carrying the given frames, protected with the keys that the given original destination connection ID of the client implies, as an observer can do too. The frames are padded so that the datagram is at least `min_datagram` long.
+fn initial_packet(
+    out: &mut Vec<u8>,
+    pn: u64,
+    original_id: &[u8],
+    (dcid, scid): (&[u8], &[u8]),
+    sender: Role,
+    frames: &[u8],
+    min_datagram: usize,
+) {
+    let start = out.len();
+    out.push(0xc0 | (INITIAL << 4) | (PN_LEN as u8 - 1));
+    out.extend_from_slice(&VERSION);
+    out.push(dcid.len() as u8);
+    out.extend_from_slice(dcid);
+    out.push(scid.len() as u8);
+    out.extend_from_slice(scid);
+    // no token
+    out.push(0);
+    let header_len = out.len() + 2 + PN_LEN;
+    let mut payload = frames.to_vec();
+    payload.resize(
+        frames
+            .len()
+            .max(min_datagram.saturating_sub(header_len + TAG_LEN)),
+        0,
+    );
+    varint2(out, PN_LEN + payload.len() + TAG_LEN);
+    let pn_offset = out.len();
+    out.extend_from_slice(&(pn as u16).to_be_bytes());
+
+    let (key, iv, hp) = initial_keys(original_id, sender);
+    let mut nonce = iv;
+    for (n, p) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+        *n ^= p;
+    }
+    let tag = key
+        .seal_in_place_separate_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&out[start..]),
+            &mut payload,
+        )
+        .expect("cannot protect Initial packet");
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(tag.as_ref());
+    let mask = hp
+        .new_mask(&out[pn_offset + 4..pn_offset + 4 + 16])
+        .expect("cannot protect Initial header");
+    out[start] ^= mask[0] & 0x0f;
+    for (i, m) in mask[1..=PN_LEN].iter().enumerate() {
+        out[pn_offset + i] ^= m;
+    }
+}
+
+/// Derives the packet protection key, IV and header protection key of the Initial packets of the given sender.
+fn initial_keys(
+    original_id: &[u8],
+    sender: Role,
+) -> (aead::LessSafeKey, [u8; 12], aead::quic::HeaderProtectionKey) {
+    let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT).extract(original_id);
+    let mut secret = [0u8; 32];
+    expand_label(
+        &initial,
+        match sender {
+            Role::Client => b"client in",
+            Role::Server => b"server in",
+        },
+        &mut secret,
+    );
+    let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+    let mut key = [0u8; 16];
+    let mut iv = [0u8; 12];
+    let mut hp = [0u8; 16];
+    expand_label(&secret, b"quic key", &mut key);
+    expand_label(&secret, b"quic iv", &mut iv);
+    expand_label(&secret, b"quic hp", &mut hp);
+    (
+        aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).unwrap()),
+        iv,
+        aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).unwrap(),
+    )
+}
+
+/// HKDF-Expand-Label of TLS 1.3, with an empty context.
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) {
+    struct Len(usize);
+    impl hkdf::KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info = [&len[..], &label_len[..], b"tls13 ", label, &[0]];
+    prk.expand(&info, Len(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .expect("cannot expand label");
+}
+
+/// Appends a CRYPTO frame at offset 0 with the contents written by the given closure.
+fn crypto_frame(out: &mut Vec<u8>, contents: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(&[0x06, 0x00]);
+    let start = out.len();
+    out.extend_from_slice(&[0, 0]);
+    contents(out);
+    let len = out.len() - start - 2;
+    out[start..start + 2].copy_from_slice(&(0x4000 | len as u16).to_be_bytes());
+}
+
+/// Appends a varint in its 2-byte form.
+fn varint2(out: &mut Vec<u8>, val: usize) {
+    out.extend_from_slice(&(0x4000 | val as u16).to_be_bytes());
+}
+
+/// Appends the TLS 1.3 ClientHello of an HTTP/3 client.
+fn client_hello(peer: &Peer, out: &mut Vec<u8>) {
+    out.push(1);
+    with_len(out, 3, |out| {
+        out.extend_from_slice(&[3, 3]);
+        out.extend_from_slice(&peer.hello_random);
+        // QUIC has no use for session IDs
+        out.push(0);
+        with_len(out, 2, |out| {
+            out.extend_from_slice(&[0x13, 0x01, 0x13, 0x02, 0x13, 0x03])
+        });
+        out.extend_from_slice(&[1, 0]);
+        with_len(out, 2, |out| {
+            extension(out, 0x0000, |out| {
+                with_len(out, 2, |out| {
+                    out.push(0);
+                    with_len(out, 2, |out| {
+                        out.extend_from_slice(peer.server_name.as_bytes())
+                    });
+                })
+            });
+            extension(out, 0x000a, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[0x00, 0x1d, 0x00, 0x17, 0x00, 0x18])
+                })
+            });
+            extension(out, 0x000d, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[
+                        0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x03, 0x08, 0x05, 0x05, 0x01,
+                        0x08, 0x06, 0x06, 0x01,
+                    ])
+                })
+            });
+            extension(out, 0x0010, |out| {
+                with_len(out, 2, |out| {
+                    with_len(out, 1, |out| out.extend_from_slice(b"h3"))
+                })
+            });
+            extension(out, 0x0033, |out| {
+                with_len(out, 2, |out| {
+                    out.extend_from_slice(&[0x00, 0x1d]);
+                    with_len(out, 2, |out| out.extend_from_slice(&peer.key_share));
+                })
+            });
+            extension(out, 0x002d, |out| with_len(out, 1, |out| out.push(1)));
+            extension(out, 0x002b, |out| {
+                with_len(out, 1, |out| out.extend_from_slice(&[3, 4]))
+            });
+            extension(out, 0x0039, |out| {
+                // idle timeout, flow control limits, and our connection ID
+                for (id, value) in [
+                    (0x01, &[0x80, 0x00, 0x75, 0x30][..]),
+                    (0x04, &[0x80, 0xf0, 0x00, 0x00]),
+                    (0x05, &[0x80, 0x60, 0x00, 0x00]),
+                    (0x06, &[0x80, 0x60, 0x00, 0x00]),
+                    (0x07, &[0x80, 0x60, 0x00, 0x00]),
+                    (0x08, &[0x40, 0x64]),
+                    (0x09, &[0x40, 0x64]),
+                    (0x0f, &peer.local_id),
+                ] {
+                    out.push(id);
+                    with_len(out, 1, |out| out.extend_from_slice(value));
+                }
+            });
+        });
+    });
+}
+
+/// Appends a TLS 1.3 ServerHello.
+fn server_hello(out: &mut Vec<u8>) {
+    out.push(2);
+    with_len(out, 3, |out| {
+        out.extend_from_slice(&[3, 3]);
+        out.extend((0..32).map(|_| rand::random::<u8>()));
+        out.push(0);
+        out.extend_from_slice(&[0x13, 0x01, 0x00]);
+        with_len(out, 2, |out| {
+            extension(out, 0x0033, |out| {
+                out.extend_from_slice(&[0x00, 0x1d]);
+                with_len(out, 2, |out| {
+                    out.extend((0..32).map(|_| rand::random::<u8>()))
+                });
+            });
+            extension(out, 0x002b, |out| out.extend_from_slice(&[3, 4]));
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    /// Original destination connection ID of the examples of RFC 9001, Appendix A.
+    const RFC_ODCID: &str = "8394c8f03e515708";
+
+    #[test]
+    fn initial_keys_match_rfc_9001() {
+        let odcid = unhex(RFC_ODCID);
+        // the samples and masks of Appendix A.2 and A.3
+        for (sender, iv, sample, mask) in [
+            (
+                Role::Client,
+                "fa044b2f42a3fd3b46fb255c",
+                "d1b1c98dd7689fb8ec11d242b123dc9b",
+                "437b9aec36",
+            ),
+            (
+                Role::Server,
+                "0ac1493ca1905853b0bba03e",
+                "2cd0991cd25b0aac406a5816b6394100",
+                "2ec0d8356a",
+            ),
+        ] {
+            let (_, actual_iv, hp) = initial_keys(&odcid, sender);
+            assert_eq!(actual_iv.to_vec(), unhex(iv));
+            assert_eq!(hp.new_mask(&unhex(sample)).unwrap().to_vec(), unhex(mask));
+        }
+    }
+
+    #[test]
+    fn initial_packets_match_rfc_9001() {
+        // the server Initial of Appendix A.3, which has packet number 1 and a 2-byte packet number like ours
+        let frames = unhex("02000000000600405a020000560303eefce7f7b37ba1d1632e96677825ddf73988cfc79825df566dc5430b9a045a1200130100002e00330024001d00209d3c940d89690b84d08a60993c144eca684d1081287c834d5311bcf32bb9da1a002b00020304");
+        let mut out = Vec::new();
+        initial_packet(
+            &mut out,
+            1,
+            &unhex(RFC_ODCID),
+            (&[], &unhex("f067a5502a4262b5")),
+            Role::Server,
+            &frames,
+            0,
+        );
+        assert_eq!(hex::encode(out), "cf000000010008f067a5502a4262b5004075c0d95a482cd0991cd25b0aac406a5816b6394100f37a1c69797554780bb38cc5a99f5ede4cf73c3ec2493a1839b3dbcba3f6ea46c5b7684df3548e7ddeb9c3bf9c73cc3f3bded74b562bfb19fb84022f8ef4cdd93795d77d06edbb7aaf2f58891850abbdca3d20398c276456cbc42158407dd074ee");
+    }
+
+    #[test]
+    fn client_initials_parse() {
+        let peer = Peer::new();
+        let mut datagram = Vec::new();
+        client_initial(&peer, &mut datagram);
+        assert!(datagram.len() >= MIN_INITIAL_DATAGRAM);
+        let header = LongHeader::parse(&datagram).unwrap();
+        assert_eq!(header.packet_type, INITIAL);
+        assert_eq!(header.dcid, &*peer.remote_id.lock());
+        assert_eq!(header.scid, &peer.local_id);
+        assert_eq!(header.payload.end, datagram.len());
+        // whatever the rest is, no prefix of it parses
+        for len in 0..datagram.len() {
+            assert!(LongHeader::parse(&datagram[..len]).is_none(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn varints_match_rfc_9000() {
+        // the examples of RFC 9000, Appendix A.1
+        for (hex, value) in [
+            ("c2197c5eff14e88c", 151288809941952652),
+            ("9d7f3e7d", 494878333),
+            ("7bbd", 15293),
+            ("25", 37),
+            ("4025", 37),
+        ] {
+            let bytes = unhex(hex);
+            let mut reader = Reader {
+                pkt: &bytes,
+                pos: 0,
+            };
+            assert_eq!(reader.varint(), Some(value));
+            assert_eq!(reader.pos, bytes.len());
+            for len in 0..bytes.len() {
+                let mut reader = Reader {
+                    pkt: &bytes[..len],
+                    pos: 0,
+                };
+                assert_eq!(reader.varint(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_survived() {
+        let mut reply = Vec::new();
+        for _ in 0..10000 {
+            let len = rand::random::<usize>() % 100;
+            let mut pkt: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            // long headers with our version get further
+            if len > 5 && rand::random() {
+                pkt[0] |= 0x80;
+                pkt[1..5].copy_from_slice(&VERSION);
+            }
+            for role in [Role::Client, Role::Server] {
+                let received = parse(&Peer::new(), role, &pkt, &mut reply);
+                if let Received::Data(range) = received {
+                    assert!(range.end <= pkt.len());
+                }
+            }
+        }
+    }
+}
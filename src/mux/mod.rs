@@ -1,12 +1,17 @@
-use crate::{buffer::Buff, runtime, Session};
-use smol::channel::{Receiver, Sender};
+use crate::{buffer::Buff, runtime, ClientConfig, ConnectError, Session};
+use smol::{
+    channel::{Receiver, Sender},
+    prelude::*,
+};
 use std::sync::Arc;
 mod congestion;
 mod multiplex_actor;
 pub mod pkt_trace;
+mod reconnect;
 mod relconn;
 mod structs;
 // pub use congestion::*;
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
 pub use relconn::RelConn;
 
 /// A multiplex session over a sosistab session, implementing both reliable "streams" and unreliable messages.
@@ -15,7 +20,9 @@ pub struct Multiplex {
     urel_recv: Receiver<Buff>,
     conn_open: Sender<(Option<String>, Sender<RelConn>)>,
     conn_accept: Receiver<RelConn>,
-    send_session: Sender<Session>,
+    send_session: Sender<(Session, bool)>,
+    recv_reconnect_event: Receiver<ReconnectEvent>,
+    _reconnect_task: Option<smol::Task<()>>,
     _task: smol::Task<()>,
 }
 
@@ -31,7 +38,7 @@ impl Multiplex {
         let (urel_recv_send, urel_recv) = smol::channel::bounded(256);
         let (conn_open, conn_open_recv) = smol::channel::unbounded();
         let (conn_accept_send, conn_accept) = smol::channel::bounded(100);
-        send_session.try_send(session).unwrap();
+        send_session.try_send((session, false)).unwrap();
         let _task = runtime::spawn(async move {
             let retval = multiplex_actor::multiplex(
                 recv_session,
@@ -49,10 +56,37 @@ impl Multiplex {
             conn_open,
             conn_accept,
             send_session,
+            recv_reconnect_event: reconnect::no_events(),
+            _reconnect_task: None,
             _task,
         }
     }
 
+    /// Connects to a server with the given ClientConfig, creating a multiplex that outlives the session. Whenever the session dies, a new one is connected following the given policy, and replaces it. [ReconnectEvent]s tell how that goes.
+    pub async fn connect_with(
+        cfg: ClientConfig,
+        policy: ReconnectPolicy,
+    ) -> Result<Self, ConnectError> {
+        let session = cfg.clone().connect().await?;
+        let wait_dead = session.wait_dead().boxed();
+        let mut mux = Self::new(session);
+        let (send_event, recv_event) = smol::channel::bounded(100);
+        mux.recv_reconnect_event = recv_event;
+        mux._reconnect_task = Some(runtime::spawn(reconnect::reconnect_loop(
+            reconnect::connector(cfg),
+            policy,
+            wait_dead,
+            mux.send_session.clone(),
+            send_event,
+        )));
+        Ok(mux)
+    }
+
+    /// Waits for the next [ReconnectEvent]. Fails right away for a multiplex not made with [Multiplex::connect_with], and once its reconnecting gave up.
+    pub async fn recv_reconnect_event(&self) -> std::io::Result<ReconnectEvent> {
+        self.recv_reconnect_event.recv().await.map_err(to_ioerror)
+    }
+
     /// Sends an unreliable message to the other side
     pub async fn send_urel(&self, msg: impl Into<Buff>) -> std::io::Result<()> {
         self.urel_send.send(msg.into()).await.map_err(to_ioerror)
@@ -74,7 +108,7 @@ impl Multiplex {
 
     /// Replaces the internal Session. This drops the previous Session, but this is not guaranteed to happen immediately.
     pub async fn replace_session(&self, sess: Session) {
-        let _ = self.send_session.try_send(sess);
+        let _ = self.send_session.try_send((sess, false));
     }
 
     /// Open a reliable conn to the other end.
@@ -7,7 +7,7 @@ use std::{ops::DerefMut, sync::Arc, time::Duration};
 use crate::{
     buffer::{Buff, BuffMut},
     mux::pkt_trace::PktTraceCtx,
-    runtime, safe_deserialize, RelConn, Session,
+    runtime, safe_deserialize, RelConn, Session, SessionEvent,
 };
 
 use super::{
@@ -16,7 +16,7 @@ use super::{
 };
 
 pub async fn multiplex(
-    recv_session: Receiver<Session>,
+    recv_session: Receiver<(Session, bool)>,
     urel_send_recv: Receiver<Buff>,
     urel_recv_send: Sender<Buff>,
     conn_open_recv: Receiver<(Option<String>, Sender<RelConn>)>,
@@ -41,13 +41,16 @@ pub async fn multiplex(
         }
     };
 
-    let mut session = recv_session.recv().await?;
+    let (mut session, _) = recv_session.recv().await?;
 
     // enum of possible events
     enum Event {
-        SessionReplace(Session),
+        /// A replacement session, and whether it is a brand-new one that the other side knows none of our streams over.
+        SessionReplace(Session, bool),
+        SessionEvent(SessionEvent),
         RecvMsg(Message),
-        SendMsg(Message),
+        /// A message to send, and whether it is a retransmission.
+        SendMsg(Message, bool),
         ConnOpen(Option<String>, Sender<RelConn>),
         Dead(u16),
     }
@@ -55,8 +58,13 @@ pub async fn multiplex(
     loop {
         // fires on session replacement
         let sess_replace = async {
-            let new_session = recv_session.recv().await?;
-            Ok::<_, anyhow::Error>(Event::SessionReplace(new_session))
+            let (new_session, fresh) = recv_session.recv().await?;
+            Ok::<_, anyhow::Error>(Event::SessionReplace(new_session, fresh))
+        };
+        // fires on out-of-band session events
+        let sess_event = async {
+            let evt = session.recv_event().await?;
+            Ok::<_, anyhow::Error>(Event::SessionEvent(evt))
         };
         // fires on receiving messages
         let recv_msg = async {
@@ -67,18 +75,18 @@ pub async fn multiplex(
             } else {
                 tracing::trace!("unrecognizable message from sess");
                 // In this case, we echo back an empty packet.
-                Ok(Event::SendMsg(Message::Empty))
+                Ok(Event::SendMsg(Message::Empty, false))
             }
         };
         // fires on sending urel
         let send_urel = async {
             let msg = urel_send_recv.recv().await?;
-            Ok(Event::SendMsg(Message::Urel(msg)))
+            Ok(Event::SendMsg(Message::Urel(msg), false))
         };
         // fires on sending messages
         let send_msg = async {
-            let to_send = glob_recv.recv().await?;
-            Ok::<_, anyhow::Error>(Event::SendMsg(to_send))
+            let (to_send, retransmission) = glob_recv.recv().await?;
+            Ok::<_, anyhow::Error>(Event::SendMsg(to_send, retransmission))
         };
         // fires on stream open events
         let conn_open = async {
@@ -92,10 +100,27 @@ pub async fn multiplex(
         };
         // match on the event
         match conn_open
-            .or(recv_msg.or(send_urel.or(send_msg.or(sess_replace.or(death)))))
+            .or(recv_msg.or(send_urel.or(send_msg.or(sess_replace.or(sess_event.or(death))))))
             .await?
         {
-            Event::SessionReplace(new_sess) => session = new_sess,
+            Event::SessionReplace(new_sess, fresh) => {
+                session = new_sess;
+                if fresh {
+                    // our streams would otherwise stall forever, since the other side has never heard of them
+                    tracing::warn!("replaced by a fresh session; resetting all streams");
+                    conn_tab.reset_all();
+                }
+            }
+            Event::SessionEvent(SessionEvent::RemoteReset) => {
+                // the other side forgot about all our streams, so we reset them rather than let them stall forever
+                tracing::warn!("remote reset; resetting all streams");
+                conn_tab.reset_all();
+            }
+            Event::SessionEvent(SessionEvent::RemoteClosed) => {
+                // nothing more will arrive over this session, so streams would otherwise stall until a replacement session comes along
+                tracing::warn!("remote closed the session; resetting all streams");
+                conn_tab.reset_all();
+            }
             Event::Dead(id) => conn_tab.del_stream(id),
             Event::ConnOpen(additional_data, result_chan) => {
                 let conn_tab = conn_tab.clone();
@@ -129,23 +154,31 @@ pub async fn multiplex(
                         }
                     };
                     tracing::trace!("conn open send {}", stream_id);
-                    let _ = glob_send.try_send(Message::Rel {
-                        kind: RelKind::Syn,
-                        stream_id,
-                        seqno: 0,
-                        payload: Buff::copy_from_slice(
-                            additional_data.clone().unwrap_or_default().as_bytes(),
-                        ),
-                    });
+                    let _ = glob_send.try_send((
+                        Message::Rel {
+                            kind: RelKind::Syn,
+                            stream_id,
+                            seqno: 0,
+                            payload: Buff::copy_from_slice(
+                                additional_data.clone().unwrap_or_default().as_bytes(),
+                            ),
+                        },
+                        false,
+                    ));
                 })
                 .detach();
             }
-            Event::SendMsg(msg) => {
+            Event::SendMsg(msg, retransmission) => {
                 trace_ctx.trace_pkt(&msg, true);
                 let mut to_send = BuffMut::new();
                 let r: &mut Vec<u8> = &mut to_send;
                 bincode::serialize_into(r, &msg).unwrap();
-                session.send_bytes(to_send.freeze()).await?;
+                // losing these stalls a whole stream for at least an RTO, so they are worth sending redundantly
+                if retransmission || msg.is_handshake() {
+                    session.send_bytes_important(to_send.freeze()).await?;
+                } else {
+                    session.send_bytes(to_send.freeze()).await?;
+                }
             }
             Event::RecvMsg(msg) => {
                 trace_ctx.trace_pkt(&msg, false);
@@ -174,7 +207,7 @@ pub async fn multiplex(
                             };
                             let mut bts = BuffMut::new();
                             bincode::serialize_into(bts.deref_mut(), &msg).unwrap();
-                            session.send_bytes(bts.freeze()).await?;
+                            session.send_bytes_important(bts.freeze()).await?;
                         } else {
                             tracing::trace!("syn recv {} ACCEPT", stream_id);
                             let lala = String::from_utf8_lossy(&payload).to_string();
@@ -242,6 +275,17 @@ impl ConnTable {
         self.sid_to_stream.remove(&id);
     }
 
+    fn reset_all(&self) {
+        for entry in self.sid_to_stream.iter() {
+            entry.value().process(Message::Rel {
+                kind: RelKind::Rst,
+                stream_id: *entry.key(),
+                seqno: 0,
+                payload: Buff::new(),
+            })
+        }
+    }
+
     fn find_id(&self) -> Option<u16> {
         if self.sid_to_stream.len() >= 65535 {
             tracing::warn!("ran out of descriptors ({})", self.sid_to_stream.len());
@@ -0,0 +1,270 @@
+use std::time::Duration;
+
+use smol::{
+    channel::{Receiver, Sender},
+    future::Boxed,
+    prelude::*,
+};
+
+use crate::{ClientConfig, ConnectError, Session};
+
+/// How a [Multiplex](super::Multiplex) made with [connect_with](super::Multiplex::connect_with) reconnects once its session dies.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// How long to wait after the first failed attempt to reconnect. This doubles after every further failure.
+    pub initial_backoff: Duration,
+    /// Longest wait between attempts to reconnect.
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts in a row. If None, reconnecting goes on forever. Every attempt is itself bounded by the `connect_timeout` and `max_attempts` of the [ClientConfig].
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Something that happened while keeping a [Multiplex](super::Multiplex) connected.
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// The session died, and this attempt to replace it, counting from 1, is starting.
+    Reconnecting { attempt: usize },
+    /// A new session replaced the dead one. Streams over the dead session were reset.
+    Reconnected,
+    /// Every attempt to reconnect failed, the last one for the given reason. The multiplex is left with its dead session.
+    GaveUp(ConnectError),
+}
+
+/// Connects a new session, as [ClientConfig::connect] does.
+pub(crate) type SessionConnector =
+    Box<dyn Fn() -> Boxed<Result<Session, ConnectError>> + Send + Sync + 'static>;
+
+/// Creates a connector of sessions following the given ClientConfig.
+pub(crate) fn connector(cfg: ClientConfig) -> SessionConnector {
+    Box::new(move || cfg.clone().connect().boxed())
+}
+
+/// Replaces the session of a multiplex whenever it dies, given a future that resolves once the first one does.
+pub(crate) async fn reconnect_loop(
+    connect: SessionConnector,
+    policy: ReconnectPolicy,
+    mut wait_dead: Boxed<()>,
+    send_session: Sender<(Session, bool)>,
+    send_event: Sender<ReconnectEvent>,
+) {
+    loop {
+        wait_dead.await;
+        tracing::warn!("session died; reconnecting");
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 0;
+        let session = loop {
+            attempt += 1;
+            let _ = send_event.try_send(ReconnectEvent::Reconnecting { attempt });
+            match connect().await {
+                Ok(session) => break session,
+                Err(err) => {
+                    tracing::warn!("reconnect attempt {} failed: {}", attempt, err);
+                    if policy
+                        .max_attempts
+                        .map(|max_attempts| attempt >= max_attempts)
+                        .unwrap_or_default()
+                    {
+                        let _ = send_event.try_send(ReconnectEvent::GaveUp(err));
+                        return;
+                    }
+                    smol::Timer::after(backoff).await;
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                }
+            }
+        };
+        wait_dead = session.wait_dead().boxed();
+        if send_session.send((session, true)).await.is_err() {
+            return;
+        }
+        let _ = send_event.try_send(ReconnectEvent::Reconnected);
+    }
+}
+
+/// A receiver of [ReconnectEvent]s that is closed from the start, for multiplexes that do not reconnect.
+pub(crate) fn no_events() -> Receiver<ReconnectEvent> {
+    smol::channel::bounded(1).1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use crate::{Role, SessionBack, SessionConfig};
+
+    use super::*;
+
+    /// Scripted reconnecting: every call to the connector takes the next result, recording when it was made. Successes are new sessions, whose backs are kept so they can be declared dead.
+    struct Script {
+        results: Mutex<std::collections::VecDeque<bool>>,
+        calls: Mutex<Vec<Instant>>,
+        backs: Mutex<Vec<SessionBack>>,
+    }
+
+    impl Script {
+        fn new(results: &[bool]) -> Arc<Self> {
+            Arc::new(Self {
+                results: Mutex::new(results.iter().copied().collect()),
+                calls: Mutex::new(Vec::new()),
+                backs: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn connector(self: &Arc<Self>) -> SessionConnector {
+            let this = self.clone();
+            Box::new(move || {
+                this.calls.lock().unwrap().push(Instant::now());
+                let res = if this.results.lock().unwrap().pop_front().unwrap_or(false) {
+                    let (session, back) = Session::new(SessionConfig {
+                        version: 4,
+                        session_key: vec![0; 32],
+                        role: Role::Client,
+                        gather: Default::default(),
+                        shape: Default::default(),
+                    });
+                    this.backs.lock().unwrap().push(back);
+                    Ok(session)
+                } else {
+                    Err(ConnectError::NoResponse)
+                };
+                async move { res }.boxed()
+            })
+        }
+
+        /// Gaps between successive calls to the connector.
+        fn gaps(&self) -> Vec<Duration> {
+            let calls = self.calls.lock().unwrap();
+            calls.windows(2).map(|w| w[1] - w[0]).collect()
+        }
+    }
+
+    fn policy(max_attempts: Option<usize>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(400),
+            max_attempts,
+        }
+    }
+
+    /// Runs the loop over a first session that is already dead, returning the channels it reports to.
+    fn start(
+        script: &Arc<Script>,
+        policy: ReconnectPolicy,
+    ) -> (
+        smol::Task<()>,
+        Receiver<(Session, bool)>,
+        Receiver<ReconnectEvent>,
+    ) {
+        let (send_session, recv_session) = smol::channel::unbounded();
+        let (send_event, recv_event) = smol::channel::unbounded();
+        let task = smol::spawn(reconnect_loop(
+            script.connector(),
+            policy,
+            async {}.boxed(),
+            send_session,
+            send_event,
+        ));
+        (task, recv_session, recv_event)
+    }
+
+    /// Whether the gaps are the given backoffs, allowing for timer lateness.
+    fn backs_off(gaps: &[Duration], expected: &[u64]) -> bool {
+        gaps.len() == expected.len()
+            && gaps.iter().zip(expected).all(|(gap, ms)| {
+                *gap >= Duration::from_millis(*ms) && *gap < Duration::from_millis(*ms + 90)
+            })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_then_gives_up() {
+        smol::block_on(async {
+            let script = Script::new(&[]);
+            let (task, _sessions, events) = start(&script, policy(Some(5)));
+            task.await;
+            for attempt in 1..=5 {
+                assert!(matches!(
+                    events.recv().await.unwrap(),
+                    ReconnectEvent::Reconnecting { attempt: a } if a == attempt
+                ));
+            }
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::GaveUp(ConnectError::NoResponse)
+            ));
+            assert!(events.recv().await.is_err());
+            let gaps = script.gaps();
+            assert!(backs_off(&gaps, &[100, 200, 400, 400]), "{:?}", gaps);
+        })
+    }
+
+    #[test]
+    fn sessions_are_handed_over_before_reconnected() {
+        smol::block_on(async {
+            let script = Script::new(&[false, false, true]);
+            let (_task, sessions, events) = start(&script, policy(None));
+            for attempt in 1..=3 {
+                assert!(matches!(
+                    events.recv().await.unwrap(),
+                    ReconnectEvent::Reconnecting { attempt: a } if a == attempt
+                ));
+            }
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::Reconnected
+            ));
+            // the new session must already be with the multiplex
+            let (_session, replaced) = sessions.try_recv().unwrap();
+            assert!(replaced);
+        })
+    }
+
+    #[test]
+    fn new_sessions_start_over() {
+        smol::block_on(async {
+            let script = Script::new(&[false, false, true, false, true]);
+            let (_task, sessions, events) = start(&script, policy(None));
+            let _first = sessions.recv().await.unwrap();
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::Reconnecting { attempt: 1 }
+            ));
+            while !matches!(events.recv().await.unwrap(), ReconnectEvent::Reconnected) {}
+            // nothing happens while the new session lives
+            smol::Timer::after(Duration::from_millis(500)).await;
+            assert_eq!(script.calls.lock().unwrap().len(), 3);
+            script.backs.lock().unwrap()[0].declare_dead();
+            // counting and backing off start over
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::Reconnecting { attempt: 1 }
+            ));
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::Reconnecting { attempt: 2 }
+            ));
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                ReconnectEvent::Reconnected
+            ));
+            let _second = sessions.recv().await.unwrap();
+            let gaps = script.gaps();
+            assert!(
+                backs_off(&[gaps[0], gaps[1], gaps[3]], &[100, 200, 100]),
+                "{:?}",
+                gaps
+            );
+        })
+    }
+}
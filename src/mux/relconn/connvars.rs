@@ -96,6 +96,7 @@ impl ConnVars {
         send_read: &mut BipeWriter,
         recv_wire_read: &Receiver<Message>,
         transmit: impl Fn(Message),
+        retransmit: impl Fn(Message),
     ) -> anyhow::Result<()> {
         assert_eq!(self.inflight.lost_count(), self.lost_seqnos.len());
         match self.next_event(recv_write, recv_wire_read).await {
@@ -109,7 +110,7 @@ impl ConnVars {
                     //     self.cc.cwnd(),
                     //     self.inflight.lost_count(),
                     // );
-                    transmit(msg);
+                    retransmit(msg);
                 }
                 assert_eq!(self.inflight.lost_count(), self.lost_seqnos.len());
                 Ok(())
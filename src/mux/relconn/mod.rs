@@ -25,7 +25,7 @@ pub struct RelConn {
 impl RelConn {
     pub(crate) fn new(
         state: RelConnState,
-        output: Sender<Message>,
+        output: Sender<(Message, bool)>,
         dropper: impl FnOnce() + Send + 'static,
         additional_info: Option<String>,
     ) -> (Self, RelConnBack) {
@@ -131,13 +131,17 @@ async fn relconn_actor(
     mut recv_write: BipeReader,
     mut send_read: BipeWriter,
     recv_wire_read: Receiver<Message>,
-    send_wire_write: Sender<Message>,
+    send_wire_write: Sender<(Message, bool)>,
     additional_info: Option<String>,
     dropper: impl FnOnce(),
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| dropper());
+    // outgoing messages are tagged with whether they are retransmissions
     let transmit = |msg| {
-        let _ = send_wire_write.try_send(msg);
+        let _ = send_wire_write.try_send((msg, false));
+    };
+    let retransmit = |msg| {
+        let _ = send_wire_write.try_send((msg, true));
     };
     loop {
         state = match state {
@@ -217,6 +221,7 @@ async fn relconn_actor(
                         &mut send_read,
                         &recv_wire_read,
                         transmit,
+                        retransmit,
                     )
                     .await
                 {
@@ -18,6 +18,23 @@ pub enum Message {
     Empty,
 }
 
+impl Message {
+    /// Whether this message opens, closes, or resets a stream.
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            Message::Rel {
+                kind: RelKind::Syn
+                    | RelKind::SynAck
+                    | RelKind::Fin
+                    | RelKind::FinAck
+                    | RelKind::Rst,
+                ..
+            }
+        )
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum RelKind {
     Syn,
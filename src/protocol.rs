@@ -1,10 +1,12 @@
-use std::ops::DerefMut;
+use std::{net::SocketAddr, ops::DerefMut};
 
 use bincode::Options;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::buffer::{Buff, BuffMut};
+use crate::{
+    buffer::{Buff, BuffMut},
+    PaddingPolicy,
+};
 
 /// Frame sent as a session-negotiation message. This is always encrypted with the cookie.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -30,6 +32,62 @@ pub enum HandshakeFrame {
         /// Which shard is this
         shard_id: u8,
     },
+
+    /// Frame sent from server to client in response to every ClientResume. This is encrypted with the cookie.
+    ServerResume {
+        /// Which shard is this
+        shard_id: u8,
+        /// Random identifier of the server-side session state. This changes iff the server lost the session and rebuilt it from the resume token.
+        incarnation: u64,
+        /// Whether the ClientResume resumed a session the server already had, rather than creating a brand-new one.
+        resumed: bool,
+    },
+
+    /// Hybrid post-quantum version of ClientHello, used from version 5 onwards. This is always globally encrypted.
+    ClientHelloHybrid {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        version: u64,
+        /// ML-KEM-512 encapsulation key.
+        kem_ek: Buff,
+    },
+    /// Hybrid post-quantum version of ServerHello, sent in response to ClientHelloHybrid.
+    ServerHelloHybrid {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        resume_token: Buff,
+        /// ML-KEM-512 ciphertext encapsulated to the client's key. Its shared secret is mixed into the session key.
+        kem_ct: Buff,
+    },
+
+    /// Version of ClientResume used from version 4 onwards, which proves that the client holds the ephemeral key its resume token is bound to. This is globally encrypted.
+    ClientResumeBound {
+        resume_token: Buff,
+        /// Which shard is this
+        shard_id: u8,
+        /// When the client made the frame, in milliseconds since the Unix epoch. Servers refuse frames that are too old, or that they have seen before.
+        time_ms: u64,
+        /// Keyed hash of the token, shard ID and time, under a key derived from the client's ephemeral key.
+        proof: [u8; 32],
+    },
+
+    /// Frame sent from server to client, alongside a ServerResume, to replace an aging resume token. This is encrypted with the cookie.
+    NewResumeToken { resume_token: Buff },
+
+    /// Frame sent from server to client, alongside a ServerResume, when a shard of an existing session resumes from a new address. The shard only moves there once the client answers with a PathResponse. This is encrypted with the cookie.
+    PathChallenge { challenge: [u8; 32] },
+
+    /// Frame sent from client to server to answer a PathChallenge, proving that whoever is at the new address holds the session key. This is globally encrypted.
+    PathResponse {
+        /// Keyed hash of the challenge, under a key derived from the session key.
+        response: [u8; 32],
+    },
+
+    /// Frame sent from server to client in response to a hello of a version the server does not accept, so that the client can give up instead of retrying. This is encrypted with the cookie.
+    ServerRejectVersion {
+        /// Versions the server does accept.
+        supported: Vec<u64>,
+    },
 }
 
 impl HandshakeFrame {
@@ -67,22 +125,34 @@ pub enum DataFrameV2 {
         pad_size: usize,
         body: Buff,
     },
+    /// Tells the other side that the session is going away, for example because the server is shutting down.
+    Close {
+        /// Counter shared with data frames, so that closes cannot be replayed.
+        frame_no: u64,
+    },
+    /// Asks the client to move the session to another address of the same server, where the server uses the given long-term public key.
+    Migrate {
+        frame_no: u64,
+        addr: SocketAddr,
+        server_pk: x25519_dalek::PublicKey,
+    },
+    /// Carries nothing, and is only sent as cover traffic.
+    Cover,
 }
 
 impl DataFrameV2 {
-    /// Pads the frame to prepare for encryption.
-    pub fn pad(&self, hidden_data: u8) -> Buff {
+    /// Pads the frame as the given policy says, to prepare for encryption. Returns the padded frame and how much padding it got.
+    pub fn pad(&self, hidden_data: u8, padding: &PaddingPolicy) -> (Buff, usize) {
         let options = bincode::DefaultOptions::new()
             .with_little_endian()
             .with_varint_encoding()
             .allow_trailing_bytes();
-        // TODO: padding
         let mut toret = BuffMut::new();
         options.serialize_into(toret.deref_mut(), self).unwrap();
         toret.extend_from_slice(&[hidden_data]);
-        let padd_amount = rand::thread_rng().gen_range(0, 10) + (32 - toret.len() % 32);
+        let padd_amount = padding.padding_for(toret.len());
         toret.extend_from_slice(&vec![0xff; padd_amount]);
-        toret.into()
+        (toret.into(), padd_amount)
     }
 
     /// Depads a decrypted frame.
@@ -1,4 +1,5 @@
 use once_cell::sync::OnceCell;
+use smol::net::TcpStream;
 use smol::prelude::*;
 use smol::Async;
 use smol::Executor;
@@ -8,6 +9,12 @@ use std::{
     net::{SocketAddr, UdpSocket},
 };
 
+/// Default receive buffer size of UDP sockets.
+pub(crate) const UDP_RECV_BUFFER: usize = 10 * 1024 * 1024;
+
+/// Default send buffer size of UDP sockets.
+pub(crate) const UDP_SEND_BUFFER: usize = 512 * 1024;
+
 static USER_EXEC: OnceCell<&'static Executor> = OnceCell::new();
 
 // /// Sets the sosistab executor. If not set, smolscale will be used.
@@ -28,24 +35,72 @@ pub(crate) fn spawn<T: Send + 'static>(
 
 /// Create a new UDP socket that has a largeish buffer and isn't bound to anything.
 pub(crate) fn new_udp_socket_bind(addr: SocketAddr) -> std::io::Result<Async<UdpSocket>> {
+    let socket = new_udp_socket(addr, UDP_RECV_BUFFER, UDP_SEND_BUFFER);
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket().try_into().unwrap())
+}
+
+/// Connects to a TCP address from the given local address, which is typically that of a particular network interface.
+pub(crate) async fn connect_tcp_from(
+    local: SocketAddr,
+    remote: SocketAddr,
+) -> std::io::Result<TcpStream> {
     let socket = Socket::new(
-        match addr {
+        match remote {
             SocketAddr::V4(_) => Domain::ipv4(),
             SocketAddr::V6(_) => Domain::ipv6(),
         },
-        Type::dgram(),
+        Type::stream(),
         None,
-    )
-    .unwrap();
-    drop(socket.set_only_v6(false));
-    let _ = socket.set_recv_buffer_size(10 * 1024 * 1024);
-    let _ = socket.set_send_buffer_size(512 * 1024);
+    )?;
+    socket.bind(&local.into())?;
+    // a non-blocking connect only starts connecting, and the socket becomes writable once the connection either succeeds or fails
+    socket.set_nonblocking(true)?;
+    match socket.connect(&remote.into()) {
+        Ok(()) => {}
+        Err(err) if connect_in_progress(&err) => {}
+        Err(err) => return Err(err),
+    }
+    let stream = Async::new(socket.into_tcp_stream())?;
+    stream.writable().await?;
+    if let Some(err) = stream.get_ref().take_error()? {
+        return Err(err);
+    }
+    Ok(stream.into())
+}
+
+/// Whether the error from a non-blocking connect only means that connecting has started.
+fn connect_in_progress(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    return err.raw_os_error() == Some(libc::EINPROGRESS);
+    #[cfg(not(unix))]
+    return err.kind() == std::io::ErrorKind::WouldBlock;
+}
+
+/// Create a new UDP socket with the given buffer sizes, bound to the given address. With `reuse_port`, several sockets can share the same address through `SO_REUSEPORT`, which is only supported on Unix.
+pub(crate) fn new_udp_socket_bind_sync(
+    addr: SocketAddr,
+    reuse_port: bool,
+    recv_buffer: usize,
+    send_buffer: usize,
+) -> std::io::Result<UdpSocket> {
+    let socket = new_udp_socket(addr, recv_buffer, send_buffer);
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
     socket.bind(&addr.into())?;
-    Ok(socket.into_udp_socket().try_into().unwrap())
+    Ok(socket.into_udp_socket())
 }
 
-/// Create a new UDP socket that has a largeish buffer and isn't bound to anything.
-pub(crate) fn new_udp_socket_bind_sync(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+fn new_udp_socket(addr: SocketAddr, recv_buffer: usize, send_buffer: usize) -> Socket {
     let socket = Socket::new(
         match addr {
             SocketAddr::V4(_) => Domain::ipv4(),
@@ -56,8 +111,30 @@ pub(crate) fn new_udp_socket_bind_sync(addr: SocketAddr) -> std::io::Result<UdpS
     )
     .unwrap();
     drop(socket.set_only_v6(false));
-    let _ = socket.set_recv_buffer_size(10 * 1024 * 1024);
-    let _ = socket.set_send_buffer_size(512 * 1024);
-    socket.bind(&addr.into())?;
-    Ok(socket.into_udp_socket())
+    let _ = socket.set_recv_buffer_size(recv_buffer);
+    let _ = socket.set_send_buffer_size(send_buffer);
+    socket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_connects_from_a_local_address() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let remote = listener.local_addr().unwrap();
+            let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let (stream, accepted) =
+                smol::future::zip(connect_tcp_from(local, remote), listener.accept()).await;
+            let stream = stream.unwrap();
+            let (_, peer) = accepted.unwrap();
+            assert_eq!(stream.local_addr().unwrap(), peer);
+            // nothing listens here any more, so connecting fails rather than hangs
+            drop(listener);
+            let err = connect_tcp_from(local, remote).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        })
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -28,6 +29,9 @@ pub(crate) struct RecvMachine {
     recv_crypt: NgAead,
     replay_filter: ReplayFilter,
     ping_calc: Arc<StatsCalculator>,
+    closed: bool,
+    remote_closed: bool,
+    migration_hint: Option<(SocketAddr, x25519_dalek::PublicKey)>,
 }
 
 static TOTAL_MACHINES: AtomicUsize = AtomicUsize::new(0);
@@ -47,7 +51,7 @@ impl RecvMachine {
         direction: Role,
     ) -> Self {
         let count = TOTAL_MACHINES.fetch_add(1, Ordering::Relaxed);
-        eprintln!("***** {count} RecvMachines *****");
+        tracing::debug!("{} receive machines alive", count + 1);
         let recv_crypt_key = match direction {
             Role::Server => blake3::keyed_hash(crate::crypt::UP_KEY, session_key),
             Role::Client => blake3::keyed_hash(crate::crypt::DN_KEY, session_key),
@@ -60,9 +64,19 @@ impl RecvMachine {
             recv_crypt,
             replay_filter: ReplayFilter::default(),
             ping_calc: calculator,
+            closed: false,
+            remote_closed: false,
+            migration_hint: None,
         }
     }
 
+    /// Forgets everything learned about the frames sent by the other side. This must be called when the other side restarts its frame numbering.
+    pub fn reset(&mut self) {
+        self.oob_decoder = OobDecoder::new();
+        self.replay_filter = ReplayFilter::default();
+        *self.rloss.lock() = RecvLossCalc::new(1.0);
+    }
+
     /// Processes a single frame. If successfully decoded, return the inner data.
     pub fn process(&mut self, packet: &[u8]) -> Result<Option<SVec<(Buff, u64)>>, AeadError> {
         self.process_ng(packet)
@@ -132,9 +146,41 @@ impl RecvMachine {
                     Ok(None)
                 }
             }
+            Some((DataFrameV2::Close { frame_no }, _)) => {
+                // closes are sent several times, but only reported once
+                if self.replay_filter.add(frame_no) && !self.closed {
+                    self.closed = true;
+                    self.remote_closed = true;
+                }
+                Ok(None)
+            }
+            Some((
+                DataFrameV2::Migrate {
+                    frame_no,
+                    addr,
+                    server_pk,
+                },
+                _,
+            )) => {
+                if self.replay_filter.add(frame_no) {
+                    self.migration_hint = Some((addr, server_pk));
+                }
+                Ok(None)
+            }
+            Some((DataFrameV2::Cover, _)) => Ok(None),
             None => Ok(None),
         }
     }
+
+    /// Takes the latest address the other side asked us to migrate to, if any.
+    pub fn take_migration_hint(&mut self) -> Option<(SocketAddr, x25519_dalek::PublicKey)> {
+        self.migration_hint.take()
+    }
+
+    /// Returns whether the other side closed the session since the last call.
+    pub fn take_remote_closed(&mut self) -> bool {
+        std::mem::take(&mut self.remote_closed)
+    }
 }
 
 /// A filter for replays. Records recently seen seqnos and rejects either repeats or really old seqnos.
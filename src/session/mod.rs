@@ -10,17 +10,20 @@ use smol::prelude::*;
 use stats::StatsCalculator;
 
 use std::{
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
 mod machine;
 mod rloss;
+mod shape;
 mod stats;
+pub use shape::{PaddingPolicy, TrafficShape};
 
 #[derive(Debug, Clone)]
 pub(crate) struct SessionConfig {
@@ -28,6 +31,7 @@ pub(crate) struct SessionConfig {
     pub session_key: Vec<u8>,
     pub role: Role,
     pub gather: Arc<StatsGatherer>,
+    pub shape: TrafficShape,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -36,6 +40,15 @@ pub(crate) enum Role {
     Client,
 }
 
+/// An out-of-band event concerning a [Session], as opposed to the datagrams carried by it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The other side lost all state associated with this session and rebuilt it from scratch, for example because the server restarted. The session itself keeps working, but any higher-level state carried over it (such as [Multiplex] streams) is gone.
+    RemoteReset,
+    /// The other side announced that it is closing the session, for example because the server is shutting down. Nothing more will arrive, so the session should be dropped.
+    RemoteClosed,
+}
+
 #[derive(Error, Debug)]
 pub enum SessionError {
     #[error("session dropped")]
@@ -46,8 +59,11 @@ pub enum SessionError {
 ///
 /// [Session] should be used directly only if an unreliable connection is all you need. For most applications, use [Multiplex](crate::mux::Multiplex), which wraps a [Session] and provides QUIC-like reliable streams as well as unreliable messages, all multiplexed over a single [Session].
 pub struct Session {
-    send_tosend: Sender<Buff>,
+    send_tosend: Sender<(Buff, bool)>,
     recv_decoded: Receiver<Buff>,
+    recv_event: Receiver<SessionEvent>,
+    /// Closed once the session is declared dead.
+    recv_dead: Receiver<()>,
     statistics: Arc<StatsGatherer>,
     dropper: Vec<Box<dyn FnOnce() + Send + Sync + 'static>>,
     _task: smol::Task<()>,
@@ -68,7 +84,7 @@ impl Session {
     /// Creates a Session.
     pub(crate) fn new(cfg: SessionConfig) -> (Self, SessionBack) {
         let count = TOTAL_SESSIONS.fetch_add(1, Ordering::Relaxed);
-        eprintln!("***** {count} Sessions *****");
+        tracing::debug!("{} sessions alive", count + 1);
         let (send_tosend, recv_tosend) = smol::channel::bounded(256);
         let gather = cfg.gather.clone();
         let calculator = Arc::new(StatsCalculator::new(gather.clone()));
@@ -82,13 +98,20 @@ impl Session {
 
         let (send_decoded, recv_decoded) = smol::channel::bounded(256);
         let (send_outgoing, recv_outgoing) = smol::channel::bounded(256);
+        let (send_event, recv_event) = smol::channel::unbounded();
+        let (send_control, recv_control) = smol::channel::bounded(16);
+        let (send_dead, recv_dead) = smol::channel::bounded(1);
         let session_back = SessionBack {
             machine,
             send_decoded,
             recv_outgoing,
+            send_event,
+            remote_incarnation: Mutex::new(None),
+            send_control,
+            send_dead: Mutex::new(Some(send_dead)),
         };
         let count = TOTAL_BACKS.fetch_add(1, Ordering::Relaxed);
-        eprintln!("***** {count} SessionBacks *****");
+        tracing::debug!("{} session backs alive", count + 1);
         let send_crypt_key = match cfg.role {
             Role::Server => blake3::keyed_hash(crate::crypt::DN_KEY, &cfg.session_key),
             Role::Client => blake3::keyed_hash(crate::crypt::UP_KEY, &cfg.session_key),
@@ -100,6 +123,7 @@ impl Session {
             gather: gather.clone(),
             rloss,
             recv_tosend,
+            recv_control,
             send_crypt,
             send_outgoing,
         };
@@ -107,6 +131,8 @@ impl Session {
         let session = Session {
             send_tosend,
             recv_decoded,
+            recv_event,
+            recv_dead,
             statistics: gather,
             dropper: Vec::new(),
             _task: task,
@@ -121,10 +147,18 @@ impl Session {
 
     /// Takes a [Buff] to be sent and stuffs it into the session.
     pub async fn send_bytes(&self, to_send: impl Into<Buff>) -> Result<(), SessionError> {
-        let to_send: Buff = to_send.into();
+        self.send_bytes_inner(to_send.into(), false)
+    }
+
+    /// Like [Session::send_bytes], but for datagrams that are worth extra bandwidth to get through, such as connection handshakes and retransmissions. Clients send these over more than one shard at once.
+    pub async fn send_bytes_important(&self, to_send: impl Into<Buff>) -> Result<(), SessionError> {
+        self.send_bytes_inner(to_send.into(), true)
+    }
+
+    fn send_bytes_inner(&self, to_send: Buff, important: bool) -> Result<(), SessionError> {
         self.statistics
             .increment("total_sent_bytes", to_send.len() as f32);
-        if let Err(TrySendError::Closed(_)) = self.send_tosend.try_send(to_send) {
+        if let Err(TrySendError::Closed(_)) = self.send_tosend.try_send((to_send, important)) {
             Err(SessionError::SessionDropped)
         } else {
             Ok(())
@@ -143,19 +177,55 @@ impl Session {
         Ok(recv)
     }
 
+    /// Waits until the next [SessionEvent] happens.
+    pub async fn recv_event(&self) -> Result<SessionEvent, SessionError> {
+        self.recv_event
+            .recv()
+            .await
+            .map_err(|_| SessionError::SessionDropped)
+    }
+
+    /// Returns a future that resolves once the session is dead: the other side closed it, or, for client sessions, nothing sent has gotten through for a long while. The future does not borrow the session, so it can be awaited after handing the session over to a [Multiplex].
+    pub fn wait_dead(&self) -> impl Future<Output = ()> + Send + 'static {
+        let recv_dead = self.recv_dead.clone();
+        async move {
+            let _ = recv_dead.recv().await;
+        }
+    }
+
+    /// Gets the statistics gatherer of this session.
+    pub fn stats(&self) -> Arc<StatsGatherer> {
+        self.statistics.clone()
+    }
+
     /// "Upgrades" this session into a [Multiplex]
     pub fn multiplex(self) -> Multiplex {
         Multiplex::new(self)
     }
 }
 
+/// Out-of-band messages a [SessionBack] asks its session to send.
+#[derive(Debug, Clone, Copy)]
+enum SessionControl {
+    Close,
+    Migrate {
+        addr: SocketAddr,
+        server_pk: x25519_dalek::PublicKey,
+    },
+}
+
 static TOTAL_BACKS: AtomicUsize = AtomicUsize::new(0);
 
 /// "Back side" of a Session.
 pub(crate) struct SessionBack {
     machine: Mutex<RecvMachine>,
     send_decoded: Sender<Buff>,
-    recv_outgoing: Receiver<Buff>,
+    recv_outgoing: Receiver<(Buff, bool)>,
+    send_event: Sender<SessionEvent>,
+    remote_incarnation: Mutex<Option<u64>>,
+    send_control: Sender<SessionControl>,
+    /// Dropped to declare the session dead.
+    send_dead: Mutex<Option<Sender<()>>>,
 }
 
 impl Drop for SessionBack {
@@ -165,19 +235,72 @@ impl Drop for SessionBack {
 }
 
 impl SessionBack {
-    /// Given an incoming raw packet, injects it into the sessionback. If decryption fails, returns an error.
-    pub fn inject_incoming(&self, pkt: &[u8]) -> Result<(), AeadError> {
-        let decoded = self.machine.lock().process(pkt)?;
+    /// Given an incoming raw packet, injects it into the sessionback. If decryption fails, returns an error. Otherwise, returns whether the packet delivered data that had not been seen before, which a replayed packet never does.
+    pub fn inject_incoming(&self, pkt: &[u8]) -> Result<bool, AeadError> {
+        let mut machine = self.machine.lock();
+        let decoded = machine.process(pkt)?;
+        let fresh = decoded.is_some();
         if let Some(decoded) = decoded {
             for decoded in decoded {
                 let _ = self.send_decoded.try_send(decoded.0);
             }
         }
-        Ok(())
+        if machine.take_remote_closed() {
+            tracing::debug!("remote closed the session");
+            let _ = self.send_event.try_send(SessionEvent::RemoteClosed);
+            self.declare_dead();
+        }
+        Ok(fresh)
+    }
+
+    /// Declares the session dead, waking up whatever waits in [Session::wait_dead].
+    pub fn declare_dead(&self) {
+        if self.send_dead.lock().take().is_some() {
+            tracing::debug!("session declared dead");
+        }
+    }
+
+    /// Tells the other side that the session is being closed.
+    pub fn send_close(&self) {
+        let _ = self.send_control.try_send(SessionControl::Close);
+    }
+
+    /// Asks the other side to migrate the session to another address, where the server uses the given long-term public key.
+    pub fn send_migrate(&self, addr: SocketAddr, server_pk: x25519_dalek::PublicKey) {
+        let _ = self
+            .send_control
+            .try_send(SessionControl::Migrate { addr, server_pk });
+    }
+
+    /// Takes the latest address the other side asked us to migrate to, if any.
+    pub fn take_migration_hint(&self) -> Option<(SocketAddr, x25519_dalek::PublicKey)> {
+        self.machine.lock().take_migration_hint()
+    }
+
+    /// Gets the incarnation of the remote session state, if known.
+    pub fn remote_incarnation(&self) -> Option<u64> {
+        *self.remote_incarnation.lock()
+    }
+
+    /// Records the incarnation of the remote session state, as reported by the other side. If it changed, the other side must have rebuilt the session from scratch, so we reset our receiving state to match and signal a [SessionEvent::RemoteReset].
+    pub fn observe_remote_incarnation(&self, incarnation: u64) {
+        let mut remote_incarnation = self.remote_incarnation.lock();
+        match remote_incarnation.replace(incarnation) {
+            Some(old) if old != incarnation => {
+                tracing::warn!(
+                    "remote session state reset ({:x} => {:x})",
+                    old,
+                    incarnation
+                );
+                self.machine.lock().reset();
+                let _ = self.send_event.try_send(SessionEvent::RemoteReset);
+            }
+            _ => {}
+        }
     }
 
-    /// Wait for an outgoing packet from the session.
-    pub async fn next_outgoing(&self) -> Result<Buff, SessionError> {
+    /// Wait for an outgoing packet from the session, together with whether it is important enough to be sent redundantly.
+    pub async fn next_outgoing(&self) -> Result<(Buff, bool), SessionError> {
         self.recv_outgoing
             .recv()
             .await
@@ -191,9 +314,10 @@ struct SessionSendCtx {
     statg: Arc<StatsCalculator>,
     gather: Arc<StatsGatherer>,
     rloss: Arc<Mutex<RecvLossCalc>>,
-    recv_tosend: Receiver<Buff>,
+    recv_tosend: Receiver<(Buff, bool)>,
+    recv_control: Receiver<SessionControl>,
     send_crypt: NgAead,
-    send_outgoing: Sender<Buff>,
+    send_outgoing: Sender<(Buff, bool)>,
 }
 
 // #[tracing::instrument(skip(ctx))]
@@ -215,8 +339,10 @@ static SOSISTAB_NO_FEC: Lazy<bool> = Lazy::new(|| std::env::var("SOSISTAB_NO_FEC
 async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<()> {
     // let mut pacer = Pacer::new(Duration::from_millis(1) / 30);
     enum Event {
-        NewPayload(Buff),
+        NewPayload(Buff, bool),
         FecTimeout,
+        Control(SessionControl),
+        Cover,
     }
 
     const FEC_TIMEOUT_MS: u64 = 20;
@@ -227,6 +353,18 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
     let mut unfecked: Vec<(u64, Buff)> = Vec::new();
     let mut fec_encoder = FrameEncoder::new(10); // around 4 percent
     let mut frame_no = 0;
+    let shape = ctx.cfg.shape.clone();
+    let mut sender = FrameSender::new(
+        shape.clone(),
+        ctx.send_crypt.clone(),
+        ctx.gather.clone(),
+        ctx.send_outgoing.clone(),
+    );
+    // cover frames are only sent while nothing else is
+    let mut cover_timer = shape
+        .next_cover_delay()
+        .map(smol::Timer::after)
+        .unwrap_or_else(smol::Timer::never);
     loop {
         // either we have something new to send, or the FEC timer expired.
         let event: Option<Event> = async {
@@ -239,24 +377,34 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
             }
             Some(Event::FecTimeout)
         }
-        .or(async { Some(Event::NewPayload(ctx.recv_tosend.recv().await.ok()?)) })
+        .or(async {
+            let (payload, important) = ctx.recv_tosend.recv().await.ok()?;
+            Some(Event::NewPayload(payload, important))
+        })
+        .or(async { Some(Event::Control(ctx.recv_control.recv().await.ok()?)) })
+        .or(async {
+            (&mut cover_timer).await;
+            Some(Event::Cover)
+        })
         .await;
         let loss = ctx.rloss.lock().calculate_loss();
         let loss_u8 = (loss * 254.0) as u8;
         ctx.gather.update("recv_loss", loss as f32);
-        match event? {
+        let event = event?;
+        if let Some(delay) = shape.next_cover_delay() {
+            cover_timer.set_after(delay);
+        }
+        match event {
             // we have something to send as a data packet.
-            Event::NewPayload(send_payload) => {
+            Event::NewPayload(send_payload, important) => {
                 let send_framed = DataFrameV2::Data {
                     frame_no,
                     high_recv_frame_no: ctx.statg.high_recv_frame_no(),
                     total_recv_frames: ctx.statg.total_recv_frames(),
                     body: send_payload.clone(),
                 };
-                let send_padded = send_framed.pad(loss_u8);
                 ctx.statg.ping_send(frame_no);
-                let send_encrypted = ctx.send_crypt.encrypt(&send_padded);
-                ctx.send_outgoing.send(send_encrypted).await.ok()?;
+                sender.send(&send_framed, loss_u8, important).await?;
                 // we now add to unfecked
                 unfecked.push((frame_no, send_payload));
                 // increment frame no
@@ -265,6 +413,29 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                 fec_timer.set_after(Duration::from_millis(FEC_TIMEOUT_MS));
                 // pacer.wait_next().await;
             }
+            // control frames are sent a few times, since they may get lost.
+            Event::Control(control) => {
+                for _ in 0..3 {
+                    let send_framed = match control {
+                        SessionControl::Close => DataFrameV2::Close { frame_no },
+                        SessionControl::Migrate { addr, server_pk } => DataFrameV2::Migrate {
+                            frame_no,
+                            addr,
+                            server_pk,
+                        },
+                    };
+                    frame_no += 1;
+                    if !sender.try_send(&send_framed, loss_u8, true) {
+                        tracing::warn!("dropping control frame due to backpressure");
+                    }
+                }
+            }
+            // nothing has been sent for a while, so we send something that looks like it matters
+            Event::Cover => {
+                if !sender.try_send(&DataFrameV2::Cover, loss_u8, false) {
+                    tracing::trace!("dropping cover frame due to backpressure");
+                }
+            }
             // we have something to send, as a FEC packet.
             Event::FecTimeout => {
                 // reset fec timer
@@ -301,9 +472,7 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                         body: parity.clone(),
                         pad_size,
                     };
-                    let send_padded = send_framed.pad(loss_u8);
-                    let send_encrypted = ctx.send_crypt.encrypt(&send_padded);
-                    if ctx.send_outgoing.try_send(send_encrypted).is_err() {
+                    if !sender.try_send(&send_framed, loss_u8, false) {
                         tracing::warn!("dropping send due to backpressure");
                     }
                     // // Pace the FEC packets!
@@ -314,3 +483,346 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
         }
     }
 }
+
+/// Pads, encrypts and sends frames as the traffic shape says, accounting for what shaping costs.
+struct FrameSender {
+    shape: TrafficShape,
+    send_crypt: NgAead,
+    gather: Arc<StatsGatherer>,
+    send_outgoing: Sender<(Buff, bool)>,
+    /// Frames on their way out through the jitter delay, along with when each is due, if jitter is on.
+    delayed: Option<Sender<(Buff, bool, Instant)>>,
+    _delay_task: Option<smol::Task<()>>,
+    last_due: Instant,
+    total_bytes: f64,
+    shaping_bytes: f64,
+}
+
+impl FrameSender {
+    fn new(
+        shape: TrafficShape,
+        send_crypt: NgAead,
+        gather: Arc<StatsGatherer>,
+        send_outgoing: Sender<(Buff, bool)>,
+    ) -> Self {
+        let (delayed, _delay_task) = if shape.max_jitter.is_zero() {
+            (None, None)
+        } else {
+            let (send_delayed, recv_delayed) = smol::channel::bounded(256);
+            let send_outgoing = send_outgoing.clone();
+            // frames become due in order, so waiting for each in turn delays none of them more than it should
+            let task = runtime::spawn(async move {
+                while let Ok((frame, important, due)) = recv_delayed.recv().await {
+                    smol::Timer::at(due).await;
+                    if send_outgoing.send((frame, important)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            (Some(send_delayed), Some(task))
+        };
+        Self {
+            shape,
+            send_crypt,
+            gather,
+            send_outgoing,
+            delayed,
+            _delay_task,
+            last_due: Instant::now(),
+            total_bytes: 0.0,
+            shaping_bytes: 0.0,
+        }
+    }
+
+    /// Pads and encrypts a frame, recording how much of it is due to shaping.
+    fn seal(&mut self, frame: &DataFrameV2, hidden_data: u8) -> Buff {
+        let (padded, padding) = frame.pad(hidden_data, &self.shape.padding);
+        let sealed = self.send_crypt.encrypt(&padded);
+        let shaping = if matches!(frame, DataFrameV2::Cover) {
+            self.gather
+                .increment("sent_cover_bytes", sealed.len() as f32);
+            sealed.len()
+        } else {
+            self.gather.increment("sent_padding_bytes", padding as f32);
+            padding
+        };
+        self.total_bytes += sealed.len() as f64;
+        self.shaping_bytes += shaping as f64;
+        self.gather.update(
+            "shaping_overhead",
+            (self.shaping_bytes / self.total_bytes) as f32,
+        );
+        sealed
+    }
+
+    /// When the next frame is due to go out of the jitter delay, never before the previous one.
+    fn next_due(shape: &TrafficShape, last_due: &mut Instant) -> Instant {
+        let due = (Instant::now() + shape.sample_jitter()).max(*last_due);
+        *last_due = due;
+        due
+    }
+
+    /// Sends a frame, waiting if the outgoing queue is full.
+    async fn send(&mut self, frame: &DataFrameV2, hidden_data: u8, important: bool) -> Option<()> {
+        let sealed = self.seal(frame, hidden_data);
+        if let Some(send_delayed) = &self.delayed {
+            let due = Self::next_due(&self.shape, &mut self.last_due);
+            send_delayed.send((sealed, important, due)).await.ok()
+        } else {
+            self.send_outgoing.send((sealed, important)).await.ok()
+        }
+    }
+
+    /// Sends a frame if the outgoing queue has room, returning whether it did.
+    fn try_send(&mut self, frame: &DataFrameV2, hidden_data: u8, important: bool) -> bool {
+        let sealed = self.seal(frame, hidden_data);
+        if let Some(send_delayed) = &self.delayed {
+            let due = Self::next_due(&self.shape, &mut self.last_due);
+            send_delayed.try_send((sealed, important, due)).is_ok()
+        } else {
+            self.send_outgoing.try_send((sealed, important)).is_ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long a side must send nothing before it counts as quiet.
+    const QUIET: Duration = Duration::from_millis(200);
+
+    fn config(role: Role, shape: TrafficShape) -> SessionConfig {
+        SessionConfig {
+            version: 4,
+            session_key: vec![7; 32],
+            role,
+            gather: Default::default(),
+            shape,
+        }
+    }
+
+    /// Creates the client and server ends of one session.
+    fn pair(shape: TrafficShape) -> ((Session, SessionBack), (Session, SessionBack)) {
+        (
+            Session::new(config(Role::Client, shape.clone())),
+            Session::new(config(Role::Server, shape)),
+        )
+    }
+
+    /// Collects what one side sends until it goes quiet.
+    async fn outgoing(back: &SessionBack) -> Vec<Buff> {
+        let mut packets = Vec::new();
+        loop {
+            let next = async { back.next_outgoing().await.ok() }
+                .or(async {
+                    smol::Timer::after(QUIET).await;
+                    None
+                })
+                .await;
+            match next {
+                Some((packet, _)) => packets.push(packet),
+                None => return packets,
+            }
+        }
+    }
+
+    /// Passes what one side sends to the other until it goes quiet, returning the packets passed.
+    async fn deliver(from: &SessionBack, to: &SessionBack) -> Vec<Buff> {
+        let packets = outgoing(from).await;
+        for packet in packets.iter() {
+            to.inject_incoming(packet).unwrap();
+        }
+        packets
+    }
+
+    #[test]
+    fn new_incarnations_reset_the_session() {
+        smol::block_on(async {
+            let ((client, client_back), (server, server_back)) = pair(Default::default());
+            client
+                .send_bytes(Buff::copy_from_slice(b"hello"))
+                .await
+                .unwrap();
+            let sent = deliver(&client_back, &server_back).await;
+            assert_eq!(sent.len(), 1);
+            assert_eq!(&server.recv_bytes().await.unwrap()[..], b"hello");
+            assert!(!server_back.inject_incoming(&sent[0]).unwrap());
+            // the first incarnation we hear of, and hearing it again, change nothing
+            assert_eq!(server_back.remote_incarnation(), None);
+            server_back.observe_remote_incarnation(1);
+            server_back.observe_remote_incarnation(1);
+            assert_eq!(server_back.remote_incarnation(), Some(1));
+            assert!(smol::future::poll_once(server.recv_event()).await.is_none());
+            // a new one means the client started over, frame numbers and all
+            server_back.observe_remote_incarnation(2);
+            assert_eq!(server_back.remote_incarnation(), Some(2));
+            assert_eq!(
+                server.recv_event().await.unwrap(),
+                SessionEvent::RemoteReset
+            );
+            assert!(server_back.inject_incoming(&sent[0]).unwrap());
+            assert_eq!(&server.recv_bytes().await.unwrap()[..], b"hello");
+        })
+    }
+
+    #[test]
+    fn events_end_with_the_session() {
+        smol::block_on(async {
+            let (_, (server, server_back)) = pair(Default::default());
+            server_back.observe_remote_incarnation(1);
+            server_back.observe_remote_incarnation(2);
+            drop(server_back);
+            // events sent before the back went away still arrive
+            assert_eq!(
+                server.recv_event().await.unwrap(),
+                SessionEvent::RemoteReset
+            );
+            assert!(matches!(
+                server.recv_event().await,
+                Err(SessionError::SessionDropped)
+            ));
+        })
+    }
+
+    #[test]
+    fn closes_are_reported_once() {
+        smol::block_on(async {
+            let ((client, client_back), (_server, server_back)) = pair(Default::default());
+            server_back.send_close();
+            // sent more than once, in case some are lost
+            let sent = deliver(&server_back, &client_back).await;
+            assert_eq!(sent.len(), 3);
+            assert_eq!(
+                client.recv_event().await.unwrap(),
+                SessionEvent::RemoteClosed
+            );
+            assert!(smol::future::poll_once(client.recv_event()).await.is_none());
+            assert!(smol::future::poll_once(client.wait_dead()).await.is_some());
+        })
+    }
+
+    #[test]
+    fn closes_survive_losing_some_copies() {
+        smol::block_on(async {
+            let ((client, client_back), (_server, server_back)) = pair(Default::default());
+            server_back.send_close();
+            let sent = outgoing(&server_back).await;
+            client_back.inject_incoming(sent.last().unwrap()).unwrap();
+            assert_eq!(
+                client.recv_event().await.unwrap(),
+                SessionEvent::RemoteClosed
+            );
+        })
+    }
+
+    #[test]
+    fn migration_hints_are_sent_again() {
+        smol::block_on(async {
+            let ((_client, client_back), (_server, server_back)) = pair(Default::default());
+            let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+            let server_pk =
+                x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::new(rand::thread_rng()));
+            server_back.send_migrate(addr, server_pk);
+            let sent = deliver(&server_back, &client_back).await;
+            assert_eq!(sent.len(), 3);
+            let (hint_addr, hint_pk) = client_back.take_migration_hint().unwrap();
+            assert_eq!(hint_addr, addr);
+            assert_eq!(hint_pk.as_bytes(), server_pk.as_bytes());
+            assert!(client_back.take_migration_hint().is_none());
+            // replays of the copies already seen are not taken for new hints
+            for packet in sent.iter() {
+                client_back.inject_incoming(packet).unwrap();
+            }
+            assert!(client_back.take_migration_hint().is_none());
+            // the hint gets through as long as one copy does
+            server_back.send_migrate(addr, server_pk);
+            let sent = outgoing(&server_back).await;
+            client_back.inject_incoming(&sent[1]).unwrap();
+            assert_eq!(client_back.take_migration_hint().unwrap().0, addr);
+        })
+    }
+
+    #[test]
+    fn fixed_padding_hides_payload_sizes() {
+        smol::block_on(async {
+            let shape = TrafficShape {
+                padding: PaddingPolicy::Fixed(500),
+                ..Default::default()
+            };
+            let (client, client_back) = Session::new(SessionConfig {
+                gather: Arc::new(StatsGatherer::new_active()),
+                ..config(Role::Client, shape.clone())
+            });
+            let (server, server_back) = Session::new(config(Role::Server, shape));
+            for len in [1, 100, 400, 800] {
+                client
+                    .send_bytes(Buff::copy_from_slice(&vec![1u8; len]))
+                    .await
+                    .unwrap();
+                let sent = deliver(&client_back, &server_back).await;
+                assert_eq!(sent.len(), 1);
+                // frames too large to pad are left as they are
+                if len <= 400 {
+                    assert_eq!(sent[0].len(), 500);
+                } else {
+                    assert!(sent[0].len() > 500);
+                }
+                assert_eq!(server.recv_bytes().await.unwrap().len(), len);
+            }
+            let stats = client.stats();
+            assert!(stats.get_last("sent_padding_bytes").unwrap() > 400.0);
+            let overhead = stats.get_last("shaping_overhead").unwrap();
+            assert!(overhead > 0.0 && overhead < 1.0);
+        })
+    }
+
+    #[test]
+    fn cover_frames_carry_nothing() {
+        smol::block_on(async {
+            let shape = TrafficShape {
+                cover_interval: Some(Duration::from_millis(10)),
+                ..Default::default()
+            };
+            let (client, client_back) = Session::new(SessionConfig {
+                gather: Arc::new(StatsGatherer::new_active()),
+                ..config(Role::Client, shape)
+            });
+            let (server, server_back) = Session::new(config(Role::Server, Default::default()));
+            // an idle session keeps sending
+            for _ in 0..10 {
+                let (packet, important) = client_back.next_outgoing().await.unwrap();
+                assert!(!important);
+                assert!(!server_back.inject_incoming(&packet).unwrap());
+            }
+            assert!(smol::future::poll_once(server.recv_bytes()).await.is_none());
+            assert!(client.stats().get_last("sent_cover_bytes").unwrap() > 0.0);
+            assert_eq!(client.stats().get_last("shaping_overhead"), Some(1.0));
+        })
+    }
+
+    #[test]
+    fn jitter_keeps_frames_in_order() {
+        smol::block_on(async {
+            let ((client, client_back), (server, server_back)) = pair(TrafficShape {
+                max_jitter: Duration::from_millis(50),
+                ..Default::default()
+            });
+            let start = Instant::now();
+            for i in 0..50u8 {
+                client
+                    .send_bytes(Buff::copy_from_slice(&[i; 10]))
+                    .await
+                    .unwrap();
+            }
+            deliver(&client_back, &server_back).await;
+            for i in 0..50u8 {
+                assert_eq!(server.recv_bytes().await.unwrap()[0], i);
+            }
+            // the frames were held back for a while, but not for the jitter of every one of them added up
+            let elapsed = start.elapsed() - QUIET;
+            assert!(elapsed > Duration::from_millis(10));
+            assert!(elapsed < Duration::from_secs(1));
+        })
+    }
+}
@@ -0,0 +1,161 @@
+use std::{sync::Arc, time::Duration};
+
+use rand::Rng;
+
+use crate::crypt::NgAead;
+
+/// How the frames of a session are shaped on the wire, to make the traffic harder to fingerprint. Each side shapes what it sends, so the two sides need not agree. What shaping costs is recorded in the session's gatherer as `sent_padding_bytes` and `sent_cover_bytes`, and as `shaping_overhead`, their share of all bytes sent.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficShape {
+    /// How frames are padded.
+    pub padding: PaddingPolicy,
+    /// Delay every frame by a random time up to this long, keeping frames in order, to blur inter-packet timing. This adds latency but does not limit throughput.
+    pub max_jitter: Duration,
+    /// While nothing else is being sent, send a cover frame after random intervals averaging this long, so that idle periods look less idle. Cover frames are padded like any other.
+    pub cover_interval: Option<Duration>,
+}
+
+/// How frames are padded before encryption. Sizes here are of whole encrypted frames, as they go on the wire inside UDP datagrams.
+#[derive(Clone, Debug, Default)]
+pub enum PaddingPolicy {
+    /// A few random bytes, rounded up to a multiple of 32. This is cheap, but sizes still closely track payloads.
+    #[default]
+    Minimal,
+    /// Pad every frame to the given size, hiding payload sizes entirely. Larger frames are left as they are.
+    Fixed(usize),
+    /// Pad every frame to a size sampled from the given sizes, weighted by the given weights, among those at least as large as the frame. Larger frames are left as they are.
+    Sampled(Arc<[(usize, u32)]>),
+}
+
+impl PaddingPolicy {
+    /// Frame sizes resembling QUIC carrying web traffic: mostly full packets of the common QUIC sizes, along with small acknowledgements.
+    pub fn quic_like() -> Self {
+        Self::Sampled(Arc::new([
+            (56, 12),
+            (72, 10),
+            (96, 5),
+            (160, 3),
+            (1252, 50),
+            (1350, 20),
+        ]))
+    }
+
+    /// Returns how much padding a frame of the given length, before padding and encryption, gets.
+    pub(crate) fn padding_for(&self, plain_len: usize) -> usize {
+        let wire_len = plain_len + NgAead::overhead();
+        match self {
+            PaddingPolicy::Minimal => rand::thread_rng().gen_range(0, 10) + (32 - plain_len % 32),
+            PaddingPolicy::Fixed(size) => size.saturating_sub(wire_len),
+            PaddingPolicy::Sampled(sizes) => {
+                let eligible = || sizes.iter().filter(|(size, _)| *size >= wire_len);
+                let total: u64 = eligible().map(|(_, weight)| *weight as u64).sum();
+                if total == 0 {
+                    return 0;
+                }
+                let mut pick = rand::thread_rng().gen_range(0, total);
+                for (size, weight) in eligible() {
+                    if pick < *weight as u64 {
+                        return size - wire_len;
+                    }
+                    pick -= *weight as u64;
+                }
+                0
+            }
+        }
+    }
+}
+
+impl TrafficShape {
+    /// Samples how long to wait before the next cover frame, if cover traffic is on. Intervals are exponentially distributed, like the gaps between independent events.
+    pub(crate) fn next_cover_delay(&self) -> Option<Duration> {
+        let mean = self.cover_interval?;
+        let uniform: f64 = rand::thread_rng().gen_range(f64::EPSILON, 1.0);
+        Some(mean.mul_f64(-uniform.ln()).min(mean * 10))
+    }
+
+    /// Samples how long to delay a frame by.
+    pub(crate) fn sample_jitter(&self) -> Duration {
+        if self.max_jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.max_jitter.mul_f64(rand::random())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_padding_rounds_up() {
+        for plain_len in [1, 31, 32, 100, 1000] {
+            for _ in 0..100 {
+                let padded = plain_len + PaddingPolicy::Minimal.padding_for(plain_len);
+                let next_multiple = (plain_len / 32 + 1) * 32;
+                assert!((next_multiple..next_multiple + 10).contains(&padded));
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_padding_fills_frames() {
+        let overhead = NgAead::overhead();
+        let policy = PaddingPolicy::Fixed(1000);
+        for plain_len in [1, 500, 1000 - overhead] {
+            assert_eq!(plain_len + overhead + policy.padding_for(plain_len), 1000);
+        }
+        assert_eq!(policy.padding_for(1000), 0);
+    }
+
+    #[test]
+    fn sampled_padding_follows_the_weights() {
+        let overhead = NgAead::overhead();
+        let policy = PaddingPolicy::quic_like();
+        let sizes = [56, 72, 96, 160, 1252, 1350];
+        // frames only ever grow to sizes at least as large as they are
+        let mut counts = [0; 6];
+        for _ in 0..10000 {
+            let wire_len = 100 + overhead + policy.padding_for(100);
+            counts[sizes.iter().position(|size| *size == wire_len).unwrap()] += 1;
+        }
+        assert_eq!(counts[..3], [0, 0, 0]);
+        // 160, 1252 and 1350 are weighted 3, 50 and 20
+        assert!((200..700).contains(&counts[3]));
+        assert!((6400..7300).contains(&counts[4]));
+        assert!((2400..3100).contains(&counts[5]));
+        assert_eq!(policy.padding_for(1400), 0);
+        assert_eq!(PaddingPolicy::Sampled(Arc::new([])).padding_for(10), 0);
+    }
+
+    #[test]
+    fn cover_delays_average_the_interval() {
+        assert!(TrafficShape::default().next_cover_delay().is_none());
+        let interval = Duration::from_millis(100);
+        let shape = TrafficShape {
+            cover_interval: Some(interval),
+            ..Default::default()
+        };
+        let delays: Vec<Duration> = (0..10000)
+            .map(|_| shape.next_cover_delay().unwrap())
+            .collect();
+        assert!(delays.iter().all(|delay| *delay <= interval * 10));
+        let mean = delays.iter().sum::<Duration>() / delays.len() as u32;
+        assert!(mean > interval.mul_f64(0.9) && mean < interval.mul_f64(1.1));
+        // exponentially distributed, so most are shorter than the mean
+        let shorter = delays.iter().filter(|delay| **delay < interval).count();
+        assert!((6000..6600).contains(&shorter));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        assert_eq!(TrafficShape::default().sample_jitter(), Duration::ZERO);
+        let shape = TrafficShape {
+            max_jitter: Duration::from_millis(50),
+            ..Default::default()
+        };
+        for _ in 0..1000 {
+            assert!(shape.sample_jitter() <= shape.max_jitter);
+        }
+    }
+}
@@ -152,6 +152,11 @@ impl EmaCalculator {
     pub fn mean(&self) -> f64 {
         self.mean_accum
     }
+
+    /// Whether there is any data point or initial estimate yet
+    pub fn is_set(&self) -> bool {
+        self.set
+    }
 }
 
 /// A generic statistics gatherer, logically a string-keyed map of f64-valued time series. It has a fairly cheap Clone implementation, allowing easy "snapshots" of the stats at a given point in time. The Default implementation creates a no-op that does nothing.
@@ -1,4 +1,3 @@
-use async_native_tls::Protocol;
 use dashmap::DashMap;
 use rustc_hash::FxHashMap;
 use smol::channel::{Receiver, Sender};
@@ -21,8 +20,8 @@ use anyhow::Context;
 use smol_timeout::TimeoutExt;
 
 use super::{
-    read_encrypted, write_encrypted, DynAsyncRead, DynAsyncWrite, ObfsTcp, CONN_LIFETIME,
-    TCP_DN_KEY, TCP_UP_KEY,
+    read_encrypted, write_encrypted, DynAsyncRead, DynAsyncWrite, ObfsTcp, TlsConfig,
+    WebSocketConfig, CONN_LIFETIME, TCP_DN_KEY, TCP_UP_KEY,
 };
 
 /// A TCP-based backhaul, client-side.
@@ -34,12 +33,13 @@ pub struct TcpClientBackhaul {
     send_incoming: Sender<(Buff, SocketAddr)>,
 
     connect: Connector,
-    tls: bool,
+    tls: Option<TlsConfig>,
+    websocket: Option<WebSocketConfig>,
 }
 
 impl TcpClientBackhaul {
-    /// Creates a new TCP client backhaul.
-    pub fn new(connect: Option<Connector>, tls: bool) -> Self {
+    /// Creates a new TCP client backhaul, doing TLS as configured if given a TLS config.
+    pub fn new(connect: Option<Connector>, tls: Option<TlsConfig>) -> Self {
         // dummy here
         let (send_incoming, incoming) = smol::channel::unbounded();
         let fake_addr = rand::random::<u128>();
@@ -53,9 +53,16 @@ impl TcpClientBackhaul {
                 Arc::new(move |addr| smol::net::TcpStream::connect(addr).boxed())
             }),
             tls,
+            websocket: None,
         }
     }
 
+    /// Makes connections go through WebSocket, inside TLS if there is any.
+    pub fn websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket = Some(websocket);
+        self
+    }
+
     /// Adds a binding.
     pub fn add_remote_key(mut self, addr: SocketAddr, key: x25519_dalek::PublicKey) -> Self {
         self.dest_to_key.insert(addr, key);
@@ -95,30 +102,19 @@ impl TcpClientBackhaul {
                 .ok_or_else(|| anyhow::anyhow!("remote address doesn't have a public key"))?;
             let cookie = Cookie::new(pubkey);
             // first connect
-            let (mut remote_write, mut remote_read): (DynAsyncWrite, DynAsyncRead) = if self.tls {
-                let tcp = (self.connect)(addr).await?;
-                tcp.set_nodelay(true)?;
-                let connector = async_native_tls::TlsConnector::new()
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .min_protocol_version(Some(Protocol::Tlsv12))
-                    .max_protocol_version(Some(Protocol::Tlsv12))
-                    .use_sni(true);
-                let fake_domain = format!(
-                    "{}.{}.com",
-                    eff_wordlist::large::random_word(),
-                    eff_wordlist::large::random_word()
-                );
-                let tls = async_dup::Arc::new(async_dup::Mutex::new(
-                    connector.connect(&fake_domain, tcp).await?,
-                ));
-                eprintln!("*** TLS ESTABLISHED YAAAY!!!! (fake domain {fake_domain}) ***");
-                (Box::new(tls.clone()), Box::new(tls))
-            } else {
-                let tcp = (self.connect)(addr).await?;
-                tcp.set_nodelay(true)?;
-                (Box::new(tcp.clone()), Box::new(tcp))
-            };
+            let (mut remote_write, mut remote_read): (DynAsyncWrite, DynAsyncRead) =
+                if let Some(tls) = &self.tls {
+                    let tcp = (self.connect)(addr).await?;
+                    tcp.set_nodelay(true)?;
+                    tls.connect(tcp).await?
+                } else {
+                    let tcp = (self.connect)(addr).await?;
+                    tcp.set_nodelay(true)?;
+                    (Box::new(tcp.clone()), Box::new(tcp))
+                };
+            if let Some(websocket) = &self.websocket {
+                (remote_write, remote_read) = websocket.connect(remote_write, remote_read).await?;
+            }
 
             // then we send a hello
             let init_c2s = cookie.generate_c2s().next().unwrap();
@@ -0,0 +1,190 @@
+use std::{convert::TryFrom, time::SystemTime};
+
+use anyhow::Context;
+use ring::{digest, signature};
+
+use crate::tcp::CertVerification;
+
+/// Signature algorithms that certificates may be signed with, as webpki names them.
+static CERT_SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+/// Checks the server's certificate chain, which starts with its own certificate, as the TLS config asks. Verified chains must lead up to one of Mozilla's trusted roots, as webpki-roots has them, or one of the extra roots, since the platform's roots are out of reach of sosistab's own TLS client.
+pub(super) fn check_chain(
+    chain: &[Vec<u8>],
+    server_name: &str,
+    verify: &CertVerification,
+) -> anyhow::Result<()> {
+    let leaf = chain.first().context("server sent no certificate")?;
+    match verify {
+        CertVerification::AcceptAny => Ok(()),
+        CertVerification::Pinned(pins) => {
+            let hash = digest::digest(&digest::SHA256, leaf);
+            if !pins.iter().any(|pin| pin[..] == *hash.as_ref()) {
+                anyhow::bail!("server certificate is not pinned")
+            }
+            Ok(())
+        }
+        CertVerification::Verify { extra_roots } => {
+            let extra_roots = extra_roots
+                .iter()
+                .map(|pem| pem_to_der(pem))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .context("cannot parse extra root certificate")?;
+            let mut anchors: Vec<webpki::TrustAnchor> = webpki_roots::TLS_SERVER_ROOTS
+                .0
+                .iter()
+                .map(|anchor| webpki::TrustAnchor {
+                    subject: anchor.subject,
+                    spki: anchor.spki,
+                    name_constraints: anchor.name_constraints,
+                })
+                .collect();
+            for root in extra_roots.iter() {
+                anchors.push(
+                    webpki::TrustAnchor::try_from_cert_der(root)
+                        .context("cannot parse extra root certificate")?,
+                );
+            }
+            let intermediates: Vec<&[u8]> = chain[1..].iter().map(Vec::as_slice).collect();
+            let cert = webpki::EndEntityCert::try_from(leaf.as_slice())
+                .context("cannot parse server certificate")?;
+            cert.verify_is_valid_tls_server_cert(
+                CERT_SIGNATURE_ALGORITHMS,
+                &webpki::TlsServerTrustAnchors(&anchors),
+                &intermediates,
+                webpki::Time::try_from(SystemTime::now())?,
+            )
+            .context("server certificate is not trusted")?;
+            cert.verify_is_valid_for_dns_name(
+                webpki::DnsNameRef::try_from_ascii_str(server_name)
+                    .context("server name is not a domain")?,
+            )
+            .context("server certificate is not valid for the server name")
+        }
+    }
+}
+
+/// Decodes a PEM-encoded certificate.
+fn pem_to_der(pem: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let pem = std::str::from_utf8(pem)?;
+    let start = pem
+        .find("-----BEGIN CERTIFICATE-----")
+        .context("no certificate in PEM")?
+        + "-----BEGIN CERTIFICATE-----".len();
+    let end = start
+        + pem[start..]
+            .find("-----END CERTIFICATE-----")
+            .context("unterminated certificate in PEM")?;
+    let base64: String = pem[start..end]
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    Ok(base64::decode(base64)?)
+}
+
+/// Kinds of public keys that certificates may have.
+enum KeyType {
+    Rsa,
+    P256,
+    P384,
+    Ed25519,
+}
+
+// object identifiers of public key algorithms and curves
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Checks that the given certificate's key made the given signature.
+pub(super) fn verify_signature(
+    cert: &[u8],
+    scheme: u16,
+    message: &[u8],
+    sig: &[u8],
+) -> anyhow::Result<()> {
+    let (key_type, key) = public_key(cert).context("cannot find key of server certificate")?;
+    let algorithm: &'static dyn signature::VerificationAlgorithm = match (key_type, scheme) {
+        (KeyType::Rsa, 0x0401) => &signature::RSA_PKCS1_2048_8192_SHA256,
+        (KeyType::Rsa, 0x0501) => &signature::RSA_PKCS1_2048_8192_SHA384,
+        (KeyType::Rsa, 0x0601) => &signature::RSA_PKCS1_2048_8192_SHA512,
+        (KeyType::Rsa, 0x0804) => &signature::RSA_PSS_2048_8192_SHA256,
+        (KeyType::Rsa, 0x0805) => &signature::RSA_PSS_2048_8192_SHA384,
+        (KeyType::Rsa, 0x0806) => &signature::RSA_PSS_2048_8192_SHA512,
+        (KeyType::P256, 0x0403) => &signature::ECDSA_P256_SHA256_ASN1,
+        (KeyType::P256, 0x0503) => &signature::ECDSA_P256_SHA384_ASN1,
+        (KeyType::P384, 0x0403) => &signature::ECDSA_P384_SHA256_ASN1,
+        (KeyType::P384, 0x0503) => &signature::ECDSA_P384_SHA384_ASN1,
+        (KeyType::Ed25519, 0x0807) => &signature::ED25519,
+        _ => anyhow::bail!("cannot check signature scheme {:04x} of server", scheme),
+    };
+    signature::UnparsedPublicKey::new(algorithm, key)
+        .verify(message, sig)
+        .map_err(|_| anyhow::anyhow!("bad signature from server"))
+}
+
+/// Digs the public key out of a DER-encoded certificate.
+fn public_key(cert: &[u8]) -> Option<(KeyType, &[u8])> {
+    let (_, cert, _) = der(cert)?;
+    let (_, tbs, _) = der(cert)?;
+    let mut rest = tbs;
+    // the version is optional
+    if rest.first() == Some(&0xa0) {
+        rest = der(rest)?.2;
+    }
+    // skips the serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der(rest)?.2;
+    }
+    let (_, spki, _) = der(rest)?;
+    let (_, algorithm, rest) = der(spki)?;
+    let (_, bits, _) = der(rest)?;
+    let (_, oid, params) = der(algorithm)?;
+    let key_type = match oid {
+        RSA_ENCRYPTION => KeyType::Rsa,
+        EC_PUBLIC_KEY => match der(params)?.1 {
+            SECP256R1 => KeyType::P256,
+            SECP384R1 => KeyType::P384,
+            _ => return None,
+        },
+        ED25519 => KeyType::Ed25519,
+        _ => return None,
+    };
+    // skips the count of unused bits
+    Some((key_type, bits.get(1..)?))
+}
+
+/// Splits a DER element off the input, returning its tag, its contents and what follows it.
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header_len) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let len_bytes = first & 0x7f;
+        if len_bytes == 0 || len_bytes > 3 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        (len, 2 + len_bytes)
+    };
+    let contents = input.get(header_len..header_len + len)?;
+    Some((tag, contents, &input[header_len + len..]))
+}
@@ -0,0 +1,699 @@
+use std::convert::TryInto;
+
+use anyhow::Context;
+use ring::{agreement, digest, hmac};
+use smol::{net::TcpStream, prelude::*};
+
+use crate::{
+    mimic::with_len,
+    tcp::{ClientHelloProfile, TlsConfig, TlsVersion},
+};
+
+use super::{
+    certs::{check_chain, verify_signature},
+    hello::{client_hello, HelloParams, HelloShape},
+    record::{
+        write_record, RecordCipher, TlsReader, TlsWriter, ALERT, CHANGE_CIPHER_SPEC, HANDSHAKE,
+    },
+    tls13,
+};
+
+// types of handshake messages
+const SERVER_HELLO: u8 = 2;
+pub(super) const NEW_SESSION_TICKET: u8 = 4;
+pub(super) const ENCRYPTED_EXTENSIONS: u8 = 8;
+pub(super) const CERTIFICATE: u8 = 11;
+const SERVER_KEY_EXCHANGE: u8 = 12;
+pub(super) const CERTIFICATE_REQUEST: u8 = 13;
+const SERVER_HELLO_DONE: u8 = 14;
+pub(super) const CERTIFICATE_VERIFY: u8 = 15;
+const CLIENT_KEY_EXCHANGE: u8 = 16;
+pub(super) const FINISHED: u8 = 20;
+const CERTIFICATE_STATUS: u8 = 22;
+pub(super) const KEY_UPDATE: u8 = 24;
+pub(super) const COMPRESSED_CERTIFICATE: u8 = 25;
+const MESSAGE_HASH: u8 = 254;
+
+// versions on the wire
+const TLS12: u16 = 0x0303;
+const TLS13: u16 = 0x0304;
+
+/// Random of a ServerHello that is really a HelloRetryRequest, the SHA-256 hash of "HelloRetryRequest".
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// Ends of the random of a ServerHello from a server that could have picked TLS 1.3 but picked TLS 1.2, or something older, as RFC 8446 has them.
+const DOWNGRADE_SENTINELS: [&[u8]; 2] = [b"DOWNGRD\x01", b"DOWNGRD\x00"];
+
+/// Longest handshake message accepted, which leaves plenty of room for long certificate chains.
+pub(super) const MAX_MESSAGE_LEN: usize = 1 << 17;
+
+/// An ephemeral private key and its encoded public key.
+type KeyPair = (agreement::EphemeralPrivateKey, Vec<u8>);
+
+/// Does a TLS handshake over the given connection, presenting a ClientHello as the profile describes, and returns the two halves of the TLS connection. The server picks TLS 1.2 or TLS 1.3, of what the profile offers, and the TLS config must allow what it picks.
+pub(crate) async fn connect(
+    profile: &ClientHelloProfile,
+    cfg: &TlsConfig,
+    server_name: &str,
+    tcp: TcpStream,
+) -> anyhow::Result<(TlsWriter, TlsReader)> {
+    let sni = cfg.use_sni.then_some(server_name);
+    let mut key_shares = profile
+        .key_shares
+        .iter()
+        .map(|group| Ok((*group, generate_key(*group)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let shape = HelloShape::new(profile, sni);
+    let client_random: [u8; 32] = rand::random();
+    let session_id: [u8; 32] = rand::random();
+    let encode_hello = |key_shares: &[(u16, KeyPair)], cookie: Option<&[u8]>, retry: bool| {
+        client_hello(
+            profile,
+            &shape,
+            &HelloParams {
+                random: client_random,
+                session_id,
+                server_name: sni,
+                alpn: &cfg.alpn,
+                key_shares: &key_shares
+                    .iter()
+                    .map(|(group, (_, public))| (*group, public.clone()))
+                    .collect::<Vec<_>>(),
+                cookie,
+                retry,
+            },
+        )
+    };
+    let hello = encode_hello(&key_shares, None, false);
+    let mut transcript = hello.clone();
+    let mut flight = Vec::new();
+    write_record(HANDSHAKE, &hello, &mut flight);
+    // like browsers, claims TLS 1.0 in the record carrying the ClientHello
+    flight[1..3].copy_from_slice(&[3, 1]);
+    let mut write_tcp = tcp.clone();
+    write_tcp.write_all(&flight).await?;
+    let mut messages = Messages {
+        reader: TlsReader::new(tcp),
+        buf: Vec::new(),
+        next_cipher: None,
+        tls13: false,
+    };
+
+    let mut msg = messages.next().await?;
+    let mut server_hello = ServerHello::parse(&msg).context("bad ServerHello")?;
+    let mut sent_ccs = false;
+    if server_hello.is_retry() {
+        // the server wants a key share for another group, or its cookie back
+        let suite = CipherSuite::get(server_hello.cipher_suite)
+            .filter(|suite| suite.tls13 && server_hello.version == TLS13)
+            .context("bad HelloRetryRequest")?;
+        messages.tls13 = true;
+        let group = server_hello.key_share.as_ref().map(|(group, _)| *group);
+        if let Some(group) = group {
+            if !profile.supported_groups.contains(&group)
+                || key_shares.iter().any(|(g, _)| *g == group)
+            {
+                anyhow::bail!("server asked for a key share for group {:04x}", group)
+            }
+            key_shares = vec![(group, generate_key(group)?)];
+        } else if server_hello.cookie.is_none() {
+            anyhow::bail!("HelloRetryRequest asks for nothing")
+        }
+        // the first ClientHello is replaced by its hash
+        let hello_hash = digest::digest(suite.hash, &transcript);
+        transcript = handshake_message(MESSAGE_HASH, |out| {
+            out.extend_from_slice(hello_hash.as_ref())
+        });
+        transcript.extend_from_slice(&msg);
+        let hello = encode_hello(&key_shares, server_hello.cookie.as_deref(), true);
+        transcript.extend_from_slice(&hello);
+        let mut flight = Vec::new();
+        // like browsers, for middleboxes that expect a resumed TLS 1.2 session
+        write_record(CHANGE_CIPHER_SPEC, &[1], &mut flight);
+        write_record(HANDSHAKE, &hello, &mut flight);
+        write_tcp.write_all(&flight).await?;
+        sent_ccs = true;
+        let retry = server_hello;
+        msg = messages.next().await?;
+        server_hello = ServerHello::parse(&msg).context("bad ServerHello")?;
+        if server_hello.is_retry()
+            || server_hello.version != TLS13
+            || server_hello.cipher_suite != retry.cipher_suite
+            || (group.is_some() && server_hello.key_share.as_ref().map(|(g, _)| *g) != group)
+        {
+            anyhow::bail!("ServerHello does not match HelloRetryRequest")
+        }
+    }
+    transcript.extend_from_slice(&msg);
+
+    let version = match server_hello.version {
+        TLS12 => TlsVersion::Tls12,
+        TLS13 => TlsVersion::Tls13,
+        other => anyhow::bail!("server picked version {:04x}, which we cannot do", other),
+    };
+    if !profile.versions.contains(&server_hello.version) {
+        anyhow::bail!("server picked {:?}, which we did not offer", version)
+    }
+    if matches!(cfg.min_version, Some(min) if version < min)
+        || matches!(cfg.max_version, Some(max) if version > max)
+    {
+        anyhow::bail!(
+            "server picked {:?}, which the TLS config does not allow",
+            version
+        )
+    }
+    if version == TlsVersion::Tls12
+        && profile.versions.contains(&TLS13)
+        && DOWNGRADE_SENTINELS.contains(&&server_hello.random[24..])
+    {
+        anyhow::bail!("server says it was made to pick an older TLS version than it can do")
+    }
+    if version == TlsVersion::Tls13 && server_hello.session_id != session_id {
+        anyhow::bail!("server did not echo our session ID")
+    }
+    let suite = Some(server_hello.cipher_suite)
+        .filter(|suite| profile.cipher_suites.contains(suite))
+        .and_then(CipherSuite::get)
+        .filter(|suite| suite.tls13 == (version == TlsVersion::Tls13))
+        .with_context(|| {
+            format!(
+                "server picked cipher suite {:04x}, which we cannot do",
+                server_hello.cipher_suite
+            )
+        })?;
+    if let Some(protocol) = &server_hello.alpn {
+        check_alpn(cfg, protocol)?;
+    }
+    messages.tls13 = version == TlsVersion::Tls13;
+    let handshake = Handshake {
+        profile,
+        cfg,
+        server_name,
+        messages,
+        write_tcp,
+        transcript,
+        client_random,
+        server_hello,
+        suite,
+        key_shares,
+        sent_ccs,
+    };
+    if version == TlsVersion::Tls13 {
+        tls13::finish(handshake).await
+    } else {
+        finish_tls12(handshake).await
+    }
+}
+
+/// A handshake that has gotten as far as the ServerHello.
+pub(super) struct Handshake<'a> {
+    pub profile: &'a ClientHelloProfile,
+    pub cfg: &'a TlsConfig,
+    /// Name that the server's certificate is checked against, if it is verified.
+    pub server_name: &'a str,
+    pub messages: Messages,
+    pub write_tcp: TcpStream,
+    /// Handshake messages so far, ServerHello included.
+    pub transcript: Vec<u8>,
+    pub client_random: [u8; 32],
+    pub server_hello: ServerHello,
+    pub suite: CipherSuite,
+    /// Key shares of the last ClientHello.
+    pub key_shares: Vec<(u16, KeyPair)>,
+    /// Whether a ChangeCipherSpec was sent already, as it is before a second ClientHello.
+    pub sent_ccs: bool,
+}
+
+impl Handshake<'_> {
+    /// Takes the key for the given group, either from a key share sent or made anew.
+    pub fn take_key(&mut self, group: u16) -> anyhow::Result<KeyPair> {
+        match self.key_shares.iter().position(|(g, _)| *g == group) {
+            Some(idx) => Ok(self.key_shares.swap_remove(idx).1),
+            None => generate_key(group),
+        }
+    }
+}
+
+/// Finishes a TLS 1.2 handshake.
+async fn finish_tls12(mut hs: Handshake<'_>) -> anyhow::Result<(TlsWriter, TlsReader)> {
+    let suite = hs.suite;
+    let mut chain: Option<Vec<Vec<u8>>> = None;
+    let mut server_key = None;
+    let mut cert_requested = false;
+    loop {
+        let msg = hs.messages.next().await?;
+        hs.transcript.extend_from_slice(&msg);
+        let mut body = Reader(&msg[4..]);
+        match msg[0] {
+            CERTIFICATE => {
+                let mut certs = Reader(body.vec(3)?);
+                let mut certs_read = Vec::new();
+                while !certs.0.is_empty() {
+                    certs_read.push(certs.vec(3)?.to_vec());
+                }
+                check_chain(&certs_read, hs.server_name, &hs.cfg.verify)?;
+                chain = Some(certs_read);
+            }
+            CERTIFICATE_STATUS => {}
+            SERVER_KEY_EXCHANGE => {
+                if body.u8()? != 3 {
+                    anyhow::bail!("server key exchange does not use a named group")
+                }
+                let group = body.u16()?;
+                let public = body.vec(1)?.to_vec();
+                let params_len = msg.len() - 4 - body.0.len();
+                let scheme = body.u16()?;
+                let sig = body.vec(2)?;
+                if !hs.profile.supported_groups.contains(&group) {
+                    anyhow::bail!("server picked group {:04x}, which we did not offer", group)
+                }
+                if !hs.profile.signature_algorithms.contains(&scheme) {
+                    anyhow::bail!("server signed with {:04x}, which we did not offer", scheme)
+                }
+                let signed = [
+                    &hs.client_random[..],
+                    &hs.server_hello.random[..],
+                    &msg[4..4 + params_len],
+                ]
+                .concat();
+                let chain = chain
+                    .as_ref()
+                    .context("server key exchange came before certificate")?;
+                verify_signature(&chain[0], scheme, &signed, sig)?;
+                server_key = Some((group, public));
+            }
+            CERTIFICATE_REQUEST => cert_requested = true,
+            SERVER_HELLO_DONE => break,
+            other => anyhow::bail!("unexpected handshake message {}", other),
+        }
+    }
+
+    // agrees on the premaster secret, with a key share we already made if we can
+    let (group, server_public) = server_key.context("server sent no key exchange")?;
+    let (private, public) = hs.take_key(group)?;
+    let pre_master = agree(private, group, &server_public)?;
+
+    // our flight, ending with the first protected record
+    let mut clear = Vec::new();
+    if cert_requested {
+        clear.extend(handshake_message(CERTIFICATE, |out| {
+            with_len(out, 3, |_| {})
+        }));
+    }
+    clear.extend(handshake_message(CLIENT_KEY_EXCHANGE, |out| {
+        with_len(out, 1, |out| out.extend_from_slice(&public))
+    }));
+    hs.transcript.extend_from_slice(&clear);
+    let client_random = hs.client_random;
+    let server_random = hs.server_hello.random;
+    let master = if hs.server_hello.extended_master_secret {
+        let session_hash = digest::digest(suite.hash, &hs.transcript);
+        prf(
+            suite.prf,
+            &pre_master,
+            b"extended master secret",
+            session_hash.as_ref(),
+            48,
+        )
+    } else {
+        let seed = [&client_random[..], &server_random[..]].concat();
+        prf(suite.prf, &pre_master, b"master secret", &seed, 48)
+    };
+    let key_len = suite.aead.key_len();
+    let seed = [&server_random[..], &client_random[..]].concat();
+    let key_block = prf(
+        suite.prf,
+        &master,
+        b"key expansion",
+        &seed,
+        2 * (key_len + suite.iv_len),
+    );
+    let (client_write_key, rest) = key_block.split_at(key_len);
+    let (server_write_key, rest) = rest.split_at(key_len);
+    let (client_iv, server_iv) = rest.split_at(suite.iv_len);
+    let mut write_cipher = RecordCipher::new(
+        suite.aead,
+        client_write_key,
+        client_iv,
+        suite.explicit_nonce,
+    );
+    hs.messages.next_cipher = Some(RecordCipher::new(
+        suite.aead,
+        server_write_key,
+        server_iv,
+        suite.explicit_nonce,
+    ));
+    let verify_data = prf(
+        suite.prf,
+        &master,
+        b"client finished",
+        digest::digest(suite.hash, &hs.transcript).as_ref(),
+        12,
+    );
+    let finished = handshake_message(FINISHED, |out| out.extend_from_slice(&verify_data));
+    hs.transcript.extend_from_slice(&finished);
+    let mut flight = Vec::new();
+    write_record(HANDSHAKE, &clear, &mut flight);
+    write_record(CHANGE_CIPHER_SPEC, &[1], &mut flight);
+    write_cipher.seal(HANDSHAKE, &finished, &mut flight);
+    hs.write_tcp.write_all(&flight).await?;
+
+    loop {
+        let msg = hs.messages.next().await?;
+        match msg[0] {
+            NEW_SESSION_TICKET if hs.messages.reader.cipher.is_none() => {
+                hs.transcript.extend_from_slice(&msg)
+            }
+            FINISHED if hs.messages.reader.cipher.is_some() => {
+                let expected = prf(
+                    suite.prf,
+                    &master,
+                    b"server finished",
+                    digest::digest(suite.hash, &hs.transcript).as_ref(),
+                    12,
+                );
+                if ring::constant_time::verify_slices_are_equal(&msg[4..], &expected).is_err() {
+                    anyhow::bail!("bad Finished from server")
+                }
+                break;
+            }
+            other => anyhow::bail!("unexpected handshake message {}", other),
+        }
+    }
+    if !hs.messages.buf.is_empty() {
+        anyhow::bail!("unexpected handshake data after Finished")
+    }
+    let writer = TlsWriter::new(hs.write_tcp, write_cipher, &hs.messages.reader);
+    Ok((writer, hs.messages.reader))
+}
+
+/// Checks that the ALPN protocol the server picked is one we offered.
+pub(super) fn check_alpn(cfg: &TlsConfig, protocol: &[u8]) -> anyhow::Result<()> {
+    if !cfg.alpn.iter().any(|p| p.as_bytes() == protocol) {
+        anyhow::bail!("server picked an ALPN protocol we did not offer")
+    }
+    Ok(())
+}
+
+/// A cipher suite that we can actually do.
+#[derive(Clone, Copy)]
+pub(super) struct CipherSuite {
+    pub aead: &'static ring::aead::Algorithm,
+    iv_len: usize,
+    explicit_nonce: bool,
+    pub prf: hmac::Algorithm,
+    pub hash: &'static digest::Algorithm,
+    /// Whether the suite is for TLS 1.3 rather than TLS 1.2.
+    tls13: bool,
+}
+
+impl CipherSuite {
+    fn get(id: u16) -> Option<Self> {
+        let (aead, iv_len, explicit_nonce, prf, hash, tls13) = match id {
+            // ECDHE with AES-128-GCM
+            0xc02b | 0xc02f => (
+                &ring::aead::AES_128_GCM,
+                4,
+                true,
+                hmac::HMAC_SHA256,
+                &digest::SHA256,
+                false,
+            ),
+            // ECDHE with AES-256-GCM
+            0xc02c | 0xc030 => (
+                &ring::aead::AES_256_GCM,
+                4,
+                true,
+                hmac::HMAC_SHA384,
+                &digest::SHA384,
+                false,
+            ),
+            // ECDHE with ChaCha20-Poly1305
+            0xcca8 | 0xcca9 => (
+                &ring::aead::CHACHA20_POLY1305,
+                12,
+                false,
+                hmac::HMAC_SHA256,
+                &digest::SHA256,
+                false,
+            ),
+            // TLS 1.3 with AES-128-GCM
+            0x1301 => (
+                &ring::aead::AES_128_GCM,
+                12,
+                false,
+                hmac::HMAC_SHA256,
+                &digest::SHA256,
+                true,
+            ),
+            // TLS 1.3 with AES-256-GCM
+            0x1302 => (
+                &ring::aead::AES_256_GCM,
+                12,
+                false,
+                hmac::HMAC_SHA384,
+                &digest::SHA384,
+                true,
+            ),
+            // TLS 1.3 with ChaCha20-Poly1305
+            0x1303 => (
+                &ring::aead::CHACHA20_POLY1305,
+                12,
+                false,
+                hmac::HMAC_SHA256,
+                &digest::SHA256,
+                true,
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            aead,
+            iv_len,
+            explicit_nonce,
+            prf,
+            hash,
+            tls13,
+        })
+    }
+}
+
+/// What we need out of a ServerHello, or a HelloRetryRequest, which looks like one.
+pub(super) struct ServerHello {
+    random: [u8; 32],
+    session_id: Vec<u8>,
+    cipher_suite: u16,
+    /// Version picked, through the supported versions extension if there is one.
+    version: u16,
+    extended_master_secret: bool,
+    alpn: Option<Vec<u8>>,
+    /// Group and public key of the server's key share, for TLS 1.3. A HelloRetryRequest gives just the group.
+    pub key_share: Option<(u16, Vec<u8>)>,
+    /// Cookie from a HelloRetryRequest.
+    cookie: Option<Vec<u8>>,
+}
+
+impl ServerHello {
+    /// Parses a ServerHello handshake message, header included.
+    fn parse(msg: &[u8]) -> anyhow::Result<Self> {
+        if msg[0] != SERVER_HELLO {
+            anyhow::bail!("expected ServerHello, got handshake message {}", msg[0])
+        }
+        let mut body = Reader(&msg[4..]);
+        let version = body.u16()?;
+        let random = body.take(32)?.try_into().unwrap();
+        let session_id = body.vec(1)?.to_vec();
+        let cipher_suite = body.u16()?;
+        if body.u8()? != 0 {
+            anyhow::bail!("server picked compression")
+        }
+        let mut hello = Self {
+            random,
+            session_id,
+            cipher_suite,
+            version,
+            extended_master_secret: false,
+            alpn: None,
+            key_share: None,
+            cookie: None,
+        };
+        let mut extensions = Reader(if body.0.is_empty() { &[] } else { body.vec(2)? });
+        while !extensions.0.is_empty() {
+            let ext_type = extensions.u16()?;
+            let mut contents = Reader(extensions.vec(2)?);
+            match ext_type {
+                0x0017 => hello.extended_master_secret = true,
+                0x0010 => {
+                    let mut protocols = Reader(contents.vec(2)?);
+                    hello.alpn = Some(protocols.vec(1)?.to_vec());
+                }
+                0x002b if version == TLS12 => hello.version = contents.u16()?,
+                0x0033 => {
+                    let group = contents.u16()?;
+                    let public = if hello.is_retry() {
+                        Vec::new()
+                    } else {
+                        contents.vec(2)?.to_vec()
+                    };
+                    hello.key_share = Some((group, public));
+                }
+                0x002c => hello.cookie = Some(contents.vec(2)?.to_vec()),
+                _ => {}
+            }
+        }
+        Ok(hello)
+    }
+
+    fn is_retry(&self) -> bool {
+        self.random == HELLO_RETRY_RANDOM
+    }
+}
+
+/// Reads handshake messages out of records, however they are split between them.
+pub(super) struct Messages {
+    pub reader: TlsReader,
+    pub buf: Vec<u8>,
+    /// Cipher that server records are protected with once the server says so with a ChangeCipherSpec, in TLS 1.2.
+    next_cipher: Option<RecordCipher>,
+    /// Whether TLS 1.3 is being done, in which servers may send a ChangeCipherSpec that means nothing, for middleboxes.
+    tls13: bool,
+}
+
+impl Messages {
+    /// Reads the next handshake message, header included.
+    pub async fn next(&mut self) -> anyhow::Result<Vec<u8>> {
+        loop {
+            if self.buf.len() >= 4 {
+                let len = u32::from_be_bytes([0, self.buf[1], self.buf[2], self.buf[3]]) as usize;
+                if len > MAX_MESSAGE_LEN {
+                    anyhow::bail!("handshake message too long")
+                }
+                if self.buf.len() >= 4 + len {
+                    return Ok(self.buf.drain(..4 + len).collect());
+                }
+            }
+            let (content_type, fragment) = self.reader.read_record().await?;
+            match content_type {
+                HANDSHAKE => self.buf.extend_from_slice(&fragment),
+                CHANGE_CIPHER_SPEC if self.tls13 => {}
+                CHANGE_CIPHER_SPEC => {
+                    self.reader.cipher = Some(
+                        self.next_cipher
+                            .take()
+                            .context("unexpected ChangeCipherSpec")?,
+                    )
+                }
+                ALERT => anyhow::bail!("server sent alert {:?}", fragment),
+                other => anyhow::bail!("unexpected record of type {} during handshake", other),
+            }
+        }
+    }
+}
+
+/// A cursor over the fields of a message.
+pub(super) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            anyhow::bail!("truncated handshake message")
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u24(&mut self) -> anyhow::Result<usize> {
+        Ok(self
+            .take(3)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize))
+    }
+
+    /// Takes a field prefixed with its length in the given number of bytes.
+    pub fn vec(&mut self, len_bytes: usize) -> anyhow::Result<&'a [u8]> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        self.take(len)
+    }
+}
+
+/// Encodes a handshake message with the contents written by the given closure.
+pub(super) fn handshake_message(msg_type: u8, contents: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut out = vec![msg_type];
+    with_len(&mut out, 3, contents);
+    out
+}
+
+fn agreement_algorithm(group: u16) -> Option<&'static agreement::Algorithm> {
+    match group {
+        0x001d => Some(&agreement::X25519),
+        0x0017 => Some(&agreement::ECDH_P256),
+        0x0018 => Some(&agreement::ECDH_P384),
+        _ => None,
+    }
+}
+
+/// Agrees on a shared secret with the server, given its public key.
+pub(super) fn agree(
+    private: agreement::EphemeralPrivateKey,
+    group: u16,
+    server_public: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let algorithm = agreement_algorithm(group)
+        .with_context(|| format!("cannot do key exchange over group {:04x}", group))?;
+    agreement::agree_ephemeral(
+        private,
+        &agreement::UnparsedPublicKey::new(algorithm, server_public),
+        anyhow::anyhow!("bad key from server"),
+        |secret| Ok(secret.to_vec()),
+    )
+}
+
+/// Makes an ephemeral key for the given group, returning it along with its encoded public key.
+fn generate_key(group: u16) -> anyhow::Result<KeyPair> {
+    let algorithm = agreement_algorithm(group)
+        .with_context(|| format!("cannot do key exchange over group {:04x}", group))?;
+    let private =
+        agreement::EphemeralPrivateKey::generate(algorithm, &ring::rand::SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("cannot generate key"))?;
+    let public = private
+        .compute_public_key()
+        .map_err(|_| anyhow::anyhow!("cannot compute public key"))?;
+    Ok((private, public.as_ref().to_vec()))
+}
+
+/// The TLS 1.2 pseudorandom function, from RFC 5246.
+fn prf(
+    algorithm: hmac::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    seed: &[u8],
+    len: usize,
+) -> Vec<u8> {
+    let key = hmac::Key::new(algorithm, secret);
+    let seed = [label, seed].concat();
+    let mut a = hmac::sign(&key, &seed);
+    let mut out = Vec::with_capacity(len + 64);
+    while out.len() < len {
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(a.as_ref());
+        ctx.update(&seed);
+        out.extend_from_slice(ctx.sign().as_ref());
+        a = hmac::sign(&key, a.as_ref());
+    }
+    out.truncate(len);
+    out
+}
@@ -0,0 +1,495 @@
+use rand::{seq::SliceRandom, Rng};
+
+use crate::mimic::{extension, with_len};
+
+use super::{ClientHelloProfile, HelloExtension};
+
+/// Type of a ClientHello handshake message.
+pub(super) const CLIENT_HELLO: u8 = 1;
+
+/// What a ClientHello carries besides what its profile says.
+pub(super) struct HelloParams<'a> {
+    pub random: [u8; 32],
+    pub session_id: [u8; 32],
+    pub server_name: Option<&'a str>,
+    pub alpn: &'a [String],
+    /// Groups and public keys of the key shares.
+    pub key_shares: &'a [(u16, Vec<u8>)],
+    /// Cookie from a HelloRetryRequest, to be sent back.
+    pub cookie: Option<&'a [u8]>,
+    /// Whether this is a second ClientHello, after a HelloRetryRequest, which sends no GREASE key share, as BoringSSL does.
+    pub retry: bool,
+}
+
+/// What a ClientHello picks at random besides its random fields, which a second ClientHello after a HelloRetryRequest must keep.
+pub(super) struct HelloShape {
+    grease: Option<Grease>,
+    /// Extensions in the order they are sent, without padding.
+    extensions: Vec<HelloExtension>,
+    /// Contents of the GREASE encrypted client hello extension.
+    ech: Vec<u8>,
+}
+
+impl HelloShape {
+    pub fn new(profile: &ClientHelloProfile, server_name: Option<&str>) -> Self {
+        let mut extensions: Vec<HelloExtension> = profile
+            .extensions
+            .iter()
+            .filter(|ext| **ext != HelloExtension::Padding)
+            .cloned()
+            .collect();
+        if profile.shuffle_extensions {
+            extensions.shuffle(&mut rand::thread_rng());
+        }
+        Self {
+            grease: profile.grease.then(Grease::new),
+            extensions,
+            ech: grease_ech(server_name),
+        }
+    }
+}
+
+/// GREASE values of a ClientHello, all picked at random.
+struct Grease {
+    cipher_suite: u16,
+    group: u16,
+    first_extension: u16,
+    last_extension: u16,
+    version: u16,
+}
+
+impl Grease {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut pick = || 0x0a0a + 0x1010 * rng.gen_range(0u16, 16);
+        let first_extension = pick();
+        let mut last_extension = pick();
+        while last_extension == first_extension {
+            last_extension = pick();
+        }
+        Self {
+            cipher_suite: pick(),
+            group: pick(),
+            first_extension,
+            last_extension,
+            version: pick(),
+        }
+    }
+}
+
+/// Encodes a ClientHello handshake message, header included, as the profile describes.
+pub(super) fn client_hello(
+    profile: &ClientHelloProfile,
+    shape: &HelloShape,
+    params: &HelloParams,
+) -> Vec<u8> {
+    let grease = shape.grease.as_ref();
+    let padded = profile.extensions.contains(&HelloExtension::Padding);
+    let encode = |padding: usize| {
+        let mut out = vec![CLIENT_HELLO];
+        with_len(&mut out, 3, |out| {
+            out.extend_from_slice(&[0x03, 0x03]);
+            out.extend_from_slice(&params.random);
+            with_len(out, 1, |out| out.extend_from_slice(&params.session_id));
+            with_len(out, 2, |out| {
+                if let Some(grease) = grease {
+                    out.extend_from_slice(&grease.cipher_suite.to_be_bytes());
+                }
+                for suite in profile.cipher_suites.iter() {
+                    out.extend_from_slice(&suite.to_be_bytes());
+                }
+            });
+            // only null compression
+            out.extend_from_slice(&[1, 0]);
+            with_len(out, 2, |out| {
+                if let Some(grease) = grease {
+                    extension(out, grease.first_extension, |_| {});
+                }
+                for ext in shape.extensions.iter() {
+                    encode_extension(ext, profile, params, grease, &shape.ech, out);
+                }
+                if let Some(cookie) = params.cookie {
+                    extension(out, 0x002c, |out| {
+                        with_len(out, 2, |out| out.extend_from_slice(cookie))
+                    });
+                }
+                if let Some(grease) = grease {
+                    extension(out, grease.last_extension, |out| out.push(0));
+                }
+                if padding > 0 {
+                    extension(out, 0x0015, |out| out.resize(out.len() + padding - 4, 0));
+                }
+            });
+        });
+        out
+    };
+    let hello = encode(0);
+    // like BoringSSL, pads ClientHellos of 256 to 511 bytes up to 512, with at least one byte of padding
+    let len = hello.len();
+    if padded && (0x100..0x200).contains(&len) {
+        encode((0x200 - len).max(5))
+    } else {
+        hello
+    }
+}
+
+/// Appends one extension of a ClientHello.
+fn encode_extension(
+    ext: &HelloExtension,
+    profile: &ClientHelloProfile,
+    params: &HelloParams,
+    grease: Option<&Grease>,
+    ech: &[u8],
+    out: &mut Vec<u8>,
+) {
+    let grease_group = grease.map(|grease| grease.group);
+    match ext {
+        HelloExtension::ServerName => {
+            if let Some(name) = params.server_name {
+                extension(out, 0x0000, |out| {
+                    with_len(out, 2, |out| {
+                        out.push(0);
+                        with_len(out, 2, |out| out.extend_from_slice(name.as_bytes()));
+                    })
+                })
+            }
+        }
+        HelloExtension::ExtendedMasterSecret => extension(out, 0x0017, |_| {}),
+        HelloExtension::RenegotiationInfo => extension(out, 0xff01, |out| out.push(0)),
+        HelloExtension::SupportedGroups => extension(out, 0x000a, |out| {
+            with_len(out, 2, |out| {
+                for group in grease_group.iter().chain(profile.supported_groups.iter()) {
+                    out.extend_from_slice(&group.to_be_bytes());
+                }
+            })
+        }),
+        HelloExtension::EcPointFormats => {
+            extension(out, 0x000b, |out| with_len(out, 1, |out| out.push(0)))
+        }
+        HelloExtension::SessionTicket => extension(out, 0x0023, |_| {}),
+        HelloExtension::Alpn => {
+            if !params.alpn.is_empty() {
+                extension(out, 0x0010, |out| {
+                    with_len(out, 2, |out| {
+                        for protocol in params.alpn {
+                            with_len(out, 1, |out| out.extend_from_slice(protocol.as_bytes()));
+                        }
+                    })
+                })
+            }
+        }
+        HelloExtension::StatusRequest => {
+            extension(out, 0x0005, |out| out.extend_from_slice(&[1, 0, 0, 0, 0]))
+        }
+        HelloExtension::SignatureAlgorithms => extension(out, 0x000d, |out| {
+            with_len(out, 2, |out| {
+                for scheme in profile.signature_algorithms.iter() {
+                    out.extend_from_slice(&scheme.to_be_bytes());
+                }
+            })
+        }),
+        HelloExtension::SignedCertificateTimestamp => extension(out, 0x0012, |_| {}),
+        HelloExtension::KeyShare => extension(out, 0x0033, |out| {
+            with_len(out, 2, |out| {
+                if let Some(group) = grease_group.filter(|_| !params.retry) {
+                    out.extend_from_slice(&group.to_be_bytes());
+                    with_len(out, 2, |out| out.push(0));
+                }
+                for (group, public) in params.key_shares {
+                    out.extend_from_slice(&group.to_be_bytes());
+                    with_len(out, 2, |out| out.extend_from_slice(public));
+                }
+            })
+        }),
+        HelloExtension::PskKeyExchangeModes => {
+            extension(out, 0x002d, |out| with_len(out, 1, |out| out.push(1)))
+        }
+        HelloExtension::SupportedVersions => extension(out, 0x002b, |out| {
+            with_len(out, 1, |out| {
+                for version in grease
+                    .map(|grease| grease.version)
+                    .iter()
+                    .chain(profile.versions.iter())
+                {
+                    out.extend_from_slice(&version.to_be_bytes());
+                }
+            })
+        }),
+        HelloExtension::GreaseEch => extension(out, 0xfe0d, |out| out.extend_from_slice(ech)),
+        // added at the very end, if at all
+        HelloExtension::Padding => {}
+        HelloExtension::Raw(ext_type, contents) => {
+            extension(out, *ext_type, |out| out.extend_from_slice(contents))
+        }
+    }
+}
+
+/// Makes up the contents of a GREASE encrypted client hello extension: an outer ECH with HKDF-SHA256 and AES-128-GCM, under a made-up config, with a random key and payload. The payload is as long as an encrypted ClientHelloInner for the server name would be, padded to a multiple of 32 bytes as in the ECH draft, so that whether the ClientHello gets padded stays the same from one connection to the next.
+fn grease_ech(server_name: Option<&str>) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut out = vec![0, 0, 1, 0, 1, rng.gen()];
+    with_len(&mut out, 2, |out| {
+        out.extend((0..32).map(|_| rng.gen::<u8>()))
+    });
+    let inner_len = 128 + server_name.map_or(0, str::len);
+    // plus the AEAD tag
+    let payload_len = inner_len.div_ceil(32) * 32 + 16;
+    with_len(&mut out, 2, |out| {
+        out.extend((0..payload_len).map(|_| rng.gen::<u8>()))
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_grease(value: u16) -> bool {
+        value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+    }
+
+    /// Cipher suites and extensions of a ClientHello handshake message.
+    struct Parsed {
+        cipher_suites: Vec<u16>,
+        extensions: Vec<(u16, Vec<u8>)>,
+    }
+
+    impl Parsed {
+        fn new(hello: &[u8]) -> Self {
+            assert_eq!(hello[0], CLIENT_HELLO);
+            let len = u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize;
+            assert_eq!(len + 4, hello.len());
+            let mut body = &hello[4 + 2 + 32..];
+            let mut take = |n: usize| {
+                let (taken, rest) = body.split_at(n);
+                body = rest;
+                taken
+            };
+            let session_id_len = take(1)[0] as usize;
+            take(session_id_len);
+            let suites_len = u16::from_be_bytes(take(2).try_into().unwrap()) as usize;
+            let cipher_suites = take(suites_len)
+                .chunks(2)
+                .map(|suite| u16::from_be_bytes([suite[0], suite[1]]))
+                .collect();
+            assert_eq!(take(2), [1, 0]);
+            let extensions_len = u16::from_be_bytes(take(2).try_into().unwrap()) as usize;
+            let mut extensions = Vec::new();
+            let mut rest = take(extensions_len);
+            while !rest.is_empty() {
+                let ext_type = u16::from_be_bytes([rest[0], rest[1]]);
+                let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+                extensions.push((ext_type, rest[4..4 + len].to_vec()));
+                rest = &rest[4 + len..];
+            }
+            assert!(body.is_empty());
+            Self {
+                cipher_suites,
+                extensions,
+            }
+        }
+
+        fn extension(&self, ext_type: u16) -> &[u8] {
+            &self
+                .extensions
+                .iter()
+                .find(|(t, _)| *t == ext_type)
+                .expect("extension missing")
+                .1
+        }
+
+        /// The JA3 string of the ClientHello, with GREASE values left out, and extensions sorted if they are shuffled.
+        fn ja3(&self, sort_extensions: bool) -> String {
+            let list = |values: Vec<u16>| {
+                values
+                    .into_iter()
+                    .filter(|value| !is_grease(*value))
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join("-")
+            };
+            let mut extensions: Vec<u16> = self.extensions.iter().map(|(t, _)| *t).collect();
+            if sort_extensions {
+                extensions.sort_unstable();
+            }
+            let groups = self.extension(0x000a)[2..]
+                .chunks(2)
+                .map(|group| u16::from_be_bytes([group[0], group[1]]))
+                .collect();
+            let point_formats = self.extension(0x000b)[1..]
+                .iter()
+                .map(|format| *format as u16)
+                .collect();
+            format!(
+                "771,{},{},{},{}",
+                list(self.cipher_suites.clone()),
+                list(extensions),
+                list(groups),
+                list(point_formats)
+            )
+        }
+    }
+
+    fn hello(profile: &ClientHelloProfile, server_name: &str) -> Vec<u8> {
+        let key_shares: Vec<(u16, Vec<u8>)> = profile
+            .key_shares
+            .iter()
+            .map(|group| (*group, vec![7; if *group == 0x001d { 32 } else { 65 }]))
+            .collect();
+        let alpn = ["h2".to_string(), "http/1.1".to_string()];
+        client_hello(
+            profile,
+            &HelloShape::new(profile, Some(server_name)),
+            &HelloParams {
+                random: rand::random(),
+                session_id: rand::random(),
+                server_name: Some(server_name),
+                alpn: &alpn,
+                key_shares: &key_shares,
+                cookie: None,
+                retry: false,
+            },
+        )
+    }
+
+    #[test]
+    fn grease_goes_first_and_last() {
+        let profile = ClientHelloProfile::chrome();
+        for _ in 0..50 {
+            let parsed = Parsed::new(&hello(&profile, "example.com"));
+            assert!(is_grease(parsed.cipher_suites[0]));
+            assert!(parsed.cipher_suites[1..].iter().all(|s| !is_grease(*s)));
+            let types: Vec<u16> = parsed.extensions.iter().map(|(t, _)| *t).collect();
+            // padding, if any, comes after the last GREASE extension
+            let end = types.len() - (types.last() == Some(&0x0015)) as usize;
+            assert!(is_grease(types[0]));
+            assert!(is_grease(types[end - 1]));
+            assert_ne!(types[0], types[end - 1]);
+            assert!(types[1..end - 1].iter().all(|t| !is_grease(*t)));
+            assert!(types[..end].iter().all(|t| *t != 0x0015));
+            for (ext_type, offset) in [(0x000a, 2), (0x002b, 1), (0x0033, 2)] {
+                let contents = parsed.extension(ext_type);
+                assert!(is_grease(u16::from_be_bytes([
+                    contents[offset],
+                    contents[offset + 1]
+                ])));
+            }
+        }
+    }
+
+    #[test]
+    fn extensions_keep_their_order() {
+        let profile = ClientHelloProfile::firefox();
+        let expected: Vec<u16> = profile
+            .extensions
+            .iter()
+            .map(|ext| match ext {
+                HelloExtension::ServerName => 0x0000,
+                HelloExtension::ExtendedMasterSecret => 0x0017,
+                HelloExtension::RenegotiationInfo => 0xff01,
+                HelloExtension::SupportedGroups => 0x000a,
+                HelloExtension::EcPointFormats => 0x000b,
+                HelloExtension::SessionTicket => 0x0023,
+                HelloExtension::Alpn => 0x0010,
+                HelloExtension::StatusRequest => 0x0005,
+                HelloExtension::SignatureAlgorithms => 0x000d,
+                HelloExtension::SignedCertificateTimestamp => 0x0012,
+                HelloExtension::KeyShare => 0x0033,
+                HelloExtension::PskKeyExchangeModes => 0x002d,
+                HelloExtension::SupportedVersions => 0x002b,
+                HelloExtension::GreaseEch => 0xfe0d,
+                HelloExtension::Padding => 0x0015,
+                HelloExtension::Raw(ext_type, _) => *ext_type,
+            })
+            .collect();
+        for _ in 0..10 {
+            let parsed = Parsed::new(&hello(&profile, "example.com"));
+            let types: Vec<u16> = parsed.extensions.iter().map(|(t, _)| *t).collect();
+            assert_eq!(types, expected);
+            assert_eq!(parsed.cipher_suites, profile.cipher_suites);
+        }
+    }
+
+    #[test]
+    fn padding_avoids_bad_lengths() {
+        // without GREASE ECH, which makes Chrome's ClientHellos long enough to need no padding
+        let mut profile = ClientHelloProfile::chrome();
+        profile
+            .extensions
+            .retain(|ext| *ext != HelloExtension::GreaseEch);
+        let mut padded = 0;
+        for name_len in 1..400 {
+            let hello = hello(&profile, &"a".repeat(name_len));
+            assert!(!(0x100..0x200).contains(&hello.len()), "{}", hello.len());
+            let parsed = Parsed::new(&hello);
+            if let Some((_, padding)) = parsed.extensions.iter().find(|(t, _)| *t == 0x0015) {
+                // up to 512 bytes, unless that leaves no room for a byte of padding
+                assert!(hello.len() == 0x200 || padding.len() == 1);
+                assert!(!padding.is_empty() && padding.iter().all(|b| *b == 0));
+                assert_eq!(parsed.extensions.last().unwrap().0, 0x0015);
+                padded += 1;
+            }
+        }
+        assert!(padded > 0);
+    }
+
+    #[test]
+    fn ja3_is_stable() {
+        for (profile, shuffled) in [
+            (ClientHelloProfile::chrome(), true),
+            (ClientHelloProfile::firefox(), false),
+        ] {
+            let ja3 = Parsed::new(&hello(&profile, "example.com")).ja3(shuffled);
+            for _ in 0..20 {
+                let parsed = Parsed::new(&hello(&profile, "example.com"));
+                assert_eq!(parsed.ja3(shuffled), ja3);
+            }
+        }
+    }
+
+    #[test]
+    fn retries_keep_the_shape() {
+        let profile = ClientHelloProfile::chrome();
+        let shape = HelloShape::new(&profile, Some("example.com"));
+        let alpn = ["h2".to_string()];
+        let params = HelloParams {
+            random: rand::random(),
+            session_id: rand::random(),
+            server_name: Some("example.com"),
+            alpn: &alpn,
+            key_shares: &[(0x001d, vec![7; 32])],
+            cookie: None,
+            retry: false,
+        };
+        let first = Parsed::new(&client_hello(&profile, &shape, &params));
+        let second = Parsed::new(&client_hello(
+            &profile,
+            &shape,
+            &HelloParams {
+                key_shares: &[(0x0017, vec![7; 65])],
+                cookie: Some(b"cookie"),
+                retry: true,
+                ..params
+            },
+        ));
+        assert_eq!(first.cipher_suites, second.cipher_suites);
+        let mut types: Vec<u16> = first.extensions.iter().map(|(t, _)| *t).collect();
+        types.insert(types.len() - 1, 0x002c);
+        assert_eq!(
+            types,
+            second
+                .extensions
+                .iter()
+                .map(|(t, _)| *t)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(second.extension(0x002c), b"\x00\x06cookie");
+        // just the key share asked for, without GREASE
+        let mut share = vec![0, 69, 0x00, 0x17, 0, 65];
+        share.extend_from_slice(&[7; 65]);
+        assert_eq!(second.extension(0x0033), share);
+        for ext_type in [0x000a, 0x002b, 0xfe0d] {
+            assert_eq!(first.extension(ext_type), second.extension(ext_type));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+mod certs;
+mod handshake;
+mod hello;
+mod record;
+mod tls13;
+
+pub(crate) use handshake::connect;
+
+/// A ClientHello for TLS connections to present, so that they can have the same fingerprint as some browser, whatever the platform. Connections using one do TLS with sosistab's own TLS client rather than the platform TLS library, and while the ClientHello may offer whatever it likes, only TLS 1.2 and TLS 1.3, with ECDHE over x25519, P-256 or P-384 and cipher suites using AES-GCM or ChaCha20-Poly1305, can actually be negotiated, and a server that picks anything else fails the handshake. As with browsers, TLS 1.3 servers that want a key share for a group that none was sent for get one after a HelloRetryRequest, and certificates compressed with brotli are accepted. Sessions are never resumed.
+#[derive(Clone, Debug)]
+pub struct ClientHelloProfile {
+    /// Cipher suites offered, in order of preference.
+    pub cipher_suites: Vec<u16>,
+    /// Extensions sent, in order unless they are shuffled.
+    pub extensions: Vec<HelloExtension>,
+    /// Named groups offered through the supported groups extension.
+    pub supported_groups: Vec<u16>,
+    /// Named groups that key shares are sent for through the key share extension.
+    pub key_shares: Vec<u16>,
+    /// Signature schemes offered through the signature algorithms extension.
+    pub signature_algorithms: Vec<u16>,
+    /// Versions offered through the supported versions extension, which should be TLS 1.3 and TLS 1.2 for a browser. When TLS 1.3 is offered, servers that pick TLS 1.2 while saying, as RFC 8446 has them do, that they could have picked TLS 1.3, fail the handshake, since someone in between must have made them.
+    pub versions: Vec<u16>,
+    /// Whether to send GREASE values, as in RFC 8701, among cipher suites, extensions, groups, key shares and versions, as Chromium-based browsers do. They are picked anew for every connection.
+    pub grease: bool,
+    /// Whether to shuffle the extensions for every connection, as Chromium-based browsers do. GREASE extensions stay first and last, and padding after them.
+    pub shuffle_extensions: bool,
+}
+
+/// An extension in a ClientHello. Most get their contents from the rest of the [ClientHelloProfile] and the [TlsConfig](crate::TlsConfig).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HelloExtension {
+    /// Server name indication, if the TLS config uses it.
+    ServerName,
+    /// Extended master secret, from RFC 7627.
+    ExtendedMasterSecret,
+    /// Empty secure renegotiation info.
+    RenegotiationInfo,
+    /// Supported groups.
+    SupportedGroups,
+    /// Uncompressed EC point formats.
+    EcPointFormats,
+    /// An empty session ticket.
+    SessionTicket,
+    /// ALPN with the protocols of the TLS config, left out if there are none.
+    Alpn,
+    /// OCSP status request.
+    StatusRequest,
+    /// Signature algorithms.
+    SignatureAlgorithms,
+    /// Signed certificate timestamps.
+    SignedCertificateTimestamp,
+    /// Key shares for TLS 1.3, for the groups in `key_shares`.
+    KeyShare,
+    /// PSK with (EC)DHE key exchange mode for TLS 1.3, though no PSK is ever offered, since sessions are never resumed.
+    PskKeyExchangeModes,
+    /// Supported versions.
+    SupportedVersions,
+    /// An encrypted client hello extension with a random payload, as browsers send when they have no ECH config for a server.
+    GreaseEch,
+    /// Padding that keeps the ClientHello from being between 256 and 511 bytes long, as BoringSSL adds to work around buggy servers. It is always sent last, and only when needed.
+    Padding,
+    /// An extension of the given type with the given contents.
+    Raw(u16, Vec<u8>),
+}
+
+impl ClientHelloProfile {
+    /// A ClientHello like that of Chrome 120 on any platform. Chrome offers `h2` and `http/1.1` through ALPN, which the TLS config should offer too.
+    pub fn chrome() -> Self {
+        Self {
+            cipher_suites: vec![
+                0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+                0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                HelloExtension::ServerName,
+                HelloExtension::ExtendedMasterSecret,
+                HelloExtension::RenegotiationInfo,
+                HelloExtension::SupportedGroups,
+                HelloExtension::EcPointFormats,
+                HelloExtension::SessionTicket,
+                HelloExtension::Alpn,
+                HelloExtension::StatusRequest,
+                HelloExtension::SignatureAlgorithms,
+                HelloExtension::SignedCertificateTimestamp,
+                HelloExtension::KeyShare,
+                HelloExtension::PskKeyExchangeModes,
+                HelloExtension::SupportedVersions,
+                // brotli certificate compression
+                HelloExtension::Raw(0x001b, vec![0x02, 0x00, 0x02]),
+                // application settings for h2
+                HelloExtension::Raw(0x4469, vec![0x00, 0x03, 0x02, b'h', b'2']),
+                HelloExtension::GreaseEch,
+                HelloExtension::Padding,
+            ],
+            supported_groups: vec![0x001d, 0x0017, 0x0018],
+            key_shares: vec![0x001d],
+            signature_algorithms: vec![
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+            ],
+            versions: vec![0x0304, 0x0303],
+            grease: true,
+            shuffle_extensions: true,
+        }
+    }
+
+    /// A ClientHello like that of Firefox 120 on any platform. Firefox offers `h2` and `http/1.1` through ALPN, which the TLS config should offer too.
+    pub fn firefox() -> Self {
+        Self {
+            cipher_suites: vec![
+                0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a,
+                0xc009, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                HelloExtension::ServerName,
+                HelloExtension::ExtendedMasterSecret,
+                HelloExtension::RenegotiationInfo,
+                HelloExtension::SupportedGroups,
+                HelloExtension::EcPointFormats,
+                HelloExtension::SessionTicket,
+                HelloExtension::Alpn,
+                HelloExtension::StatusRequest,
+                // delegated credentials
+                HelloExtension::Raw(
+                    0x0022,
+                    vec![0x00, 0x08, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x02, 0x03],
+                ),
+                HelloExtension::KeyShare,
+                HelloExtension::SupportedVersions,
+                HelloExtension::SignatureAlgorithms,
+                HelloExtension::PskKeyExchangeModes,
+                // record size limit
+                HelloExtension::Raw(0x001c, vec![0x40, 0x01]),
+                HelloExtension::GreaseEch,
+            ],
+            supported_groups: vec![0x001d, 0x0017, 0x0018, 0x0019, 0x0100, 0x0101],
+            key_shares: vec![0x001d, 0x0017],
+            signature_algorithms: vec![
+                0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203,
+                0x0201,
+            ],
+            versions: vec![0x0304, 0x0303],
+            grease: false,
+            shuffle_extensions: false,
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey};
+use smol::{net::TcpStream, prelude::*};
+
+use super::{
+    handshake::{KEY_UPDATE, NEW_SESSION_TICKET},
+    tls13::TrafficSecret,
+};
+
+// types of records
+pub(super) const CHANGE_CIPHER_SPEC: u8 = 20;
+pub(super) const ALERT: u8 = 21;
+pub(super) const HANDSHAKE: u8 = 22;
+pub(super) const APPLICATION_DATA: u8 = 23;
+
+/// Longest plaintext a record may carry.
+const MAX_FRAGMENT: usize = 16384;
+
+/// Longest ciphertext a record may carry.
+const MAX_CIPHERTEXT: usize = MAX_FRAGMENT + 2048;
+
+/// Length of a record header.
+const HEADER_LEN: usize = 5;
+
+/// Protection of the records going one way, with an AEAD cipher as TLS 1.2 or TLS 1.3 uses them.
+pub(super) struct RecordCipher {
+    key: LessSafeKey,
+    /// The IV, which for AES-GCM in TLS 1.2 is the implicit part of the nonce followed by zeros.
+    iv: [u8; 12],
+    /// Whether the rest of the nonce is sent with every record, as with AES-GCM in TLS 1.2, rather than being the sequence number, as otherwise.
+    explicit_nonce: bool,
+    /// For TLS 1.3, the traffic secret that the key and IV come from, and that key updates derive the next one from. None for TLS 1.2.
+    secret: Option<TrafficSecret>,
+    seq: u64,
+}
+
+impl RecordCipher {
+    pub fn new(
+        algorithm: &'static ring::aead::Algorithm,
+        key: &[u8],
+        iv: &[u8],
+        explicit_nonce: bool,
+    ) -> Self {
+        let mut full_iv = [0; 12];
+        full_iv[..iv.len()].copy_from_slice(iv);
+        Self {
+            key: LessSafeKey::new(UnboundKey::new(algorithm, key).expect("bad key length")),
+            iv: full_iv,
+            explicit_nonce,
+            secret: None,
+            seq: 0,
+        }
+    }
+
+    /// Creates a TLS 1.3 cipher, keyed with the given traffic secret.
+    pub fn tls13(secret: TrafficSecret) -> Self {
+        let (key, iv) = secret.key_and_iv();
+        let mut cipher = Self::new(secret.aead, &key, &iv, false);
+        cipher.secret = Some(secret);
+        cipher
+    }
+
+    /// Moves on to the next traffic secret, as a TLS 1.3 KeyUpdate asks.
+    fn update(&mut self) -> io::Result<()> {
+        let secret = self.secret.as_ref().ok_or_else(bad_record)?.next();
+        *self = Self::tls13(secret);
+        Ok(())
+    }
+
+    fn nonce(&self, nonce_suffix: [u8; 8]) -> Nonce {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[4..].iter_mut().zip(nonce_suffix.iter()) {
+            *n ^= s;
+        }
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Additional data of a record of the given type, which covers the sequence number and the length of the plaintext in TLS 1.2, but just the record header, with the length of the ciphertext, in TLS 1.3.
+    fn aad(&self, content_type: u8, len: usize) -> Aad<Vec<u8>> {
+        let mut aad = Vec::with_capacity(13);
+        if self.secret.is_none() {
+            aad.extend_from_slice(&self.seq.to_be_bytes());
+        }
+        aad.push(content_type);
+        aad.extend_from_slice(&[3, 3]);
+        aad.extend_from_slice(&(len as u16).to_be_bytes());
+        Aad::from(aad)
+    }
+
+    /// Appends a record carrying the given plaintext, which must fit in one record.
+    pub fn seal(&mut self, content_type: u8, plain: &[u8], out: &mut Vec<u8>) {
+        let nonce_suffix = self.seq.to_be_bytes();
+        let mut fragment = Vec::with_capacity(plain.len() + 24);
+        if self.explicit_nonce {
+            fragment.extend_from_slice(&nonce_suffix);
+        }
+        let start = fragment.len();
+        fragment.extend_from_slice(plain);
+        let (outer_type, aad) = if self.secret.is_some() {
+            // TLS 1.3 hides the real type inside, and makes every record look like application data
+            fragment.push(content_type);
+            let sealed_len = plain.len() + 1 + self.key.algorithm().tag_len();
+            (APPLICATION_DATA, self.aad(APPLICATION_DATA, sealed_len))
+        } else {
+            (content_type, self.aad(content_type, plain.len()))
+        };
+        let mut sealed = fragment.split_off(start);
+        self.key
+            .seal_in_place_append_tag(self.nonce(nonce_suffix), aad, &mut sealed)
+            .expect("cannot seal record");
+        fragment.extend_from_slice(&sealed);
+        self.seq += 1;
+        write_record(outer_type, &fragment, out);
+    }
+
+    /// Opens the fragment of a record in place, returning the real type of the record, which TLS 1.3 hides inside, and the plaintext.
+    fn open<'a>(&mut self, content_type: u8, fragment: &'a mut [u8]) -> io::Result<(u8, &'a [u8])> {
+        if self.secret.is_some() {
+            if content_type != APPLICATION_DATA {
+                return Err(bad_record());
+            }
+            let aad = self.aad(APPLICATION_DATA, fragment.len());
+            let plain = self
+                .key
+                .open_in_place(self.nonce(self.seq.to_be_bytes()), aad, fragment)
+                .map_err(|_| bad_record())?;
+            self.seq += 1;
+            // the real type is the last byte that is not padding
+            let type_pos = plain.iter().rposition(|b| *b != 0).ok_or_else(bad_record)?;
+            return Ok((plain[type_pos], &plain[..type_pos]));
+        }
+        let (nonce_suffix, sealed) = if self.explicit_nonce {
+            if fragment.len() < 8 {
+                return Err(bad_record());
+            }
+            let (explicit, sealed) = fragment.split_at_mut(8);
+            let mut nonce_suffix = [0; 8];
+            nonce_suffix.copy_from_slice(explicit);
+            (nonce_suffix, sealed)
+        } else {
+            (self.seq.to_be_bytes(), fragment)
+        };
+        let plain_len = sealed
+            .len()
+            .checked_sub(self.key.algorithm().tag_len())
+            .ok_or_else(bad_record)?;
+        let plain = self
+            .key
+            .open_in_place(
+                self.nonce(nonce_suffix),
+                self.aad(content_type, plain_len),
+                sealed,
+            )
+            .map_err(|_| bad_record())?;
+        self.seq += 1;
+        Ok((content_type, plain))
+    }
+}
+
+/// Appends a record carrying the given fragment as is.
+pub(super) fn write_record(content_type: u8, fragment: &[u8], out: &mut Vec<u8>) {
+    out.push(content_type);
+    out.extend_from_slice(&[3, 3]);
+    out.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+    out.extend_from_slice(fragment);
+}
+
+fn bad_record() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "bad TLS record")
+}
+
+/// The reading half of a TLS connection.
+pub(crate) struct TlsReader {
+    tcp: TcpStream,
+    /// How records are protected, or None while they are still in the clear.
+    pub(super) cipher: Option<RecordCipher>,
+    /// Bytes read that do not yet make up a whole record.
+    raw: Vec<u8>,
+    /// Plaintext of the last application data record, which is being read.
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// Handshake messages that TLS 1.3 servers send after the handshake, which may be split between records.
+    handshake: Vec<u8>,
+    /// Set when the server asks for a key update, which the writing half then sends.
+    pub(super) update_requested: Arc<AtomicBool>,
+    closed: bool,
+}
+
+impl TlsReader {
+    pub(super) fn new(tcp: TcpStream) -> Self {
+        Self {
+            tcp,
+            cipher: None,
+            raw: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            handshake: Vec::new(),
+            update_requested: Arc::new(AtomicBool::new(false)),
+            closed: false,
+        }
+    }
+
+    /// Reads the next record, returning its type and plaintext.
+    pub(super) fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(u8, Vec<u8>)>> {
+        loop {
+            if self.raw.len() >= HEADER_LEN {
+                let len = u16::from_be_bytes([self.raw[3], self.raw[4]]) as usize;
+                if len > MAX_CIPHERTEXT {
+                    return Poll::Ready(Err(bad_record()));
+                }
+                if self.raw.len() >= HEADER_LEN + len {
+                    let content_type = self.raw[0];
+                    let mut fragment = self.raw[HEADER_LEN..HEADER_LEN + len].to_vec();
+                    self.raw.drain(..HEADER_LEN + len);
+                    return Poll::Ready(Ok(match &mut self.cipher {
+                        // ChangeCipherSpec is never protected
+                        Some(cipher) if content_type != CHANGE_CIPHER_SPEC => {
+                            let (content_type, plain) = cipher.open(content_type, &mut fragment)?;
+                            (content_type, plain.to_vec())
+                        }
+                        _ => (content_type, fragment),
+                    }));
+                }
+            }
+            let mut chunk = [0; 8192];
+            let n = match Pin::new(&mut self.tcp).poll_read(cx, &mut chunk) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.raw.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Reads the next record.
+    pub(super) async fn read_record(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        futures_util::future::poll_fn(|cx| self.poll_record(cx)).await
+    }
+
+    /// Handles the handshake messages that TLS 1.3 servers may send after the handshake: session tickets, which are ignored, since sessions are never resumed, and key updates.
+    fn post_handshake(&mut self, fragment: &[u8]) -> io::Result<()> {
+        let cipher = self.cipher.as_mut().ok_or_else(bad_record)?;
+        if cipher.secret.is_none() {
+            return Err(bad_record());
+        }
+        self.handshake.extend_from_slice(fragment);
+        while self.handshake.len() >= 4 {
+            let len =
+                u32::from_be_bytes([0, self.handshake[1], self.handshake[2], self.handshake[3]])
+                    as usize;
+            if self.handshake.len() < 4 + len {
+                break;
+            }
+            let msg: Vec<u8> = self.handshake.drain(..4 + len).collect();
+            match msg[0] {
+                NEW_SESSION_TICKET => {}
+                // a key update must end the record it comes in
+                KEY_UPDATE if len == 1 && self.handshake.is_empty() => {
+                    cipher.update()?;
+                    if msg[4] == 1 {
+                        self.update_requested.store(true, Ordering::Relaxed);
+                    }
+                }
+                _ => return Err(bad_record()),
+            }
+        }
+        if self.handshake.len() > MAX_CIPHERTEXT {
+            return Err(bad_record());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for TlsReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.plain_pos == this.plain.len() {
+            if this.closed {
+                return Poll::Ready(Ok(0));
+            }
+            let (content_type, plain) = match this.poll_record(cx) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            match content_type {
+                APPLICATION_DATA => {
+                    this.plain = plain;
+                    this.plain_pos = 0;
+                }
+                HANDSHAKE => this.post_handshake(&plain)?,
+                // close_notify
+                ALERT if plain.get(1) == Some(&0) => this.closed = true,
+                ALERT => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("TLS alert {:?}", plain),
+                    )))
+                }
+                _ => return Poll::Ready(Err(bad_record())),
+            }
+        }
+        let n = buf.len().min(this.plain.len() - this.plain_pos);
+        buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+        this.plain_pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// The writing half of a TLS connection.
+pub(crate) struct TlsWriter {
+    tcp: TcpStream,
+    cipher: RecordCipher,
+    /// Record not yet written out, and how much of it has been.
+    pending: Vec<u8>,
+    written: usize,
+    /// Length of the plaintext of the pending record.
+    pending_plain: usize,
+    /// Set by the reading half when the server asks for a key update.
+    update_requested: Arc<AtomicBool>,
+}
+
+impl TlsWriter {
+    /// Creates the writing half of the connection whose reading half is given.
+    pub(super) fn new(tcp: TcpStream, cipher: RecordCipher, reader: &TlsReader) -> Self {
+        Self {
+            tcp,
+            cipher,
+            pending: Vec::new(),
+            written: 0,
+            pending_plain: 0,
+            update_requested: reader.update_requested.clone(),
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = match Pin::new(&mut self.tcp).poll_write(cx, &self.pending[self.written..]) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TlsWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // like other TLS streams, writes each record through before taking more, expecting to be called with the same bytes again until it does
+        if this.pending.is_empty() {
+            if this.update_requested.swap(false, Ordering::Relaxed) {
+                // a KeyUpdate that asks for none back, under the old key
+                this.cipher
+                    .seal(HANDSHAKE, &[KEY_UPDATE, 0, 0, 1, 0], &mut this.pending);
+                this.cipher.update()?;
+            }
+            this.pending_plain = buf.len().min(MAX_FRAGMENT);
+            this.cipher.seal(
+                APPLICATION_DATA,
+                &buf[..this.pending_plain],
+                &mut this.pending,
+            );
+        }
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.pending_plain)),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.tcp).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.tcp).poll_close(cx),
+            other => other,
+        }
+    }
+}
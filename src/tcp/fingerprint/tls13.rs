@@ -0,0 +1,359 @@
+use std::io::Read;
+
+use anyhow::Context;
+use ring::{digest, hmac};
+use smol::prelude::*;
+
+use crate::mimic::with_len;
+
+use super::{
+    certs::{check_chain, verify_signature},
+    handshake::{
+        agree, check_alpn, handshake_message, Handshake, Reader, CERTIFICATE, CERTIFICATE_REQUEST,
+        CERTIFICATE_VERIFY, COMPRESSED_CERTIFICATE, ENCRYPTED_EXTENSIONS, FINISHED,
+        MAX_MESSAGE_LEN,
+    },
+    record::{write_record, RecordCipher, TlsReader, TlsWriter, CHANGE_CIPHER_SPEC, HANDSHAKE},
+};
+
+/// A TLS 1.3 traffic secret, which protects the records going one way, along with the algorithms of the cipher suite.
+#[derive(Clone)]
+pub(super) struct TrafficSecret {
+    pub aead: &'static ring::aead::Algorithm,
+    prf: hmac::Algorithm,
+    secret: Vec<u8>,
+}
+
+impl TrafficSecret {
+    /// The key and IV that records are protected with.
+    pub fn key_and_iv(&self) -> (Vec<u8>, Vec<u8>) {
+        (
+            expand_label(self.prf, &self.secret, b"key", &[], self.aead.key_len()),
+            expand_label(self.prf, &self.secret, b"iv", &[], 12),
+        )
+    }
+
+    /// The secret that follows this one after a key update.
+    pub fn next(&self) -> Self {
+        Self {
+            secret: expand_label(
+                self.prf,
+                &self.secret,
+                b"traffic upd",
+                &[],
+                self.secret.len(),
+            ),
+            ..self.clone()
+        }
+    }
+
+    /// Computes the contents of the Finished message sent under this secret, given the hash of the transcript before it.
+    fn verify_data(&self, transcript_hash: &[u8]) -> hmac::Tag {
+        let key = expand_label(self.prf, &self.secret, b"finished", &[], self.secret.len());
+        hmac::sign(&hmac::Key::new(self.prf, &key), transcript_hash)
+    }
+}
+
+/// Finishes a TLS 1.3 handshake, once the ServerHello has been read. Sessions are never resumed, so the server must authenticate with a certificate.
+pub(super) async fn finish(mut hs: Handshake<'_>) -> anyhow::Result<(TlsWriter, TlsReader)> {
+    let suite = hs.suite;
+    let (group, server_public) = hs
+        .server_hello
+        .key_share
+        .take()
+        .context("server sent no key share")?;
+    if !hs.key_shares.iter().any(|(g, _)| *g == group) {
+        anyhow::bail!(
+            "server picked group {:04x}, which we sent no key share for",
+            group
+        )
+    }
+    let (private, _) = hs.take_key(group)?;
+    let shared = agree(private, group, &server_public)?;
+
+    // the key schedule, up to the handshake traffic secrets
+    let zeros = vec![0; suite.hash.output_len];
+    let empty_hash = digest::digest(suite.hash, &[]);
+    let early_secret = extract(suite.prf, &zeros, &zeros);
+    let handshake_secret = extract(
+        suite.prf,
+        &derive_secret(suite.prf, &early_secret, b"derived", empty_hash.as_ref()),
+        &shared,
+    );
+    let master_secret = extract(
+        suite.prf,
+        &derive_secret(
+            suite.prf,
+            &handshake_secret,
+            b"derived",
+            empty_hash.as_ref(),
+        ),
+        &zeros,
+    );
+    let traffic_secret = |base: &[u8], label: &[u8], transcript: &[u8]| TrafficSecret {
+        aead: suite.aead,
+        prf: suite.prf,
+        secret: derive_secret(
+            suite.prf,
+            base,
+            label,
+            digest::digest(suite.hash, transcript).as_ref(),
+        ),
+    };
+    let client_handshake = traffic_secret(&handshake_secret, b"c hs traffic", &hs.transcript);
+    let server_handshake = traffic_secret(&handshake_secret, b"s hs traffic", &hs.transcript);
+    if !hs.messages.buf.is_empty() {
+        anyhow::bail!("unexpected handshake data after ServerHello")
+    }
+    hs.messages.reader.cipher = Some(RecordCipher::tls13(server_handshake.clone()));
+
+    let msg = hs.messages.next().await?;
+    if msg[0] != ENCRYPTED_EXTENSIONS {
+        anyhow::bail!(
+            "expected EncryptedExtensions, got handshake message {}",
+            msg[0]
+        )
+    }
+    hs.transcript.extend_from_slice(&msg);
+    let mut extensions = Reader(Reader(&msg[4..]).vec(2)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut contents = Reader(extensions.vec(2)?);
+        if ext_type == 0x0010 {
+            let mut protocols = Reader(contents.vec(2)?);
+            check_alpn(hs.cfg, protocols.vec(1)?)?;
+        }
+    }
+
+    let mut cert_request = None;
+    let mut chain: Option<Vec<Vec<u8>>> = None;
+    let mut cert_verified = false;
+    loop {
+        let msg = hs.messages.next().await?;
+        let transcript_hash = digest::digest(suite.hash, &hs.transcript);
+        hs.transcript.extend_from_slice(&msg);
+        let mut body = Reader(&msg[4..]);
+        match msg[0] {
+            CERTIFICATE_REQUEST if chain.is_none() => cert_request = Some(body.vec(1)?.to_vec()),
+            CERTIFICATE if chain.is_none() => {
+                let certs = parse_certificate(body.0)?;
+                check_chain(&certs, hs.server_name, &hs.cfg.verify)?;
+                chain = Some(certs);
+            }
+            COMPRESSED_CERTIFICATE if chain.is_none() => {
+                let certs = parse_certificate(&decompress_certificate(&mut body)?)?;
+                check_chain(&certs, hs.server_name, &hs.cfg.verify)?;
+                chain = Some(certs);
+            }
+            CERTIFICATE_VERIFY if !cert_verified => {
+                let chain = chain
+                    .as_ref()
+                    .context("CertificateVerify came before certificate")?;
+                let scheme = body.u16()?;
+                let sig = body.vec(2)?;
+                if !hs.profile.signature_algorithms.contains(&scheme) {
+                    anyhow::bail!("server signed with {:04x}, which we did not offer", scheme)
+                }
+                // PKCS #1 signatures are only for certificates in TLS 1.3
+                if scheme & 0xff == 0x01 {
+                    anyhow::bail!("server signed with {:04x}, which TLS 1.3 forbids", scheme)
+                }
+                let signed = [
+                    &[0x20; 64][..],
+                    b"TLS 1.3, server CertificateVerify\0",
+                    transcript_hash.as_ref(),
+                ]
+                .concat();
+                verify_signature(&chain[0], scheme, &signed, sig)?;
+                cert_verified = true;
+            }
+            FINISHED if cert_verified => {
+                let expected = server_handshake.verify_data(transcript_hash.as_ref());
+                if ring::constant_time::verify_slices_are_equal(body.0, expected.as_ref()).is_err()
+                {
+                    anyhow::bail!("bad Finished from server")
+                }
+                break;
+            }
+            other => anyhow::bail!("unexpected handshake message {}", other),
+        }
+    }
+    if !hs.messages.buf.is_empty() {
+        anyhow::bail!("unexpected handshake data after Finished")
+    }
+    let client_application = traffic_secret(&master_secret, b"c ap traffic", &hs.transcript);
+    let server_application = traffic_secret(&master_secret, b"s ap traffic", &hs.transcript);
+
+    // our flight, protected but for the ChangeCipherSpec that browsers send for middleboxes
+    let mut flight = Vec::new();
+    if !hs.sent_ccs {
+        write_record(CHANGE_CIPHER_SPEC, &[1], &mut flight);
+    }
+    let mut handshake_cipher = RecordCipher::tls13(client_handshake.clone());
+    if let Some(context) = cert_request {
+        // an empty certificate, since we have none
+        let cert = handshake_message(CERTIFICATE, |out| {
+            with_len(out, 1, |out| out.extend_from_slice(&context));
+            with_len(out, 3, |_| {});
+        });
+        hs.transcript.extend_from_slice(&cert);
+        handshake_cipher.seal(HANDSHAKE, &cert, &mut flight);
+    }
+    let verify_data =
+        client_handshake.verify_data(digest::digest(suite.hash, &hs.transcript).as_ref());
+    let finished = handshake_message(FINISHED, |out| out.extend_from_slice(verify_data.as_ref()));
+    handshake_cipher.seal(HANDSHAKE, &finished, &mut flight);
+    hs.write_tcp.write_all(&flight).await?;
+
+    hs.messages.reader.cipher = Some(RecordCipher::tls13(server_application));
+    let writer = TlsWriter::new(
+        hs.write_tcp,
+        RecordCipher::tls13(client_application),
+        &hs.messages.reader,
+    );
+    Ok((writer, hs.messages.reader))
+}
+
+/// Parses the body of a Certificate message into the certificates of the chain, leaving out their extensions.
+fn parse_certificate(body: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut body = Reader(body);
+    body.vec(1)?;
+    let mut entries = Reader(body.vec(3)?);
+    let mut chain = Vec::new();
+    while !entries.0.is_empty() {
+        chain.push(entries.vec(3)?.to_vec());
+        entries.vec(2)?;
+    }
+    Ok(chain)
+}
+
+/// Decompresses the body of a CompressedCertificate message, from RFC 8879, which servers send when offered certificate compression, as Chrome offers it with brotli.
+fn decompress_certificate(body: &mut Reader) -> anyhow::Result<Vec<u8>> {
+    let algorithm = body.u16()?;
+    if algorithm != 2 {
+        anyhow::bail!(
+            "server compressed its certificate with {}, which we cannot undo",
+            algorithm
+        )
+    }
+    let len = body.u24()?;
+    if len > MAX_MESSAGE_LEN {
+        anyhow::bail!("compressed certificate too long")
+    }
+    let mut decompressed = Vec::with_capacity(len);
+    brotli_decompressor::Decompressor::new(body.vec(3)?, 4096)
+        .take(len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .context("cannot decompress certificate")?;
+    if decompressed.len() != len {
+        anyhow::bail!("compressed certificate has the wrong length")
+    }
+    Ok(decompressed)
+}
+
+/// HKDF-Extract, from RFC 5869.
+fn extract(prf: hmac::Algorithm, salt: &[u8], secret: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(prf, salt), secret)
+        .as_ref()
+        .to_vec()
+}
+
+/// HKDF-Expand-Label, from RFC 8446.
+fn expand_label(
+    prf: hmac::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    context: &[u8],
+    len: usize,
+) -> Vec<u8> {
+    let mut info = (len as u16).to_be_bytes().to_vec();
+    with_len(&mut info, 1, |out| {
+        out.extend_from_slice(b"tls13 ");
+        out.extend_from_slice(label);
+    });
+    with_len(&mut info, 1, |out| out.extend_from_slice(context));
+    let key = hmac::Key::new(prf, secret);
+    let mut out = Vec::with_capacity(len + 64);
+    let mut block = Vec::new();
+    let mut counter = 1u8;
+    while out.len() < len {
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(&block);
+        ctx.update(&info);
+        ctx.update(&[counter]);
+        block = ctx.sign().as_ref().to_vec();
+        out.extend_from_slice(&block);
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+/// Derive-Secret, from RFC 8446, given the hash of the transcript.
+fn derive_secret(
+    prf: hmac::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    transcript_hash: &[u8],
+) -> Vec<u8> {
+    expand_label(
+        prf,
+        secret,
+        label,
+        transcript_hash,
+        prf.digest_algorithm().output_len,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    #[test]
+    fn key_schedule_matches_rfc8448() {
+        // the simple 1-RTT handshake of RFC 8448
+        let prf = hmac::HMAC_SHA256;
+        let zeros = [0; 32];
+        let empty_hash = digest::digest(&digest::SHA256, &[]);
+        let early_secret = extract(prf, &zeros, &zeros);
+        assert_eq!(
+            early_secret,
+            unhex("33ad0a1c607ec03b09e6cd9893680ce210adf300aa1f2660e1b22e10f170f92a")
+        );
+        let derived = derive_secret(prf, &early_secret, b"derived", empty_hash.as_ref());
+        assert_eq!(
+            derived,
+            unhex("6f2615a108c702c5678f54fc9dbab69716c076189c48250cebeac3576c3611ba")
+        );
+        let handshake_secret = extract(
+            prf,
+            &derived,
+            &unhex("8bd4054fb55b9d63fdfbacf9f04b9f0d35e6d63f537563efd46272900f89492d"),
+        );
+        assert_eq!(
+            handshake_secret,
+            unhex("1dc826e93606aa6fdc0aadc12f741b01046aa6b99f691ed221a9f0ca043fbeac")
+        );
+        let server_handshake = TrafficSecret {
+            aead: &ring::aead::AES_128_GCM,
+            prf,
+            secret: derive_secret(
+                prf,
+                &handshake_secret,
+                b"s hs traffic",
+                &unhex("860c06edc07858ee8e78f0e7428c58edd6b43f2ca3e6e95f02ed063cf0e1cad8"),
+            ),
+        };
+        assert_eq!(
+            server_handshake.secret,
+            unhex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38")
+        );
+        let (key, iv) = server_handshake.key_and_iv();
+        assert_eq!(key, unhex("3fce516009c21727d0f2e4e86ee403bc"));
+        assert_eq!(iv, unhex("5d313eb2671276ee13000b30"));
+    }
+}
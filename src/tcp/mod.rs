@@ -10,13 +10,24 @@ use smol::io::BufReader;
 use smol::prelude::*;
 
 mod client;
+mod fingerprint;
+mod proxy;
+mod tls_config;
 mod tls_helpers;
+mod websocket;
 pub use client::*;
+pub use fingerprint::{ClientHelloProfile, HelloExtension};
+pub use proxy::*;
+pub use tls_config::*;
+pub use websocket::WebSocketConfig;
 mod server;
 pub use server::*;
 
 use crate::{buffer::Buff, crypt::NgAead};
 
+/// Longest HTTP head accepted, as in WebSocket handshakes and proxy responses.
+const MAX_HTTP_HEAD_LEN: usize = 8192;
+
 const CONN_LIFETIME: Duration = Duration::from_secs(600);
 
 const TCP_UP_KEY: &[u8; 32] = b"uploadtcp-----------------------";
@@ -119,3 +130,19 @@ async fn write_encrypted<W: AsyncWrite + Unpin>(
     writer.write_all(&to_send).await?;
     Ok(())
 }
+
+/// Reads an HTTP head, up to and including the empty line that ends it, a byte at a time so that nothing past it is read.
+async fn read_http_head<R: AsyncRead + Unpin>(rdr: &mut R) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        if rdr.read(&mut byte).await? == 0 {
+            anyhow::bail!("connection closed in the middle of an HTTP head")
+        }
+        if head.len() >= MAX_HTTP_HEAD_LEN {
+            anyhow::bail!("HTTP head too long")
+        }
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8(head)?)
+}
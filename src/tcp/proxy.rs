@@ -0,0 +1,439 @@
+use std::{io, net::SocketAddr};
+
+use smol::{net::TcpStream, prelude::*};
+
+use crate::Connector;
+
+use super::read_http_head;
+
+/// An HTTP proxy that TCP connections are tunneled through with `CONNECT`, as on networks where nothing else gets out.
+#[derive(Clone, Debug)]
+pub struct HttpProxy {
+    /// Address of the proxy.
+    pub addr: SocketAddr,
+    /// Username and password to log in to the proxy with basic authentication, if it wants them.
+    pub auth: Option<(String, String)>,
+}
+
+impl HttpProxy {
+    /// Creates a proxy at the given address, which needs no authentication.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, auth: None }
+    }
+
+    /// Logs in to the proxy with the given username and password.
+    pub fn auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Creates a connector that tunnels connections through this proxy, for [Protocol::ProxiedTcp](crate::Protocol::ProxiedTcp), [Protocol::ProxiedTls](crate::Protocol::ProxiedTls) or [WebSocketConfig::connector](crate::WebSocketConfig::connector).
+    pub fn connector(self) -> Connector {
+        std::sync::Arc::new(move |dest| {
+            let proxy = self.clone();
+            async move { proxy.connect(dest).await }.boxed()
+        })
+    }
+
+    /// Opens a tunnel to the given address.
+    async fn connect(&self, dest: SocketAddr) -> io::Result<TcpStream> {
+        let mut tcp = TcpStream::connect(self.addr).await?;
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", dest, dest);
+        if let Some((username, password)) = &self.auth {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", username, password))
+            ));
+        }
+        request.push_str("\r\n");
+        tcp.write_all(request.as_bytes()).await?;
+        let head = read_http_head(&mut tcp)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let status = head.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(tcp),
+            Some("407") => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("proxy wants other credentials: {}", status),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("proxy refused to connect to {}: {}", dest, status),
+            )),
+        }
+    }
+}
+
+/// A SOCKS5 proxy that TCP connections are opened through, such as one that SSH or Tor runs.
+#[derive(Clone, Debug)]
+pub struct Socks5Proxy {
+    /// Address of the proxy.
+    pub addr: SocketAddr,
+    /// Username and password to log in to the proxy with, as in RFC 1929, if it wants them.
+    pub auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    /// Creates a proxy at the given address, which needs no authentication.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, auth: None }
+    }
+
+    /// Logs in to the proxy with the given username and password.
+    pub fn auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Creates a connector that opens connections through this proxy, for [Protocol::ProxiedTcp](crate::Protocol::ProxiedTcp), [Protocol::ProxiedTls](crate::Protocol::ProxiedTls) or [WebSocketConfig::connector](crate::WebSocketConfig::connector).
+    pub fn connector(self) -> Connector {
+        std::sync::Arc::new(move |dest| {
+            let proxy = self.clone();
+            async move { proxy.connect(dest).await }.boxed()
+        })
+    }
+
+    /// Opens a connection to the given address.
+    async fn connect(&self, dest: SocketAddr) -> io::Result<TcpStream> {
+        let mut tcp = TcpStream::connect(self.addr).await?;
+        // offer username and password authentication only when we have them
+        let methods: &[u8] = if self.auth.is_some() { &[0, 2] } else { &[0] };
+        let mut greeting = vec![5, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        tcp.write_all(&greeting).await?;
+        let mut choice = [0; 2];
+        tcp.read_exact(&mut choice).await?;
+        if choice[0] != 5 {
+            return Err(bad_socks());
+        }
+        match (choice[1], &self.auth) {
+            (0, _) => {}
+            (2, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "SOCKS5 username or password too long",
+                    ));
+                }
+                let mut login = vec![1, username.len() as u8];
+                login.extend_from_slice(username.as_bytes());
+                login.push(password.len() as u8);
+                login.extend_from_slice(password.as_bytes());
+                tcp.write_all(&login).await?;
+                let mut status = [0; 2];
+                tcp.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 proxy rejected our credentials",
+                    ));
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy wants an authentication method we do not have",
+                ))
+            }
+        }
+        let mut request = vec![5, 1, 0];
+        match dest {
+            SocketAddr::V4(addr) => {
+                request.push(1);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                request.push(4);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        request.extend_from_slice(&dest.port().to_be_bytes());
+        tcp.write_all(&request).await?;
+        let mut reply = [0; 4];
+        tcp.read_exact(&mut reply).await?;
+        if reply[0] != 5 {
+            return Err(bad_socks());
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("SOCKS5 proxy refused to connect to {}: {}", dest, reply[1]),
+            ));
+        }
+        // skip the bound address, which we do not need
+        let bound_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                tcp.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(bad_socks()),
+        };
+        tcp.read_exact(&mut vec![0; bound_len + 2]).await?;
+        Ok(tcp)
+    }
+}
+
+fn bad_socks() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "bad SOCKS5 reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::TcpListener;
+
+    use crate::{
+        buffer::Buff,
+        tcp::{TcpClientBackhaul, TcpServerBackhaul, TlsConfig, TlsServer, TlsVersion},
+        Backhaul,
+    };
+
+    use super::*;
+
+    const USERNAME: &str = "user";
+    const PASSWORD: &str = "pass";
+
+    /// Serves every connection from a new listener with the given function.
+    async fn serve<F: Future<Output = io::Result<()>> + Send + 'static>(
+        serve_one: impl Fn(TcpStream) -> F + Send + 'static,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        smol::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                smol::spawn(serve_one(tcp)).detach();
+            }
+        })
+        .detach();
+        addr
+    }
+
+    async fn echo_server() -> SocketAddr {
+        serve(|tcp| async move {
+            smol::io::copy(tcp.clone(), &mut tcp.clone()).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// An address that nothing listens on.
+    async fn nowhere() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn forward(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+        smol::io::copy(client.clone(), &mut upstream.clone())
+            .race(smol::io::copy(upstream, &mut client.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Stands in for an HTTP proxy, wanting the test credentials through basic authentication if `auth` is set, and sending the heads of the requests it gets down the returned channel.
+    async fn http_stand_in(auth: bool) -> (SocketAddr, smol::channel::Receiver<String>) {
+        let (send_head, recv_head) = smol::channel::unbounded();
+        let addr = serve(move |mut tcp| {
+            let send_head = send_head.clone();
+            async move {
+                let head = read_http_head(&mut tcp)
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let _ = send_head.send(head.clone()).await;
+                let credentials = format!(
+                    "Proxy-Authorization: Basic {}",
+                    base64::encode(format!("{}:{}", USERNAME, PASSWORD))
+                );
+                if auth && !head.lines().any(|line| line == credentials) {
+                    tcp.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"test\"\r\n\r\n").await?;
+                    return Ok(());
+                }
+                let dest = head.split_whitespace().nth(1).unwrap_or_default();
+                match TcpStream::connect(dest).await {
+                    Ok(upstream) => {
+                        tcp.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                            .await?;
+                        forward(tcp, upstream).await
+                    }
+                    Err(_) => {
+                        tcp.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+                        Ok(())
+                    }
+                }
+            }
+        })
+        .await;
+        (addr, recv_head)
+    }
+
+    /// Stands in for a SOCKS5 proxy, wanting the test credentials if `auth` is set.
+    async fn socks_stand_in(auth: bool) -> SocketAddr {
+        serve(move |mut tcp| async move {
+            let mut greeting = [0; 2];
+            tcp.read_exact(&mut greeting).await?;
+            let mut methods = vec![0; greeting[1] as usize];
+            tcp.read_exact(&mut methods).await?;
+            let method = if auth { 2 } else { 0 };
+            if !methods.contains(&method) {
+                return tcp.write_all(&[5, 0xff]).await;
+            }
+            tcp.write_all(&[5, method]).await?;
+            if auth {
+                let mut len = [0; 2];
+                tcp.read_exact(&mut len).await?;
+                let mut username = vec![0; len[1] as usize];
+                tcp.read_exact(&mut username).await?;
+                tcp.read_exact(&mut len[..1]).await?;
+                let mut password = vec![0; len[0] as usize];
+                tcp.read_exact(&mut password).await?;
+                if username != USERNAME.as_bytes() || password != PASSWORD.as_bytes() {
+                    return tcp.write_all(&[1, 1]).await;
+                }
+                tcp.write_all(&[1, 0]).await?;
+            }
+            let mut request = [0; 4];
+            tcp.read_exact(&mut request).await?;
+            assert_eq!(request[..3], [5, 1, 0]);
+            assert_eq!(request[3], 1);
+            let mut dest = [0; 6];
+            tcp.read_exact(&mut dest).await?;
+            let dest = SocketAddr::from((
+                [dest[0], dest[1], dest[2], dest[3]],
+                u16::from_be_bytes([dest[4], dest[5]]),
+            ));
+            match TcpStream::connect(dest).await {
+                Ok(upstream) => {
+                    tcp.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+                    forward(tcp, upstream).await
+                }
+                // connection refused
+                Err(_) => tcp.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await,
+            }
+        })
+        .await
+    }
+
+    /// Checks that the connector reaches the echo server.
+    async fn echoes(connector: &Connector, echo: SocketAddr) -> io::Result<()> {
+        let mut tcp = connector(echo).await?;
+        tcp.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        tcp.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn http_proxies_tunnel() {
+        smol::block_on(async {
+            let echo = echo_server().await;
+            let (addr, heads) = http_stand_in(false).await;
+            let connector = HttpProxy::new(addr).connector();
+            echoes(&connector, echo).await.unwrap();
+            let head = heads.recv().await.unwrap();
+            assert!(head.starts_with(&format!("CONNECT {} HTTP/1.1\r\n", echo)));
+            assert!(!head.to_lowercase().contains("proxy-authorization"));
+            let err = connector(nowhere().await).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        })
+    }
+
+    #[test]
+    fn http_proxies_log_in() {
+        smol::block_on(async {
+            let echo = echo_server().await;
+            let (addr, _heads) = http_stand_in(true).await;
+            echoes(
+                &HttpProxy::new(addr).auth(USERNAME, PASSWORD).connector(),
+                echo,
+            )
+            .await
+            .unwrap();
+            for proxy in [
+                HttpProxy::new(addr),
+                HttpProxy::new(addr).auth(USERNAME, "wrong"),
+            ] {
+                let err = echoes(&proxy.connector(), echo).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            }
+        })
+    }
+
+    #[test]
+    fn socks5_proxies_tunnel() {
+        smol::block_on(async {
+            let echo = echo_server().await;
+            let connector = Socks5Proxy::new(socks_stand_in(false).await).connector();
+            echoes(&connector, echo).await.unwrap();
+            let err = connector(nowhere().await).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        })
+    }
+
+    #[test]
+    fn socks5_proxies_log_in() {
+        smol::block_on(async {
+            let echo = echo_server().await;
+            let addr = socks_stand_in(true).await;
+            echoes(
+                &Socks5Proxy::new(addr).auth(USERNAME, PASSWORD).connector(),
+                echo,
+            )
+            .await
+            .unwrap();
+            let err = echoes(
+                &Socks5Proxy::new(addr).auth(USERNAME, "wrong").connector(),
+                echo,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(err.to_string().contains("rejected our credentials"));
+            let err = echoes(&Socks5Proxy::new(addr).connector(), echo)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        })
+    }
+
+    #[test]
+    fn tls_goes_through_proxies() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            let server = TcpServerBackhaul::new(
+                listener,
+                vec![long_sk.clone()],
+                TlsServer::new(None, Vec::new(), (None, Some(TlsVersion::Tls12))).unwrap(),
+            );
+            let (http, heads) = http_stand_in(true).await;
+            for connector in [
+                HttpProxy::new(http).auth(USERNAME, PASSWORD).connector(),
+                Socks5Proxy::new(socks_stand_in(true).await)
+                    .auth(USERNAME, PASSWORD)
+                    .connector(),
+            ] {
+                let client = TcpClientBackhaul::new(Some(connector), Some(TlsConfig::default()))
+                    .add_remote_key(server_addr, (&long_sk).into());
+                for i in 0..5u8 {
+                    let up = Buff::copy_from_slice(&[i; 100]);
+                    client.send_to(up.clone(), server_addr).await.unwrap();
+                    let (received, client_addr) = server.recv_from().await.unwrap();
+                    assert_eq!(received, up);
+                    let down = Buff::copy_from_slice(&[i; 200]);
+                    server.send_to(down.clone(), client_addr).await.unwrap();
+                    let (received, from) = client.recv_from().await.unwrap();
+                    assert_eq!((received, from), (down, server_addr));
+                }
+            }
+            let head = heads.recv().await.unwrap();
+            assert!(head.starts_with(&format!("CONNECT {} ", server_addr)));
+        })
+    }
+}
@@ -21,8 +21,8 @@ use crate::{
 };
 
 use super::{
-    tls_helpers::opportunistic_tls_serve, write_encrypted, ObfsTcp, CONN_LIFETIME, TCP_DN_KEY,
-    TCP_UP_KEY,
+    tls_helpers::opportunistic_tls_serve, websocket::opportunistic_websocket_serve,
+    write_encrypted, ObfsTcp, TlsServer, CONN_LIFETIME, TCP_DN_KEY, TCP_UP_KEY,
 };
 
 /// A TCP-based backhaul, server-side.
@@ -33,13 +33,25 @@ pub struct TcpServerBackhaul {
 }
 
 impl TcpServerBackhaul {
-    /// Creates a new TCP server-side backhaul.
-    pub fn new(listener: TcpListener, seckey: x25519_dalek::StaticSecret) -> Self {
+    /// Creates a new TCP server-side backhaul, accepting clients that use any of the given keys, serving TLS to those that want it as the given server does, and WebSocket, inside TLS or not, to those that want that.
+    pub(crate) fn new(
+        listener: TcpListener,
+        seckeys: Vec<x25519_dalek::StaticSecret>,
+        tls: TlsServer,
+    ) -> Self {
         let down_table = Arc::new(DownTable::default());
         let table_cloned = down_table.clone();
         let (send_upcoming, recv_upcoming) = smol::channel::bounded(1000);
         let _task = runtime::spawn(async move {
-            if let Err(err) = backhaul_loop(listener, seckey, table_cloned, send_upcoming).await {
+            if let Err(err) = backhaul_loop(
+                listener,
+                seckeys,
+                Arc::new(tls),
+                table_cloned,
+                send_upcoming,
+            )
+            .await
+            {
                 tracing::debug!("backhaul_loop exited: {:?}", err)
             }
         });
@@ -64,22 +76,28 @@ impl Backhaul for TcpServerBackhaul {
         self.down_table.send_to(to_send, dest);
         Ok(())
     }
+
+    fn peer_addr(&self, addr: SocketAddr) -> SocketAddr {
+        self.down_table.peer_addr(addr).unwrap_or(addr)
+    }
 }
 
 async fn backhaul_loop(
     listener: TcpListener,
-    seckey: x25519_dalek::StaticSecret,
+    seckeys: Vec<x25519_dalek::StaticSecret>,
+    tls: Arc<TlsServer>,
     down_table: Arc<DownTable>,
     send_upcoming: Sender<(Buff, SocketAddr)>,
 ) -> anyhow::Result<()> {
     loop {
-        let (client, _) = listener.accept().await?;
+        let (client, peer) = listener.accept().await?;
         client.set_nodelay(true)?;
         let down_table = down_table.clone();
         let send_upcoming = send_upcoming.clone();
-        let seckey = seckey.clone();
+        let seckeys = seckeys.clone();
+        let tls = tls.clone();
         smolscale::spawn(async move {
-            if let Err(err) = backhaul_one(client, seckey.clone(), down_table, send_upcoming)
+            if let Err(err) = backhaul_one(client, peer, &seckeys, &tls, down_table, send_upcoming)
                 .or(async {
                     smol::Timer::after(CONN_LIFETIME * 2).await;
                     Ok(())
@@ -96,19 +114,29 @@ async fn backhaul_loop(
 /// handle a TCP stream
 async fn backhaul_one(
     mut client: TcpStream,
-    seckey: x25519_dalek::StaticSecret,
+    peer: SocketAddr,
+    seckeys: &[x25519_dalek::StaticSecret],
+    tls: &TlsServer,
     down_table: Arc<DownTable>,
     send_upcoming: Sender<(Buff, SocketAddr)>,
 ) -> anyhow::Result<()> {
-    let mut client = async_dup::Arc::new(async_dup::Mutex::new(
-        opportunistic_tls_serve(client).await?,
+    let client = async_dup::Arc::new(async_dup::Mutex::new(
+        opportunistic_tls_serve(client, tls).await?,
     ));
+    let (mut write, mut read) =
+        opportunistic_websocket_serve(Box::new(client.clone()), Box::new(client)).await?;
 
-    let cookie = Cookie::new((&seckey).into());
     // read the initial length
     let mut encrypted_hello_length = vec![0u8; NgAead::overhead() + 2];
-    client.read_exact(&mut encrypted_hello_length).await?;
-    for (possible_c2s, possible_s2c) in cookie.generate_c2s().zip(cookie.generate_s2c()) {
+    read.read_exact(&mut encrypted_hello_length).await?;
+    let possible_keys = seckeys.iter().flat_map(|seckey| {
+        let cookie = Cookie::new(seckey.into());
+        cookie
+            .generate_c2s()
+            .zip(cookie.generate_s2c())
+            .map(move |(c2s, s2c)| (seckey, c2s, s2c))
+    });
+    for (seckey, possible_c2s, possible_s2c) in possible_keys {
         let c2s_key = blake3::keyed_hash(TCP_UP_KEY, &possible_c2s);
         let c2s_dec = NgAead::new(c2s_key.as_bytes());
         let s2c_key = blake3::keyed_hash(TCP_DN_KEY, &possible_s2c);
@@ -121,7 +149,7 @@ async fn backhaul_one(
                     .context("hello length is the wrong size")?,
             ) as usize;
             let mut encrypted_hello = vec![0u8; hello_length];
-            client.read_exact(&mut encrypted_hello).await?;
+            read.read_exact(&mut encrypted_hello).await?;
             let raw_hello = c2s_dec
                 .decrypt(&encrypted_hello)
                 .context("cannot decrypt hello")?;
@@ -141,20 +169,21 @@ async fn backhaul_one(
             {
                 let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
                 let response = HandshakeFrame::ServerHello {
-                    long_pk: (&seckey).into(),
+                    long_pk: seckey.into(),
                     eph_pk: (&my_eph_sk).into(),
                     resume_token: Buff::new(),
                 };
-                write_encrypted(s2c_enc, &response.to_bytes(), &mut client).await?;
-                let ss = triple_ecdh(&seckey, &my_eph_sk, &long_pk, &eph_pk);
-                let obfs_tcp = ObfsTcp::new(ss, true, Box::new(client.clone()), Box::new(client));
+                write_encrypted(s2c_enc, &response.to_bytes(), &mut write).await?;
+                let ss = triple_ecdh(seckey, &my_eph_sk, &long_pk, &eph_pk);
+                let obfs_tcp = ObfsTcp::new(ss, true, write, read);
                 let mut fake_addr = [0u8; 16];
                 obfs_tcp
                     .read_exact(&mut fake_addr)
                     .await
                     .context("cannot read fakeaddr")?;
                 let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::from(fake_addr)), 0);
-                return backhaul_one_inner_obfs(obfs_tcp, addr, &down_table, &send_upcoming).await;
+                return backhaul_one_inner_obfs(obfs_tcp, addr, peer, &down_table, &send_upcoming)
+                    .await;
             }
         }
     }
@@ -165,6 +194,7 @@ async fn backhaul_one(
 async fn backhaul_one_inner_obfs(
     obfs_tcp: ObfsTcp,
     addr: SocketAddr,
+    peer: SocketAddr,
     down_table: &DownTable,
     send_upcoming: &Sender<(Buff, SocketAddr)>,
 ) -> anyhow::Result<()> {
@@ -172,7 +202,7 @@ async fn backhaul_one_inner_obfs(
     let up_loop = async {
         let mut buff = [0u8; 4096];
         loop {
-            down_table.set(addr, send_down.clone());
+            down_table.set(addr, peer, send_down.clone());
             obfs_tcp.read_exact(&mut buff[..2]).await?;
             let length = u16::from_be_bytes(
                 (&buff[..2])
@@ -207,24 +237,33 @@ async fn backhaul_one_inner_obfs(
 
 #[derive(Default)]
 struct DownTable {
-    /// maps fake IPv6 addresses (u128, 0) back through a channel to a connection actor. only keeps track of the connection that had the *latest* activity.
-    mapping: DashMap<SocketAddr, (Sender<Buff>, Instant)>,
+    /// maps fake IPv6 addresses (u128, 0) back through a channel to a connection actor, along with the real address of the connection. only keeps track of the connection that had the *latest* activity.
+    mapping: DashMap<SocketAddr, (Sender<Buff>, Instant, SocketAddr)>,
 }
 
 impl DownTable {
     /// Creates/overwrites a new entry in the table.
-    fn set(&self, addr: SocketAddr, sender: Sender<Buff>) {
+    fn set(&self, addr: SocketAddr, peer: SocketAddr, sender: Sender<Buff>) {
         if rand::random::<usize>() % self.mapping.len().max(1000) == 0 {
             self.gc()
         }
         let now = Instant::now();
-        let mut entry = self.mapping.entry(addr).or_insert((sender.clone(), now));
+        let mut entry = self
+            .mapping
+            .entry(addr)
+            .or_insert((sender.clone(), now, peer));
         if entry.1 != now {
             entry.1 = now;
-            entry.0 = sender
+            entry.0 = sender;
+            entry.2 = peer;
         }
     }
 
+    /// Gets the real address of the connection behind a fake address.
+    fn peer_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        self.mapping.get(&addr).map(|val| val.value().2)
+    }
+
     /// Sends something to a socketaddr. Silently drops on error.
     fn send_to(&self, msg: Buff, dest: SocketAddr) {
         if let Some(val) = self.mapping.get(&dest) {
@@ -247,3 +286,28 @@ impl DownTable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn down_table_knows_real_peers() {
+        let table = DownTable::default();
+        let fake: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let (send, _recv) = smol::channel::bounded(1);
+        assert_eq!(table.peer_addr(fake), None);
+        table.set(fake, "192.0.2.1:1234".parse().unwrap(), send.clone());
+        assert_eq!(
+            table.peer_addr(fake),
+            Some("192.0.2.1:1234".parse().unwrap())
+        );
+        // a reconnect from elsewhere takes over
+        std::thread::sleep(Duration::from_millis(1));
+        table.set(fake, "192.0.2.2:4321".parse().unwrap(), send);
+        assert_eq!(
+            table.peer_addr(fake),
+            Some("192.0.2.2:4321".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use std::path::Path;
+
+use anyhow::Context;
+use rcgen::generate_simple_self_signed;
+use smol::net::TcpStream;
+
+use super::{fingerprint, ClientHelloProfile, DynAsyncRead, DynAsyncWrite};
+
+/// How a client does TLS, for [Protocol::DirectTls](crate::Protocol::DirectTls), [Protocol::ProxiedTls](crate::Protocol::ProxiedTls) and the TLS fallback of [Protocol::Auto](crate::Protocol::Auto). The default makes up a server name for every connection, only does TLS 1.2, and accepts any certificate, since the sosistab handshake inside TLS authenticates the server anyway.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Server name sent through SNI and, if certificates are verified, checked against the server's certificate. If None, every connection uses a random made-up domain. For domain fronting, this is the innocuous domain, while the connector leads to the CDN actually serving it.
+    pub server_name: Option<String>,
+    /// Whether to send the server name through SNI at all.
+    pub use_sni: bool,
+    /// ALPN protocols offered, in order of preference, such as `h2` and `http/1.1`.
+    pub alpn: Vec<String>,
+    /// Oldest TLS version allowed. If None, whatever the TLS library allows.
+    pub min_version: Option<TlsVersion>,
+    /// Newest TLS version allowed. If None, the newest the TLS library supports, typically TLS 1.3. Sosistab servers speak up to whatever their `ListenerConfig::tls_max_version` allows, TLS 1.2 by default.
+    pub max_version: Option<TlsVersion>,
+    /// How the server's certificate is checked.
+    pub verify: CertVerification,
+    /// ClientHello to present, so that the TLS fingerprint is that of some browser on every platform. The ClientHello then offers the versions of the profile, whatever `min_version` and `max_version` say, but the server must still pick one that they allow, so reaching servers that do TLS 1.3, such as CDNs, takes a `max_version` of at least TLS 1.3. Verified certificates must chain up to Mozilla's roots rather than the system's. If None, the ClientHello is whatever the platform TLS library presents, which differs between platforms.
+    pub fingerprint: Option<ClientHelloProfile>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            use_sni: true,
+            alpn: Vec::new(),
+            min_version: Some(TlsVersion::Tls12),
+            max_version: Some(TlsVersion::Tls12),
+            verify: CertVerification::AcceptAny,
+            fingerprint: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Does the TLS handshake over the given connection, returning the two halves of the TLS connection.
+    pub(crate) async fn connect(
+        &self,
+        tcp: TcpStream,
+    ) -> anyhow::Result<(DynAsyncWrite, DynAsyncRead)> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| {
+            format!(
+                "{}.{}.com",
+                eff_wordlist::large::random_word(),
+                eff_wordlist::large::random_word()
+            )
+        });
+        if let Some(profile) = &self.fingerprint {
+            let (write, read) = fingerprint::connect(profile, self, &server_name, tcp).await?;
+            tracing::debug!(
+                "TLS established with {} using a ClientHello profile",
+                server_name
+            );
+            return Ok((Box::new(write), Box::new(read)));
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        builder
+            .min_protocol_version(self.min_version.map(TlsVersion::to_native))
+            .max_protocol_version(self.max_version.map(TlsVersion::to_native))
+            .use_sni(self.use_sni)
+            .request_alpns(&self.alpn.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+        match &self.verify {
+            // pinned certificates are checked once the handshake is done
+            CertVerification::AcceptAny | CertVerification::Pinned(_) => {
+                builder
+                    .danger_accept_invalid_certs(true)
+                    .danger_accept_invalid_hostnames(true);
+            }
+            CertVerification::Verify { extra_roots } => {
+                for root in extra_roots {
+                    builder.add_root_certificate(
+                        native_tls::Certificate::from_pem(root)
+                            .context("cannot parse extra root certificate")?,
+                    );
+                }
+            }
+        }
+        let tls = async_native_tls::TlsConnector::from(builder)
+            .connect(server_name.as_str(), tcp)
+            .await?;
+        if let CertVerification::Pinned(pins) = &self.verify {
+            let cert = tls
+                .peer_certificate()?
+                .context("server presented no certificate")?;
+            let hash = ring::digest::digest(&ring::digest::SHA256, &cert.to_der()?);
+            if !pins.iter().any(|pin| pin[..] == *hash.as_ref()) {
+                anyhow::bail!("server certificate is not pinned")
+            }
+        }
+        tracing::debug!("TLS established with {}", server_name);
+        let tls = async_dup::Arc::new(async_dup::Mutex::new(tls));
+        Ok((Box::new(tls.clone()), Box::new(tls)))
+    }
+}
+
+/// A version of TLS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn to_native(self) -> native_tls::Protocol {
+        match self {
+            TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+            TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+            TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+            TlsVersion::Tls13 => native_tls::Protocol::Tlsv13,
+        }
+    }
+}
+
+/// How a client checks the certificate of a TLS server.
+#[derive(Clone, Debug)]
+pub enum CertVerification {
+    /// Any certificate is accepted, as a self-signed one from a server without a real certificate.
+    AcceptAny,
+    /// The certificate must chain up to one of the system's trusted roots, or one of the given PEM-encoded ones, and be valid for the server name. With a [ClientHelloProfile], Mozilla's trusted roots, as the `webpki-roots` crate has them, stand in for the system's.
+    Verify { extra_roots: Vec<Vec<u8>> },
+    /// The certificate must be one of those whose SHA-256 hashes, of their DER encodings, are given. Nothing else about it is checked.
+    Pinned(Vec<[u8; 32]>),
+}
+
+/// A certificate chain and its private key, which a server presents to TLS clients.
+#[derive(Clone)]
+pub struct TlsIdentity(native_tls::Identity);
+
+impl TlsIdentity {
+    /// Creates an identity from a PEM-encoded certificate chain, starting with the server's own certificate, and a PEM-encoded PKCS #8 private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> std::io::Result<Self> {
+        native_tls::Identity::from_pkcs8(cert_chain, key)
+            .map(Self)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    /// Loads an identity from files, as with [TlsIdentity::from_pem].
+    pub fn from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> std::io::Result<Self> {
+        Self::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(key)?)
+    }
+}
+
+/// How a server serves TLS.
+pub(crate) struct TlsServer {
+    /// Acceptor presenting the configured identity, if any.
+    acceptor: Option<async_native_tls::TlsAcceptor>,
+    alpn: Vec<String>,
+    /// Oldest and newest TLS versions allowed, or None for whatever the TLS library allows.
+    versions: (Option<TlsVersion>, Option<TlsVersion>),
+}
+
+impl TlsServer {
+    /// Creates a server presenting the given identity, or if None, a fresh self-signed certificate for a made-up domain on every connection, and allowing the given oldest and newest TLS versions.
+    pub fn new(
+        identity: Option<TlsIdentity>,
+        alpn: Vec<String>,
+        versions: (Option<TlsVersion>, Option<TlsVersion>),
+    ) -> std::io::Result<Self> {
+        let acceptor = identity
+            .map(|identity| build_acceptor(identity.0, &alpn, versions))
+            .transpose()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        Ok(Self {
+            acceptor,
+            alpn,
+            versions,
+        })
+    }
+
+    /// Gets the acceptor for a new connection.
+    pub fn acceptor(&self) -> anyhow::Result<async_native_tls::TlsAcceptor> {
+        if let Some(acceptor) = &self.acceptor {
+            return Ok(acceptor.clone());
+        }
+        let names = vec![format!(
+            "{}{}.com",
+            eff_wordlist::large::random_word(),
+            eff_wordlist::large::random_word()
+        )];
+        let cert = generate_simple_self_signed(names)?;
+        let cert_pem = cert.serialize_pem()?;
+        let cert_key = cert.serialize_private_key_pem();
+        let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), cert_key.as_bytes())
+            .expect("wtf cannot decode id???");
+        Ok(build_acceptor(identity, &self.alpn, self.versions)?)
+    }
+}
+
+fn build_acceptor(
+    identity: native_tls::Identity,
+    alpn: &[String],
+    (min_version, max_version): (Option<TlsVersion>, Option<TlsVersion>),
+) -> native_tls::Result<async_native_tls::TlsAcceptor> {
+    Ok(native_tls::TlsAcceptor::builder(identity)
+        .accept_alpn(alpn)
+        .min_protocol_version(min_version.map(TlsVersion::to_native))
+        .max_protocol_version(max_version.map(TlsVersion::to_native))
+        .build()?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Does a TLS handshake between a server allowing the given versions and a client with the given configuration, then has the server echo a message from the client.
+    fn handshake(
+        server_versions: (Option<TlsVersion>, Option<TlsVersion>),
+        client: TlsConfig,
+    ) -> anyhow::Result<()> {
+        handshake_with(TlsServer::new(None, Vec::new(), server_versions)?, client)
+    }
+
+    fn handshake_with(server: TlsServer, client: TlsConfig) -> anyhow::Result<()> {
+        use smol::prelude::*;
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                let (tcp, _) = listener.accept().await?;
+                let mut tls = server.acceptor()?.accept(tcp).await?;
+                let mut buf = [0; 5];
+                tls.read_exact(&mut buf).await?;
+                tls.write_all(&buf).await?;
+                tls.flush().await?;
+                anyhow::Ok(())
+            };
+            futures_util::try_join!(accept, echo(addr, &client))?;
+            Ok(())
+        })
+    }
+
+    /// Connects to a server that echoes a message, and checks that it does.
+    async fn echo(addr: std::net::SocketAddr, client: &TlsConfig) -> anyhow::Result<()> {
+        use smol::prelude::*;
+        let (mut write, mut read) = client.connect(TcpStream::connect(addr).await?).await?;
+        write.write_all(b"hello").await?;
+        write.flush().await?;
+        let mut buf = [0; 5];
+        read.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    /// Starts an OpenSSL server, which unlike sosistab's own can do TLS 1.3, for one connection that echoes a message. It presents the given certificate chain and key, does TLS 1.3 and, if asked, TLS 1.2, and only does key exchange over the given groups.
+    fn openssl_server(
+        cert_chain: &str,
+        key: &str,
+        tls12: bool,
+        groups: &str,
+    ) -> std::net::SocketAddr {
+        use openssl::ssl::{SslAcceptor, SslMethod, SslVersion};
+        use std::io::{Read, Write};
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key(&openssl::pkey::PKey::private_key_from_pem(key.as_bytes()).unwrap())
+            .unwrap();
+        let mut chain = openssl::x509::X509::stack_from_pem(cert_chain.as_bytes())
+            .unwrap()
+            .into_iter();
+        acceptor.set_certificate(&chain.next().unwrap()).unwrap();
+        for cert in chain {
+            acceptor.add_extra_chain_cert(cert).unwrap();
+        }
+        if !tls12 {
+            acceptor
+                .set_min_proto_version(Some(SslVersion::TLS1_3))
+                .unwrap();
+        }
+        acceptor.set_groups_list(groups).unwrap();
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            if let Ok(mut tls) = acceptor.accept(tcp) {
+                let mut buf = [0; 5];
+                if tls.read_exact(&mut buf).is_ok() {
+                    let _ = tls.write_all(&buf);
+                }
+            }
+        });
+        addr
+    }
+
+    /// A certificate for the given name, signed by a CA, as its PEM-encoded chain and key, along with the PEM-encoded certificate of the CA.
+    fn signed_cert(name: &str) -> (String, String, Vec<u8>) {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let leaf = generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (
+            leaf.serialize_pem_with_signer(&ca).unwrap(),
+            leaf.serialize_private_key_pem(),
+            ca.serialize_pem().unwrap().into_bytes(),
+        )
+    }
+
+    fn profile_client(profile: ClientHelloProfile) -> TlsConfig {
+        TlsConfig {
+            max_version: None,
+            fingerprint: Some(profile),
+            ..Default::default()
+        }
+    }
+
+    fn client(min_version: TlsVersion, max_version: TlsVersion) -> TlsConfig {
+        TlsConfig {
+            min_version: Some(min_version),
+            max_version: Some(max_version),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn servers_speak_the_versions_they_allow() {
+        let tls12 = client(TlsVersion::Tls12, TlsVersion::Tls12);
+        let tls13 = client(TlsVersion::Tls13, TlsVersion::Tls13);
+        handshake((None, None), tls12.clone()).unwrap();
+        handshake((None, None), client(TlsVersion::Tls12, TlsVersion::Tls13)).unwrap();
+        handshake((None, Some(TlsVersion::Tls12)), tls12.clone()).unwrap();
+        handshake((None, Some(TlsVersion::Tls12)), tls13).unwrap_err();
+        handshake((Some(TlsVersion::Tls13), None), tls12).unwrap_err();
+    }
+
+    #[test]
+    fn profiles_reach_listeners_by_default() {
+        let versions = {
+            let cfg =
+                crate::ListenerConfig::new(x25519_dalek::StaticSecret::new(rand::thread_rng()));
+            (cfg.tls_min_version, cfg.tls_max_version)
+        };
+        for profile in [ClientHelloProfile::chrome(), ClientHelloProfile::firefox()] {
+            let client = TlsConfig {
+                fingerprint: Some(profile),
+                ..Default::default()
+            };
+            handshake(versions, client).unwrap();
+        }
+    }
+
+    #[test]
+    fn profiles_speak_tls13() {
+        let (chain, key, _) = signed_cert("sosistab.example.com");
+        for profile in [ClientHelloProfile::chrome(), ClientHelloProfile::firefox()] {
+            let addr = openssl_server(&chain, &key, true, "X25519:P-256");
+            smol::block_on(echo(addr, &profile_client(profile.clone()))).unwrap();
+            // a server that only does TLS 1.2 is no downgrade
+            handshake((None, None), profile_client(profile)).unwrap();
+        }
+    }
+
+    #[test]
+    fn profiles_send_key_shares_again_when_asked() {
+        let (chain, key, _) = signed_cert("sosistab.example.com");
+        // Chrome only sends a key share for x25519
+        let addr = openssl_server(&chain, &key, false, "P-384:P-256");
+        smol::block_on(echo(addr, &profile_client(ClientHelloProfile::chrome()))).unwrap();
+    }
+
+    #[test]
+    fn profiles_keep_to_the_allowed_versions() {
+        let (chain, key, _) = signed_cert("sosistab.example.com");
+        // the ClientHello still offers TLS 1.3, but the client refuses it
+        let client = TlsConfig {
+            max_version: Some(TlsVersion::Tls12),
+            ..profile_client(ClientHelloProfile::chrome())
+        };
+        let addr = openssl_server(&chain, &key, true, "X25519");
+        smol::block_on(echo(addr, &client)).unwrap_err();
+    }
+
+    #[test]
+    fn profiles_notice_downgrades() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = async {
+                use smol::prelude::*;
+                let (mut tcp, _) = listener.accept().await.unwrap();
+                let mut header = [0; 5];
+                tcp.read_exact(&mut header).await.unwrap();
+                let mut hello = vec![0; u16::from_be_bytes([header[3], header[4]]) as usize];
+                tcp.read_exact(&mut hello).await.unwrap();
+                // a ServerHello picking TLS 1.2, from a server that says it could have done TLS 1.3
+                let mut random = [7; 32];
+                random[24..].copy_from_slice(b"DOWNGRD\x01");
+                let mut body = vec![3, 3];
+                body.extend_from_slice(&random);
+                body.extend_from_slice(&[0, 0xc0, 0x2f, 0, 0, 0]);
+                let mut msg = vec![2, 0, 0, body.len() as u8];
+                msg.extend_from_slice(&body);
+                let mut record = vec![22, 3, 3, 0, msg.len() as u8];
+                record.extend_from_slice(&msg);
+                tcp.write_all(&record).await.unwrap();
+                tcp
+            };
+            let client = profile_client(ClientHelloProfile::chrome());
+            let connect = async { client.connect(TcpStream::connect(addr).await?).await };
+            let (_tcp, res) = futures_util::join!(server, connect);
+            let err = res.err().expect("downgrade not noticed");
+            assert!(err.to_string().contains("older TLS version"), "{}", err);
+        })
+    }
+
+    #[test]
+    fn profiles_verify_certificates() {
+        let name = "sosistab.example.com";
+        let (chain, key, ca) = signed_cert(name);
+        let identity = TlsIdentity::from_pem(chain.as_bytes(), key.as_bytes()).unwrap();
+        let client = |server_name: &str, extra_roots: Vec<Vec<u8>>| TlsConfig {
+            server_name: Some(server_name.to_string()),
+            verify: CertVerification::Verify { extra_roots },
+            ..profile_client(ClientHelloProfile::chrome())
+        };
+        for tls13 in [false, true] {
+            let handshake = |client: TlsConfig| {
+                if tls13 {
+                    let addr = openssl_server(&chain, &key, false, "X25519");
+                    smol::block_on(echo(addr, &client))
+                } else {
+                    let server = TlsServer::new(Some(identity.clone()), Vec::new(), (None, None));
+                    handshake_with(server.unwrap(), client)
+                }
+            };
+            handshake(client(name, vec![ca.clone()])).unwrap();
+            handshake(client("example.com", vec![ca.clone()])).unwrap_err();
+            handshake(client(name, Vec::new())).unwrap_err();
+        }
+    }
+}
@@ -1,9 +1,13 @@
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
-use rcgen::generate_simple_self_signed;
 use smol::{io::BufReader, net::TcpStream, prelude::*};
 
+use super::TlsServer;
+
 /// Negotiates an *optional* TLS connection.
-pub async fn opportunistic_tls_serve(client: TcpStream) -> anyhow::Result<CompositeReadWrite> {
+pub async fn opportunistic_tls_serve(
+    client: TcpStream,
+    tls: &TlsServer,
+) -> anyhow::Result<CompositeReadWrite> {
     let mut client_up = BufReader::with_capacity(4096, client.clone());
     let initial = client_up.fill_buf().await?;
     // Checks to see whether the initial bit looks like a clienthello at all
@@ -20,18 +24,7 @@ pub async fn opportunistic_tls_serve(client: TcpStream) -> anyhow::Result<Compos
             reader: Box::new(client_up),
             writer: Box::new(client),
         };
-        let names = vec![format!(
-            "{}{}.com",
-            eff_wordlist::large::random_word(),
-            eff_wordlist::large::random_word()
-        )];
-        let cert = generate_simple_self_signed(names)?;
-        let cert_pem = cert.serialize_pem()?;
-        let cert_key = cert.serialize_private_key_pem();
-        let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), cert_key.as_bytes())
-            .expect("wtf cannot decode id???");
-        let acceptor: async_native_tls::TlsAcceptor =
-            native_tls::TlsAcceptor::new(identity)?.into();
+        let acceptor = tls.acceptor()?;
         let client = acceptor.accept(composite).await?;
         let client = async_dup::Arc::new(async_dup::Mutex::new(client));
         Ok(CompositeReadWrite {
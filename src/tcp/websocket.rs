@@ -0,0 +1,614 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use smol::{io::BufReader, prelude::*};
+
+use crate::Connector;
+
+use super::{read_http_head, DynAsyncRead, DynAsyncWrite};
+
+/// GUID that the key of a WebSocket handshake is hashed with, from RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// opcodes of frames
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+
+/// How a client reaches the server through WebSocket, for [Protocol::WebSocket](crate::Protocol::WebSocket). Packets go inside binary messages, framed and obfuscated just like over plain TCP, so the server needs nothing but a TCP address, which also accepts WebSocket clients. This gets through networks that only let HTTP(S) out, through an HTTP proxy or a CDN that passes WebSocket on to the server.
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    /// Host header of the upgrade request. If None, every connection uses a random made-up domain.
+    pub host: Option<String>,
+    /// Path of the upgrade request.
+    pub path: String,
+    /// Further headers of the upgrade request, such as `User-Agent` or `Origin`.
+    pub headers: Vec<(String, String)>,
+    /// Whether to do WebSocket over TLS, done as the client's TLS config says.
+    pub tls: bool,
+    /// How to open the TCP connection, such as through an [HttpProxy](crate::HttpProxy). If None, the server is connected to directly.
+    pub connector: Option<Connector>,
+}
+
+impl WebSocketConfig {
+    /// Creates a config for plain WebSocket to the root path, connecting directly.
+    pub fn new() -> Self {
+        Self {
+            host: None,
+            path: "/".into(),
+            headers: Vec::new(),
+            tls: false,
+            connector: None,
+        }
+    }
+
+    /// Does the client side of the WebSocket handshake over the given connection, returning the two halves of the WebSocket connection.
+    pub(crate) async fn connect(
+        &self,
+        mut write: DynAsyncWrite,
+        read: DynAsyncRead,
+    ) -> anyhow::Result<(DynAsyncWrite, DynAsyncRead)> {
+        let host = self.host.clone().unwrap_or_else(|| {
+            format!(
+                "{}.{}.com",
+                eff_wordlist::large::random_word(),
+                eff_wordlist::large::random_word()
+            )
+        });
+        let key = base64::encode(rand::random::<[u8; 16]>());
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            self.path, host, key
+        );
+        for (name, value) in self.headers.iter() {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        write.write_all(request.as_bytes()).await?;
+        write.flush().await?;
+        let mut read = BufReader::new(read);
+        let head = read_http_head(&mut read).await?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            anyhow::bail!("WebSocket upgrade refused: {}", status)
+        }
+        if header(&head, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+            anyhow::bail!("WebSocket upgrade accepted with the wrong key")
+        }
+        Ok((
+            Box::new(WsWriter::new(write, true)),
+            Box::new(WsReader::new(read)),
+        ))
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves the WebSocket handshake to a client that starts with one, returning the two halves of the WebSocket connection, or of the connection as it is for clients that do not. Other HTTP requests are answered with an error.
+pub(crate) async fn opportunistic_websocket_serve(
+    mut write: DynAsyncWrite,
+    read: DynAsyncRead,
+) -> anyhow::Result<(DynAsyncWrite, DynAsyncRead)> {
+    let mut read = BufReader::new(read);
+    if !read.fill_buf().await?.starts_with(b"GET ") {
+        return Ok((write, Box::new(read)));
+    }
+    let head = read_http_head(&mut read).await?;
+    let key = match header(&head, "sec-websocket-key") {
+        Some(key)
+            if header(&head, "upgrade").map(|u| u.eq_ignore_ascii_case("websocket"))
+                == Some(true) =>
+        {
+            key.to_string()
+        }
+        _ => {
+            // looks like any web server to whoever is probing
+            write
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            write.flush().await?;
+            anyhow::bail!("HTTP request that is not a WebSocket upgrade")
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    write.write_all(response.as_bytes()).await?;
+    write.flush().await?;
+    Ok((
+        Box::new(WsWriter::new(write, false)),
+        Box::new(WsReader::new(read)),
+    ))
+}
+
+/// Gets the value of a header out of an HTTP head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (line_name, value) = line.split_once(':')?;
+        if line_name.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Computes the accept key that a server answers the given handshake key with.
+fn accept_key(key: &str) -> String {
+    let hash = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    );
+    base64::encode(hash.as_ref())
+}
+
+/// The reading half of a WebSocket connection, reading the payloads of data messages as one stream. Pings are ignored rather than answered, and a close, or the connection ending between frames, ends the stream.
+struct WsReader<R> {
+    inner: R,
+    /// Header of the next frame, as much as has been read.
+    header: Vec<u8>,
+    /// Bytes of the current frame's payload yet to be read.
+    remaining: u64,
+    /// Whether the current frame's payload is thrown away, as for control frames.
+    discard: bool,
+    mask: Option<[u8; 4]>,
+    mask_pos: usize,
+    closed: bool,
+}
+
+impl<R: AsyncRead + Unpin> WsReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            header: Vec::with_capacity(14),
+            remaining: 0,
+            discard: false,
+            mask: None,
+            mask_pos: 0,
+            closed: false,
+        }
+    }
+
+    /// Length of the header being read, as far as can be told from what has been read.
+    fn header_len(&self) -> usize {
+        if self.header.len() < 2 {
+            return 2;
+        }
+        let mask_len = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        match self.header[1] & 0x7f {
+            126 => 4 + mask_len,
+            127 => 10 + mask_len,
+            _ => 2 + mask_len,
+        }
+    }
+
+    /// Starts on the frame whose header has been read.
+    fn start_frame(&mut self) -> io::Result<()> {
+        let opcode = self.header[0] & 0x0f;
+        let (remaining, mask_start) = match self.header[1] & 0x7f {
+            126 => (
+                u16::from_be_bytes([self.header[2], self.header[3]]) as u64,
+                4,
+            ),
+            127 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.header[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (len as u64, 2),
+        };
+        self.remaining = remaining;
+        self.mask = self.header.get(mask_start..mask_start + 4).map(|mask| {
+            let mut key = [0; 4];
+            key.copy_from_slice(mask);
+            key
+        });
+        self.mask_pos = 0;
+        self.header.clear();
+        match opcode {
+            CONTINUATION | TEXT | BINARY => self.discard = false,
+            CLOSE => self.closed = true,
+            // pings and pongs
+            0x9 | 0xa => self.discard = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad WebSocket frame",
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for WsReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(Ok(0));
+            }
+            if this.remaining > 0 {
+                let mut scratch = [0; 1024];
+                let out = if this.discard {
+                    &mut scratch[..]
+                } else {
+                    &mut *buf
+                };
+                let max = out
+                    .len()
+                    .min(this.remaining.min(usize::MAX as u64) as usize);
+                let n = match Pin::new(&mut this.inner).poll_read(cx, &mut out[..max]) {
+                    Poll::Ready(res) => res?,
+                    Poll::Pending => return Poll::Pending,
+                };
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                if let Some(mask) = this.mask {
+                    for byte in out[..n].iter_mut() {
+                        *byte ^= mask[this.mask_pos % 4];
+                        this.mask_pos += 1;
+                    }
+                }
+                this.remaining -= n as u64;
+                if this.discard {
+                    continue;
+                }
+                return Poll::Ready(Ok(n));
+            }
+            let needed = this.header_len();
+            if this.header.len() < needed {
+                let mut chunk = [0; 14];
+                let chunk = &mut chunk[..needed - this.header.len()];
+                let n = match Pin::new(&mut this.inner).poll_read(cx, chunk) {
+                    Poll::Ready(res) => res?,
+                    Poll::Pending => return Poll::Pending,
+                };
+                if n == 0 {
+                    // closing between frames ends the stream, just like a close frame
+                    if this.header.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.header.extend_from_slice(&chunk[..n]);
+                continue;
+            }
+            this.start_frame()?;
+        }
+    }
+}
+
+/// The writing half of a WebSocket connection, sending every write as a binary message, masked if we are the client.
+struct WsWriter<W> {
+    inner: W,
+    masked: bool,
+    /// Frame not yet written out, and how much of it has been.
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> WsWriter<W> {
+    fn new(inner: W, masked: bool) -> Self {
+        Self {
+            inner,
+            masked,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    fn encode_frame(&mut self, payload: &[u8]) {
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        self.pending.push(0x80 | BINARY);
+        if payload.len() < 126 {
+            self.pending.push(mask_bit | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            self.pending.push(mask_bit | 126);
+            self.pending
+                .extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            self.pending.push(mask_bit | 127);
+            self.pending
+                .extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        let start = self.pending.len();
+        self.pending.extend_from_slice(payload);
+        if self.masked {
+            let mask: [u8; 4] = rand::random();
+            for (i, byte) in self.pending[start..].iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            self.pending.splice(start..start, mask);
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WsWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // the frame taken by the last write must be written through before we take another
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+        // once encoded, the frame is ours to write out, here or on the next write or flush, so the caller need not pass the same bytes again
+        this.encode_frame(buf);
+        match this.poll_drain(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::{
+        io::Cursor,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Encodes a frame as a client would, masked.
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mask: [u8; 4] = rand::random();
+        let mut out = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    fn write_frame(payload: &[u8], masked: bool) -> Vec<u8> {
+        smol::block_on(async {
+            let mut writer = WsWriter::new(Vec::new(), masked);
+            writer.write_all(payload).await.unwrap();
+            writer.flush().await.unwrap();
+            writer.inner
+        })
+    }
+
+    fn read_all(stream: Vec<u8>) -> io::Result<Vec<u8>> {
+        smol::block_on(async {
+            let mut out = Vec::new();
+            WsReader::new(Cursor::new(stream))
+                .read_to_end(&mut out)
+                .await?;
+            Ok(out)
+        })
+    }
+
+    #[test]
+    fn only_clients_mask() {
+        let server = write_frame(b"hello", false);
+        assert_eq!(server, b"\x82\x05hello");
+        let client = write_frame(b"hello", true);
+        assert_eq!(client[..2], [0x82, 0x85]);
+        let mask = &client[2..6];
+        let unmasked: Vec<u8> = client[6..]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        assert_eq!(unmasked, b"hello");
+        assert_eq!(read_all(client).unwrap(), b"hello");
+        assert_eq!(read_all(server).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn lengths_round_trip() {
+        for (len, len_byte, header_len) in [
+            (1, 1, 2),
+            (125, 125, 2),
+            (126, 126, 4),
+            (65535, 126, 4),
+            (65536, 127, 10),
+            (100_000, 127, 10),
+        ] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            for masked in [false, true] {
+                let frame = write_frame(&payload, masked);
+                assert_eq!(frame[1] & 0x7f, len_byte);
+                let header_len = header_len + if masked { 4 } else { 0 };
+                assert_eq!(frame.len(), header_len + len);
+                assert_eq!(read_all(frame).unwrap(), payload);
+            }
+        }
+    }
+
+    /// A writer that only takes a byte at a time, and is not ready on every other call.
+    #[derive(Default)]
+    struct Trickle {
+        written: Vec<u8>,
+        stalled: bool,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.stalled = !this.stalled;
+            if this.stalled {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            this.written.push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn writes_may_change_after_pending() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut writer = WsWriter::new(Trickle::default(), true);
+        // callers may give up on a write that is pending, and write something else instead
+        let payloads: [&[u8]; 3] = [b"one", b"two!", b"three"];
+        let mut accepted = Vec::new();
+        for i in 0..100 {
+            let buf = payloads[i % payloads.len()];
+            if let Poll::Ready(n) = Pin::new(&mut writer).poll_write(&mut cx, buf) {
+                assert_eq!(n.unwrap(), buf.len());
+                accepted.extend_from_slice(buf);
+            }
+        }
+        while Pin::new(&mut writer).poll_flush(&mut cx).is_pending() {}
+        assert!(accepted.len() > 10);
+        assert_eq!(read_all(writer.inner.written).unwrap(), accepted);
+    }
+
+    #[test]
+    fn control_frames_are_skipped_and_close_ends() {
+        let mut stream = frame(0x9, b"ping");
+        stream.extend(frame(BINARY, b"abc"));
+        stream.extend(frame(0xa, b"pong"));
+        // a fragmented text message
+        let mut first = frame(TEXT, b"d");
+        first[0] &= 0x7f;
+        stream.extend(first);
+        stream.extend(frame(CONTINUATION, b"e"));
+        stream.extend(frame(CLOSE, &[0x03, 0xe8]));
+        stream.extend(frame(BINARY, b"after close"));
+        assert_eq!(read_all(stream).unwrap(), b"abcde");
+        let err = read_all(frame(0x3, b"reserved")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut truncated = frame(BINARY, b"abc");
+        truncated.pop();
+        assert_eq!(
+            read_all(truncated).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    /// Serves one connection opportunistically, echoing whatever is read through it.
+    async fn echo_server() -> (std::net::SocketAddr, smol::Task<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = smol::spawn(async move {
+            let (tcp, _) = listener.accept().await?;
+            let (mut write, mut read) =
+                opportunistic_websocket_serve(Box::new(tcp.clone()), Box::new(tcp)).await?;
+            let mut buf = [0; 1024];
+            loop {
+                let n = read.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                write.write_all(&buf[..n]).await?;
+                write.flush().await?;
+            }
+        });
+        (addr, task)
+    }
+
+    #[test]
+    fn raw_clients_are_served_as_they_are() {
+        smol::block_on(async {
+            let (addr, _server) = echo_server().await;
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"\x00\x05hello").await.unwrap();
+            let mut buf = [0; 7];
+            tcp.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x00\x05hello");
+        })
+    }
+
+    #[test]
+    fn websocket_clients_are_upgraded() {
+        smol::block_on(async {
+            let (addr, _server) = echo_server().await;
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let (mut write, mut read) = WebSocketConfig::new()
+                .connect(Box::new(tcp.clone()), Box::new(tcp))
+                .await
+                .unwrap();
+            for len in [5, 300, 5000] {
+                let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                write.write_all(&payload).await.unwrap();
+                write.flush().await.unwrap();
+                let mut buf = vec![0; len];
+                read.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, payload);
+            }
+        })
+    }
+
+    #[test]
+    fn other_requests_get_404() {
+        smol::block_on(async {
+            let (addr, server) = echo_server().await;
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let head = read_http_head(&mut tcp).await.unwrap();
+            assert!(head.starts_with("HTTP/1.1 404"));
+            assert!(server.await.is_err());
+        })
+    }
+}